@@ -1,7 +1,12 @@
 use cgmath::EuclideanSpace;
 use gilrs::{Axis, Button, Gilrs};
-use std::{f64::consts::PI, path::PathBuf};
+use std::{
+    f64::consts::PI,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
 use structopt::StructOpt;
+use terra::{CloudLayer, DebugMode, Tonemapping};
 use winit::{
     event,
     event_loop::{ControlFlow, EventLoop},
@@ -17,6 +22,9 @@ struct Opt {
     elevation: f64,
     #[structopt(long)]
     generate: Option<PathBuf>,
+    /// Render a single frame to the given image file and exit, without opening a window.
+    #[structopt(long)]
+    screenshot: Option<PathBuf>,
 }
 
 fn compute_projection_matrix(width: f32, height: f32) -> cgmath::Matrix4<f32> {
@@ -32,6 +40,52 @@ fn compute_projection_matrix(width: f32, height: f32) -> cgmath::Matrix4<f32> {
         0.0,       0.0,  near,  0.0)
 }
 
+/// Compute the view-projection matrix and camera position for looking at the given location.
+fn compute_camera(
+    terrain: &terra::Terrain,
+    lat: f64,
+    long: f64,
+    angle: f64,
+    altitude: f64,
+    width: u32,
+    height: u32,
+) -> (mint::ColumnMatrix4<f32>, mint::Point3<f64>) {
+    let planet_radius = terra::PlanetDesc::EARTH.radius;
+
+    let surface_height = terrain.get_height(lat, long) as f64;
+    let r = altitude + planet_radius + surface_height + 2.0;
+    let eye =
+        cgmath::Point3::new(r * lat.cos() * long.cos(), r * lat.cos() * long.sin(), r * lat.sin());
+
+    let dt = (planet_radius / (planet_radius + altitude)).acos() * 0.3;
+    let latc = lat + angle.cos() * dt;
+    let longc = long - angle.sin() * dt;
+
+    let center = cgmath::Point3::new(
+        planet_radius * latc.cos() * longc.cos() - eye.x,
+        planet_radius * latc.cos() * longc.sin() - eye.y,
+        planet_radius * latc.sin() - eye.z,
+    );
+    let up = cgmath::Vector3::new(eye.x as f32, eye.y as f32, eye.z as f32);
+
+    let view = cgmath::Matrix4::look_at_rh(
+        cgmath::Point3::origin(),
+        cgmath::Point3::new(center.x as f32, center.y as f32, center.z as f32),
+        up,
+    );
+
+    let proj = compute_projection_matrix(width as f32, height as f32);
+    let view_proj = proj * view;
+    let view_proj = mint::ColumnMatrix4 {
+        x: view_proj.x.into(),
+        y: view_proj.y.into(),
+        z: view_proj.z.into(),
+        w: view_proj.w.into(),
+    };
+
+    (view_proj, eye.into())
+}
+
 fn make_swapchain(
     device: &wgpu::Device,
     surface: &wgpu::Surface,
@@ -58,7 +112,7 @@ fn make_depth_buffer(device: &wgpu::Device, width: u32, height: u32) -> wgpu::Te
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
             format: wgpu::TextureFormat::Depth32Float,
-            usage: wgpu::TextureUsage::RENDER_ATTACHMENT,
+            usage: wgpu::TextureUsage::RENDER_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
             label: None,
         })
         .create_view(&Default::default())
@@ -68,6 +122,7 @@ fn main() {
     env_logger::init();
 
     let runtime = tokio::runtime::Runtime::new().unwrap();
+    let opt = Opt::from_args();
 
     let trace_path: Option<&std::path::Path> = if cfg!(feature = "trace") {
         std::fs::create_dir_all("trace").unwrap();
@@ -76,26 +131,37 @@ fn main() {
         None
     };
 
-    let event_loop = EventLoop::new();
-    let monitor = event_loop
-        .available_monitors()
-        .find(|monitor| monitor.video_modes().any(|mode| mode.size().width == 1920));
-    let window = winit::window::WindowBuilder::new()
-        .with_visible(false)
-        .with_fullscreen(Some(winit::window::Fullscreen::Borderless(monitor)))
-        .build(&event_loop)
-        .unwrap();
+    // Screenshots are taken without ever opening a window, so that they also work on machines
+    // without a display.
+    let window = if opt.screenshot.is_none() {
+        let event_loop = EventLoop::new();
+        let monitor = event_loop
+            .available_monitors()
+            .find(|monitor| monitor.video_modes().any(|mode| mode.size().width == 1920));
+        let window = winit::window::WindowBuilder::new()
+            .with_visible(false)
+            .with_fullscreen(Some(winit::window::Fullscreen::Borderless(monitor)))
+            .build(&event_loop)
+            .unwrap();
+        Some((event_loop, window))
+    } else {
+        None
+    };
 
     let instance = wgpu::Instance::new(wgpu::BackendBit::PRIMARY);
-    let surface = unsafe { instance.create_surface(&window) };
+    let surface = window.as_ref().map(|(_, window)| unsafe { instance.create_surface(window) });
     let adapter = runtime
         .block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
             power_preference: wgpu::PowerPreference::HighPerformance,
-            compatible_surface: Some(&surface),
+            compatible_surface: surface.as_ref(),
         }))
         .expect("Unable to create compatible wgpu adapter");
-    let swapchain_format = adapter.get_swap_chain_preferred_format(&surface)
-        .expect("No compatible swapchain formats");
+    let swapchain_format = match surface {
+        Some(ref surface) => adapter
+            .get_swap_chain_preferred_format(surface)
+            .expect("No compatible swapchain formats"),
+        None => wgpu::TextureFormat::Rgba8UnormSrgb,
+    };
 
     // Terra requires support for BC texture compression.
     assert!(adapter.features().contains(wgpu::Features::TEXTURE_COMPRESSION_BC));
@@ -107,7 +173,8 @@ fn main() {
     } else {
         wgpu::Features::TEXTURE_COMPRESSION_BC | wgpu::Features::SHADER_FLOAT64
     };
-    let features = features | adapter.features() & wgpu::Features::MULTI_DRAW_INDIRECT;
+    let features = features
+        | adapter.features() & (wgpu::Features::MULTI_DRAW_INDIRECT | wgpu::Features::TIMESTAMP_QUERY);
 
     let (device, queue) = runtime
         .block_on(adapter.request_device(
@@ -116,37 +183,18 @@ fn main() {
         ))
         .expect("Unable to create compatible wgpu device");
 
-    let mut size = window.inner_size();
-    let mut swap_chain = None;
-    let mut depth_buffer = None;
-
-    #[cfg(feature = "smaa")]
-    let mut smaa_target = smaa::SmaaTarget::new(
-        &device,
-        &queue,
-        size.width,
-        size.height,
-        swapchain_format,
-        smaa::SmaaMode::Smaa1X,
-    );
-
-    let mut gilrs = Gilrs::new().unwrap();
-    let mut current_gamepad = None;
-    for (_id, gamepad) in gilrs.gamepads() {
-        current_gamepad = Some(gamepad.id());
-    }
-
-    let opt = Opt::from_args();
     let plus_center =
         open_location_code::decode(&opt.plus).expect("Failed to parse plus code").center;
 
-    let planet_radius = 6371000.0;
+    let planet_radius = terra::PlanetDesc::EARTH.radius;
     let mut angle = opt.heading.to_radians();
     let mut lat = plus_center.y().to_radians();
     let mut long = plus_center.x().to_radians();
     let mut altitude = opt.elevation;
+    let mut time = SystemTime::now();
 
-    let mut terrain = terra::Terrain::new(&device, &queue).unwrap();
+    let mut terrain =
+        terra::TerrainBuilder::new().color_format(swapchain_format).build(&device, &queue).unwrap();
 
     if let Some(dataset_directory) = opt.generate {
         let pb = indicatif::ProgressBar::new(100);
@@ -195,6 +243,38 @@ fn main() {
         }
     }
 
+    if let Some(path) = opt.screenshot {
+        terrain.set_time(time);
+        let (width, height) = (1920, 1080);
+        let (view_proj, eye) = compute_camera(&terrain, lat, long, angle, altitude, width, height);
+        let image =
+            terrain.render_to_image(&device, &queue, eye, view_proj, (width, height)).unwrap();
+        image.save(path).unwrap();
+        return;
+    }
+
+    let (event_loop, window) = window.unwrap();
+    let surface = surface.unwrap();
+    let mut size = window.inner_size();
+    let mut swap_chain = None;
+    let mut depth_buffer = None;
+
+    #[cfg(feature = "smaa")]
+    let mut smaa_target = smaa::SmaaTarget::new(
+        &device,
+        &queue,
+        size.width,
+        size.height,
+        swapchain_format,
+        smaa::SmaaMode::Smaa1X,
+    );
+
+    let mut gilrs = Gilrs::new().unwrap();
+    let mut current_gamepad = None;
+    for (_id, gamepad) in gilrs.gamepads() {
+        current_gamepad = Some(gamepad.id());
+    }
+
     let mut set_visible = false;
     event_loop.run(move |event, _, control_flow| {
         *control_flow = if cfg!(feature = "metal-auto-capture") {
@@ -237,6 +317,31 @@ fn main() {
                         lat += angle.cos() * -(0.0000001 * altitude).min(0.01);
                         long += -angle.sin() * -(0.0000001 * altitude).min(0.01);
                     }
+                    event::VirtualKeyCode::F1 => terrain.set_debug_mode(DebugMode::None),
+                    event::VirtualKeyCode::F2 => terrain.set_debug_mode(DebugMode::LodLevel),
+                    event::VirtualKeyCode::F3 => terrain.set_debug_mode(DebugMode::NodeBoundaries),
+                    event::VirtualKeyCode::F4 => terrain.set_debug_mode(DebugMode::TextureMipLevel),
+                    event::VirtualKeyCode::F5 => terrain.set_debug_mode(DebugMode::TexelDensity),
+                    event::VirtualKeyCode::F6 => terrain.set_debug_mode(DebugMode::Wireframe),
+                    event::VirtualKeyCode::F7 => {
+                        terrain.set_debug_mode(DebugMode::AncestorFallback)
+                    }
+                    event::VirtualKeyCode::F8 => match terrain.clouds() {
+                        Some(_) => terrain.set_clouds(None),
+                        None => terrain.set_clouds(Some(CloudLayer::default())),
+                    },
+                    event::VirtualKeyCode::F9 => terrain.set_tonemapping(Tonemapping::Aces),
+                    event::VirtualKeyCode::F10 => terrain.set_tonemapping(Tonemapping::Reinhard),
+                    event::VirtualKeyCode::F11 => terrain.set_tonemapping(Tonemapping::AgX),
+                    event::VirtualKeyCode::F12 => terrain.set_tonemapping(Tonemapping::None),
+                    event::VirtualKeyCode::LBracket => time -= Duration::from_secs(3600),
+                    event::VirtualKeyCode::RBracket => time += Duration::from_secs(3600),
+                    event::VirtualKeyCode::Minus => {
+                        terrain.set_exposure_compensation(terrain.exposure_compensation() - 0.5)
+                    }
+                    event::VirtualKeyCode::Equals => {
+                        terrain.set_exposure_compensation(terrain.exposure_compensation() + 0.5)
+                    }
                     _ => {}
                 },
                 event::WindowEvent::Resized(new_size) => {
@@ -294,48 +399,19 @@ fn main() {
                     long -= PI * 2.0;
                 }
 
-                let surface_height = terrain.get_height(lat, long) as f64;
-                let r = altitude + planet_radius + surface_height + 2.0;
-                let eye = cgmath::Point3::new(
-                    r * lat.cos() * long.cos(),
-                    r * lat.cos() * long.sin(),
-                    r * lat.sin(),
-                );
-
-                let dt = (planet_radius / (planet_radius + altitude)).acos() * 0.3;
-                let latc = lat + angle.cos() * dt;
-                let longc = long - angle.sin() * dt;
-
-                let center = cgmath::Point3::new(
-                    planet_radius * latc.cos() * longc.cos() - eye.x,
-                    planet_radius * latc.cos() * longc.sin() - eye.y,
-                    planet_radius * latc.sin() - eye.z,
-                );
-                let up = cgmath::Vector3::new(eye.x as f32, eye.y as f32, eye.z as f32);
-
-                let view = cgmath::Matrix4::look_at_rh(
-                    cgmath::Point3::origin(),
-                    cgmath::Point3::new(center.x as f32, center.y as f32, center.z as f32),
-                    up,
-                );
-
-                let proj = compute_projection_matrix(size.width as f32, size.height as f32);
-                let view_proj = proj * view;
-                let view_proj = mint::ColumnMatrix4 {
-                    x: view_proj.x.into(),
-                    y: view_proj.y.into(),
-                    z: view_proj.z.into(),
-                    w: view_proj.w.into(),
-                };
+                let (view_proj, eye) =
+                    compute_camera(&terrain, lat, long, angle, altitude, size.width, size.height);
 
+                terrain.set_time(time);
                 terrain.render(
                     &device,
                     &queue,
                     &*frame,
+                    None,
                     depth_buffer.as_ref().unwrap(),
                     (size.width, size.height),
                     view_proj,
-                    eye.into(),
+                    eye,
                 );
 
                 if !set_visible {
@@ -9,6 +9,8 @@ use std::path::{Path, PathBuf};
 use std::sync::mpsc::{self, Receiver};
 use std::sync::Mutex;
 use std::time::{Duration, Instant};
+
+#[derive(Clone)]
 pub enum ShaderSource {
     Inline {
         name: &'static str,
@@ -399,7 +401,9 @@ fn reflect(
                 },
                 DescriptorType::Image(_, spirq::ty::Type::Image(ty)) => {
                     let view_dimension = match ty.arng {
-                        ImageArrangement::Image2D => wgpu::TextureViewDimension::D2,
+                        ImageArrangement::Image2D | ImageArrangement::Image2DMS => {
+                            wgpu::TextureViewDimension::D2
+                        }
                         ImageArrangement::Image2DArray => wgpu::TextureViewDimension::D2Array,
                         ImageArrangement::Image3D => wgpu::TextureViewDimension::D3,
                         _ => unimplemented!(),
@@ -429,11 +433,15 @@ fn reflect(
                             },
                         },
                         spirq::ty::ImageUnitFormat::Sampled => wgpu::BindingType::Texture {
-                            multisampled: false,
+                            multisampled: matches!(ty.arng, ImageArrangement::Image2DMS),
                             view_dimension,
                             sample_type: wgpu::TextureSampleType::Float { filterable: true },
                         },
-                        spirq::ty::ImageUnitFormat::Depth => unimplemented!(),
+                        spirq::ty::ImageUnitFormat::Depth => wgpu::BindingType::Texture {
+                            multisampled: matches!(ty.arng, ImageArrangement::Image2DMS),
+                            view_dimension,
+                            sample_type: wgpu::TextureSampleType::Depth,
+                        },
                     }
                 }
                 DescriptorType::StorageBuffer(..) => wgpu::BindingType::Buffer {
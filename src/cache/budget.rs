@@ -0,0 +1,384 @@
+use futures::future::BoxFuture;
+use futures::stream::futures_unordered::FuturesUnordered;
+use futures::task::{waker_ref, ArcWake, AtomicWaker};
+use futures::{Future, FutureExt, StreamExt};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Maximum number of generator invocations that can be timed in a single submission.
+const MAX_TIMED_INVOCATIONS: u32 = 256;
+
+/// How quickly cost estimates adapt to new measurements.
+const SMOOTHING: f64 = 0.1;
+
+/// Cost assumed for a generator before any measurements of it have completed.
+const DEFAULT_ESTIMATE: f64 = 0.0002;
+
+struct TimestampQueries {
+    query_set: wgpu::QuerySet,
+    period: f64,
+}
+
+enum Measurement {
+    /// GPU timestamps written before and after each invocation.
+    Timestamps { buffer: wgpu::Buffer, names: Vec<String>, period: f64 },
+    /// Time from submission until the GPU reported that all submitted work was done.
+    Elapsed { names: Vec<String>, seconds: f64 },
+}
+
+/// Records when a future was last woken. The pending measurements are only polled once per frame,
+/// so the time at which they are found to be complete would include up to a frame of latency.
+struct WakeTime {
+    time: Mutex<Option<Instant>>,
+    waker: AtomicWaker,
+}
+impl ArcWake for WakeTime {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        *arc_self.time.lock().unwrap() = Some(Instant::now());
+        arc_self.waker.wake();
+    }
+}
+
+/// Resolves to the time at which `inner` was woken to complete.
+struct CompletionTime {
+    inner: BoxFuture<'static, ()>,
+    woken: Arc<WakeTime>,
+}
+impl CompletionTime {
+    fn new<F: Future<Output = ()> + Send + 'static>(inner: F) -> Self {
+        let woken = Arc::new(WakeTime { time: Mutex::new(None), waker: AtomicWaker::new() });
+        Self { inner: inner.boxed(), woken }
+    }
+}
+impl Future for CompletionTime {
+    type Output = Instant;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Instant> {
+        let this = &mut *self;
+        this.woken.waker.register(cx.waker());
+        let waker = waker_ref(&this.woken);
+        match this.inner.poll_unpin(&mut Context::from_waker(&waker)) {
+            Poll::Ready(()) => {
+                Poll::Ready(this.woken.time.lock().unwrap().unwrap_or_else(Instant::now))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Limits how much GPU time is spent generating tiles, meshes and textures each frame.
+///
+/// Each generator invocation is charged against a per-frame budget using a running estimate of
+/// its cost. The estimates are refined from GPU timestamp queries when the device supports them,
+/// and otherwise from how long after submission the GPU reports that the work is done.
+pub(crate) struct GenerationBudget {
+    frame_budget: Duration,
+    remaining: f64,
+    reserved_this_frame: bool,
+
+    estimates: HashMap<String, f64>,
+
+    timestamps: Option<TimestampQueries>,
+    timed: Vec<String>,
+    timing: bool,
+    recorded: Option<Measurement>,
+    pending: FuturesUnordered<BoxFuture<'static, Result<Measurement, ()>>>,
+}
+impl GenerationBudget {
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, frame_budget: Duration) -> Self {
+        let timestamps = if device.features().contains(wgpu::Features::TIMESTAMP_QUERY) {
+            Some(TimestampQueries {
+                query_set: device.create_query_set(&wgpu::QuerySetDescriptor {
+                    ty: wgpu::QueryType::Timestamp,
+                    count: MAX_TIMED_INVOCATIONS * 2,
+                }),
+                period: queue.get_timestamp_period() as f64 * 1e-9,
+            })
+        } else {
+            None
+        };
+        Self::with_timestamps(frame_budget, timestamps)
+    }
+
+    fn with_timestamps(frame_budget: Duration, timestamps: Option<TimestampQueries>) -> Self {
+        Self {
+            frame_budget,
+            remaining: frame_budget.as_secs_f64(),
+            reserved_this_frame: false,
+            estimates: HashMap::new(),
+            timestamps,
+            timed: Vec::new(),
+            timing: false,
+            recorded: None,
+            pending: FuturesUnordered::new(),
+        }
+    }
+
+    pub fn frame_budget(&self) -> Duration {
+        self.frame_budget
+    }
+    pub fn set_frame_budget(&mut self, frame_budget: Duration) {
+        self.frame_budget = frame_budget;
+    }
+
+    /// Returns the current estimate of how long a single invocation of `name` takes.
+    pub fn estimate(&self, name: &str) -> Duration {
+        Duration::from_secs_f64(self.estimates.get(name).copied().unwrap_or(DEFAULT_ESTIMATE))
+    }
+
+    /// Incorporate any completed measurements and reset the budget for a new frame.
+    pub fn start_frame(&mut self) {
+        self.poll_pending();
+        self.remaining = self.frame_budget.as_secs_f64();
+        self.reserved_this_frame = false;
+    }
+
+    fn poll_pending(&mut self) {
+        loop {
+            futures::select! {
+                m = self.pending.select_next_some() => {
+                    if let Ok(m) = m {
+                        self.record_measurement(m);
+                    }
+                }
+                default => break,
+                complete => break,
+            }
+        }
+    }
+
+    /// Attempt to charge one invocation of `name` against this frame's budget. The first
+    /// reservation each frame always succeeds so that generation can never stall completely.
+    pub fn try_reserve(&mut self, name: &str) -> bool {
+        let cost = self.estimates.get(name).copied().unwrap_or(DEFAULT_ESTIMATE);
+        if self.reserved_this_frame && cost > self.remaining {
+            return false;
+        }
+        self.reserved_this_frame = true;
+        self.remaining -= cost;
+        true
+    }
+
+    /// Must be called immediately before recording an invocation of `name` into `encoder`.
+    pub fn begin(&mut self, encoder: &mut wgpu::CommandEncoder, name: &str) {
+        if self.timestamps.is_none() {
+            self.timed.push(name.to_owned());
+            return;
+        }
+        if let Some(query) = self.start_timing(name) {
+            encoder.write_timestamp(&self.timestamps.as_ref().unwrap().query_set, query);
+        }
+    }
+
+    /// Must be called immediately after recording an invocation started with `begin`.
+    pub fn end(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        if let Some(query) = self.finish_timing() {
+            encoder.write_timestamp(&self.timestamps.as_ref().unwrap().query_set, query);
+        }
+    }
+
+    /// Returns the query to write before an invocation of `name`, or None if the query set is full.
+    fn start_timing(&mut self, name: &str) -> Option<u32> {
+        self.timing = (self.timed.len() as u32) < MAX_TIMED_INVOCATIONS;
+        if !self.timing {
+            return None;
+        }
+        self.timed.push(name.to_owned());
+        Some(self.timed.len() as u32 * 2 - 2)
+    }
+
+    /// Returns the query to write after the invocation, if its start was timed.
+    fn finish_timing(&mut self) -> Option<u32> {
+        if !std::mem::replace(&mut self.timing, false) {
+            return None;
+        }
+        Some(self.timed.len() as u32 * 2 - 1)
+    }
+
+    /// Record commands to read back the measurements for all invocations recorded since the last
+    /// call. Must be followed by `submitted` once `encoder` has been submitted.
+    pub fn resolve(&mut self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder) {
+        if self.timed.is_empty() {
+            return;
+        }
+        let names = std::mem::take(&mut self.timed);
+        let timestamps = match self.timestamps {
+            Some(ref timestamps) => timestamps,
+            None => {
+                self.recorded = Some(Measurement::Elapsed { names, seconds: 0.0 });
+                return;
+            }
+        };
+
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            size: (names.len() * 2 * 8) as u64,
+            usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::MAP_READ,
+            label: Some("buffer.generate.timestamps"),
+            mapped_at_creation: false,
+        });
+        encoder.resolve_query_set(&timestamps.query_set, 0..(names.len() as u32 * 2), &buffer, 0);
+        self.recorded = Some(Measurement::Timestamps { buffer, names, period: timestamps.period });
+    }
+
+    /// Begin waiting on the measurements recorded by the last `resolve`.
+    pub fn submitted(&mut self, queue: &wgpu::Queue) {
+        match self.recorded.take() {
+            Some(Measurement::Timestamps { buffer, names, period }) => {
+                let map = buffer.slice(..).map_async(wgpu::MapMode::Read);
+                self.pending.push(
+                    map.then(move |result| {
+                        futures::future::ready(match result {
+                            Ok(()) => Ok(Measurement::Timestamps { buffer, names, period }),
+                            Err(_) => Err(()),
+                        })
+                    })
+                    .boxed(),
+                );
+            }
+            Some(Measurement::Elapsed { names, .. }) => {
+                let submitted = Instant::now();
+                let done = CompletionTime::new(queue.on_submitted_work_done());
+                self.pending.push(
+                    done.map(move |done| {
+                        let seconds = done.saturating_duration_since(submitted).as_secs_f64();
+                        Ok(Measurement::Elapsed { names, seconds })
+                    })
+                    .boxed(),
+                );
+
+                // Poll once so that the completion callback can record when it fires.
+                self.poll_pending();
+            }
+            None => {}
+        }
+    }
+
+    fn record_measurement(&mut self, measurement: Measurement) {
+        match measurement {
+            Measurement::Timestamps { buffer, names, period } => {
+                {
+                    let mapped = buffer.slice(..).get_mapped_range();
+                    let timestamps: &[u64] = bytemuck::cast_slice(&*mapped);
+                    for (name, t) in names.iter().zip(timestamps.chunks_exact(2)) {
+                        let seconds = t[1].saturating_sub(t[0]) as f64 * period;
+                        self.update_estimate(name, seconds);
+                    }
+                }
+                buffer.unmap();
+            }
+            Measurement::Elapsed { names, seconds } => self.record_elapsed(&names, seconds),
+        }
+    }
+
+    /// Split the time taken by a submission between its invocations in proportion to their
+    /// current estimates. The time includes any other work that was queued ahead of it, which
+    /// errs on the side of keeping frame times low.
+    fn record_elapsed(&mut self, names: &[String], seconds: f64) {
+        let estimate =
+            |name: &String| self.estimates.get(name).copied().unwrap_or(DEFAULT_ESTIMATE);
+        let total: f64 = names.iter().map(estimate).sum();
+        let shares: Vec<f64> = names.iter().map(|name| seconds * estimate(name) / total).collect();
+        for (name, share) in names.iter().zip(shares) {
+            self.update_estimate(name, share);
+        }
+    }
+
+    fn update_estimate(&mut self, name: &str, seconds: f64) {
+        let estimate = self.estimates.entry(name.to_owned()).or_insert(seconds);
+        *estimate = *estimate * (1.0 - SMOOTHING) + seconds * SMOOTHING;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget(micros: u64) -> GenerationBudget {
+        GenerationBudget::with_timestamps(Duration::from_micros(micros), None)
+    }
+
+    #[test]
+    fn reservations_limited_by_budget() {
+        let mut budget = budget(1100);
+
+        budget.start_frame();
+        let reserved = (0..100).take_while(|_| budget.try_reserve("a")).count();
+        assert_eq!(reserved, 5);
+        assert!(!budget.try_reserve("a"));
+
+        budget.start_frame();
+        assert!(budget.try_reserve("a"));
+    }
+
+    #[test]
+    fn first_reservation_always_succeeds() {
+        let mut budget = budget(1000);
+        budget.update_estimate("slow", 0.5);
+
+        budget.start_frame();
+        assert!(budget.try_reserve("slow"));
+        assert!(!budget.try_reserve("slow"));
+        assert!(!budget.try_reserve("fast"));
+    }
+
+    #[test]
+    fn estimates_follow_measurements() {
+        let mut budget = budget(1000);
+        assert_eq!(budget.estimate("a"), Duration::from_secs_f64(DEFAULT_ESTIMATE));
+
+        budget.update_estimate("a", 0.004);
+        assert!((budget.estimate("a").as_secs_f64() - 0.004).abs() < 1e-9);
+
+        for _ in 0..200 {
+            budget.update_estimate("a", 0.001);
+        }
+        assert!((budget.estimate("a").as_secs_f64() - 0.001).abs() < 1e-6);
+        assert_eq!(budget.estimate("b"), Duration::from_secs_f64(DEFAULT_ESTIMATE));
+    }
+
+    #[test]
+    fn estimates_follow_elapsed_time() {
+        let mut budget = budget(2100);
+        let names: Vec<String> = ["a", "a", "b"].iter().map(|&n| n.to_owned()).collect();
+        for _ in 0..200 {
+            budget.record_elapsed(&names, 0.003);
+        }
+        assert!((budget.estimate("a").as_secs_f64() - 0.001).abs() < 1e-6);
+        assert!((budget.estimate("b").as_secs_f64() - 0.001).abs() < 1e-6);
+
+        budget.start_frame();
+        assert_eq!((0..100).take_while(|_| budget.try_reserve("a")).count(), 2);
+    }
+
+    #[test]
+    fn completion_time_taken_when_woken() {
+        let (sender, receiver) = futures::channel::oneshot::channel::<()>();
+        let mut done = CompletionTime::new(receiver.map(|_| ()));
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        assert!(done.poll_unpin(&mut cx).is_pending());
+
+        let sent = Instant::now();
+        sender.send(()).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        match done.poll_unpin(&mut cx) {
+            Poll::Ready(time) => assert!(time >= sent && time - sent < Duration::from_millis(10)),
+            Poll::Pending => panic!("future should be complete"),
+        }
+    }
+
+    #[test]
+    fn timed_invocations_limited_by_query_set() {
+        let mut budget = budget(1000);
+        for i in 0..MAX_TIMED_INVOCATIONS {
+            assert_eq!(budget.start_timing("a"), Some(i * 2));
+            assert_eq!(budget.finish_timing(), Some(i * 2 + 1));
+        }
+
+        // Invocations past the end of the query set must not overwrite the last timestamp.
+        assert_eq!(budget.start_timing("a"), None);
+        assert_eq!(budget.finish_timing(), None);
+        assert_eq!(budget.timed.len() as u32, MAX_TIMED_INVOCATIONS);
+    }
+}
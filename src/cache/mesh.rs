@@ -2,13 +2,17 @@ use crate::{
     cache::{MeshType, Priority, PriorityCache, PriorityCacheEntry},
     generate::ComputeShader,
     gpu_state::{DrawIndexedIndirect, GpuMeshLayer, GpuState},
-    terrain::quadtree::{QuadTree, VNode},
+    terrain::{
+        quadtree::{QuadTree, Surface, VNode},
+        shadows::{Cascade, Shadows},
+    },
     utils::math::InfiniteFrustum,
+    RenderTargets,
 };
 use cgmath::Vector2;
 use maplit::hashmap;
 use std::mem;
-use std::{collections::HashMap, convert::TryInto};
+use std::{borrow::Cow, collections::HashMap, convert::TryInto};
 use wgpu::util::DeviceExt;
 
 use super::{GeneratorMask, LayerMask, TileCache, UnifiedPriorityCache};
@@ -34,9 +38,24 @@ impl PriorityCacheEntry for Entry {
     }
 }
 
+/// Uniforms passed to the `generate` shader of a mesh layer for each node.
+///
+/// Generator shaders should declare them at binding 0 with the std140 layout:
+///
+/// ```glsl
+/// layout(binding = 0) uniform UniformBlock {
+///     uint texture_slot;   // offset 0: array layer of the ancestor tile in the dependent texture
+///     float texture_step;  // offset 4: scale from node coordinates to texture coordinates
+///     vec2 texture_origin; // offset 8: texture coordinates of the node's corner
+///     uint tile_slot;      // offset 16: array layer of the node in the tile cache
+///     uint output_slot;    // offset 20: first of the 16 mesh entries reserved for the node
+///     uint level;          // offset 24: level of the node
+///     uint padding;        // offset 28
+/// } ubo;
+/// ```
 #[repr(C)]
 #[derive(Copy, Clone)]
-pub(crate) struct MeshGenerateUniforms {
+pub struct MeshGenerateUniforms {
     texture_slot: u32,
     texture_step: f32,
     texture_origin: [f32; 2],
@@ -87,27 +106,57 @@ struct MeshNodeState {
 unsafe impl bytemuck::Zeroable for MeshNodeState {}
 unsafe impl bytemuck::Pod for MeshNodeState {}
 
-pub(crate) struct MeshCacheDesc {
+/// Description of a layer of GPU generated, instanced meshes.
+///
+/// Shaders refer to the layer's buffers as `<name>_storage`, `<name>_indirect` and
+/// `<name>_bounding`. The generate shader writes up to 32x32 entries per draw into storage, each
+/// 64 bytes and starting with a `vec3 position`, which is used to compute bounding spheres for
+/// culling.
+pub struct MeshCacheDesc {
+    pub name: String,
     pub max_bytes_per_entry: u64,
     pub index_buffer: wgpu::Buffer,
-    pub generate: ComputeShader<MeshGenerateUniforms>,
+    /// Number of indices drawn for each storage entry.
+    pub indices_per_entry: u32,
+    /// Compute shader run for each node with `MeshGenerateUniforms`.
+    pub generate: rshader::ShaderSource,
+    /// Shaders that draw the meshes into the HDR target. Fragment shaders should output linear
+    /// radiance multiplied by the value of the `exposure` storage buffer.
     pub render: rshader::ShaderSet,
+    /// Shaders used to draw the meshes into the shadow cascades, or None if they don't cast
+    /// shadows. The fragment shader needn't output anything.
+    pub shadow: Option<rshader::ShaderSet>,
+    /// Number of workgroups in each dimension when running `generate`.
     pub dimensions: u32,
+    /// Layers that must be present for a node before meshes can be generated for it.
     pub dependency_mask: LayerMask,
     pub min_level: u8,
     pub max_level: u8,
     pub ty: MeshType,
+    /// Maximum number of nodes to generate meshes for at once.
     pub size: usize,
 }
 
 pub(crate) struct MeshCache {
     pub(super) inner: PriorityCache<Entry>,
     pub(super) desc: MeshCacheDesc,
+    pub(super) generate: ComputeShader<MeshGenerateUniforms>,
 
     nodes: wgpu::Buffer,
     bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::RenderPipeline)>,
 
-    compute_bounds: ComputeShader<u32>,
+    compute_bounds: ComputeShader<[u32; 2]>,
+    cull: ComputeShader<CullMeshUniforms>,
+
+    /// State for drawing into each shadow cascade, created as needed.
+    shadow_passes: Vec<ShadowPass>,
+}
+
+/// Culling results and pipeline for drawing a mesh layer into one shadow cascade.
+struct ShadowPass {
+    indirect: wgpu::Buffer,
+    cull: ComputeShader<CullMeshUniforms>,
+    bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::RenderPipeline)>,
 }
 impl MeshCache {
     pub(super) fn new(device: &wgpu::Device, desc: MeshCacheDesc) -> Self {
@@ -115,10 +164,50 @@ impl MeshCache {
             size: (mem::size_of::<MeshNodeState>() * desc.size) as u64,
             usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
             mapped_at_creation: false,
-            label: Some("grass.nodes_buffer"),
+            label: Some(&format!("{}.nodes_buffer", desc.name)),
         });
-        let compute_bounds = ComputeShader::new(rshader::shader_source!("../shaders", "bounding-sphere.comp", "declarations.glsl"), "bounding-sphere".to_owned());
-        Self { inner: PriorityCache::new(desc.size), desc, nodes, bindgroup_pipeline: None, compute_bounds }
+        let generate = ComputeShader::new(desc.generate.clone(), format!("gen-{}", desc.name));
+        let compute_bounds = ComputeShader::new(rshader::shader_source!("../shaders", "bounding-sphere.comp", "declarations.glsl"), format!("bounding-sphere.{}", desc.name));
+        let cull = Self::make_cull_shader(format!("cull-meshes.{}", desc.name));
+        Self {
+            inner: PriorityCache::new(desc.size),
+            desc,
+            generate,
+            nodes,
+            bindgroup_pipeline: None,
+            compute_bounds,
+            cull,
+            shadow_passes: Vec::new(),
+        }
+    }
+
+    fn make_cull_shader(name: String) -> ComputeShader<CullMeshUniforms> {
+        ComputeShader::new(
+            rshader::shader_source!(
+                "../shaders",
+                "cull-meshes.comp",
+                "declarations.glsl",
+                "hiz.glsl"
+            ),
+            name,
+        )
+    }
+
+    /// Buffers of this layer, bound under generic names for the shared culling and bounding shaders.
+    fn mesh_buffers<'a>(
+        &self,
+        gpu_state: &'a GpuState,
+    ) -> HashMap<Cow<'static, str>, (bool, wgpu::BindingResource<'a>)> {
+        let layer = &gpu_state.mesh_cache[self.desc.ty];
+        let binding = |buffer: &'a wgpu::Buffer| {
+            let resource = wgpu::BufferBinding { buffer, offset: 0, size: None };
+            (false, wgpu::BindingResource::Buffer(resource))
+        };
+        hashmap![
+            "mesh_indirect".into() => binding(&layer.indirect),
+            "mesh_bounding".into() => binding(&layer.bounding),
+            "mesh_storage".into() => binding(&layer.storage),
+        ]
     }
 
     pub(super) fn make_buffers(&self, device: &wgpu::Device) -> GpuMeshLayer {
@@ -126,9 +215,10 @@ impl MeshCache {
             size: (mem::size_of::<DrawIndexedIndirect>() * self.inner.size() * 16) as u64,
             usage: wgpu::BufferUsage::STORAGE
                 | wgpu::BufferUsage::INDIRECT
+                | wgpu::BufferUsage::COPY_SRC
                 | wgpu::BufferUsage::COPY_DST,
             mapped_at_creation: true,
-            label: Some("grass.indirect"),
+            label: Some(&format!("{}.indirect", self.desc.name)),
         });
         for b in &mut *indirect.slice(..).get_mapped_range_mut() {
             *b = 0;
@@ -141,7 +231,7 @@ impl MeshCache {
                 | wgpu::BufferUsage::STORAGE
                 | wgpu::BufferUsage::COPY_DST,
             mapped_at_creation: true,
-            label: Some("grass.bounding"),
+            label: Some(&format!("{}.bounding", self.desc.name)),
         });
         for b in &mut *bounding.slice(..).get_mapped_range_mut() {
             *b = 0;
@@ -155,7 +245,7 @@ impl MeshCache {
                 size: self.desc.max_bytes_per_entry * self.inner.size() as u64,
                 usage: wgpu::BufferUsage::STORAGE,
                 mapped_at_creation: false,
-                label: Some("grass.storage"),
+                label: Some(&format!("{}.storage", self.desc.name)),
             }),
         }
     }
@@ -170,7 +260,7 @@ impl MeshCache {
 
         // Find any tiles that may need to be added.
         let mut missing = Vec::new();
-        VNode::breadth_first(|node| {
+        VNode::breadth_first(quadtree.surface(), |node| {
             let priority = quadtree.node_priority(node);
             if priority < Priority::cutoff() {
                 return false;
@@ -196,13 +286,14 @@ impl MeshCache {
     ) {
         let mut generated = Vec::new();
         let mut command_buffers = Vec::new();
-        for mesh_type in MeshType::iter() {
-            let m = &mut cache.meshes[mesh_type];
+        for m in cache.meshes.values_mut() {
+            let mesh_type = m.desc.ty;
 
             let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                label: Some(&format!("{}.command_encoder", mesh_type.name())),
+                label: Some(&format!("{}.command_encoder", m.desc.name)),
             });
 
+            let budget_name = format!("mesh.{}", m.desc.name);
             let mut zero_buffer = None;
             'outer: for (index, entry) in m.inner.slots_mut().into_iter().enumerate() {
                 if entry.valid || entry.priority < Priority::cutoff() {
@@ -239,11 +330,15 @@ impl MeshCache {
                 }
                 let texture_slot = texture_slot.unwrap_or((0, 0.0, [0., 0.]));
 
+                if !cache.budget.try_reserve(&budget_name) {
+                    break;
+                }
+
                 if zero_buffer.is_none() {
                     zero_buffer =
                         Some(device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                             usage: wgpu::BufferUsage::COPY_SRC,
-                            label: Some(&format!("{}.clear_indirect.tmp", mesh_type.name())),
+                            label: Some(&format!("{}.clear_indirect.tmp", m.desc.name)),
                             contents: &vec![0; mem::size_of::<DrawIndexedIndirect>() * 16],
                         }));
                 }
@@ -256,7 +351,8 @@ impl MeshCache {
                     mem::size_of::<DrawIndexedIndirect>() as u64 * 16,
                 );
 
-                m.desc.generate.run(
+                cache.budget.begin(&mut encoder, &budget_name);
+                m.generate.run(
                     device,
                     &mut encoder,
                     gpu_state,
@@ -271,49 +367,155 @@ impl MeshCache {
                         padding: 0,
                     },
                 );
-                m.compute_bounds.run(device, &mut encoder, gpu_state, (16, 1, 1), &(index as u32 * 16));
+                let buffers = m.mesh_buffers(gpu_state);
+                m.compute_bounds.run_with_buffers(
+                    device,
+                    &mut encoder,
+                    gpu_state,
+                    (16, 1, 1),
+                    &[index as u32 * 16, m.desc.indices_per_entry],
+                    buffers,
+                );
+                cache.budget.end(&mut encoder);
 
                 entry.valid = true;
                 generated.push((mesh_type, entry.node));
             }
             command_buffers.push(encoder.finish());
         }
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("encoder.meshes.resolve_timing"),
+        });
+        cache.budget.resolve(device, &mut encoder);
+        command_buffers.push(encoder.finish());
+
         for (mesh_type, node) in generated {
             cache.meshes[mesh_type].inner.entry_mut(&node).unwrap().generators =
                 cache.generator_dependencies(node, cache.meshes[mesh_type].desc.dependency_mask);
         }
 
         queue.submit(command_buffers);
+        cache.budget.submitted(queue);
     }
 
     pub fn cull_meshes<'a>(
-        &self,
+        &mut self,
         device: &wgpu::Device,
         encoder: &mut wgpu::CommandEncoder,
         gpu_state: &GpuState,
         tile_cache: &TileCache,
+        surface: &Surface,
         camera: mint::Point3<f64>,
         frustum: &InfiniteFrustum,
-        cull_shader: &mut ComputeShader<CullMeshUniforms>,
     ) {
+        let cull_ubo = self.cull_uniforms(tile_cache, surface, camera, frustum);
+        self.cull.refresh();
+        let buffers = self.mesh_buffers(gpu_state);
+        self.cull.run_with_buffers(
+            device,
+            encoder,
+            &gpu_state,
+            ((self.desc.size as u32 * 16 + 63) / 64, 1, 1),
+            &cull_ubo,
+            buffers,
+        );
+    }
+
+    /// Like `cull_meshes`, but culls against shadow cascade `index` and writes the results to a
+    /// separate indirect buffer so that the main pass is unaffected.
+    pub fn cull_shadow_meshes(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        gpu_state: &GpuState,
+        tile_cache: &TileCache,
+        surface: &Surface,
+        camera: mint::Point3<f64>,
+        index: usize,
+        cascade: &Cascade,
+    ) {
+        if self.desc.shadow.is_none() {
+            return;
+        }
+        while self.shadow_passes.len() <= index {
+            let i = self.shadow_passes.len();
+            self.shadow_passes.push(ShadowPass {
+                indirect: device.create_buffer(&wgpu::BufferDescriptor {
+                    size: (mem::size_of::<DrawIndexedIndirect>() * self.inner.size() * 16) as u64,
+                    usage: wgpu::BufferUsage::STORAGE
+                        | wgpu::BufferUsage::INDIRECT
+                        | wgpu::BufferUsage::COPY_DST,
+                    mapped_at_creation: false,
+                    label: Some(&format!("{}.shadow{}.indirect", self.desc.name, i)),
+                }),
+                cull: Self::make_cull_shader(format!("cull-meshes.{}.shadow{}", self.desc.name, i)),
+                bindgroup_pipeline: None,
+            });
+        }
+
+        // The draw arguments are written by the generate shader, so start from a copy of them and
+        // only let culling decide the instance counts.
+        encoder.copy_buffer_to_buffer(
+            &gpu_state.mesh_cache[self.desc.ty].indirect,
+            0,
+            &self.shadow_passes[index].indirect,
+            0,
+            (mem::size_of::<DrawIndexedIndirect>() * self.inner.size() * 16) as u64,
+        );
+
+        let cull_ubo = self.cull_uniforms(tile_cache, surface, camera, &cascade.caster_volume);
+        let mut buffers = self.mesh_buffers(gpu_state);
+        let binding = |buffer| {
+            let resource = wgpu::BufferBinding { buffer, offset: 0, size: None };
+            (false, wgpu::BindingResource::Buffer(resource))
+        };
+        let pass = &mut self.shadow_passes[index];
+        buffers.insert("mesh_indirect".into(), binding(&pass.indirect));
+        buffers.insert("globals".into(), binding(&cascade.globals));
+        pass.cull.refresh();
+        pass.cull.run_with_buffers(
+            device,
+            encoder,
+            &gpu_state,
+            ((self.desc.size as u32 * 16 + 63) / 64, 1, 1),
+            &cull_ubo,
+            buffers,
+        );
+    }
+
+    fn cull_uniforms(
+        &self,
+        tile_cache: &TileCache,
+        surface: &Surface,
+        camera: mint::Point3<f64>,
+        frustum: &InfiniteFrustum,
+    ) -> CullMeshUniforms {
         let mut cull_ubo = CullMeshUniforms::default();
         cull_ubo.num_nodes = self.desc.size as u32;
         for (i, entry) in self.inner.slots().into_iter().enumerate() {
             cull_ubo.nodes[i] = (
-                (cgmath::Point3::from(camera) - entry.node.center_wspace())
+                (cgmath::Point3::from(camera) - entry.node.center_wspace(surface))
                     .cast::<f32>()
                     .unwrap()
                     .into(),
-                    (entry.valid && entry.priority > Priority::cutoff() && entry.node.in_frustum(frustum, tile_cache.get_height_range(entry.node))) as u32,
+                (entry.valid
+                    && entry.priority > Priority::cutoff()
+                    && entry.node.in_frustum(
+                        surface,
+                        frustum,
+                        tile_cache.get_height_range(entry.node),
+                    )) as u32,
             );
         }
-        cull_shader.run(
-            device,
-            encoder,
-            &gpu_state,
-            ((self.desc.size as u32 * 16 + 63) / 64, 1, 1),
-            &cull_ubo,
-        );
+        cull_ubo
+    }
+
+    /// Recreate the culling bind groups, which refer to the depth pyramid.
+    pub(super) fn reset_cull_bindings(&mut self) {
+        self.cull.reset_bindings();
+        for pass in &mut self.shadow_passes {
+            pass.cull.reset_bindings();
+        }
     }
 
     pub fn render<'a>(
@@ -322,6 +524,8 @@ impl MeshCache {
         queue: &'a wgpu::Queue,
         rpass: &mut wgpu::RenderPass<'a>,
         gpu_state: &'a GpuState,
+        targets: &RenderTargets,
+        surface: &Surface,
         camera: mint::Point3<f64>,
     ) {
         if self.desc.render.refresh() {
@@ -339,13 +543,13 @@ impl MeshCache {
                     }))
                 ],
                 HashMap::new(),
-                "grass",
+                &self.desc.name,
             );
             let render_pipeline_layout =
                 device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                     bind_group_layouts: &[&bind_group_layout],
                     push_constant_ranges: &[],
-                    label: Some("grass.pipeline_layout"),
+                    label: Some(&format!("{}.pipeline_layout", self.desc.name)),
                 });
             self.bindgroup_pipeline = Some((
                 bind_group,
@@ -353,7 +557,7 @@ impl MeshCache {
                     layout: Some(&render_pipeline_layout),
                     vertex: wgpu::VertexState {
                         module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
-                            label: Some("grass.vertex_shader"),
+                            label: Some(&format!("{}.vertex_shader", self.desc.name)),
                             source: wgpu::ShaderSource::SpirV(self.desc.render.vertex().into()),
                             flags: wgpu::ShaderFlags::empty(),
                         }),
@@ -362,30 +566,19 @@ impl MeshCache {
                     },
                     fragment: Some(wgpu::FragmentState {
                         module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
-                            label: Some("grass.fragment_shader"),
+                            label: Some(&format!("{}.fragment_shader", self.desc.name)),
                             source: wgpu::ShaderSource::SpirV(self.desc.render.fragment().into()),
                             flags: wgpu::ShaderFlags::empty(),
                         }),
                         entry_point: "main",
-                        targets: &[wgpu::ColorTargetState {
-                            format: wgpu::TextureFormat::Bgra8UnormSrgb,
-                            blend: Some(wgpu::BlendState {
-                                color: wgpu::BlendComponent::REPLACE,
-                                alpha: wgpu::BlendComponent::REPLACE,
-                            }),
-                            write_mask: wgpu::ColorWrite::ALL,
-                        }],
+                        targets: &[targets.hdr_target()],
                     }),
                     primitive: Default::default(),
-                    depth_stencil: Some(wgpu::DepthStencilState {
-                        format: wgpu::TextureFormat::Depth32Float,
-                        depth_write_enabled: true,
-                        depth_compare: wgpu::CompareFunction::Greater,
-                        bias: Default::default(),
-                        stencil: Default::default(),
-                    }),
-                    multisample: Default::default(),
-                    label: Some("grass.render_pipeline"),
+                    depth_stencil: Some(
+                        targets.depth_stencil(true, wgpu::CompareFunction::Greater),
+                    ),
+                    multisample: targets.multisample(),
+                    label: Some(&format!("{}.render_pipeline", self.desc.name)),
                 }),
             ));
         }
@@ -400,16 +593,16 @@ impl MeshCache {
             // .filter_map(|(i, e)| tile_cache.get_slot(e.node).map(|s| (i, s, e)))
             // .map(|(i, j, &Entry { node, .. })| MeshNodeState {
             .map(|(i, &Entry { node, .. })| MeshNodeState {
-                relative_position: (cgmath::Point3::from(camera) - node.center_wspace())
+                relative_position: (cgmath::Point3::from(camera) - node.center_wspace(surface))
                     .cast::<f32>()
                     .unwrap()
                     .into(),
                 parent_relative_position: (cgmath::Point3::from(camera)
-                    - node.parent().map(|x| x.0).unwrap_or(node).center_wspace())
+                    - node.parent().map(|x| x.0).unwrap_or(node).center_wspace(surface))
                 .cast::<f32>()
                 .unwrap()
                 .into(),
-                min_distance: node.min_distance() as f32,
+                min_distance: node.min_distance(surface) as f32,
                 slot: i as u32,
                 face: node.face() as u32,
                 //tile_slot: j as u32,
@@ -427,15 +620,112 @@ impl MeshCache {
                 &self.bindgroup_pipeline.as_ref().unwrap().0,
                 &[],
             );
-            if device.features().contains(wgpu::Features::MULTI_DRAW_INDIRECT) {
-                rpass.multi_draw_indexed_indirect(&gpu_state.mesh_cache[self.desc.ty].indirect, 0, nodes.len() as u32 * 16);
-            } else {
-                for i in 0..(nodes.len() * 16) {
-                    rpass.draw_indexed_indirect(
-                        &gpu_state.mesh_cache[self.desc.ty].indirect,
-                        i as u64 * mem::size_of::<DrawIndexedIndirect>() as u64,
-                    );
-                }
+            Self::draw_indirect(
+                device,
+                rpass,
+                &gpu_state.mesh_cache[self.desc.ty].indirect,
+                nodes.len() as u32 * 16,
+            );
+        }
+    }
+
+    /// Draw the meshes into shadow cascade `index`, using the results of the last call to
+    /// `cull_shadow_meshes`. Relies on `render` having uploaded the node states for this frame.
+    pub fn render_shadow<'a>(
+        &'a mut self,
+        device: &wgpu::Device,
+        rpass: &mut wgpu::RenderPass<'a>,
+        gpu_state: &GpuState,
+        index: usize,
+        cascade: &Cascade,
+    ) {
+        let shader = match self.desc.shadow {
+            Some(ref mut shader) => shader,
+            None => return,
+        };
+        let pass = match self.shadow_passes.get_mut(index) {
+            Some(pass) => pass,
+            None => return,
+        };
+        if shader.refresh() {
+            pass.bindgroup_pipeline = None;
+        }
+        if pass.bindgroup_pipeline.is_none() {
+            let name = format!("{}.shadow{}", self.desc.name, index);
+            let binding = |buffer| {
+                let resource = wgpu::BufferBinding { buffer, offset: 0, size: None };
+                (false, wgpu::BindingResource::Buffer(resource))
+            };
+            let (bind_group, bind_group_layout) = gpu_state.bind_group_for_shader(
+                device,
+                shader,
+                hashmap![
+                    "nodes".into() => binding(&self.nodes),
+                    "globals".into() => binding(&cascade.globals),
+                ],
+                HashMap::new(),
+                &name,
+            );
+            let render_pipeline_layout =
+                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    bind_group_layouts: &[&bind_group_layout],
+                    push_constant_ranges: &[],
+                    label: Some(&format!("{}.pipeline_layout", name)),
+                });
+            pass.bindgroup_pipeline = Some((
+                bind_group,
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    layout: Some(&render_pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                            label: Some(&format!("{}.vertex_shader", name)),
+                            source: wgpu::ShaderSource::SpirV(shader.vertex().into()),
+                            flags: wgpu::ShaderFlags::empty(),
+                        }),
+                        entry_point: "main",
+                        buffers: &[],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                            label: Some(&format!("{}.fragment_shader", name)),
+                            source: wgpu::ShaderSource::SpirV(shader.fragment().into()),
+                            flags: wgpu::ShaderFlags::empty(),
+                        }),
+                        entry_point: "main",
+                        targets: &[],
+                    }),
+                    primitive: Default::default(),
+                    depth_stencil: Some(Shadows::depth_stencil_state()),
+                    multisample: Default::default(),
+                    label: Some(&format!("{}.render_pipeline", name)),
+                }),
+            ));
+        }
+
+        let num_nodes = self.inner.slots().len() as u32;
+        if num_nodes > 0 {
+            let (bind_group, pipeline) = pass.bindgroup_pipeline.as_ref().unwrap();
+            rpass.set_pipeline(pipeline);
+            rpass.set_index_buffer(self.desc.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+            rpass.set_bind_group(0, bind_group, &[]);
+            Self::draw_indirect(device, rpass, &pass.indirect, num_nodes * 16);
+        }
+    }
+
+    fn draw_indirect<'a>(
+        device: &wgpu::Device,
+        rpass: &mut wgpu::RenderPass<'a>,
+        indirect: &'a wgpu::Buffer,
+        count: u32,
+    ) {
+        if device.features().contains(wgpu::Features::MULTI_DRAW_INDIRECT) {
+            rpass.multi_draw_indexed_indirect(indirect, 0, count);
+        } else {
+            for i in 0..count {
+                rpass.draw_indexed_indirect(
+                    indirect,
+                    i as u64 * mem::size_of::<DrawIndexedIndirect>() as u64,
+                );
             }
         }
     }
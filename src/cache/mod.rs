@@ -1,18 +1,26 @@
+mod budget;
 mod mesh;
 mod texture;
 mod tile;
 
+use budget::GenerationBudget;
 use cgmath::Vector2;
-pub(crate) use mesh::{MeshCache, MeshCacheDesc};
+pub(crate) use mesh::MeshCache;
+pub use mesh::{MeshCacheDesc, MeshGenerateUniforms};
 pub(crate) use texture::{SingularLayerCache, SingularLayerDesc};
-pub(crate) use tile::{LayerParams, TextureFormat, TileCache};
+pub(crate) use tile::{LayerParams, TileCache};
+pub use tile::{CustomLayerDesc, TextureFormat};
 
 use crate::{
-    generate::{ComputeShader, GenerateTile},
+    generate::GenerateTile,
     gpu_state::{GpuMeshLayer, GpuState},
     mapfile::MapFile,
-    terrain::quadtree::{QuadTree, VNode},
+    terrain::{
+        quadtree::{QuadTree, Surface, VNode},
+        shadows::Cascade,
+    },
     utils::math::InfiniteFrustum,
+    RenderTargets,
 };
 use serde::{Deserialize, Serialize};
 use std::hash::Hash;
@@ -21,20 +29,46 @@ use std::{
     cmp::{Eq, Ord, PartialOrd},
     sync::Arc,
 };
-use std::{collections::HashMap, num::NonZeroU32};
+use std::{
+    collections::HashMap,
+    num::{NonZeroU32, NonZeroU64},
+    time::Duration,
+};
 use vec_map::VecMap;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) enum LayerType {
-    Displacements = 0,
-    Albedo = 1,
-    Roughness = 2,
-    Normals = 3,
-    Heightmaps = 4,
+/// Maximum number of user-defined tile layers.
+pub const MAX_CUSTOM_LAYERS: usize = 16;
+
+const NUM_BUILTIN_LAYERS: usize = 7;
+
+/// Identifies a tile layer. Layers registered through `CustomLayerDesc` are assigned
+/// `LayerType::Custom(i)` in the order they are provided.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LayerType {
+    Displacements,
+    Albedo,
+    Roughness,
+    Normals,
+    Heightmaps,
+    /// Elevation of the horizon in four directions, used to shadow terrain beyond the reach of
+    /// the shadow maps.
+    Horizons,
+    /// Direction that lakes and rivers flow in, used to animate their surface.
+    Water,
+    Custom(u8),
 }
 impl LayerType {
     pub fn index(&self) -> usize {
-        *self as usize
+        match *self {
+            LayerType::Displacements => 0,
+            LayerType::Albedo => 1,
+            LayerType::Roughness => 2,
+            LayerType::Normals => 3,
+            LayerType::Heightmaps => 4,
+            LayerType::Horizons => 5,
+            LayerType::Water => 6,
+            LayerType::Custom(i) => NUM_BUILTIN_LAYERS + i as usize,
+        }
     }
     pub fn from_index(i: usize) -> Self {
         match i {
@@ -43,75 +77,92 @@ impl LayerType {
             2 => LayerType::Roughness,
             3 => LayerType::Normals,
             4 => LayerType::Heightmaps,
+            5 => LayerType::Horizons,
+            6 => LayerType::Water,
+            i if i < NUM_BUILTIN_LAYERS + MAX_CUSTOM_LAYERS => {
+                LayerType::Custom((i - NUM_BUILTIN_LAYERS) as u8)
+            }
             _ => unreachable!(),
         }
     }
     pub fn bit_mask(&self) -> LayerMask {
         (*self).into()
     }
-    pub fn name(&self) -> &'static str {
+    /// Name of built-in layers. Custom layers are named by their `LayerParams`.
+    pub(crate) fn name(&self) -> &'static str {
         match *self {
             LayerType::Displacements => "displacements",
             LayerType::Albedo => "albedo",
             LayerType::Roughness => "roughness",
             LayerType::Normals => "normals",
             LayerType::Heightmaps => "heightmaps",
+            LayerType::Horizons => "horizons",
+            LayerType::Water => "water",
+            LayerType::Custom(_) => "custom",
         }
     }
+    /// Whether a custom layer can't be called `name` because its tiles would be stored in the same
+    /// directory as those of a built-in layer or the tile metadata.
+    pub(crate) fn is_reserved_name(name: &str) -> bool {
+        name.eq_ignore_ascii_case("meta")
+            || (0..NUM_BUILTIN_LAYERS)
+                .any(|i| Self::from_index(i).name().eq_ignore_ascii_case(name))
+    }
+
     fn iter() -> impl Iterator<Item = Self> {
-        (0..=4).map(Self::from_index)
+        (0..(NUM_BUILTIN_LAYERS + MAX_CUSTOM_LAYERS)).map(Self::from_index)
     }
 }
 impl<T> Index<LayerType> for VecMap<T> {
     type Output = T;
     fn index(&self, i: LayerType) -> &Self::Output {
-        &self[i as usize]
+        &self[i.index()]
     }
 }
 impl<T> IndexMut<LayerType> for VecMap<T> {
     fn index_mut(&mut self, i: LayerType) -> &mut Self::Output {
-        &mut self[i as usize]
+        &mut self[i.index()]
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) enum MeshType {
-    Grass = 0,
+/// Maximum number of user-defined mesh layers.
+pub const MAX_CUSTOM_MESHES: usize = 7;
+
+/// Identifies a mesh layer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum MeshType {
+    Grass,
+    Custom(u8),
 }
 impl MeshType {
-    pub fn name(&self) -> &'static str {
+    pub fn index(&self) -> usize {
         match *self {
-            MeshType::Grass => "grass",
+            MeshType::Grass => 0,
+            MeshType::Custom(i) => 1 + i as usize,
         }
     }
-    fn from_index(i: usize) -> Self {
-        match i {
-            0 => MeshType::Grass,
-            _ => unreachable!(),
-        }
-    }
-    fn iter() -> impl Iterator<Item = Self> {
-        (0..=0).map(Self::from_index)
+    pub fn bit_mask(&self) -> LayerMask {
+        (*self).into()
     }
 }
 impl<T> Index<MeshType> for VecMap<T> {
     type Output = T;
     fn index(&self, i: MeshType) -> &Self::Output {
-        &self[i as usize]
+        &self[i.index()]
     }
 }
 impl<T> IndexMut<MeshType> for VecMap<T> {
     fn index_mut(&mut self, i: MeshType) -> &mut Self::Output {
-        &mut self[i as usize]
+        &mut self[i.index()]
     }
 }
 
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
-pub(crate) enum SingularLayerType {
+pub enum SingularLayerType {
     GrassCanopy = 0,
 }
 impl SingularLayerType {
-    pub fn name(&self) -> &'static str {
+    pub(crate) fn name(&self) -> &'static str {
         match *self {
             SingularLayerType::GrassCanopy => "grass_canopy",
         }
@@ -141,24 +192,28 @@ impl<T> IndexMut<SingularLayerType> for VecMap<T> {
     }
 }
 
+/// Set of tile layers, mesh layers and singular textures.
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
-pub(crate) struct LayerMask(NonZeroU32);
+pub struct LayerMask(NonZeroU64);
 impl LayerMask {
-    const VALID: u32 = 0x80000000;
+    const VALID: u64 = 0x8000000000000000;
+
+    const MESH_SHIFT: usize = 32;
+    const TEXTURE_SHIFT: usize = 40;
 
     pub fn empty() -> Self {
-        Self(NonZeroU32::new(Self::VALID).unwrap())
+        Self(NonZeroU64::new(Self::VALID).unwrap())
     }
     pub fn all_tiles() -> Self {
-        Self(NonZeroU32::new(Self::VALID | 0x0000ff).unwrap())
+        Self(NonZeroU64::new(Self::VALID | 0xffffffff).unwrap())
     }
     #[allow(unused)]
     pub fn all_meshes() -> Self {
-        Self(NonZeroU32::new(Self::VALID | 0x00ff00).unwrap())
+        Self(NonZeroU64::new(Self::VALID | 0xff << Self::MESH_SHIFT).unwrap())
     }
     #[allow(unused)]
     pub fn all_textures() -> Self {
-        Self(NonZeroU32::new(Self::VALID | 0xff0000).unwrap())
+        Self(NonZeroU64::new(Self::VALID | 0xff << Self::TEXTURE_SHIFT).unwrap())
     }
 
     pub fn intersects(&self, other: Self) -> bool {
@@ -166,35 +221,35 @@ impl LayerMask {
     }
 
     pub fn contains_tile(&self, t: LayerType) -> bool {
-        assert!((t as usize) < 8);
-        self.0.get() & (1 << (t as usize)) != 0
+        assert!(t.index() < Self::MESH_SHIFT);
+        self.0.get() & (1 << t.index()) != 0
     }
     #[allow(unused)]
     pub fn contains_mesh(&self, t: MeshType) -> bool {
-        assert!((t as usize) < 8);
-        self.0.get() & (1 << (t as usize + 8)) != 0
+        assert!(t.index() < 8);
+        self.0.get() & (1 << (t.index() + Self::MESH_SHIFT)) != 0
     }
     pub fn contains_texture(&self, t: SingularLayerType) -> bool {
         assert!((t as usize) < 8);
-        self.0.get() & (1 << (t as usize + 16)) != 0
+        self.0.get() & (1 << (t as usize + Self::TEXTURE_SHIFT)) != 0
     }
 }
 impl From<LayerType> for LayerMask {
     fn from(t: LayerType) -> Self {
-        assert!((t as usize) < 8);
-        Self(NonZeroU32::new(Self::VALID | (1 << (t as usize))).unwrap())
+        assert!(t.index() < Self::MESH_SHIFT);
+        Self(NonZeroU64::new(Self::VALID | (1 << t.index())).unwrap())
     }
 }
 impl From<MeshType> for LayerMask {
     fn from(t: MeshType) -> Self {
-        assert!((t as usize) < 8);
-        Self(NonZeroU32::new(Self::VALID | (1 << (t as usize + 8))).unwrap())
+        assert!(t.index() < 8);
+        Self(NonZeroU64::new(Self::VALID | (1 << (t.index() + Self::MESH_SHIFT))).unwrap())
     }
 }
 impl From<SingularLayerType> for LayerMask {
     fn from(t: SingularLayerType) -> Self {
         assert!((t as usize) < 8);
-        Self(NonZeroU32::new(Self::VALID | (1 << (t as usize + 16))).unwrap())
+        Self(NonZeroU64::new(Self::VALID | (1 << (t as usize + Self::TEXTURE_SHIFT))).unwrap())
     }
 }
 impl std::ops::BitOr for LayerMask {
@@ -211,18 +266,18 @@ impl std::ops::BitOrAssign for LayerMask {
 impl std::ops::BitAnd for LayerMask {
     type Output = Self;
     fn bitand(self, rhs: Self) -> Self {
-        Self(NonZeroU32::new(Self::VALID | (self.0.get() & rhs.0.get())).unwrap())
+        Self(NonZeroU64::new(Self::VALID | (self.0.get() & rhs.0.get())).unwrap())
     }
 }
 impl std::ops::BitAndAssign for LayerMask {
     fn bitand_assign(&mut self, rhs: Self) {
-        self.0 = NonZeroU32::new(Self::VALID | (self.0.get() & rhs.0.get())).unwrap();
+        self.0 = NonZeroU64::new(Self::VALID | (self.0.get() & rhs.0.get())).unwrap();
     }
 }
 impl std::ops::Not for LayerMask {
     type Output = Self;
     fn not(self) -> Self {
-        Self(NonZeroU32::new(Self::VALID | !self.0.get()).unwrap())
+        Self(NonZeroU64::new(Self::VALID | !self.0.get()).unwrap())
     }
 }
 
@@ -394,32 +449,40 @@ pub(crate) struct UnifiedPriorityCache {
     meshes: VecMap<MeshCache>,
     textures: VecMap<SingularLayerCache>,
 
-    cull_shader: ComputeShader<mesh::CullMeshUniforms>,
+    budget: GenerationBudget,
 }
 
 impl UnifiedPriorityCache {
     pub fn new(
         device: &wgpu::Device,
+        queue: &wgpu::Queue,
         mapfile: Arc<MapFile>,
         size: usize,
         generators: Vec<Box<dyn GenerateTile>>,
+        surface: Surface,
+        persist_generated: bool,
+        cross_check_generators: bool,
         mesh_layers: Vec<MeshCacheDesc>,
         texture_layers: Vec<SingularLayerDesc>,
     ) -> Self {
         Self {
-            tiles: TileCache::new(mapfile, generators, size),
+            tiles: TileCache::new(
+                mapfile,
+                generators,
+                surface,
+                size,
+                persist_generated,
+                cross_check_generators,
+            ),
             meshes: mesh_layers
                 .into_iter()
-                .map(|desc| (desc.ty as usize, MeshCache::new(device, desc)))
+                .map(|desc| (desc.ty.index(), MeshCache::new(device, desc)))
                 .collect(),
             textures: texture_layers
                 .into_iter()
                 .map(|desc| (desc.ty as usize, SingularLayerCache::new(desc)))
                 .collect(),
-            cull_shader: ComputeShader::new(
-                rshader::shader_source!("../shaders", "cull-meshes.comp", "declarations.glsl"),
-                "cull-meshes".to_owned(),
-            ),
+            budget: GenerationBudget::new(device, queue, Duration::from_millis(4)),
         }
     }
 
@@ -431,8 +494,12 @@ impl UnifiedPriorityCache {
         mapfile: &MapFile,
         quadtree: &QuadTree,
     ) {
+        self.budget.start_frame();
+
+        let mut refreshed = false;
         for (i, gen) in self.tiles.generators.iter_mut().enumerate() {
             if gen.needs_refresh() {
+                refreshed = true;
                 assert!(i < 32);
                 let mask = GeneratorMask::from_index(i);
                 for slot in self.tiles.inner.slots_mut() {
@@ -458,9 +525,17 @@ impl UnifiedPriorityCache {
                 }
             }
         }
+        if refreshed {
+            // Tiles streamed from disk don't record which generators produced them, so invalidate
+            // every tile of any layer whose persisted tiles were discarded.
+            let stale = self.tiles.update_shader_hashes(mapfile);
+            for slot in self.tiles.inner.slots_mut() {
+                slot.valid &= !stale;
+            }
+        }
 
         for mesh_cache in self.meshes.values_mut() {
-            if mesh_cache.desc.generate.refresh() {
+            if mesh_cache.generate.refresh() {
                 for entry in mesh_cache.inner.slots_mut() {
                     entry.valid = false;
                 }
@@ -491,6 +566,13 @@ impl UnifiedPriorityCache {
         MeshCache::generate_all(self, device, queue, gpu_state);
     }
 
+    pub fn generation_budget(&self) -> Duration {
+        self.budget.frame_budget()
+    }
+    pub fn set_generation_budget(&mut self, budget: Duration) {
+        self.budget.set_frame_budget(budget);
+    }
+
     fn generator_dependencies(&self, node: VNode, mask: LayerMask) -> GeneratorMask {
         let mut generators = GeneratorMask::empty();
 
@@ -521,12 +603,42 @@ impl UnifiedPriorityCache {
         device: &wgpu::Device,
         encoder: &mut wgpu::CommandEncoder,
         gpu_state: &'a GpuState,
+        surface: &Surface,
         frustum: &InfiniteFrustum,
         camera: mint::Point3<f64>,
     ) {
-        self.cull_shader.refresh();
         for (_, c) in &mut self.meshes {
-            c.cull_meshes(device, encoder, gpu_state, &self.tiles, camera, frustum, &mut self.cull_shader);
+            c.cull_meshes(device, encoder, gpu_state, &self.tiles, surface, camera, frustum);
+        }
+    }
+
+    pub fn cull_shadow_meshes(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        gpu_state: &GpuState,
+        surface: &Surface,
+        camera: mint::Point3<f64>,
+        index: usize,
+        cascade: &Cascade,
+    ) {
+        for (_, c) in &mut self.meshes {
+            c.cull_shadow_meshes(
+                device,
+                encoder,
+                gpu_state,
+                &self.tiles,
+                surface,
+                camera,
+                index,
+                cascade,
+            );
+        }
+    }
+
+    pub fn reset_cull_bindings(&mut self) {
+        for (_, c) in &mut self.meshes {
+            c.reset_cull_bindings();
         }
     }
 
@@ -536,17 +648,57 @@ impl UnifiedPriorityCache {
         queue: &'a wgpu::Queue,
         rpass: &mut wgpu::RenderPass<'a>,
         gpu_state: &'a GpuState,
+        targets: &RenderTargets,
+        surface: &Surface,
         camera: mint::Point3<f64>,
     ) {
         for (_, c) in &mut self.meshes {
-            c.render(device, queue, rpass, gpu_state, camera);
+            c.render(device, queue, rpass, gpu_state, targets, surface, camera);
         }
     }
 
+    pub fn render_shadow_meshes<'a>(
+        &'a mut self,
+        device: &wgpu::Device,
+        rpass: &mut wgpu::RenderPass<'a>,
+        gpu_state: &GpuState,
+        index: usize,
+        cascade: &Cascade,
+    ) {
+        for (_, c) in &mut self.meshes {
+            c.render_shadow(device, rpass, gpu_state, index, cascade);
+        }
+    }
+
+    /// Number of tiles (counting each layer separately), meshes and textures that are currently
+    /// valid, along with whether any tiles are still being streamed in. Once neither changes
+    /// between frames, everything the current view needs has been loaded or generated.
+    pub fn loading_progress(&self) -> (usize, bool) {
+        let tiles: usize = self
+            .tiles
+            .inner
+            .slots()
+            .iter()
+            .map(|e| (e.valid.0.get() & 0xffffffff).count_ones() as usize)
+            .sum();
+        let meshes = self.meshes.values().flat_map(|m| m.inner.slots()).filter(|e| e.valid).count();
+        let textures =
+            self.textures.values().flat_map(|t| t.inner.slots()).filter(|e| e.valid).count();
+        (tiles + meshes + textures, self.tiles.is_streaming())
+    }
+
     pub fn tile_desc(&self, ty: LayerType) -> &LayerParams {
         &self.tiles.layers[ty]
     }
 
+    pub fn mesh_layers(&self) -> impl Iterator<Item = &MeshCacheDesc> {
+        self.meshes.values().map(|m| &m.desc)
+    }
+
+    pub fn custom_layers(&self) -> impl Iterator<Item = &LayerParams> {
+        self.tiles.layers.values().filter(|l| matches!(l.layer_type, LayerType::Custom(_)))
+    }
+
     pub fn lookup_texture(&self, ty: SingularLayerType, n: VNode) -> Option<CacheLookup> {
         let cache = &self.textures[ty];
         if n.level() < cache.desc.level {
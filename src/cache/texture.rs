@@ -62,7 +62,7 @@ impl SingularLayerCache {
 
         // Find any tiles that may need to be added.
         let mut missing = Vec::new();
-        VNode::breadth_first(|node| {
+        VNode::breadth_first(quadtree.surface(), |node| {
             let priority = quadtree.node_priority(node);
             if priority < Priority::cutoff() {
                 return false;
@@ -94,6 +94,7 @@ impl SingularLayerCache {
                 label: Some(&format!("{}.command_encoder", layer_type.name())),
             });
 
+            let budget_name = format!("texture.{}", layer_type.name());
             for (index, entry) in m.inner.slots_mut().into_iter().enumerate() {
                 if entry.valid || entry.priority < Priority::cutoff() {
                     continue;
@@ -101,7 +102,11 @@ impl SingularLayerCache {
                 if !cache.tiles.contains_all(entry.node, m.desc.dependency_mask) {
                     continue;
                 }
+                if !cache.budget.try_reserve(&budget_name) {
+                    break;
+                }
 
+                cache.budget.begin(&mut encoder, &budget_name);
                 m.desc.generate.run(
                     device,
                     &mut encoder,
@@ -112,17 +117,25 @@ impl SingularLayerCache {
                         output_slot: index as u32,
                     },
                 );
+                cache.budget.end(&mut encoder);
                 entry.valid = true;
                 generated.push((layer_type, entry.node));
             }
             command_buffers.push(encoder.finish());
         }
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("encoder.textures.resolve_timing"),
+        });
+        cache.budget.resolve(device, &mut encoder);
+        command_buffers.push(encoder.finish());
+
         for (layer_type, node) in generated {
             cache.textures[layer_type].inner.entry_mut(&node).unwrap().generators =
                 cache.generator_dependencies(node, cache.textures[layer_type].desc.dependency_mask);
         }
 
         queue.submit(command_buffers);
+        cache.budget.submitted(queue);
     }
 
     pub(super) fn make_cache_texture(&self, device: &wgpu::Device) -> wgpu::Texture {
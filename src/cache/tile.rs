@@ -1,13 +1,13 @@
 use crate::{
     cache::{self, Priority, PriorityCacheEntry},
-    terrain::quadtree::{QuadTree, VNode},
+    terrain::quadtree::{QuadTree, Surface, VNode},
 };
 use crate::{
-    coordinates,
+    coordinates::{self, PlanetDesc},
     stream::{TileResult, TileStreamerEndpoint},
 };
 use crate::{
-    generate::GenerateTile,
+    generate::{heightmap::HeightmapTile, GenerateTile, Reference},
     gpu_state::GpuState,
     mapfile::{MapFile, TileState},
 };
@@ -18,6 +18,7 @@ use futures::future::FutureExt;
 use futures::stream::futures_unordered::FuturesUnordered;
 use futures::StreamExt;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{num::NonZeroU32, sync::Arc};
 use vec_map::VecMap;
 
@@ -110,18 +111,49 @@ pub(crate) struct ByteRange {
 pub(crate) struct LayerParams {
     /// What kind of layer this is. There can be at most one of each layer type in a file.
     pub layer_type: LayerType,
+    /// Name used to bind this layer in shaders.
+    pub name: String,
     /// Number of samples in each dimension, per tile.
     pub texture_resolution: u32,
     /// Number of samples outside the tile on each side.
     pub texture_border_size: u32,
     /// Format used by this layer.
     pub texture_format: TextureFormat,
-    /// Maximum number of tiles for this layer to generate in a single frame.
-    pub tiles_generated_per_frame: usize,
+}
+
+/// Description of a user-defined tile layer.
+pub struct CustomLayerDesc {
+    /// Shaders refer to the layer's tile cache texture array by this name. Generator shaders write
+    /// to `<name>_out` and can read the parent tile of any layer from `<name>_in`.
+    pub name: String,
+    /// Number of samples in each dimension, per tile.
+    pub texture_resolution: u32,
+    /// Number of samples outside the tile on each side.
+    pub texture_border_size: u32,
+    /// Format used by this layer. Must not be a compressed format.
+    pub texture_format: TextureFormat,
+    /// Compute shader run with one 8x8 workgroup per 8x8 block of samples to generate each tile.
+    pub generator: rshader::ShaderSource,
+    /// Layers that must be present for a tile before this layer can be generated for it.
+    pub peer_inputs: Vec<LayerType>,
+    /// Layers that must be present for the parent tile before this layer can be generated.
+    pub parent_inputs: Vec<LayerType>,
+}
+impl CustomLayerDesc {
+    pub(crate) fn layer_params(&self, layer_type: LayerType) -> LayerParams {
+        assert!(!self.texture_format.is_compressed());
+        LayerParams {
+            layer_type,
+            name: self.name.clone(),
+            texture_resolution: self.texture_resolution,
+            texture_border_size: self.texture_border_size,
+            texture_format: self.texture_format,
+        }
+    }
 }
 
 enum CpuHeightmap {
-    I16 { min: f32, max: f32, heights: Arc<Vec<i16>> },
+    I16 { min: f32, max: f32, tile: Arc<HeightmapTile> },
     F32 { min: f32, max: f32, heights: Arc<Vec<f32>> },
 }
 
@@ -168,22 +200,118 @@ pub(crate) struct TileCache {
     pub(super) inner: PriorityCache<Entry>,
     pub(super) layers: VecMap<LayerParams>,
     pub(super) generators: Vec<Box<dyn GenerateTile>>,
+    /// Surface that generated tiles cover, which they depend on along with the shaders.
+    surface: Surface,
 
     streamer: TileStreamerEndpoint,
     pending_heightmap_downloads:
         FuturesUnordered<BoxFuture<'static, Result<(VNode, wgpu::Buffer), ()>>>,
+
+    /// Whether generated tiles should be written to disk so they can be streamed in later.
+    persist_generated: bool,
+    pending_tile_writes:
+        FuturesUnordered<BoxFuture<'static, Result<(VNode, LayerType, wgpu::Buffer), ()>>>,
+
+    /// Whether to check generated tiles against the CPU reference implementations.
+    cross_check_generators: bool,
+    pending_cross_checks: FuturesUnordered<BoxFuture<'static, Result<CrossCheck, ()>>>,
+
+    /// Nodes for which the result of `get_height_range` changed since the last call to
+    /// `take_height_range_changes`. The ranges of their descendants may have changed too.
+    height_range_changes: Vec<VNode>,
 }
 impl TileCache {
-    pub fn new(mapfile: Arc<MapFile>, generators: Vec<Box<dyn GenerateTile>>, size: usize) -> Self {
-        Self {
+    pub fn new(
+        mapfile: Arc<MapFile>,
+        generators: Vec<Box<dyn GenerateTile>>,
+        surface: Surface,
+        size: usize,
+        persist_generated: bool,
+        cross_check_generators: bool,
+    ) -> Self {
+        let cache = Self {
             inner: PriorityCache::new(size),
             layers: mapfile.layers().clone(),
-            streamer: TileStreamerEndpoint::new(mapfile).unwrap(),
+            streamer: TileStreamerEndpoint::new(Arc::clone(&mapfile)).unwrap(),
             generators,
+            surface,
             pending_heightmap_downloads: FuturesUnordered::new(),
+            persist_generated,
+            pending_tile_writes: FuturesUnordered::new(),
+            cross_check_generators,
+            pending_cross_checks: FuturesUnordered::new(),
+            height_range_changes: Vec::new(),
+        };
+        cache.update_shader_hashes(&mapfile);
+        cache
+    }
+
+    /// Whether tiles of this layer are only ever produced by generators, and can therefore be
+    /// written to disk once generated.
+    fn is_gpu_generated(ty: LayerType) -> bool {
+        match ty {
+            LayerType::Heightmaps | LayerType::Albedo | LayerType::Roughness => false,
+            LayerType::Normals
+            | LayerType::Displacements
+            | LayerType::Horizons
+            | LayerType::Water
+            | LayerType::Custom(_) => true,
         }
     }
 
+    /// Hash of every generator shader that contributes to `ty`, either directly or by producing
+    /// one of its inputs, along with the parameters of the surface they were run for.
+    fn layer_hash(&self, ty: LayerType) -> [u8; 32] {
+        let mut included = vec![false; self.generators.len()];
+        let mut layers = ty.bit_mask();
+        loop {
+            let mut inputs = layers;
+            for (i, generator) in self.generators.iter().enumerate() {
+                for level in 0..=VNode::LEVEL_CELL_5MM {
+                    if generator.outputs(level).intersects(layers) {
+                        included[i] = true;
+                        inputs |= generator.peer_inputs(level) | generator.parent_inputs(level);
+                    }
+                }
+            }
+            if inputs == layers {
+                break;
+            }
+            layers = inputs;
+        }
+
+        let mut hasher = Sha256::new();
+        for (generator, _) in self.generators.iter().zip(included).filter(|(_, i)| *i) {
+            hasher.update(generator.shader_hash());
+        }
+        match self.surface {
+            Surface::Planet(planet) => {
+                hasher.update(b"planet");
+                hasher.update(planet.radius.to_le_bytes());
+                hasher.update(planet.sea_level.unwrap_or(f32::NAN).to_le_bytes());
+            }
+            Surface::Flat { extent } => {
+                hasher.update(b"flat");
+                hasher.update(extent.to_le_bytes());
+            }
+        }
+        hasher.finalize().into()
+    }
+
+    /// Discard any generated tiles on disk that were produced by outdated shaders. Returns the
+    /// layers that had tiles discarded.
+    pub(super) fn update_shader_hashes(&self, mapfile: &MapFile) -> LayerMask {
+        let mut stale = LayerMask::empty();
+        for ty in self.layers.values().map(|l| l.layer_type) {
+            if Self::is_gpu_generated(ty)
+                && mapfile.update_shader_hash(ty, self.layer_hash(ty)).unwrap()
+            {
+                stale |= ty.bit_mask();
+            }
+        }
+        stale
+    }
+
     pub(super) fn update(&mut self, quadtree: &QuadTree) {
         // Update priorities
         for entry in self.inner.slots_mut() {
@@ -194,7 +322,7 @@ impl TileCache {
 
         // Find any tiles that may need to be added.
         let mut missing = Vec::new();
-        VNode::breadth_first(|node| {
+        VNode::breadth_first(quadtree.surface(), |node| {
             let priority = quadtree.node_priority(node);
             if priority < Priority::cutoff() {
                 return false;
@@ -205,7 +333,23 @@ impl TileCache {
 
             node.level() < VNode::LEVEL_CELL_5MM
         });
+        // Inserting tiles may evict others, along with their heightmaps.
+        let heightmap_ranges: Vec<_> = if missing.is_empty() {
+            Vec::new()
+        } else {
+            self.inner
+                .slots()
+                .iter()
+                .filter(|entry| entry.heightmap.is_some())
+                .map(|entry| (entry.node, self.get_height_range(entry.node)))
+                .collect()
+        };
         self.inner.insert(missing);
+        for (node, range) in heightmap_ranges {
+            if !self.inner.contains(&node) {
+                self.height_range_updated(node, range);
+            }
+        }
     }
 
     pub(super) fn generate_tiles(
@@ -216,6 +360,8 @@ impl TileCache {
         gpu_state: &GpuState,
     ) {
         let mut planned_heightmap_downloads = Vec::new();
+        let mut planned_tile_writes = Vec::new();
+        let mut planned_cross_checks = Vec::new();
         let mut pending_generate = VecMap::new();
 
         for layer in cache.tiles.layers.values() {
@@ -256,9 +402,6 @@ impl TileCache {
             }
 
             pending_generate.sort_by_key(|n| n.level());
-            if pending_generate.len() > layer.tiles_generated_per_frame {
-                let _ = pending_generate.split_off(layer.tiles_generated_per_frame);
-            }
         }
 
         let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
@@ -296,6 +439,10 @@ impl TileCache {
                         && !root_input_missing
                         && !parent_input_missing
                     {
+                        if !cache.budget.try_reserve(generator.name()) {
+                            break;
+                        }
+
                         let slot = cache.tiles.inner.index_of(&n).unwrap();
                         let parent_slot = if let Some(p) = n.parent() {
                             cache.tiles.inner.index_of(&p.0)
@@ -304,6 +451,31 @@ impl TileCache {
                         };
 
                         let output_mask = !entry.valid & generator.outputs(n.level());
+
+                        let reference = if cache.tiles.cross_check_generators {
+                            generator.reference(*n, slot, parent_slot, output_mask)
+                        } else {
+                            None
+                        };
+                        let copy_slots =
+                            |encoder: &mut wgpu::CommandEncoder, slots: Vec<(LayerType, usize)>| {
+                                slots
+                                    .into_iter()
+                                    .map(|(layer, slot)| {
+                                        copy_tile_to_buffer(
+                                            device,
+                                            encoder,
+                                            &gpu_state.tile_cache[layer],
+                                            &cache.tiles.layers[layer],
+                                            slot,
+                                        )
+                                    })
+                                    .collect::<Vec<_>>()
+                            };
+                        let reference_inputs =
+                            reference.map(|r| copy_slots(&mut encoder, r.inputs()));
+
+                        cache.budget.begin(&mut encoder, generator.name());
                         generator.generate(
                             device,
                             &mut encoder,
@@ -314,6 +486,17 @@ impl TileCache {
                             parent_slot,
                             output_mask,
                         );
+                        cache.budget.end(&mut encoder);
+
+                        if let (Some(reference), Some(inputs)) = (reference, reference_inputs) {
+                            let outputs = copy_slots(&mut encoder, reference.outputs());
+                            planned_cross_checks.push(CrossCheck {
+                                node: *n,
+                                reference,
+                                inputs,
+                                outputs,
+                            });
+                        }
 
                         let mut input_generators = GeneratorMask::from_index(generator_index);
                         input_generators |= cache.generator_dependencies(*n, peer_inputs);
@@ -375,12 +558,30 @@ impl TileCache {
                             planned_heightmap_downloads.push((*n, buffer));
                         }
 
+                        if cache.tiles.persist_generated {
+                            for layer in cache.tiles.layers.values().filter(|l| {
+                                Self::is_gpu_generated(l.layer_type)
+                                    && output_mask.contains_tile(l.layer_type)
+                            }) {
+                                let buffer = copy_tile_to_buffer(
+                                    device,
+                                    &mut encoder,
+                                    &gpu_state.tile_cache[layer.layer_type],
+                                    layer,
+                                    slot,
+                                );
+                                planned_tile_writes.push((*n, layer.layer_type, buffer));
+                            }
+                        }
+
                         break;
                     }
                 }
             }
         }
+        cache.budget.resolve(device, &mut encoder);
         queue.submit(Some(encoder.finish()));
+        cache.budget.submitted(queue);
 
         for (n, buffer) in planned_heightmap_downloads.drain(..) {
             cache.tiles.pending_heightmap_downloads.push(
@@ -396,6 +597,36 @@ impl TileCache {
                     .boxed(),
             );
         }
+        for check in planned_cross_checks.drain(..) {
+            let maps: Vec<_> = check
+                .inputs
+                .iter()
+                .chain(&check.outputs)
+                .map(|buffer| buffer.slice(..).map_async(wgpu::MapMode::Read))
+                .collect();
+            cache.tiles.pending_cross_checks.push(
+                futures::future::try_join_all(maps)
+                    .map(move |result| match result {
+                        Ok(_) => Ok(check),
+                        Err(_) => Err(()),
+                    })
+                    .boxed(),
+            );
+        }
+        for (n, layer, buffer) in planned_tile_writes.drain(..) {
+            cache.tiles.pending_tile_writes.push(
+                buffer
+                    .slice(..)
+                    .map_async(wgpu::MapMode::Read)
+                    .then(move |result| {
+                        futures::future::ready(match result {
+                            Ok(()) => Ok((n, layer, buffer)),
+                            Err(_) => Err(()),
+                        })
+                    })
+                    .boxed(),
+            );
+        }
     }
 
     pub(super) fn upload_tiles(&mut self, queue: &wgpu::Queue, textures: &VecMap<wgpu::Texture>) {
@@ -415,17 +646,35 @@ impl TileCache {
                 let data;
                 let mut height_data;
                 match tile {
-                    TileResult::Heightmaps(node, ref heights) => {
+                    TileResult::Heightmaps(node, ref tile) => {
+                        let range = self.get_height_range(node);
                         if let Some(entry) = self.inner.entry_mut(&node) {
-                            let min = *heights.iter().min().unwrap() as f32;
-                            let max = *heights.iter().max().unwrap() as f32;
+                            let min = *tile.heights.iter().min().unwrap() as f32;
+                            let max = *tile.heights.iter().max().unwrap() as f32;
                             entry.heightmap =
-                                Some(CpuHeightmap::I16 { min, max, heights: Arc::clone(&heights) });
+                                Some(CpuHeightmap::I16 { min, max, tile: Arc::clone(&tile) });
                         }
-                        let heights: Vec<_> = heights
+                        self.height_range_updated(node, range);
+                        let water = &tile.water;
+                        let heights: Vec<_> = tile
+                            .heights
                             .iter()
-                            .map(|&h| {
-                                if h < 0 {
+                            .enumerate()
+                            .map(|(i, &h)| {
+                                // Inland water stores the level of its surface, followed by the
+                                // direction it flows in. See `is_inland_water` in
+                                // declarations.glsl.
+                                let body = water.mask.get(i).filter(|&&b| b > 0);
+                                if let Some(body) = body.map(|&b| water.bodies[b as usize - 1]) {
+                                    let flow = match body.flow {
+                                        Some(direction) => 0x2000000 | (direction as u32) << 26,
+                                        None => 0,
+                                    };
+                                    0x800000
+                                        | 0x1000000
+                                        | flow
+                                        | (((body.level as u32) + 1024) << 9).min(0x7fffff)
+                                } else if h < 0 {
                                     0x800000 | (((h + 1024).max(0) as u32) << 9)
                                 } else {
                                     (((h as u32) + 1024) << 9).min(0x7fffff)
@@ -436,9 +685,9 @@ impl TileCache {
                         height_data.copy_from_slice(bytemuck::cast_slice(&heights));
                         data = &mut height_data;
                     }
-                    TileResult::Albedo(_, ref mut d) | TileResult::Roughness(_, ref mut d) => {
-                        data = &mut *d
-                    }
+                    TileResult::Albedo(_, ref mut d)
+                    | TileResult::Roughness(_, ref mut d)
+                    | TileResult::Generated(_, _, ref mut d) => data = &mut *d,
                 }
 
                 if cfg!(feature = "small-trace") {
@@ -481,6 +730,7 @@ impl TileCache {
             futures::select! {
                 h = self.pending_heightmap_downloads.select_next_some() => {
                     if let Ok((node, buffer)) = h {
+                        let range = self.get_height_range(node);
                         if let Some(entry) = self.inner.entry_mut(&node) {
                             let bytes_per_pixel =
                             self.layers[LayerType::Heightmaps].texture_format.bytes_per_block()
@@ -508,6 +758,41 @@ impl TileCache {
                             }
                             entry.heightmap = Some(CpuHeightmap::F32 { min, max, heights: Arc::new(heights) });
                         }
+                        self.height_range_updated(node, range);
+                    }
+                }
+                w = self.pending_tile_writes.select_next_some() => {
+                    if let Ok((node, layer, buffer)) = w {
+                        // Skip tiles that were invalidated while the download was in flight.
+                        if self.inner.entry(&node).map(|e| e.valid.contains_tile(layer)).unwrap_or(false) {
+                            let data = read_tile_buffer(&buffer, &self.layers[layer]);
+                            self.streamer.write_tile(node, layer, data);
+                        }
+                    }
+                }
+                c = self.pending_cross_checks.select_next_some() => {
+                    if let Ok(check) = c {
+                        let read = |slots: Vec<(LayerType, usize)>, buffers: &[wgpu::Buffer]| {
+                            slots.into_iter().zip(buffers).map(|((layer, _), buffer)| read_tile_buffer(buffer, &self.layers[layer])).collect::<Vec<_>>()
+                        };
+                        let inputs = read(check.reference.inputs(), &check.inputs);
+                        let outputs = read(check.reference.outputs(), &check.outputs);
+
+                        for comparison in check.reference.check(&self.layers, &inputs, &outputs) {
+                            if comparison.mismatches > 0 {
+                                log::warn!(
+                                    "{} tile at level {} face {} ({}, {}): {} of {} values differ from the reference implementation (max error {})",
+                                    self.layers[comparison.layer].name,
+                                    check.node.level(),
+                                    check.node.face(),
+                                    check.node.x(),
+                                    check.node.y(),
+                                    comparison.mismatches,
+                                    comparison.values,
+                                    comparison.max_error,
+                                );
+                            }
+                        }
                     }
                 }
                 default => break,
@@ -540,13 +825,18 @@ impl TileCache {
                             } else {
                                 wgpu::TextureUsage::empty()
                             },
-                        label: Some(&format!("texture.tiles.{}", LayerType::from_index(ty).name())),
+                        label: Some(&format!("texture.tiles.{}", layer.name)),
                     }),
                 )
             })
             .collect()
     }
 
+    /// Whether any tiles are still being streamed in or read back from the GPU.
+    pub fn is_streaming(&self) -> bool {
+        self.streamer.num_inflight() > 0 || !self.pending_heightmap_downloads.is_empty()
+    }
+
     pub fn contains(&self, node: VNode, ty: LayerType) -> bool {
         self.inner.entry(&node).map(|entry| entry.valid.contains_tile(ty)).unwrap_or(false)
     }
@@ -571,8 +861,14 @@ impl TileCache {
         resolution / block_size
     }
 
-    pub fn get_height(&self, latitude: f64, longitude: f64, level: u8) -> Option<f32> {
-        let ecef = coordinates::polar_to_ecef(Vector3::new(latitude, longitude, 0.0));
+    pub fn get_height(
+        &self,
+        planet: &PlanetDesc,
+        latitude: f64,
+        longitude: f64,
+        level: u8,
+    ) -> Option<f32> {
+        let ecef = coordinates::polar_to_ecef(Vector3::new(latitude, longitude, 0.0), planet);
         let cspace = ecef / ecef.x.abs().max(ecef.y.abs()).max(ecef.z.abs());
 
         let (node, x, y) = VNode::from_cspace(cspace, level);
@@ -593,17 +889,32 @@ impl TileCache {
         let i11 = x.ceil() as usize + y.ceil() as usize * resolution;
 
         self.inner.entry(&node).and_then(|entry| Some(entry.heightmap.as_ref()?)).map(|h| match h {
-            CpuHeightmap::I16 { heights: h, .. } => (h[i00] as f32 * w00
-                + h[i10] as f32 * w10
-                + h[i01] as f32 * w01
-                + h[i11] as f32 * w11)
-                .max(0.0),
+            CpuHeightmap::I16 { tile, .. } => {
+                let h = &tile.heights;
+                (h[i00] as f32 * w00
+                    + h[i10] as f32 * w10
+                    + h[i01] as f32 * w01
+                    + h[i11] as f32 * w11)
+                    .max(0.0)
+            }
             CpuHeightmap::F32 { heights: h, .. } => {
                 (h[i00] * w00 + h[i10] * w10 + h[i01] * w01 + h[i11] * w11).max(0.0)
             }
         })
     }
 
+    /// Record `node` as changed if its height range is no longer `previous`.
+    fn height_range_updated(&mut self, node: VNode, previous: (f32, f32)) {
+        if self.get_height_range(node) != previous {
+            self.height_range_changes.push(node);
+        }
+    }
+
+    /// Returns the nodes whose height ranges changed since the last call, and clears the list.
+    pub fn take_height_range_changes(&mut self) -> Vec<VNode> {
+        std::mem::take(&mut self.height_range_changes)
+    }
+
     /// Returns a conservative estimate of the minimum and maximum heights in the given node.
     pub fn get_height_range(&self, node: VNode) -> (f32, f32) {
         let mut node = Some(node);
@@ -618,3 +929,68 @@ impl TileCache {
         (0.0, 9000.0)
     }
 }
+
+/// Buffers holding copies of the tiles read and written by a single generator invocation, which
+/// are compared against the reference implementation once they are mapped.
+struct CrossCheck {
+    node: VNode,
+    reference: Reference,
+    inputs: Vec<wgpu::Buffer>,
+    outputs: Vec<wgpu::Buffer>,
+}
+
+/// Record a copy of a single tile of `texture` into a buffer that can be mapped for reading. Rows
+/// of blocks are padded to a multiple of 256 bytes.
+fn copy_tile_to_buffer(
+    device: &wgpu::Device,
+    encoder: &mut wgpu::CommandEncoder,
+    texture: &wgpu::Texture,
+    layer: &LayerParams,
+    slot: usize,
+) -> wgpu::Buffer {
+    let resolution = layer.texture_resolution;
+    let resolution_blocks = resolution / layer.texture_format.block_size();
+    let row_bytes = resolution_blocks as u64 * layer.texture_format.bytes_per_block() as u64;
+    let row_pitch = (row_bytes + 255) & !255;
+
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        size: row_pitch * resolution_blocks as u64,
+        usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::MAP_READ,
+        label: Some(&format!("buffer.tiles.download.{}", layer.name)),
+        mapped_at_creation: false,
+    });
+    encoder.copy_texture_to_buffer(
+        wgpu::ImageCopyTexture {
+            texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d { x: 0, y: 0, z: slot as u32 },
+        },
+        wgpu::ImageCopyBuffer {
+            buffer: &buffer,
+            layout: wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(NonZeroU32::new(row_pitch as u32).unwrap()),
+                rows_per_image: None,
+            },
+        },
+        wgpu::Extent3d { width: resolution, height: resolution, depth_or_array_layers: 1 },
+    );
+    buffer
+}
+
+/// Read back a buffer filled by `copy_tile_to_buffer`, removing the row padding.
+fn read_tile_buffer(buffer: &wgpu::Buffer, layer: &LayerParams) -> Vec<u8> {
+    let resolution_blocks = (layer.texture_resolution / layer.texture_format.block_size()) as usize;
+    let row_bytes = resolution_blocks * layer.texture_format.bytes_per_block();
+    let row_pitch = (row_bytes + 255) & !255;
+
+    let mut data = Vec::with_capacity(row_bytes * resolution_blocks);
+    {
+        let mapped_buffer = buffer.slice(..).get_mapped_range();
+        for row in mapped_buffer.chunks_exact(row_pitch) {
+            data.extend_from_slice(&row[..row_bytes]);
+        }
+    }
+    buffer.unmap();
+    data
+}
@@ -5,8 +5,8 @@
 //! towards the north pole. Commonly referred to as "earth-centered, earth-fixed".
 //!
 //! *warped* - Coordinate system centered at the planet center, but warped such that all points on
-//! the planet surface are distance PLANET_RADIUS from the origin. Useful for sky rendering because
-//! the ellipsoidal shape of the earth can be ignored.
+//! the planet surface are distance `PlanetDesc::radius` from the origin. Useful for sky rendering because
+//! the ellipsoidal shape of the planet can be ignored.
 //!
 //! *lla* - Consist of latitude, longitude, and altitude (above sea level). Angle measurements
 //! are given in radians, and altitude in meters.
@@ -16,72 +16,155 @@
 //!
 //! *cspace* - Restricted to points on the unit cube, projected from polar.
 
-use cgmath::{InnerSpace, Vector3};
+use cgmath::{InnerSpace, Matrix3, Rad, Vector3};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-const WGS84_INV_FLATTENING: f64 = 298.257223563;
-const WGS84_SEMI_MAJOR_AXIS_METERS: f64 = 6378137.0;
-const WSG84_SEMI_MINOR_AXIS_METERS: f64 =
-    WGS84_SEMI_MAJOR_AXIS_METERS * (1.0 - 1.0 / WGS84_INV_FLATTENING);
+/// Physical parameters of the body that terrain is rendered on.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PlanetDesc {
+    /// Radius of the sphere that terrain heights are measured from, in meters.
+    pub radius: f64,
+    /// Semi-major axis of the reference ellipsoid, in meters.
+    pub equatorial_radius: f64,
+    /// Flattening of the reference ellipsoid, `(a - b) / a`.
+    pub flattening: f64,
+    /// Height of the top of the atmosphere above `radius`, in meters. Zero if there is none.
+    pub atmosphere_height: f64,
+    /// Height of the sea surface relative to `radius`, or `None` if the body has no oceans.
+    pub sea_level: Option<f32>,
+    /// Surface gravity, in meters per second squared.
+    pub gravity: f64,
+}
+impl PlanetDesc {
+    /// The earth, using the WGS84 ellipsoid.
+    pub const EARTH: PlanetDesc = PlanetDesc {
+        radius: 6371000.0,
+        equatorial_radius: 6378137.0,
+        flattening: 1.0 / 298.257223563,
+        atmosphere_height: 100000.0,
+        sea_level: Some(0.0),
+        gravity: 9.80665,
+    };
+    /// Mars, using the IAU 2000 ellipsoid.
+    pub const MARS: PlanetDesc = PlanetDesc {
+        radius: 3389500.0,
+        equatorial_radius: 3396190.0,
+        flattening: 1.0 / 169.894447,
+        atmosphere_height: 100000.0,
+        sea_level: None,
+        gravity: 3.72076,
+    };
+    /// The moon, which is close enough to spherical to ignore its flattening.
+    pub const MOON: PlanetDesc = PlanetDesc {
+        radius: 1737400.0,
+        equatorial_radius: 1737400.0,
+        flattening: 0.0,
+        atmosphere_height: 0.0,
+        sea_level: None,
+        gravity: 1.625,
+    };
+
+    /// Semi-minor axis of the reference ellipsoid, in meters.
+    pub fn polar_radius(&self) -> f64 {
+        self.equatorial_radius * (1.0 - self.flattening)
+    }
+
+    /// Distance from the planet center to the top of the atmosphere, in meters.
+    pub fn atmosphere_radius(&self) -> f64 {
+        self.radius + self.atmosphere_height
+    }
 
-pub const PLANET_RADIUS: f64 = 6371000.0;
+    /// Whether there is an atmosphere to render.
+    pub fn has_atmosphere(&self) -> bool {
+        self.atmosphere_height > 0.0
+    }
+}
+impl Default for PlanetDesc {
+    fn default() -> Self {
+        Self::EARTH
+    }
+}
 
 #[inline]
 #[allow(unused)]
-pub fn ecef_to_polar(ecef: Vector3<f64>) -> Vector3<f64> {
+pub fn ecef_to_polar(ecef: Vector3<f64>, planet: &PlanetDesc) -> Vector3<f64> {
     let r = f64::sqrt(ecef.x * ecef.x + ecef.y * ecef.y + ecef.z * ecef.z);
-    Vector3::new(f64::asin(ecef.z / r), f64::atan2(ecef.y, ecef.x), r - PLANET_RADIUS)
+    Vector3::new(f64::asin(ecef.z / r), f64::atan2(ecef.y, ecef.x), r - planet.radius)
 }
 #[inline]
 #[allow(unused)]
-pub fn polar_to_ecef(lla: Vector3<f64>) -> Vector3<f64> {
+pub fn polar_to_ecef(lla: Vector3<f64>, planet: &PlanetDesc) -> Vector3<f64> {
     Vector3::new(
-        (PLANET_RADIUS + lla.z) * f64::cos(lla.x) * f64::cos(lla.y),
-        (PLANET_RADIUS + lla.z) * f64::cos(lla.x) * f64::sin(lla.y),
-        (PLANET_RADIUS + lla.z) * f64::sin(lla.x),
+        (planet.radius + lla.z) * f64::cos(lla.x) * f64::cos(lla.y),
+        (planet.radius + lla.z) * f64::cos(lla.x) * f64::sin(lla.y),
+        (planet.radius + lla.z) * f64::sin(lla.x),
     )
 }
 
 #[inline]
 #[allow(unused)]
-pub fn ecef_to_warped(ecef: Vector3<f64>) -> Vector3<f64> {
+pub fn ecef_to_warped(ecef: Vector3<f64>, planet: &PlanetDesc) -> Vector3<f64> {
     Vector3::new(
-        ecef.x * PLANET_RADIUS / WGS84_SEMI_MAJOR_AXIS_METERS,
-        ecef.y * PLANET_RADIUS / WGS84_SEMI_MAJOR_AXIS_METERS,
-        ecef.z * PLANET_RADIUS / WSG84_SEMI_MINOR_AXIS_METERS,
+        ecef.x * planet.radius / planet.equatorial_radius,
+        ecef.y * planet.radius / planet.equatorial_radius,
+        ecef.z * planet.radius / planet.polar_radius(),
     )
 }
 
 #[inline]
 #[allow(unused)]
-pub fn warped_to_ecef(warped: Vector3<f64>) -> Vector3<f64> {
+pub fn warped_to_ecef(warped: Vector3<f64>, planet: &PlanetDesc) -> Vector3<f64> {
     Vector3::new(
-        warped.x * WGS84_SEMI_MAJOR_AXIS_METERS / PLANET_RADIUS,
-        warped.y * WGS84_SEMI_MAJOR_AXIS_METERS / PLANET_RADIUS,
-        warped.z * WSG84_SEMI_MINOR_AXIS_METERS / PLANET_RADIUS,
+        warped.x * planet.equatorial_radius / planet.radius,
+        warped.y * planet.equatorial_radius / planet.radius,
+        warped.z * planet.polar_radius() / planet.radius,
     )
 }
 
-#[allow(unused)]
-pub fn sun_direction() -> Vector3<f64> {
-    use astro::{coords, sun};
+/// Julian day number of the given time.
+fn julian_day(utc: SystemTime) -> f64 {
+    let seconds = match utc.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs_f64(),
+        Err(e) => -e.duration().as_secs_f64(),
+    };
+    2440587.5 + seconds / 86400.0
+}
 
-    let (ecl, distance_au) = sun::geocent_ecl_pos(180.0);
-    let distance = distance_au * 149597870700.0;
+/// Rotation from equatorial coordinates, which are fixed relative to the stars, to ecef at the
+/// given time.
+pub fn equatorial_to_ecef(utc: SystemTime) -> Matrix3<f64> {
+    // Turning by the sidereal time at Greenwich takes a right ascension to the longitude that the
+    // point is directly above.
+    Matrix3::from_angle_z(Rad(-astro::time::mn_sidr(julian_day(utc))))
+}
 
-    let e = 0.40905;
-    let declination = coords::dec_frm_ecl(ecl.long, ecl.lat, e);
-    let right_ascension = coords::asc_frm_ecl(ecl.long, ecl.lat, e);
+/// Unit vector in equatorial coordinates towards a point on the ecliptic sphere.
+fn ecliptic_to_equatorial(ecl: astro::coords::EclPoint, julian_day: f64) -> Vector3<f64> {
+    use astro::{coords, ecliptic};
 
-    let eq_rect = Vector3::new(
-        distance * declination.cos() * right_ascension.cos(),
-        distance * declination.cos() * right_ascension.sin(),
-        distance * declination.sin(),
-    );
+    let obliquity = ecliptic::mn_oblq_IAU(julian_day);
+    let declination = coords::dec_frm_ecl(ecl.long, ecl.lat, obliquity);
+    let right_ascension = coords::asc_frm_ecl(ecl.long, ecl.lat, obliquity);
+    Vector3::new(
+        declination.cos() * right_ascension.cos(),
+        declination.cos() * right_ascension.sin(),
+        declination.sin(),
+    )
+}
 
-    // TODO: Is this conversion from equatorial coordinates to ECEF actually valid?
-    let ecef = Vector3::new(eq_rect.x, -eq_rect.y, eq_rect.z);
+/// Direction towards the sun in ecef coordinates at the given time.
+pub fn sun_direction(utc: SystemTime) -> Vector3<f64> {
+    let julian_day = julian_day(utc);
+    let (ecl, _) = astro::sun::geocent_ecl_pos(julian_day);
+    equatorial_to_ecef(utc) * ecliptic_to_equatorial(ecl, julian_day)
+}
 
-    ecef.normalize()
+/// Direction towards the moon in ecef coordinates at the given time, along with its distance in
+/// meters.
+pub fn moon_position(utc: SystemTime) -> (Vector3<f64>, f64) {
+    let julian_day = julian_day(utc);
+    let (ecl, distance_km) = astro::lunar::geocent_ecl_pos(julian_day);
+    (equatorial_to_ecef(utc) * ecliptic_to_equatorial(ecl, julian_day), distance_km * 1000.0)
 }
 
 pub fn cspace_to_polar(position: Vector3<f64>) -> Vector3<f64> {
@@ -90,3 +173,52 @@ pub fn cspace_to_polar(position: Vector3<f64>) -> Vector3<f64> {
     let longitude = f64::atan2(p.y, p.x);
     Vector3::new(latitude, longitude, 0.0)
 }
+
+pub fn polar_to_cspace(polar: Vector3<f64>) -> Vector3<f64> {
+    let p = Vector3::new(
+        f64::cos(polar.x) * f64::cos(polar.y),
+        f64::cos(polar.x) * f64::sin(polar.y),
+        f64::sin(polar.x),
+    );
+    p / p.x.abs().max(p.y.abs()).max(p.z.abs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn time(unix_seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(unix_seconds)
+    }
+
+    /// Latitude and longitude, in degrees, of the point where the sun is directly overhead.
+    fn subsolar_point(unix_seconds: u64) -> (f64, f64) {
+        let polar = cspace_to_polar(sun_direction(time(unix_seconds)));
+        (polar.x.to_degrees(), polar.y.to_degrees())
+    }
+
+    #[test]
+    fn sun_declination_at_solstices() {
+        // 2021-06-21 03:32 UTC and 2021-12-21 15:59 UTC.
+        let (june, _) = subsolar_point(1624246320);
+        let (december, _) = subsolar_point(1640102340);
+        assert!((june - 23.44).abs() < 0.01, "{}", june);
+        assert!((december + 23.44).abs() < 0.01, "{}", december);
+    }
+
+    #[test]
+    fn subsolar_longitude_at_noon() {
+        // At 12:00 UTC the sun is over Greenwich, offset by the equation of time: about 16.4
+        // minutes early on November 3rd and 14.2 minutes late on February 11th.
+        let (_, november) = subsolar_point(1635940800);
+        let (_, february) = subsolar_point(1613044800);
+        assert!((november + 16.4 / 4.0).abs() < 0.1, "{}", november);
+        assert!((february - 14.2 / 4.0).abs() < 0.1, "{}", february);
+
+        // Noon UTC on January 1st, 2000.
+        let (latitude, longitude) = subsolar_point(946728000);
+        assert!((latitude + 23.0).abs() < 0.1, "{}", latitude);
+        assert!(longitude.abs() < 1.0, "{}", longitude);
+    }
+}
@@ -1,7 +1,5 @@
-use maplit::hashmap;
-
 use crate::GpuState;
-use std::{collections::HashMap, mem};
+use std::{borrow::Cow, collections::HashMap, mem};
 
 #[repr(C)]
 #[derive(Copy, Clone)]
@@ -21,7 +19,10 @@ unsafe impl bytemuck::Pod for GenHeightmapsUniforms {}
 #[derive(Copy, Clone)]
 pub(crate) struct GenDisplacementsUniforms {
     pub node_center: [f64; 3],
-    pub padding0: f64,
+    /// Side length of the surface if it is flat, or zero for a planet.
+    pub flat_extent: f64,
+    /// Radius of the planet, or zero if the surface is flat.
+    pub planet_radius: f64,
     pub origin: [i32; 2],
     pub position: [i32; 2],
     pub stride: i32,
@@ -29,6 +30,8 @@ pub(crate) struct GenDisplacementsUniforms {
     pub displacements_slot: i32,
     pub face: i32,
     pub level_resolution: u32,
+    /// Heights below this are raised up to it.
+    pub sea_level: f32,
 }
 unsafe impl bytemuck::Zeroable for GenDisplacementsUniforms {}
 unsafe impl bytemuck::Pod for GenDisplacementsUniforms {}
@@ -60,6 +63,51 @@ pub(crate) struct GenMaterialsUniforms {
 unsafe impl bytemuck::Zeroable for GenMaterialsUniforms {}
 unsafe impl bytemuck::Pod for GenMaterialsUniforms {}
 
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub(crate) struct GenHorizonsUniforms {
+    /// Position in the heightmap of the first horizon texel, and the distance between texels.
+    pub heightmaps_origin: [f32; 2],
+    pub heightmaps_stride: f32,
+    pub heightmaps_slot: i32,
+    pub parent_origin: [u32; 2],
+    pub parent_slot: i32,
+    /// Distance between heightmap samples, in meters.
+    pub spacing: f32,
+    /// Used to account for the curvature of the planet, or zero if the surface is flat.
+    pub planet_radius: f32,
+    pub padding: [f32; 3],
+}
+unsafe impl bytemuck::Zeroable for GenHorizonsUniforms {}
+unsafe impl bytemuck::Pod for GenHorizonsUniforms {}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub(crate) struct GenWaterUniforms {
+    /// Position in the heightmap of the first water texel, and the distance between texels.
+    pub heightmaps_origin: [f32; 2],
+    pub heightmaps_stride: f32,
+    pub heightmaps_slot: i32,
+}
+unsafe impl bytemuck::Zeroable for GenWaterUniforms {}
+unsafe impl bytemuck::Pod for GenWaterUniforms {}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub(crate) struct GenCustomUniforms {
+    pub node_center: [f32; 3],
+    pub spacing: f32,
+    pub parent_origin: [u32; 2],
+    pub slot: i32,
+    pub parent_slot: i32,
+    pub face: u32,
+    pub level: u32,
+    pub resolution: u32,
+    pub border: u32,
+}
+unsafe impl bytemuck::Zeroable for GenCustomUniforms {}
+unsafe impl bytemuck::Pod for GenCustomUniforms {}
+
 pub(crate) struct ComputeShader<U> {
     shader: rshader::ShaderSet,
     bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::ComputePipeline)>,
@@ -88,6 +136,12 @@ impl<U: bytemuck::Pod> ComputeShader<U> {
         }
     }
 
+    /// Force the bind group to be recreated on the next run, so that resources which have been
+    /// replaced since it was created are picked up.
+    pub fn reset_bindings(&mut self) {
+        self.bindgroup_pipeline = None;
+    }
+
     pub fn run(
         &mut self,
         device: &wgpu::Device,
@@ -95,6 +149,21 @@ impl<U: bytemuck::Pod> ComputeShader<U> {
         state: &GpuState,
         dimensions: (u32, u32, u32),
         uniforms: &U,
+    ) {
+        self.run_with_buffers(device, encoder, state, dimensions, uniforms, HashMap::new())
+    }
+
+    /// Like `run`, but binds `buffers` (which may also contain texture views) by name in addition to
+    /// the usual resources. The buffers are only consulted when the bind group is first created, so
+    /// they must not change between calls.
+    pub fn run_with_buffers(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        state: &GpuState,
+        dimensions: (u32, u32, u32),
+        uniforms: &U,
+        buffers: HashMap<Cow<str>, (bool, wgpu::BindingResource)>,
     ) {
         if self.uniforms.is_none() {
             self.uniforms = Some(device.create_buffer(&wgpu::BufferDescriptor {
@@ -105,14 +174,22 @@ impl<U: bytemuck::Pod> ComputeShader<U> {
             }));
         }
         if self.bindgroup_pipeline.is_none() {
+            let mut buffers = buffers;
+            buffers.insert(
+                "ubo".into(),
+                (
+                    false,
+                    wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                        buffer: self.uniforms.as_ref().unwrap(),
+                        offset: 0,
+                        size: None,
+                    }),
+                ),
+            );
             let (bind_group, bind_group_layout) = state.bind_group_for_shader(
                 device,
                 &self.shader,
-                hashmap!["ubo".into() => (false, wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                    buffer: self.uniforms.as_ref().unwrap(),
-                    offset: 0,
-                    size: None,
-                }))],
+                buffers,
                 HashMap::new(),
                 &format!("bindgroup.{}", self.name),
             );
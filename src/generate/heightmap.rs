@@ -25,11 +25,182 @@ pub(crate) struct Sector {
     pub y: u32,
 }
 
+/// Smallest number of connected water samples with exactly the same height that are treated as a
+/// body of water, which filters out ponds too small to show up as more than noise.
+const MIN_WATER_BODY_SAMPLES: usize = 128;
+
+/// A connected region of water above sea level, like a lake or a single step of a river (which
+/// elevation datasets flatten into a series of steps).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WaterBody {
+    /// Height of the water surface, in meters.
+    pub level: i16,
+    /// Direction that the water flows in, measured in 64ths of a turn counterclockwise from the +x
+    /// axis of the heightmap. `None` for still water.
+    pub flow: Option<u8>,
+}
+
+/// Lakes and rivers in a heightmap tile.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InlandWater {
+    pub bodies: Vec<WaterBody>,
+    /// For each heightmap sample, either zero or one plus the index of the water body covering
+    /// it. Empty if there are no bodies.
+    pub mask: Vec<u8>,
+}
+impl InlandWater {
+    /// Find the bodies of water in a full resolution heightmap, given which samples the source
+    /// data marks as water. Elevation datasets flatten lakes and wide rivers, so each large region
+    /// of water samples with exactly the same height above sea level becomes a body.
+    ///
+    /// Bodies are considered to be flowing if they are narrow and most of the water they exchange
+    /// with their surroundings enters on one side and leaves on the other, which describes the
+    /// steps of a river but not a lake.
+    pub(crate) fn find(resolution: usize, heights: &[i16], water: &[bool]) -> Self {
+        assert_eq!(heights.len(), resolution * resolution);
+        assert_eq!(water.len(), resolution * resolution);
+
+        let neighbors = |i: usize| {
+            let (x, y) = (i % resolution, i / resolution);
+            std::array::IntoIter::new([
+                (x > 0).then(|| (i - 1, [-1, 0])),
+                (x + 1 < resolution).then(|| (i + 1, [1, 0])),
+                (y > 0).then(|| (i - resolution, [0, -1])),
+                (y + 1 < resolution).then(|| (i + resolution, [0, 1])),
+            ])
+            .flatten()
+        };
+
+        // Label each region of water with equal height, and keep the ones large enough.
+        let mut labels = vec![u32::MAX; heights.len()];
+        let mut regions: Vec<Vec<usize>> = Vec::new();
+        for start in 0..heights.len() {
+            if labels[start] != u32::MAX || !water[start] || heights[start] <= 0 {
+                continue;
+            }
+
+            let label = regions.len() as u32;
+            let mut region = vec![start];
+            labels[start] = label;
+            let mut next = 0;
+            while next < region.len() {
+                let i = region[next];
+                next += 1;
+                for (j, _) in neighbors(i) {
+                    if labels[j] == u32::MAX && water[j] && heights[j] == heights[start] {
+                        labels[j] = label;
+                        region.push(j);
+                    }
+                }
+            }
+            regions.push(region);
+        }
+        let is_water = |label: u32| {
+            label != u32::MAX && regions[label as usize].len() >= MIN_WATER_BODY_SAMPLES
+        };
+
+        // Only 255 bodies fit in the mask, so drop the smallest ones if there are more.
+        let mut water_regions: Vec<u32> =
+            (0..regions.len() as u32).filter(|&label| is_water(label)).collect();
+        water_regions.sort_by_key(|&label| std::cmp::Reverse(regions[label as usize].len()));
+        water_regions.truncate(255);
+        if water_regions.is_empty() {
+            return Self::default();
+        }
+
+        let mut bodies = Vec::new();
+        let mut mask = vec![0; heights.len()];
+        for (index, &label) in water_regions.iter().enumerate() {
+            let region = &regions[label as usize];
+            let level = heights[region[0]];
+
+            // Water enters from higher bodies of water, and leaves towards anything lower.
+            let mut flow = [0i32; 2];
+            let mut exchanges = 0;
+            let mut shore_samples = 0;
+            for &i in region {
+                mask[i] = index as u8 + 1;
+
+                let mut on_shore = false;
+                for (j, direction) in neighbors(i) {
+                    if labels[j] == label {
+                        continue;
+                    }
+                    on_shore = true;
+                    if heights[j] < level {
+                        flow = [flow[0] + direction[0], flow[1] + direction[1]];
+                        exchanges += 1;
+                    } else if is_water(labels[j]) {
+                        flow = [flow[0] - direction[0], flow[1] - direction[1]];
+                        exchanges += 1;
+                    }
+                }
+                if on_shore {
+                    shore_samples += 1;
+                }
+            }
+
+            let (fx, fy) = (flow[0] as f32, flow[1] as f32);
+            let consistency = (fx * fx + fy * fy).sqrt() / exchanges.max(1) as f32;
+            let narrow = shore_samples * 4 > region.len();
+            let flow = if narrow && consistency > 0.5 {
+                let turns = fy.atan2(fx) / (2.0 * std::f32::consts::PI);
+                Some(((turns * 64.0).round() as i32).rem_euclid(64) as u8)
+            } else {
+                None
+            };
+
+            bodies.push(WaterBody { level, flow });
+        }
+
+        Self { bodies, mask }
+    }
+
+    fn write(&self, output: &mut Vec<u8>) {
+        output.push(self.bodies.len() as u8);
+        for body in &self.bodies {
+            output.extend_from_slice(&body.level.to_le_bytes());
+            output.push(body.flow.unwrap_or(u8::MAX));
+        }
+        if !self.bodies.is_empty() {
+            output.extend_from_slice(&self.mask);
+        }
+    }
+
+    fn read(resolution: usize, input: &mut impl Read) -> Self {
+        let mut count = [0];
+        input.read_exact(&mut count).unwrap();
+        if count[0] == 0 {
+            return Self::default();
+        }
+
+        let mut bodies = Vec::new();
+        for _ in 0..count[0] {
+            let mut body = [0; 3];
+            input.read_exact(&mut body).unwrap();
+            bodies.push(WaterBody {
+                level: i16::from_le_bytes([body[0], body[1]]),
+                flow: if body[2] == u8::MAX { None } else { Some(body[2]) },
+            });
+        }
+        let mut mask = vec![0; resolution * resolution];
+        input.read_exact(&mut mask).unwrap();
+        Self { bodies, mask }
+    }
+}
+
+/// A decompressed heightmap tile.
+pub(crate) struct HeightmapTile {
+    pub heights: Vec<i16>,
+    pub water: InlandWater,
+}
+
 pub fn compress_heightmap_tile(
     resolution: usize,
     log2_scale_factor: i8,
     heights: &[i16],
     parent: Option<(u8, usize, &[i16])>, // (parent_index, skirt, parent_heights)
+    water: &InlandWater,
     compression_level: u32,
 ) -> Vec<u8> {
     assert_eq!(resolution % 2, 1);
@@ -120,18 +291,23 @@ pub fn compress_heightmap_tile(
     // e.write_all(bytemuck::cast_slice(&output)).unwrap();
     // e.finish().unwrap()
 
-    let mut header = vec![3, log2_scale_factor as u8, b'T', b'E', b'R', b'R', b'A', b'!'];
+    let mut header = vec![4, log2_scale_factor as u8, b'T', b'E', b'R', b'R', b'A', b'!'];
     header.extend_from_slice(&(resolution as u32).to_le_bytes());
 
+    // Unlike the heights, the water is stored losslessly after them.
+    let mut water_bytes = Vec::new();
+    water.write(&mut water_bytes);
+
     let mut e = lz4::EncoderBuilder::new().level(compression_level).build(header).unwrap();
     e.write_all(bytemuck::cast_slice(&output)).unwrap();
+    e.write_all(&water_bytes).unwrap();
     e.finish().0
 }
 
 pub fn uncompress_heightmap_tile(
     parent: Option<(u8, usize, usize, &[i16])>,
     bytes: &[u8],
-) -> (usize, Vec<i16>) {
+) -> (usize, Vec<i16>, InlandWater) {
     let scale_factor;
     let header_end;
     let resolution;
@@ -145,7 +321,7 @@ pub fn uncompress_heightmap_tile(
         scale_factor = 1i16 << bytes[1];
         resolution = 521;
         header_end = 2;
-    } else if bytes[0] == 3 {
+    } else if bytes[0] == 3 || bytes[0] == 4 {
         assert!(bytes[1] as i8 >= 0 && bytes[1] <= 12);
         assert_eq!(&bytes[2..8], b"TERRA!");
         scale_factor = 1i16 << bytes[1];
@@ -159,12 +335,17 @@ pub fn uncompress_heightmap_tile(
     // flate2::read::ZlibDecoder::new(Cursor::new(&bytes[2..]))
     //     .read_exact(bytemuck::cast_slice_mut(&mut encoded))
     //     .unwrap();
-    lz4::Decoder::new(Cursor::new(&bytes[header_end..]))
-        .unwrap()
-        .read_exact(bytemuck::cast_slice_mut(&mut encoded))
-        .unwrap();
+    let mut decoder = lz4::Decoder::new(Cursor::new(&bytes[header_end..])).unwrap();
+    decoder.read_exact(bytemuck::cast_slice_mut(&mut encoded)).unwrap();
     // encoded.copy_from_slice(bytemuck::cast_slice(&bytes[2..]));
 
+    // Earlier versions don't record any water above sea level.
+    let water = if bytes[0] >= 4 {
+        InlandWater::read(resolution, &mut decoder)
+    } else {
+        InlandWater::default()
+    };
+
     let encoded: VecDeque<i16> = encoded.into();
     let mut encoded = encoded.into_iter();
 
@@ -251,7 +432,7 @@ pub fn uncompress_heightmap_tile(
     assert_eq!(heights[resolution + 1], q_3[0]);
     assert_eq!(heights[resolution + 2], q_2[1]);
 
-    (resolution, heights)
+    (resolution, heights, water)
 }
 
 struct Cache<K: Eq + std::hash::Hash + Copy, T> {
@@ -303,7 +484,7 @@ impl<K: std::hash::Hash + Eq + Copy, T> Cache<K, T> {
 pub(crate) struct HeightmapCache {
     resolution: usize,
     border_size: usize,
-    tiles: Cache<VNode, Vec<i16>>,
+    tiles: Cache<VNode, HeightmapTile>,
 }
 impl HeightmapCache {
     pub fn new(resolution: usize, border_size: usize, capacity: usize) -> Self {
@@ -314,7 +495,7 @@ impl HeightmapCache {
         &mut self,
         mapfile: &'a MapFile,
         node: VNode,
-    ) -> BoxFuture<'a, Result<Arc<Vec<i16>>, Error>> {
+    ) -> BoxFuture<'a, Result<Arc<HeightmapTile>, Error>> {
         let mut tiles_pending = Vec::new();
         let mut root = None;
 
@@ -338,16 +519,17 @@ impl HeightmapCache {
         async move {
             let tiles = future::join_all(tiles_pending.into_iter()).await;
             for (n, t) in tiles.into_iter().rev() {
-                let tile = Arc::new(match root.take() {
-                    None => uncompress_heightmap_tile(None, &*t?).1,
+                let (_, heights, water) = match root.take() {
+                    None => uncompress_heightmap_tile(None, &*t?),
                     Some(parent_tile) => {
+                        let parent = &parent_tile.heights;
                         uncompress_heightmap_tile(
-                            Some((n.parent().unwrap().1, border_size, resolution, &*parent_tile)),
+                            Some((n.parent().unwrap().1, border_size, resolution, parent)),
                             &*t?,
                         )
-                        .1
                     }
-                });
+                };
+                let tile = Arc::new(HeightmapTile { heights, water });
                 let _ = sender.send((n, Arc::clone(&tile)));
                 root = Some(tile);
             }
@@ -358,7 +540,7 @@ impl HeightmapCache {
 }
 
 pub(crate) struct SectorCache {
-    sectors: Cache<Sector, Vec<i16>>,
+    sectors: Cache<Sector, HeightmapTile>,
 }
 impl SectorCache {
     pub fn new(capacity: usize) -> Self {
@@ -369,7 +551,7 @@ impl SectorCache {
         &mut self,
         nasadem_reprojected_directory: &Path,
         s: Sector,
-    ) -> BoxFuture<'a, Result<Arc<Vec<i16>>, Error>> {
+    ) -> BoxFuture<'a, Result<Arc<HeightmapTile>, Error>> {
         if let Some(sector) = self.sectors.get(s) {
             return futures::future::ready(Ok(sector)).boxed();
         }
@@ -380,7 +562,8 @@ impl SectorCache {
         async move {
             let bytes = tokio::fs::read(path).await?;
             tokio::task::spawn_blocking(move || {
-                let sector = Arc::new(uncompress_heightmap_tile(None, &bytes).1);
+                let (_, heights, water) = uncompress_heightmap_tile(None, &bytes);
+                let sector = Arc::new(HeightmapTile { heights, water });
                 let _ = sender.send((s, Arc::clone(&sector)));
                 Ok(sector)
             })
@@ -442,6 +625,7 @@ impl HeightmapSectorGen {
         let resolution = self.sector_resolution;
         let fut = async move {
             let mut heightmap = vec![0i16; resolution * resolution];
+            let mut water = vec![false; resolution * resolution];
 
             let rasters: fnv::FnvHashMap<(i16, i16), Arc<Raster<_, _>>> =
                 futures::future::try_join_all(rasters)
@@ -450,23 +634,28 @@ impl HeightmapSectorGen {
                     .filter_map(|v: ((i16, i16), Option<Arc<Raster<_, _>>>)| Some((v.0, v.1?)))
                     .collect();
 
-            heightmap.par_iter_mut().zip(coordinates.into_par_iter()).for_each(
-                |(h, (lat, long))| {
+            heightmap
+                .par_iter_mut()
+                .zip(water.par_iter_mut())
+                .zip(coordinates.into_par_iter())
+                .for_each(|((h, w), (lat, long))| {
                     if let Some(r) = rasters.get(&(lat.floor() as i16, long.floor() as i16)) {
                         *h = r.interpolate(lat, long, 0).unwrap() as i16;
+                        *w = r.bands > 1 && r.interpolate(lat, long, 1).unwrap() > 0.5;
                     }
                     if *h == 0 {
                         *h = global_dem.interpolate(lat, long, 0) as i16;
                     }
-                },
-            );
+                });
 
             tokio::task::spawn_blocking(move || {
+                let water = InlandWater::find(resolution, &heightmap, &water);
                 let tile = compress_heightmap_tile(
                     resolution,
                     0, // 2 + VNode::LEVEL_CELL_76M.saturating_sub(node.level()) as i8,
                     &*heightmap,
                     None, //parent.as_ref().map(|&(i, ref a)| (i, &***a)),
+                    &water,
                     7,
                 );
 
@@ -501,7 +690,14 @@ mod tests {
         let child: Vec<i16> =
             (0..(resolution * resolution)).map(|_| dist.sample(&mut rng)).collect();
 
-        let bytes = compress_heightmap_tile(resolution, 3, &*child, Some((0, skirt, &*parent)), 9);
+        let bytes = compress_heightmap_tile(
+            resolution,
+            3,
+            &*child,
+            Some((0, skirt, &*parent)),
+            &InlandWater::default(),
+            9,
+        );
         let roundtrip =
             uncompress_heightmap_tile(Some((0, skirt, resolution, &*parent)), &*bytes).1;
 
@@ -516,6 +712,50 @@ mod tests {
         }
     }
 
+    #[test]
+    fn find_inland_water() {
+        let resolution = 65;
+
+        // A hillside with a lake, a river that steps down towards +x, and a flat dry plateau.
+        let mut heights = vec![0i16; resolution * resolution];
+        let mut is_water = vec![false; resolution * resolution];
+        for y in 0..resolution {
+            for x in 0..resolution {
+                let (height, water) = match (x, y) {
+                    (5..=24, 5..=24) => (150, true),
+                    (45..=60, 5..=24) => (300, false),
+                    (_, 40..=47) => (120 - (x as i16 / 20) * 2, true),
+                    _ => (200 + 3 * x as i16 + 5 * y as i16, false),
+                };
+                heights[x + y * resolution] = height;
+                is_water[x + y * resolution] = water;
+            }
+        }
+
+        let water = InlandWater::find(resolution, &heights, &is_water);
+        assert_eq!(
+            water.bodies,
+            vec![
+                WaterBody { level: 150, flow: None },
+                WaterBody { level: 120, flow: Some(0) },
+                WaterBody { level: 118, flow: Some(0) },
+                WaterBody { level: 116, flow: Some(0) },
+            ]
+        );
+        assert_eq!(water.mask[10 + 10 * resolution], 1);
+        assert_eq!(water.mask[30 + 42 * resolution], 3);
+        assert_eq!(water.mask[62 + 42 * resolution], 0);
+        assert_eq!(water.mask[0], 0);
+        assert_eq!(water.mask[50 + 10 * resolution], 0);
+
+        // Flat areas only become water if the source data says so.
+        let no_water = vec![false; resolution * resolution];
+        assert_eq!(InlandWater::find(resolution, &heights, &no_water), InlandWater::default());
+
+        let bytes = compress_heightmap_tile(resolution, 0, &heights, None, &water, 9);
+        assert_eq!(uncompress_heightmap_tile(None, &bytes).2, water);
+    }
+
     #[bench]
     fn bench_compress(b: &mut Bencher) {
         let skirt = 8;
@@ -529,7 +769,16 @@ mod tests {
         let child: Vec<i16> =
             (0..(resolution * resolution)).map(|_| dist.sample(&mut rng)).collect();
 
-        b.iter(|| compress_heightmap_tile(resolution, 3, &*child, Some((0, skirt, &*parent)), 9));
+        b.iter(|| {
+            compress_heightmap_tile(
+                resolution,
+                3,
+                &*child,
+                Some((0, skirt, &*parent)),
+                &InlandWater::default(),
+                9,
+            )
+        });
     }
 
     #[bench]
@@ -545,7 +794,14 @@ mod tests {
         let child: Vec<i16> =
             (0..(resolution * resolution)).map(|_| dist.sample(&mut rng)).collect();
 
-        let bytes = compress_heightmap_tile(resolution, 3, &*child, Some((0, skirt, &*parent)), 9);
+        let bytes = compress_heightmap_tile(
+            resolution,
+            3,
+            &*child,
+            Some((0, skirt, &*parent)),
+            &InlandWater::default(),
+            9,
+        );
         b.iter(|| uncompress_heightmap_tile(Some((0, skirt, resolution, &*parent)), &*bytes));
     }
 }
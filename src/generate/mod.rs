@@ -1,10 +1,10 @@
-use crate::cache::{LayerParams, LayerType, TextureFormat};
+use crate::cache::{CustomLayerDesc, LayerParams, LayerType, TextureFormat};
 use crate::generate::heightmap::{HeightmapCache, Sector, SectorCache};
 use crate::gpu_state::GpuState;
 use crate::mapfile::{MapFile, TextureDescriptor};
 use crate::srgb::SRGB_TO_LINEAR;
 use crate::terrain::dem::DemSource;
-use crate::terrain::quadtree::VNode;
+use crate::terrain::quadtree::{Surface, VNode};
 use crate::terrain::raster::GlobalRaster;
 use crate::terrain::raster::RasterCache;
 use crate::types::VFace;
@@ -12,7 +12,7 @@ use crate::{
     asset::{AssetLoadContext, AssetLoadContextBuf, WebAsset},
     cache::LayerMask,
 };
-use crate::{coordinates, Terrain};
+use crate::{coordinates, coordinates::PlanetDesc, Terrain};
 use anyhow::Error;
 use atomicwrites::{AtomicFile, OverwriteBehavior};
 use bytemuck::Pod;
@@ -25,13 +25,11 @@ use image::{png::PngDecoder, ColorType, ImageDecoder};
 use itertools::Itertools;
 use maplit::hashmap;
 use rayon::prelude::*;
+use sha2::{Digest, Sha256};
 use std::collections::HashSet;
 use std::fs;
 use std::io::Cursor;
-use std::{
-    borrow::Cow, collections::HashMap, f64::consts::PI, fs::File, mem, num::NonZeroU32,
-    path::PathBuf,
-};
+use std::{borrow::Cow, collections::HashMap, fs::File, mem, num::NonZeroU32, path::PathBuf};
 use std::{
     io::{Read, Write},
     path::Path,
@@ -41,12 +39,10 @@ use vec_map::VecMap;
 
 mod gpu;
 pub mod heightmap;
+mod reference;
 
 pub(crate) use gpu::*;
-
-/// The radius of the earth in meters.
-pub(crate) const EARTH_RADIUS: f64 = 6371000.0;
-pub(crate) const EARTH_CIRCUMFERENCE: f64 = 2.0 * PI * EARTH_RADIUS;
+pub(crate) use reference::Reference;
 
 pub const BLUE_MARBLE_URLS: [&str; 8] = [
     "https://eoimages.gsfc.nasa.gov/images/imagerecords/76000/76487/world.200406.3x21600x21600.A1.png",
@@ -60,6 +56,8 @@ pub const BLUE_MARBLE_URLS: [&str; 8] = [
 ];
 
 pub(crate) trait GenerateTile: Send {
+    /// Name used to identify this generator, including when tracking how long it takes to run.
+    fn name(&self) -> &str;
     /// Layers generated by this object. Zero means generate cannot operate for nodes of this level.
     fn outputs(&self, level: u8) -> LayerMask;
     /// Layers required to be present at `level` when generating a tile at `level`.
@@ -68,6 +66,17 @@ pub(crate) trait GenerateTile: Send {
     fn parent_inputs(&self, level: u8) -> LayerMask;
     /// Returns whether previously generated tiles from this generator are still valid.
     fn needs_refresh(&mut self) -> bool;
+    /// Hash of the compiled shader, used to detect when tiles written to disk are out of date.
+    fn shader_hash(&self) -> [u8; 32];
+    /// Returns a description of the invocation for `node` that can be checked against the CPU
+    /// reference implementation, if there is one.
+    fn reference(
+        &self,
+        node: VNode,
+        slot: usize,
+        parent_slot: Option<usize>,
+        output_mask: LayerMask,
+    ) -> Option<Reference>;
     /// Run the generator for `node`.
     fn generate(
         &mut self,
@@ -97,10 +106,14 @@ struct ShaderGen<T, F: 'static + Send + Fn(VNode, usize, Option<usize>, LayerMas
     blit_from_bc5_staging: Option<LayerType>,
     name: String,
     f: F,
+    reference: Option<fn(T) -> Reference>,
 }
 impl<T: Pod, F: 'static + Send + Fn(VNode, usize, Option<usize>, LayerMask) -> T> GenerateTile
     for ShaderGen<T, F>
 {
+    fn name(&self) -> &str {
+        &self.name
+    }
     fn outputs(&self, level: u8) -> LayerMask {
         if level > 0 {
             self.outputs
@@ -130,6 +143,18 @@ impl<T: Pod, F: 'static + Send + Fn(VNode, usize, Option<usize>, LayerMask) -> T
             false
         }
     }
+    fn shader_hash(&self) -> [u8; 32] {
+        Sha256::digest(bytemuck::cast_slice(self.shader.compute())).into()
+    }
+    fn reference(
+        &self,
+        node: VNode,
+        slot: usize,
+        parent_slot: Option<usize>,
+        output_mask: LayerMask,
+    ) -> Option<Reference> {
+        self.reference.map(|r| r((self.f)(node, slot, parent_slot, output_mask)))
+    }
     fn generate(
         &mut self,
         device: &wgpu::Device,
@@ -158,9 +183,9 @@ impl<T: Pod, F: 'static + Send + Fn(VNode, usize, Option<usize>, LayerMask) -> T
         if let Some(parent_slot) = parent_slot {
             for layer in layers.values() {
                 image_views.insert(
-                    format!("{}_in", layer.layer_type.name()).into(),
+                    format!("{}_in", layer.name).into(),
                     state.tile_cache[layer.layer_type].create_view(&wgpu::TextureViewDescriptor {
-                        label: Some(&format!("view.{}[{}]", layer.layer_type.name(), parent_slot)),
+                        label: Some(&format!("view.{}[{}]", layer.name, parent_slot)),
                         base_array_layer: parent_slot as u32,
                         array_layer_count: Some(NonZeroU32::new(1).unwrap()),
                         ..Default::default()
@@ -173,9 +198,9 @@ impl<T: Pod, F: 'static + Send + Fn(VNode, usize, Option<usize>, LayerMask) -> T
             layers.values().filter(|l| self.outputs(node.level()).contains_tile(l.layer_type))
         {
             image_views.insert(
-                format!("{}_out", layer.layer_type.name()).into(),
+                format!("{}_out", layer.name).into(),
                 state.tile_cache[layer.layer_type].create_view(&wgpu::TextureViewDescriptor {
-                    label: Some(&format!("view.{}[{}]", layer.layer_type.name(), slot)),
+                    label: Some(&format!("view.{}[{}]", layer.name, slot)),
                     base_array_layer: slot as u32,
                     array_layer_count: Some(NonZeroU32::new(1).unwrap()),
                     ..Default::default()
@@ -338,6 +363,25 @@ impl ShaderGenBuilder {
     fn build<T: Pod, F: 'static + Send + Fn(VNode, usize, Option<usize>, LayerMask) -> T>(
         self,
         f: F,
+    ) -> Box<dyn GenerateTile> {
+        self.build_inner(f, None)
+    }
+    /// Like `build`, but also records how to check the generator's output against a CPU reference
+    /// implementation.
+    fn build_with_reference<
+        T: Pod,
+        F: 'static + Send + Fn(VNode, usize, Option<usize>, LayerMask) -> T,
+    >(
+        self,
+        reference: fn(T) -> Reference,
+        f: F,
+    ) -> Box<dyn GenerateTile> {
+        self.build_inner(f, Some(reference))
+    }
+    fn build_inner<T: Pod, F: 'static + Send + Fn(VNode, usize, Option<usize>, LayerMask) -> T>(
+        self,
+        f: F,
+        reference: Option<fn(T) -> Reference>,
     ) -> Box<dyn GenerateTile> {
         Box::new(ShaderGen {
             name: self.name,
@@ -358,12 +402,14 @@ impl ShaderGenBuilder {
             root_peer_inputs: self.root_peer_inputs.unwrap_or(self.peer_inputs),
             blit_from_bc5_staging: self.blit_from_bc5_staging,
             f,
+            reference,
         })
     }
 }
 
 pub(crate) fn generators(
     layers: &VecMap<LayerParams>,
+    surface: Surface,
     soft_float64: bool,
 ) -> Vec<Box<dyn GenerateTile>> {
     let heightmaps_resolution = layers[LayerType::Heightmaps].texture_resolution;
@@ -371,6 +417,48 @@ pub(crate) fn generators(
     let displacements_resolution = layers[LayerType::Displacements].texture_resolution;
     let normals_resolution = layers[LayerType::Normals].texture_resolution;
     let normals_border = layers[LayerType::Normals].texture_border_size;
+    let horizons_resolution = layers[LayerType::Horizons].texture_resolution;
+    let horizons_border = layers[LayerType::Horizons].texture_border_size;
+    let water_resolution = layers[LayerType::Water].texture_resolution;
+    let water_border = layers[LayerType::Water].texture_border_size;
+
+    // Heights are always in meters on the planet that they were measured on, so the spacing of
+    // their samples must be too.
+    let source = Surface::Planet(surface.source_planet());
+
+    let horizons_uniforms =
+        move |node: VNode, slot: usize, parent_slot: Option<usize>, _: LayerMask| {
+            let heightmaps_cells = heightmaps_resolution - heightmaps_border * 2 - 1;
+            let stride =
+                heightmaps_cells as f32 / (horizons_resolution - horizons_border * 2) as f32;
+            let origin = heightmaps_border as f32 - horizons_border as f32 * stride;
+            let parent_origin = match node.parent() {
+                Some((_, parent_index)) => [
+                    if parent_index % 2 == 0 {
+                        horizons_border / 2
+                    } else {
+                        (horizons_resolution - horizons_border) / 2
+                    },
+                    if parent_index / 2 == 0 {
+                        horizons_border / 2
+                    } else {
+                        (horizons_resolution - horizons_border) / 2
+                    },
+                ],
+                None => [0, 0],
+            };
+
+            GenHorizonsUniforms {
+                heightmaps_origin: [origin, origin],
+                heightmaps_stride: stride,
+                heightmaps_slot: slot as i32,
+                parent_origin,
+                parent_slot: parent_slot.map(|s| s as i32).unwrap_or(-1),
+                spacing: node.aprox_side_length(&source) / heightmaps_cells as f32,
+                planet_radius: surface.planet().map(|p| p.radius).unwrap_or(0.0) as f32,
+                padding: [0.0; 3],
+            }
+        };
 
     vec![
         ShaderGenBuilder::new(
@@ -381,7 +469,8 @@ pub(crate) fn generators(
         .dimensions((heightmaps_resolution + 7) / 8)
         .parent_inputs(LayerType::Heightmaps.bit_mask())
         .no_validate() // validation doesn't support barrier() yet.
-        .build(
+        .build_with_reference(
+            Reference::Heightmaps,
             move |node: VNode,
                   slot: usize,
                   parent_slot: Option<usize>,
@@ -393,7 +482,7 @@ pub(crate) fn generators(
                     heightmaps_border as i32 / 2,
                     heightmaps_resolution as i32 / 2 - heightmaps_border as i32 / 2,
                 ];
-                let spacing = node.aprox_side_length()
+                let spacing = node.aprox_side_length(&source)
                     / (heightmaps_resolution - heightmaps_border * 2 - 1) as f32;
                 let resolution = heightmaps_resolution - heightmaps_border * 2 - 1;
                 let level_resolution = resolution << node.level();
@@ -435,7 +524,8 @@ pub(crate) fn generators(
         .parent_inputs(LayerType::Heightmaps.bit_mask())
         .root_peer_inputs(LayerType::Heightmaps.bit_mask())
         .no_validate() // shaderFloat64 causes validation errors
-        .build(
+        .build_with_reference(
+            Reference::Displacements,
             move |node: VNode,
                   slot: usize,
                   parent_slot: Option<usize>,
@@ -447,7 +537,7 @@ pub(crate) fn generators(
                     Some(_) => (Vector2::new(node.x() & 1, node.y() & 1), base_stride / 2),
                     None => (Vector2::new(0, 0), base_stride),
                 };
-                let world_center = node.center_wspace();
+                let world_center = node.center_wspace(&surface);
                 let resolution = displacements_resolution - 1;
                 let level_resolution = resolution << node.level();
                 GenDisplacementsUniforms {
@@ -469,7 +559,15 @@ pub(crate) fn generators(
                     ],
                     face: node.face() as i32,
                     level_resolution,
-                    padding0: 0.0,
+                    flat_extent: match surface {
+                        Surface::Planet(_) => 0.0,
+                        Surface::Flat { extent } => extent,
+                    },
+                    planet_radius: surface.source_planet().radius,
+                    sea_level: match surface {
+                        Surface::Planet(planet) => planet.sea_level.unwrap_or(f32::MIN),
+                        Surface::Flat { .. } => 0.0,
+                    },
                 }
             },
         ),
@@ -484,7 +582,7 @@ pub(crate) fn generators(
         .no_validate() // validation doesn't support barrier() yet.
         .build(move |node: VNode, slot: usize, _, _| -> GenNormalsUniforms {
             let spacing =
-                node.aprox_side_length() / (normals_resolution - normals_border * 2) as f32;
+                node.aprox_side_length(&source) / (normals_resolution - normals_border * 2) as f32;
 
             GenNormalsUniforms {
                 heightmaps_origin: [
@@ -507,14 +605,15 @@ pub(crate) fn generators(
         .peer_inputs(LayerType::Heightmaps.bit_mask())
         .blit_from_bc5_staging(LayerType::Normals)
         .no_validate() // validation doesn't support barrier() yet.
-        .build(
+        .build_with_reference(
+            Reference::Materials,
             move |node: VNode,
                   slot: usize,
                   parent_slot: Option<usize>,
                   output_mask: LayerMask|
                   -> GenMaterialsUniforms {
                 let spacing =
-                    node.aprox_side_length() / (normals_resolution - normals_border * 2) as f32;
+                    node.aprox_side_length(&source) / (normals_resolution - normals_border * 2) as f32;
 
                 let albedo_slot =
                     if output_mask.contains_tile(LayerType::Albedo) { slot as i32 } else { -1 };
@@ -547,72 +646,169 @@ pub(crate) fn generators(
                 }
             },
         ),
+        ShaderGenBuilder::new(
+            "root-horizons".into(),
+            rshader::shader_source!("../shaders", "gen-horizons.comp", "declarations.glsl"; "ROOT" = "1"),
+        )
+        .root_outputs(LayerType::Horizons.bit_mask())
+        .dimensions((horizons_resolution + 7) / 8)
+        .peer_inputs(LayerType::Heightmaps.bit_mask())
+        .build(horizons_uniforms),
+        ShaderGenBuilder::new(
+            "horizons".into(),
+            rshader::shader_source!("../shaders", "gen-horizons.comp", "declarations.glsl"; "ROOT" = "0"),
+        )
+        .outputs(LayerType::Horizons.bit_mask())
+        .dimensions((horizons_resolution + 7) / 8)
+        .peer_inputs(LayerType::Heightmaps.bit_mask())
+        .parent_inputs(LayerType::Horizons.bit_mask())
+        .build(horizons_uniforms),
+        ShaderGenBuilder::new(
+            "water".into(),
+            rshader::shader_source!("../shaders", "gen-water.comp", "declarations.glsl"),
+        )
+        .outputs(LayerType::Water.bit_mask())
+        .dimensions((water_resolution + 7) / 8)
+        .peer_inputs(LayerType::Heightmaps.bit_mask())
+        .build(move |_: VNode, slot: usize, _: Option<usize>, _: LayerMask| {
+            let heightmaps_cells = heightmaps_resolution - heightmaps_border * 2 - 1;
+            let stride = heightmaps_cells as f32 / (water_resolution - water_border * 2) as f32;
+            let origin = heightmaps_border as f32 - water_border as f32 * stride;
+            GenWaterUniforms {
+                heightmaps_origin: [origin, origin],
+                heightmaps_stride: stride,
+                heightmaps_slot: slot as i32,
+            }
+        }),
     ]
 }
 
+/// Build the generator for a user-defined layer.
+pub(crate) fn custom_generator(
+    desc: CustomLayerDesc,
+    layer: &LayerParams,
+    surface: Surface,
+) -> Box<dyn GenerateTile> {
+    let resolution = layer.texture_resolution;
+    let border = layer.texture_border_size;
+    let source = Surface::Planet(surface.source_planet());
+
+    let mask = |layers: &[LayerType]| {
+        layers.iter().fold(LayerMask::empty(), |mask, layer| mask | layer.bit_mask())
+    };
+
+    ShaderGenBuilder::new(desc.name.clone(), desc.generator)
+        .outputs(layer.layer_type.bit_mask())
+        .root_outputs(layer.layer_type.bit_mask())
+        .dimensions((resolution + 7) / 8)
+        .peer_inputs(mask(&desc.peer_inputs))
+        .parent_inputs(mask(&desc.parent_inputs))
+        .build(
+            move |node: VNode,
+                  slot: usize,
+                  parent_slot: Option<usize>,
+                  _|
+                  -> GenCustomUniforms {
+                let parent_origin = match node.parent() {
+                    Some((_, parent_index)) => [
+                        if parent_index % 2 == 0 { border / 2 } else { (resolution - border) / 2 },
+                        if parent_index / 2 == 0 { border / 2 } else { (resolution - border) / 2 },
+                    ],
+                    None => [0, 0],
+                };
+
+                GenCustomUniforms {
+                    node_center: node.center_wspace(&surface).cast().unwrap().into(),
+                    spacing: node.aprox_side_length(&source) / (resolution - border * 2 - 1) as f32,
+                    parent_origin,
+                    slot: slot as i32,
+                    parent_slot: parent_slot.map(|s| s as i32).unwrap_or(-1),
+                    face: node.face() as u32,
+                    level: node.level() as u32,
+                    resolution,
+                    border,
+                }
+            },
+        )
+}
+
 pub(crate) struct MapFileBuilder(MapFile);
 impl MapFileBuilder {
     pub(crate) fn new() -> Self {
         let layers: VecMap<LayerParams> = hashmap![
             LayerType::Heightmaps.index() => LayerParams {
                     layer_type: LayerType::Heightmaps,
+                    name: LayerType::Heightmaps.name().to_owned(),
                     texture_resolution: 521,
                     texture_border_size: 4,
                     texture_format: TextureFormat::R32,
-                    tiles_generated_per_frame: 16,
                     // peer_dependency_mask: 0,
                     // parent_dependency_mask: LayerType::Heightmaps.bit_mask(),
                 },
             LayerType::Displacements.index() => LayerParams {
                     layer_type: LayerType::Displacements,
+                    name: LayerType::Displacements.name().to_owned(),
                     texture_resolution: 65,
                     texture_border_size: 0,
                     texture_format: TextureFormat::RGBA32F,
-                    tiles_generated_per_frame: 128,
                     // peer_dependency_mask: 0,
                     // parent_dependency_mask: LayerType::Heightmaps.bit_mask(),
                 },
             LayerType::Albedo.index() => LayerParams {
                     layer_type: LayerType::Albedo,
+                    name: LayerType::Albedo.name().to_owned(),
                     texture_resolution: 516,
                     texture_border_size: 2,
                     texture_format: TextureFormat::RGBA8,
-                    tiles_generated_per_frame: 16,
                     // peer_dependency_mask: 0,
                     // parent_dependency_mask: LayerType::Albedo.bit_mask(),
                 },
             LayerType::Roughness.index() => LayerParams {
                     layer_type: LayerType::Roughness,
+                    name: LayerType::Roughness.name().to_owned(),
                     texture_resolution: 516,
                     texture_border_size: 2,
                     texture_format: TextureFormat::BC4,
-                    tiles_generated_per_frame: 16,
                     // peer_dependency_mask: 0,
                     // parent_dependency_mask: LayerType::Roughness.bit_mask(),
                 },
             LayerType::Normals.index() => LayerParams {
                     layer_type: LayerType::Normals,
+                    name: LayerType::Normals.name().to_owned(),
                     texture_resolution: 516,
                     texture_border_size: 2,
                     texture_format: TextureFormat::BC5,
-                    tiles_generated_per_frame: 16,
                     // peer_dependency_mask: LayerType::Heightmaps.bit_mask(),
                     // parent_dependency_mask: LayerType::Albedo.bit_mask(),
                 },
+            LayerType::Horizons.index() => LayerParams {
+                    layer_type: LayerType::Horizons,
+                    name: LayerType::Horizons.name().to_owned(),
+                    texture_resolution: 68,
+                    texture_border_size: 2,
+                    texture_format: TextureFormat::RGBA16F,
+                },
+            LayerType::Water.index() => LayerParams {
+                    layer_type: LayerType::Water,
+                    name: LayerType::Water.name().to_owned(),
+                    texture_resolution: 68,
+                    texture_border_size: 2,
+                    texture_format: TextureFormat::RGBA8,
+                },
         ]
         .into_iter()
         .collect();
 
         let mapfile = MapFile::new(layers);
-        VNode::breadth_first(|n| {
+        VNode::breadth_first(&Surface::default(), |n| {
             mapfile.reload_tile_state(LayerType::Heightmaps, n, true).unwrap();
             n.level() < VNode::LEVEL_CELL_76M
         });
-        VNode::breadth_first(|n| {
+        VNode::breadth_first(&Surface::default(), |n| {
             mapfile.reload_tile_state(LayerType::Albedo, n, true).unwrap();
             n.level() < VNode::LEVEL_CELL_610M
         });
-        VNode::breadth_first(|n| {
+        VNode::breadth_first(&Surface::default(), |n| {
             mapfile.reload_tile_state(LayerType::Roughness, n, true).unwrap();
             false
         });
@@ -620,6 +816,12 @@ impl MapFileBuilder {
         Self(mapfile)
     }
 
+    /// Add a user-defined layer to the map file.
+    pub(crate) fn custom_layer(mut self, layer: LayerParams) -> Self {
+        self.0.add_layer(layer);
+        self
+    }
+
     /// Actually construct the `QuadTree`.
     ///
     /// This function will (the first time it is called) download many gigabytes of raw data,
@@ -631,14 +833,16 @@ impl MapFileBuilder {
     /// of CPU resources. You can expect it to run at full load continiously for several full
     /// minutes, even in release builds (you *really* don't want to wait for generation in debug
     /// mode...).
-    pub(crate) async fn build(mut self) -> Result<MapFile, Error> {
+    ///
+    /// The sky lookup tables are computed for the atmosphere of `planet`.
+    pub(crate) async fn build(mut self, planet: &PlanetDesc) -> Result<MapFile, Error> {
         let mut context = AssetLoadContextBuf::new();
         let mut context = context.context("Building Terrain...", 1);
         // generate_heightmaps(&mut mapfile, &mut context).await?;
         // generate_albedo(&mut mapfile, &mut context)?;
         // generate_roughness(&mut mapfile, &mut context)?;
         generate_noise(&mut self.0, &mut context)?;
-        generate_sky(&mut self.0, &mut context)?;
+        generate_sky(&mut self.0, planet, &mut context)?;
 
         Ok(self.0)
     }
@@ -687,7 +891,7 @@ impl Terrain {
         let (missing_sectors, total_sectors) = {
             let mut missing_sectors = Vec::new();
             let mut total_sectors = 0;
-            for &root_node in &VNode::roots() {
+            for &root_node in Surface::default().roots() {
                 for x in 0..sectors_per_side {
                     for y in 0..sectors_per_side {
                         total_sectors += 1;
@@ -794,7 +998,7 @@ impl Terrain {
                     unordered.push(async move {
                         let bytes = tokio::fs::read(path).await?;
                         let tile = tokio::task::spawn_blocking(move || {
-                            let (sector_resolution, sector) =
+                            let (sector_resolution, sector, _) =
                                 heightmap::uncompress_heightmap_tile(None, &bytes);
 
                             let step = ((sector_resolution - 1) * (resolution / sector_size))
@@ -896,6 +1100,7 @@ impl Terrain {
                         node.parent().map(|p| (p.1, tile_cache.get_tile(&self.mapfile, p.0)));
 
                     let mut heights = vec![0; resolution * resolution];
+                    let mut water = vec![false; resolution * resolution];
                     let fut = if ((resolution - 1) << node.level() + 1) < face_resolution {
                         let face_scale = (face_resolution - 1) / (resolution - 1);
                         let face_step = face_scale >> node.level();
@@ -919,7 +1124,9 @@ impl Terrain {
                                                 + x * face_step];
                                         }
                                     }
-                                    heights
+                                    // Face heightmaps don't record water, so coarse tiles have
+                                    // no lakes or rivers.
+                                    (heights, water)
                                 })
                                 .await?,
                             )
@@ -976,11 +1183,13 @@ impl Terrain {
                                                 y: ((y * step + root_y) / (sector_resolution - 1)) as u32,
                                             };
                                             let sector = &sectors_map[&s];
-                                            heights[y * resolution + x] =
-                                                sector[sector_y * sector_resolution + sector_x];
+                                            let i = sector_y * sector_resolution + sector_x;
+                                            heights[y * resolution + x] = sector.heights[i];
+                                            water[y * resolution + x] =
+                                                sector.water.mask.get(i).map_or(false, |&m| m > 0);
                                         }
                                     }
-                                    heights
+                                    (heights, water)
                                 })
                                 .await?,
                             )
@@ -989,10 +1198,10 @@ impl Terrain {
                     };
 
                     unordered.push(async move {
-                        let heights = fut.await?;
+                        let (heights, water) = fut.await?;
                         let parent = if let Some(p) = parent {
                             let tile = p.1.await?;
-                            assert_eq!(tile.len(), resolution * resolution);
+                            assert_eq!(tile.heights.len(), resolution * resolution);
                             Some((p.0, border_size, tile))
                         } else {
                             None
@@ -1003,11 +1212,14 @@ impl Terrain {
                                 let parent = match parent {
                                     Some(p) => {
                                         parent_heights = p.2;
-                                        Some((p.0, p.1, &**parent_heights))
+                                        Some((p.0, p.1, &*parent_heights.heights))
                                     }
                                     None => None,
                                 };
 
+                                // Find lakes and rivers before the heights lose precision.
+                                let water =
+                                    heightmap::InlandWater::find(resolution, &heights, &water);
                                 (
                                     node,
                                     heightmap::compress_heightmap_tile(
@@ -1016,6 +1228,7 @@ impl Terrain {
                                             as i8,
                                         &heights,
                                         parent,
+                                        &water,
                                         5,
                                     ),
                                 )
@@ -1184,18 +1397,32 @@ impl Terrain {
 }
 
 fn generate_noise(mapfile: &mut MapFile, context: &mut AssetLoadContext) -> Result<(), Error> {
-    if !mapfile.reload_texture("noise") {
+    generate_noise_texture(mapfile, context, "noise", 2048)?;
+    generate_noise_texture(mapfile, context, "cloud_weather", 512)?;
+    generate_noise_texture(mapfile, context, "cloud_coverage", 1024)
+}
+
+/// Generate a tileable square texture where each successive channel holds wavelet noise with half
+/// the wavelength of the one before it, remapped to be uniformly distributed.
+fn generate_noise_texture(
+    mapfile: &mut MapFile,
+    context: &mut AssetLoadContext,
+    name: &str,
+    resolution: usize,
+) -> Result<(), Error> {
+    if !mapfile.reload_texture(name) {
         // wavelength = 1.0 / 256.0;
         let noise_desc = TextureDescriptor {
-            width: 2048,
-            height: 2048,
+            width: resolution as u32,
+            height: resolution as u32,
             depth: 1,
             format: TextureFormat::RGBA8,
-            bytes: 4 * 2048 * 2048,
+            bytes: 4 * resolution * resolution,
         };
 
-        let noise_heightmaps: Vec<_> =
-            (0..4).map(|i| crate::terrain::heightmap::wavelet_noise(64 << i, 32 >> i)).collect();
+        let noise_heightmaps: Vec<_> = (0..4)
+            .map(|i| crate::terrain::heightmap::wavelet_noise((resolution / 32) << i, 32 >> i))
+            .collect();
 
         context.reset("Generating noise textures... ", noise_heightmaps.len());
 
@@ -1210,12 +1437,16 @@ fn generate_noise(mapfile: &mut MapFile, context: &mut AssetLoadContext) -> Resu
             }
         }
 
-        mapfile.write_texture("noise", noise_desc, &heights[..])?;
+        mapfile.write_texture(name, noise_desc, &heights[..])?;
     }
     Ok(())
 }
 
-fn generate_sky(mapfile: &mut MapFile, context: &mut AssetLoadContext) -> Result<(), Error> {
+fn generate_sky(
+    mapfile: &mut MapFile,
+    planet: &PlanetDesc,
+    context: &mut AssetLoadContext,
+) -> Result<(), Error> {
     if !mapfile.reload_texture("sky") {
         context.reset("Generating sky texture... ", 1);
         let sky = WebTextureAsset {
@@ -1225,10 +1456,17 @@ fn generate_sky(mapfile: &mut MapFile, context: &mut AssetLoadContext) -> Result
         .load(context)?;
         mapfile.write_texture("sky", sky.0, &sky.1)?;
     }
-    if !mapfile.reload_texture("transmittance") || !mapfile.reload_texture("inscattering") {
-        let atmosphere = crate::sky::Atmosphere::new(context)?;
+    if !mapfile.reload_texture("stars") {
+        context.reset("Generating star catalog... ", 1);
+        let stars = crate::sky::night::StarCatalog.load(context)?;
+        mapfile.write_texture("stars", stars.0, &stars.1)?;
+    }
+    let transmittance = crate::sky::table_name("transmittance", planet);
+    let inscattering = crate::sky::table_name("inscattering", planet);
+    if !mapfile.reload_texture(&transmittance) || !mapfile.reload_texture(&inscattering) {
+        let atmosphere = crate::sky::Atmosphere::new(planet, context)?;
         mapfile.write_texture(
-            "transmittance",
+            &transmittance,
             TextureDescriptor {
                 width: atmosphere.transmittance.size[0] as u32,
                 height: atmosphere.transmittance.size[1] as u32,
@@ -1239,7 +1477,7 @@ fn generate_sky(mapfile: &mut MapFile, context: &mut AssetLoadContext) -> Result
             bytemuck::cast_slice(&atmosphere.transmittance.data),
         )?;
         mapfile.write_texture(
-            "inscattering",
+            &inscattering,
             TextureDescriptor {
                 width: atmosphere.inscattering.size[0] as u32,
                 height: atmosphere.inscattering.size[1] as u32,
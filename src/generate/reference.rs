@@ -0,0 +1,968 @@
+//! CPU reference implementations of the tile generation shaders.
+//!
+//! Each function mirrors the corresponding compute shader invocation-for-invocation, so that the
+//! generators can be tested without a GPU and GPU output can be checked against them at runtime.
+
+use crate::cache::{LayerParams, LayerType};
+use crate::generate::{GenDisplacementsUniforms, GenHeightmapsUniforms, GenMaterialsUniforms};
+use vec_map::VecMap;
+
+fn hash(mut x: u32) -> u32 {
+    x = x.wrapping_add(x << 10);
+    x ^= x >> 6;
+    x = x.wrapping_add(x << 3);
+    x ^= x >> 11;
+    x = x.wrapping_add(x << 15);
+    x
+}
+fn hash2(v: [u32; 2]) -> u32 {
+    hash(v[0] ^ hash(v[1]))
+}
+fn hash3(v: [u32; 3]) -> u32 {
+    hash(v[0] ^ hash(v[1]) ^ hash(v[2]))
+}
+fn float_construct(m: u32) -> f32 {
+    f32::from_bits((m & 0x007fffff) | 0x3f800000) - 1.0
+}
+fn random(x: f32) -> f32 {
+    float_construct(hash(x.to_bits()))
+}
+fn gaussian_random(v: [f32; 2]) -> [f32; 2] {
+    let u1 = float_construct(hash2([v[0].to_bits(), v[1].to_bits()]));
+    let u2 = random(u1);
+    let r = (-2.0 * (1.0 - u1).ln()).sqrt();
+    let theta = 2.0 * std::f32::consts::PI * u2;
+    [r * theta.sin(), r * theta.cos()]
+}
+
+fn mix(x: f32, y: f32, a: f32) -> f32 {
+    x * (1.0 - a) + y * a
+}
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+/// GLSL `sign`, which unlike `f64::signum` returns zero for zero.
+fn sign(x: f64) -> f64 {
+    if x > 0.0 {
+        1.0
+    } else if x < 0.0 {
+        -1.0
+    } else {
+        0.0
+    }
+}
+
+pub(crate) fn encode_height(height: f32) -> u32 {
+    ((height + 1024.0) * 512.0) as u32 | if height < 0.0 { 0x800000 } else { 0 }
+}
+pub(crate) fn extract_height(encoded: u32) -> f32 {
+    (encoded & 0x7fffff) as f32 * (1.0 / 512.0) - 1024.0
+}
+fn is_inland_water(encoded: u32) -> bool {
+    encoded & 0x1000000 != 0
+}
+
+/// A single layer of a tile cache texture. Loads outside the image return zero, like they do for
+/// the GPU.
+struct Image<'a, T> {
+    data: &'a [T],
+    resolution: usize,
+}
+impl<'a, T: Copy + Default> Image<'a, T> {
+    fn new(data: &'a [T], resolution: usize) -> Self {
+        assert_eq!(data.len(), resolution * resolution);
+        Self { data, resolution }
+    }
+    fn load(&self, x: i32, y: i32) -> T {
+        if x < 0 || y < 0 || x as usize >= self.resolution || y as usize >= self.resolution {
+            T::default()
+        } else {
+            self.data[x as usize + y as usize * self.resolution]
+        }
+    }
+}
+
+/// Reference implementation of `gen-heightmaps.comp`. Returns encoded heights for the tile.
+pub(crate) fn heightmaps(
+    ubo: &GenHeightmapsUniforms,
+    parent: &[u32],
+    resolution: usize,
+) -> Vec<u32> {
+    let parent = Image::new(parent, resolution);
+    let mut output = vec![0; resolution * resolution];
+
+    let groups = (resolution + 7) / 8;
+    for (group_x, group_y) in (0..groups).flat_map(|y| (0..groups).map(move |x| (x, y))) {
+        // Parent heights, x slopes, y slopes and cross derivatives, as stored in shared memory.
+        let mut h = [[[0.0f32; 4]; 10]; 10];
+        let origin = [ubo.origin[0] + group_x as i32 * 4, ubo.origin[1] + group_y as i32 * 4];
+        for (x, column) in h.iter_mut().enumerate() {
+            for (y, value) in column.iter_mut().enumerate() {
+                value[0] =
+                    extract_height(parent.load(origin[0] + x as i32 - 2, origin[1] + y as i32 - 2));
+            }
+        }
+        for x in 0..8 {
+            for y in 0..8 {
+                h[x + 1][y + 1][1] = (h[x + 2][y + 1][0] - h[x][y + 1][0]) * 0.5;
+                h[x + 1][y + 1][2] = (h[x + 1][y + 2][0] - h[x + 1][y][0]) * 0.5;
+                h[x + 1][y + 1][3] = (h[x + 2][y + 2][0] - h[x][y + 1][0] - h[x + 1][y][0]
+                    + h[x + 1][y + 1][0])
+                    * 0.5;
+            }
+        }
+
+        for (local_x, local_y) in (0..8).flat_map(|y| (0..8).map(move |x| (x, y))) {
+            let global = [group_x * 8 + local_x, group_y * 8 + local_y];
+            if global[0] >= resolution || global[1] >= resolution {
+                continue;
+            }
+
+            // Bicubic interpolation, with `f[j][i]` the coefficient for the i-th basis function in
+            // x and the j-th in y.
+            let (x, y) = (local_x / 2 + 2, local_y / 2 + 2);
+            let f = [
+                [h[x][y][0], h[x + 1][y][0], h[x][y][1], h[x + 1][y][1]],
+                [h[x][y + 1][0], h[x + 1][y + 1][0], h[x][y + 1][1], h[x + 1][y + 1][1]],
+                [h[x][y][2], h[x + 1][y][2], h[x][y][3], h[x + 1][y][3]],
+                [h[x][y + 1][2], h[x + 1][y + 1][2], h[x][y + 1][3], h[x + 1][y + 1][3]],
+            ];
+            let basis = |t: f32| {
+                [
+                    1.0 - 3.0 * t * t + 2.0 * t * t * t,
+                    3.0 * t * t - 2.0 * t * t * t,
+                    t - 2.0 * t * t + t * t * t,
+                    t * t * t - t * t,
+                ]
+            };
+            let basis_derivative = |t: f32| {
+                [
+                    6.0 * t * t - 6.0 * t,
+                    6.0 * t - 6.0 * t * t,
+                    1.0 - 4.0 * t + 3.0 * t * t,
+                    3.0 * t * t - 2.0 * t,
+                ]
+            };
+            let eval = |a: [f32; 4], b: [f32; 4]| -> f32 {
+                (0..4).map(|j| b[j] * (0..4).map(|i| a[i] * f[j][i]).sum::<f32>()).sum()
+            };
+
+            let t = [(global[0] % 2) as f32 / 2.0, (global[1] % 2) as f32 / 2.0];
+            let mut height = eval(basis(t[0]), basis(t[1]));
+            let dx = eval(basis_derivative(t[0]), basis(t[1])) / ubo.spacing;
+            let dy = eval(basis(t[0]), basis_derivative(t[1])) / ubo.spacing;
+            let mut slope = (dx * dx + dy * dy).sqrt();
+
+            let p = [global[0] as i32 + ubo.position[0], global[1] as i32 + ubo.position[1]];
+            let half = ubo.level_resolution / 2;
+            let position = match ubo.face {
+                0 => [half, p[0], -p[1]],
+                1 => [-half, -p[0], -p[1]],
+                2 => [p[0], half, p[1]],
+                3 => [-p[0], -half, p[1]],
+                4 => [p[0], -p[1], half],
+                5 => [-p[0], -p[1], -half],
+                _ => unreachable!(),
+            };
+
+            // Tiles on either side of a seam must agree on the slope there.
+            if p[0].abs() >= half || p[1].abs() >= half {
+                slope = 0.0;
+            }
+
+            let n = float_construct(hash3([
+                position[0].wrapping_add(half) as u32,
+                position[1].wrapping_add(half) as u32,
+                position[2].wrapping_add(half) as u32,
+            ]))
+            .powi(2);
+            height += n * ubo.spacing * mix(0.1, 0.4, smoothstep(0.4, 0.5, slope));
+            let mut encoded = encode_height(height);
+
+            // Lakes and rivers stay flat, so samples that lie entirely within one keep its level
+            // and flow.
+            let parent_position = [origin[0] + local_x as i32 / 2, origin[1] + local_y as i32 / 2];
+            let odd = [(global[0] % 2) as i32, (global[1] % 2) as i32];
+            let p00 = parent.load(parent_position[0], parent_position[1]);
+            if [[0, 0], [odd[0], 0], [0, odd[1]], odd].iter().all(|offset| {
+                is_inland_water(
+                    parent.load(parent_position[0] + offset[0], parent_position[1] + offset[1]),
+                )
+            }) {
+                encoded = p00;
+            }
+
+            output[global[0] + global[1] * resolution] = encoded;
+        }
+    }
+
+    output
+}
+
+/// Reference implementation of `gen-displacements.comp`. Returns the position of each vertex
+/// relative to the node center.
+pub(crate) fn displacements(
+    ubo: &GenDisplacementsUniforms,
+    heightmaps: &[u32],
+    heightmaps_resolution: usize,
+    resolution: usize,
+) -> Vec<[f32; 4]> {
+    // The shader's constants are single precision literals that get widened to double.
+    const C1_4511: f64 = 1.4511f32 as f64;
+    const C1_4511_SQUARED: f64 = (1.4511f32 * 1.4511f32) as f64;
+    const C1_8044: f64 = 1.8044f32 as f64;
+    const CINV_0_9022: f64 = (1.0f32 / 0.9022f32) as f64;
+
+    let heightmaps = Image::new(heightmaps, heightmaps_resolution);
+    let inv_level_resolution = (1.0 / ubo.level_resolution as f32) as f64;
+    let warp =
+        |f: f64| sign(f) * (C1_4511 - (C1_4511_SQUARED - C1_8044 * f.abs()).sqrt()) * CINV_0_9022;
+
+    let mut output = vec![[0.0; 4]; resolution * resolution];
+    for y in 0..resolution {
+        for x in 0..resolution {
+            let encoded = heightmaps
+                .load(ubo.origin[0] + x as i32 * ubo.stride, ubo.origin[1] + y as i32 * ubo.stride);
+            let ocean = encoded & 0x800000 != 0 && !is_inland_water(encoded);
+            let mut height = extract_height(encoded);
+            if ocean {
+                height = height.max(ubo.sea_level);
+            }
+            let water = if is_inland_water(encoded) {
+                2.0
+            } else if ocean && extract_height(encoded) < ubo.sea_level {
+                1.0 + (ubo.sea_level - extract_height(encoded))
+            } else {
+                0.0
+            };
+
+            let face_x = (2 * (x as i32 + ubo.position[0])) as f64 * inv_level_resolution;
+            let face_y = (2 * (y as i32 + ubo.position[1])) as f64 * inv_level_resolution;
+
+            if ubo.flat_extent > 0.0 {
+                let half_extent = ubo.flat_extent * 0.5;
+                let scale = ubo.flat_extent / (ubo.planet_radius * std::f64::consts::FRAC_PI_2);
+                output[x + y * resolution] = [
+                    (height as f64 * scale - ubo.node_center[0]) as f32,
+                    (face_x * half_extent - ubo.node_center[1]) as f32,
+                    (-face_y * half_extent - ubo.node_center[2]) as f32,
+                    water,
+                ];
+                continue;
+            }
+
+            let warped_x = warp(face_x);
+            let warped_y = warp(face_y);
+
+            let cube_z = (ubo.planet_radius + height as f64)
+                / (warped_x * warped_x + (warped_y * warped_y + 1.0)).sqrt();
+            let cube_x = warped_x * cube_z;
+            let cube_y = warped_y * cube_z;
+
+            let world = match ubo.face {
+                0 => [cube_z, cube_x, -cube_y],
+                1 => [-cube_z, -cube_x, -cube_y],
+                2 => [cube_x, cube_z, cube_y],
+                3 => [-cube_x, -cube_z, cube_y],
+                4 => [cube_x, -cube_y, cube_z],
+                5 => [-cube_x, -cube_y, -cube_z],
+                _ => unreachable!(),
+            };
+
+            output[x + y * resolution] = [
+                (world[0] - ubo.node_center[0]) as f32,
+                (world[1] - ubo.node_center[1]) as f32,
+                (world[2] - ubo.node_center[2]) as f32,
+                water,
+            ];
+        }
+    }
+    output
+}
+
+pub(crate) struct MaterialsOutput {
+    /// BC5 compressed normals, one 4x4 block per entry.
+    pub normals: Vec<[u32; 4]>,
+    /// Albedo and roughness, or `None` if the albedo wasn't being generated.
+    pub albedo: Option<Vec<[u8; 4]>>,
+}
+
+/// Reference implementation of `gen-materials.comp`.
+pub(crate) fn materials(
+    ubo: &GenMaterialsUniforms,
+    heightmaps: &[u32],
+    heightmaps_resolution: usize,
+    parent_albedo: Option<&[[u8; 4]]>,
+    resolution: usize,
+) -> MaterialsOutput {
+    let heightmaps = Image::new(heightmaps, heightmaps_resolution);
+    let parent_albedo = parent_albedo.map(|p| Image::new(p, resolution));
+
+    let blocks = (resolution + 3) / 4;
+    let mut normals = vec![[0; 4]; blocks * blocks];
+    let mut albedo = vec![[0; 4]; resolution * resolution];
+
+    for (block_x, block_y) in (0..blocks).flat_map(|y| (0..blocks).map(move |x| (x, y))) {
+        let mut group_normals = [[0.0f32; 2]; 16];
+        for (local_x, local_y) in (0..4).flat_map(|y| (0..4).map(move |x| (x, y))) {
+            let (x, y) = ((block_x * 4 + local_x) as i32, (block_y * 4 + local_y) as i32);
+            let (in_x, in_y) = (x + ubo.heightmaps_origin[0], y + ubo.heightmaps_origin[1]);
+
+            let e00 = heightmaps.load(in_x, in_y);
+            let e10 = heightmaps.load(in_x + 1, in_y);
+            let e01 = heightmaps.load(in_x, in_y + 1);
+            let e11 = heightmaps.load(in_x + 1, in_y + 1);
+
+            let is_water = [e00, e10, e01, e11].iter().filter(|&&e| e & 0x800000 != 0).count() > 2;
+
+            let h00 = extract_height(e00);
+            let h10 = extract_height(e10);
+            let h01 = extract_height(e01);
+            let h11 = extract_height(e11);
+
+            let mut normal = [0.0, 1.0, 0.0];
+            if !is_water {
+                normal = [h10 + h11 - h00 - h01, 2.0 * ubo.spacing, -(h01 + h11 - h00 - h10)];
+                let length =
+                    (normal[0] * normal[0] + normal[1] * normal[1] + normal[2] * normal[2]).sqrt();
+                normal = [normal[0] / length, normal[1] / length, normal[2] / length];
+            }
+
+            let noise_value = 0.5;
+            let mut albedo_roughness = [0.011, 0.03, 0.003, 0.7];
+            let rock = 1.0 - smoothstep(0.80, 0.95, normal[1]);
+
+            let grass_fraction = mix(0.0, 0.3, smoothstep(0.95, 1.0, normal[1]));
+            let grass = if noise_value < grass_fraction { 0.0 } else { 1.0 };
+            let rgb = [mix(0.03, 0.0, grass), mix(0.02, 0.1, grass), mix(0.0, 0.0, grass)];
+            for i in 0..3 {
+                albedo_roughness[i] = mix(rgb[i], 0.02, rock);
+            }
+
+            if ubo.parent_slot >= 0 {
+                let parent = parent_albedo.as_ref().expect("missing parent albedo");
+                let nv = gaussian_random([x as f32, y as f32]);
+                let offset =
+                    [(nv[0].round() as i32).clamp(-1, 1), (nv[1].round() as i32).clamp(-1, 1)];
+                let p = parent.load(
+                    ubo.parent_origin[0] as i32 + (x + offset[0]) / 2,
+                    ubo.parent_origin[1] as i32 + (y + offset[1]) / 2,
+                );
+                let p = [
+                    p[0] as f32 / 255.0,
+                    p[1] as f32 / 255.0,
+                    p[2] as f32 / 255.0,
+                    p[3] as f32 / 255.0,
+                ];
+
+                // Water texels have low roughness, so avoid blending them onto the land.
+                albedo_roughness = if p[3] > 0.5 { p } else { [0.2, 0.2, 0.15, 0.8] };
+            }
+
+            if is_water {
+                albedo_roughness[3] = 0.1;
+                let negative_depth = (h00 + h10 + h01 + h11).min(0.0);
+                let water = [0.0, 0.03, 0.2];
+                let absorption = [5.0, 0.5, 0.5];
+                for i in 0..3 {
+                    albedo_roughness[i] =
+                        mix(water[i], albedo_roughness[i], (negative_depth * absorption[i]).exp());
+                }
+            }
+
+            if x < resolution as i32 && y < resolution as i32 {
+                let unorm = |v: f32| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+                albedo[x as usize + y as usize * resolution] = [
+                    unorm(albedo_roughness[0]),
+                    unorm(albedo_roughness[1]),
+                    unorm(albedo_roughness[2]),
+                    unorm(albedo_roughness[3]),
+                ];
+            }
+            group_normals[local_x + 4 * local_y] = [normal[0] * 0.5 + 0.5, normal[2] * 0.5 + 0.5];
+        }
+
+        normals[block_x + block_y * blocks] = encode_bc5(&group_normals);
+    }
+
+    MaterialsOutput { normals, albedo: if ubo.albedo_slot >= 0 { Some(albedo) } else { None } }
+}
+
+/// Compress a 4x4 block of two channel values the same way the generator shaders do.
+fn encode_bc5(values: &[[f32; 2]; 16]) -> [u32; 4] {
+    const PERMUTE: [u32; 8] = [1, 7, 6, 5, 4, 3, 2, 0];
+
+    let mut output = [0; 4];
+    for c in 0..2 {
+        let nmin = values.iter().map(|v| v[c]).fold(values[0][c], f32::min);
+        let nmax = values.iter().map(|v| v[c]).fold(values[0][c], f32::max);
+
+        let qnmin = ((nmin * 255.0).floor() as u32).min(254);
+        let qnmax = ((nmax * 255.0).ceil() as u32).max(qnmin + 1).min(255);
+
+        let nmin = qnmin as f32 / 255.0;
+        let nmax = qnmax as f32 / 255.0;
+
+        let w: Vec<u32> = values
+            .iter()
+            .map(|v| PERMUTE[(7.0 * (v[c] - nmin) / (nmax - nmin)) as u32 as usize])
+            .collect();
+
+        output[c * 2] = qnmax
+            | qnmin << 8
+            | w[0] << 16
+            | w[1] << 19
+            | w[2] << 22
+            | w[3] << 25
+            | w[4] << 28
+            | (w[5] & 1) << 31;
+        output[c * 2 + 1] = ((w[5] & 6) >> 1)
+            | w[6] << 2
+            | w[7] << 5
+            | w[8] << 8
+            | w[9] << 11
+            | w[10] << 14
+            | w[11] << 17
+            | w[12] << 20
+            | w[13] << 23
+            | w[14] << 26
+            | w[15] << 29;
+    }
+    output
+}
+
+/// Decompress a BC5 block into two channels of 16 values each.
+fn decode_bc5(block: [u32; 4]) -> [[f32; 16]; 2] {
+    let mut output = [[0.0; 16]; 2];
+    for (c, channel) in output.iter_mut().enumerate() {
+        let (lo, hi) = (block[c * 2], block[c * 2 + 1]);
+        let (r0, r1) = ((lo & 0xff) as f32, ((lo >> 8) & 0xff) as f32);
+        let indices = (lo >> 16) as u64 | (hi as u64) << 16;
+        for (i, value) in channel.iter_mut().enumerate() {
+            *value = match (indices >> (3 * i)) & 7 {
+                0 => r0,
+                1 => r1,
+                j if r0 > r1 => ((8 - j) as f32 * r0 + (j - 1) as f32 * r1) / 7.0,
+                j @ 2..=5 => ((6 - j) as f32 * r0 + (j - 1) as f32 * r1) / 5.0,
+                6 => 0.0,
+                _ => 255.0,
+            } / 255.0;
+        }
+    }
+    output
+}
+
+/// Result of comparing one tile of GPU output against the reference implementation.
+#[derive(Debug)]
+pub(crate) struct Comparison {
+    pub layer: LayerType,
+    /// Largest difference between any GPU value and the reference.
+    pub max_error: f32,
+    /// Number of values whose difference exceeded the tolerance for the layer.
+    pub mismatches: usize,
+    pub values: usize,
+}
+impl Comparison {
+    fn new<T>(
+        layer: LayerType,
+        tolerance: f32,
+        gpu: impl IntoIterator<Item = T>,
+        reference: impl IntoIterator<Item = T>,
+        error: impl Fn(T, T) -> f32,
+    ) -> Self {
+        let mut comparison = Comparison { layer, max_error: 0.0, mismatches: 0, values: 0 };
+        for (g, r) in gpu.into_iter().zip(reference) {
+            let e = error(g, r);
+            comparison.max_error = comparison.max_error.max(e);
+            comparison.mismatches += (e > tolerance) as usize;
+            comparison.values += 1;
+        }
+        comparison
+    }
+}
+
+/// A generator invocation that can be checked against its reference implementation.
+#[derive(Copy, Clone)]
+pub(crate) enum Reference {
+    Heightmaps(GenHeightmapsUniforms),
+    Displacements(GenDisplacementsUniforms),
+    Materials(GenMaterialsUniforms),
+}
+impl Reference {
+    /// Tile cache slots read by the generator, which must be captured before it runs.
+    pub fn inputs(&self) -> Vec<(LayerType, usize)> {
+        match self {
+            Reference::Heightmaps(ubo) => vec![(LayerType::Heightmaps, ubo.in_slot as usize)],
+            Reference::Displacements(ubo) => {
+                vec![(LayerType::Heightmaps, ubo.heightmaps_slot as usize)]
+            }
+            Reference::Materials(ubo) => {
+                let mut inputs = vec![(LayerType::Heightmaps, ubo.heightmaps_slot as usize)];
+                if ubo.parent_slot >= 0 {
+                    inputs.push((LayerType::Albedo, ubo.parent_slot as usize));
+                }
+                inputs
+            }
+        }
+    }
+
+    /// Tile cache slots written by the generator.
+    pub fn outputs(&self) -> Vec<(LayerType, usize)> {
+        match self {
+            Reference::Heightmaps(ubo) => vec![(LayerType::Heightmaps, ubo.out_slot as usize)],
+            Reference::Displacements(ubo) => {
+                vec![(LayerType::Displacements, ubo.displacements_slot as usize)]
+            }
+            Reference::Materials(ubo) => {
+                let mut outputs = vec![(LayerType::Normals, ubo.normals_slot as usize)];
+                if ubo.albedo_slot >= 0 {
+                    outputs.push((LayerType::Albedo, ubo.albedo_slot as usize));
+                }
+                outputs
+            }
+        }
+    }
+
+    /// Compare GPU output against the reference implementation. `inputs` and `outputs` hold the
+    /// tightly packed contents of the slots listed by `inputs()` and `outputs()` respectively.
+    pub fn check(
+        &self,
+        layers: &VecMap<LayerParams>,
+        inputs: &[Vec<u8>],
+        outputs: &[Vec<u8>],
+    ) -> Vec<Comparison> {
+        let u32s = |data: &[u8]| -> Vec<u32> {
+            data.chunks_exact(4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect()
+        };
+        let rgba8s = |data: &[u8]| -> Vec<[u8; 4]> {
+            data.chunks_exact(4).map(|b| [b[0], b[1], b[2], b[3]]).collect()
+        };
+        let heightmaps_resolution = layers[LayerType::Heightmaps].texture_resolution as usize;
+
+        match self {
+            Reference::Heightmaps(ubo) => {
+                let reference = heightmaps(ubo, &u32s(&inputs[0]), heightmaps_resolution);
+                vec![Comparison::new(
+                    LayerType::Heightmaps,
+                    1.0 / 64.0,
+                    u32s(&outputs[0]),
+                    reference,
+                    |g, r| (extract_height(g) - extract_height(r)).abs(),
+                )]
+            }
+            Reference::Displacements(ubo) => {
+                let resolution = layers[LayerType::Displacements].texture_resolution as usize;
+                let reference =
+                    displacements(ubo, &u32s(&inputs[0]), heightmaps_resolution, resolution);
+                let gpu = u32s(&outputs[0]);
+                let gpu = gpu.chunks_exact(4).map(|v| {
+                    [f32::from_bits(v[0]), f32::from_bits(v[1]), f32::from_bits(v[2]), 0.0]
+                });
+
+                // Values are relative to the node center, so allow larger absolute errors for
+                // larger nodes.
+                vec![Comparison::new(LayerType::Displacements, 1e-5, gpu, reference, |g, r| {
+                    (0..3).map(|i| (g[i] - r[i]).abs() / r[i].abs().max(1.0)).fold(0.0, f32::max)
+                })]
+            }
+            Reference::Materials(ubo) => {
+                let resolution = layers[LayerType::Normals].texture_resolution as usize;
+                let parent_albedo = inputs.get(1).map(|d| rgba8s(d));
+                let reference = materials(
+                    ubo,
+                    &u32s(&inputs[0]),
+                    heightmaps_resolution,
+                    parent_albedo.as_deref(),
+                    resolution,
+                );
+
+                let gpu_normals = u32s(&outputs[0]);
+                let gpu_normals = gpu_normals.chunks_exact(4).map(|b| [b[0], b[1], b[2], b[3]]);
+                let mut comparisons = vec![Comparison::new(
+                    LayerType::Normals,
+                    0.02,
+                    gpu_normals.map(decode_bc5),
+                    reference.normals.into_iter().map(decode_bc5),
+                    |g, r| {
+                        (0..16)
+                            .map(|i| (g[0][i] - r[0][i]).abs().max((g[1][i] - r[1][i]).abs()))
+                            .fold(0.0, f32::max)
+                    },
+                )];
+                if let Some(reference_albedo) = reference.albedo {
+                    comparisons.push(Comparison::new(
+                        LayerType::Albedo,
+                        2.0,
+                        rgba8s(&outputs[1]),
+                        reference_albedo,
+                        |g, r| {
+                            (0..4).map(|i| (g[i] as f32 - r[i] as f32).abs()).fold(0.0, f32::max)
+                        },
+                    ));
+                }
+                comparisons
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use cgmath::{InnerSpace, Vector3};
+
+    const HEIGHTMAPS_RESOLUTION: usize = 521;
+    const NORMALS_RESOLUTION: usize = 516;
+    const DISPLACEMENTS_RESOLUTION: usize = 65;
+
+    fn heightmaps_ubo() -> GenHeightmapsUniforms {
+        GenHeightmapsUniforms {
+            position: [-516, -516],
+            origin: [2, 2],
+            spacing: 10.0,
+            in_slot: 0,
+            out_slot: 1,
+            level_resolution: 1024,
+            face: 0,
+        }
+    }
+    fn materials_ubo() -> GenMaterialsUniforms {
+        GenMaterialsUniforms {
+            heightmaps_origin: [2, 2],
+            parent_origin: [1, 1],
+            heightmaps_slot: 0,
+            normals_slot: 0,
+            albedo_slot: 0,
+            parent_slot: -1,
+            spacing: 10.0,
+            padding: 0,
+        }
+    }
+    fn root_displacements_ubo() -> GenDisplacementsUniforms {
+        GenDisplacementsUniforms {
+            node_center: [6371000.0, 0.0, 0.0],
+            flat_extent: 0.0,
+            planet_radius: 6371000.0,
+            origin: [4, 4],
+            position: [-32, -32],
+            stride: 8,
+            heightmaps_slot: 0,
+            displacements_slot: 0,
+            face: 0,
+            level_resolution: 64,
+            sea_level: 0.0,
+        }
+    }
+    fn flat(height: f32) -> Vec<u32> {
+        vec![encode_height(height); HEIGHTMAPS_RESOLUTION * HEIGHTMAPS_RESOLUTION]
+    }
+    fn pattern() -> Vec<u32> {
+        (0..HEIGHTMAPS_RESOLUTION * HEIGHTMAPS_RESOLUTION)
+            .map(|i| {
+                let (x, y) = (i % HEIGHTMAPS_RESOLUTION, i / HEIGHTMAPS_RESOLUTION);
+                encode_height((x * 7 + y * 13) as f32 % 50.0)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hash_golden() {
+        assert_eq!(hash(0), 0);
+        assert_eq!(hash(1), 307143837);
+        assert_eq!(hash3([1, 2, 3]), 102866845);
+        assert_eq!(float_construct(hash(12345)), 0.5295986);
+    }
+
+    #[test]
+    fn heightmaps_golden() {
+        let output = heightmaps(&heightmaps_ubo(), &flat(100.0), HEIGHTMAPS_RESOLUTION);
+        assert_eq!(output[0], 575748);
+        assert_eq!(output[260 + 260 * HEIGHTMAPS_RESOLUTION], 575509);
+        assert_eq!(output[7 + 3 * HEIGHTMAPS_RESOLUTION], 575816);
+        for &h in &output {
+            // Flat terrain should only have the low slope noise applied.
+            let h = extract_height(h);
+            assert!((100.0..=101.0).contains(&h), "{}", h);
+        }
+
+        let output = heightmaps(&heightmaps_ubo(), &pattern(), HEIGHTMAPS_RESOLUTION);
+        assert_eq!(output[0], 545028);
+        assert_eq!(output[260 + 260 * HEIGHTMAPS_RESOLUTION], 544852);
+        assert_eq!(output[HEIGHTMAPS_RESOLUTION * HEIGHTMAPS_RESOLUTION - 1], 544769);
+        assert_eq!(output[7 + 3 * HEIGHTMAPS_RESOLUTION], 544624);
+    }
+
+    #[test]
+    fn heightmaps_keep_inland_water() {
+        // A river flowing along the y axis, which covers the left part of the parent tile.
+        let river = encode_height(100.0) | 0x800000 | 0x1000000 | 0x2000000 | 16 << 26;
+        let parent: Vec<u32> = (0..HEIGHTMAPS_RESOLUTION * HEIGHTMAPS_RESOLUTION)
+            .map(|i| if i % HEIGHTMAPS_RESOLUTION < 100 { river } else { encode_height(100.0) })
+            .collect();
+
+        let output = heightmaps(&heightmaps_ubo(), &parent, HEIGHTMAPS_RESOLUTION);
+        assert_eq!(output[100 + 260 * HEIGHTMAPS_RESOLUTION], river);
+        assert_eq!(output[101 + 261 * HEIGHTMAPS_RESOLUTION], river);
+        assert!(!is_inland_water(output[400 + 260 * HEIGHTMAPS_RESOLUTION]));
+    }
+
+    #[test]
+    fn displacements_on_sphere() {
+        let ubo = root_displacements_ubo();
+        let output =
+            displacements(&ubo, &flat(0.0), HEIGHTMAPS_RESOLUTION, DISPLACEMENTS_RESOLUTION);
+
+        assert_eq!(output[32 + 32 * DISPLACEMENTS_RESOLUTION], [0.0; 4]);
+        for d in output {
+            let p = [
+                d[0] as f64 + ubo.node_center[0],
+                d[1] as f64 + ubo.node_center[1],
+                d[2] as f64 + ubo.node_center[2],
+            ];
+            let r = (p[0] * p[0] + p[1] * p[1] + p[2] * p[2]).sqrt();
+            assert!((r - 6371000.0).abs() < 1.0, "{}", r);
+        }
+    }
+
+    #[test]
+    fn displacements_below_sea_level() {
+        let radius_and_water = |ubo: &GenDisplacementsUniforms, heightmaps: &[u32]| {
+            let output =
+                displacements(ubo, heightmaps, HEIGHTMAPS_RESOLUTION, DISPLACEMENTS_RESOLUTION);
+            let d = output[32 + 32 * DISPLACEMENTS_RESOLUTION];
+            (d[0] as f64 + ubo.node_center[0], d[3])
+        };
+
+        // Oceans hide the terrain beneath them and record its depth, but bodies without any
+        // expose it.
+        assert_eq!(radius_and_water(&root_displacements_ubo(), &flat(-500.0)), (6371000.0, 501.0));
+        let ubo = GenDisplacementsUniforms {
+            node_center: [3389500.0, 0.0, 0.0],
+            planet_radius: 3389500.0,
+            sea_level: f32::MIN,
+            ..root_displacements_ubo()
+        };
+        assert_eq!(radius_and_water(&ubo, &flat(-500.0)), (3389000.0, 0.0));
+
+        // Dry land below sea level, like the shore of the Dead Sea, keeps its real height.
+        let dry: Vec<u32> = flat(-400.0).into_iter().map(|h| h & !0x800000).collect();
+        assert_eq!(radius_and_water(&root_displacements_ubo(), &dry), (6370600.0, 0.0));
+
+        // Lakes and rivers already store the height of their surface.
+        let lake: Vec<u32> = flat(100.0).into_iter().map(|h| h | 0x800000 | 0x1000000).collect();
+        assert_eq!(radius_and_water(&root_displacements_ubo(), &lake), (6371100.0, 2.0));
+    }
+
+    #[test]
+    fn displacements_on_plane() {
+        let ubo = GenDisplacementsUniforms {
+            node_center: [0.0, 0.0, 0.0],
+            flat_extent: 64000.0,
+            ..root_displacements_ubo()
+        };
+        let output =
+            displacements(&ubo, &flat(25.0), HEIGHTMAPS_RESOLUTION, DISPLACEMENTS_RESOLUTION);
+
+        // The plane is a scale model of the face, so heights shrink along with the distances
+        // between samples.
+        let scale = 64000.0 / (6371000.0 * std::f64::consts::FRAC_PI_2);
+        let height = (25.0 * scale) as f32;
+        assert_eq!(output[32 + 32 * DISPLACEMENTS_RESOLUTION], [height, 0.0, 0.0, 0.0]);
+        assert_eq!(output[0], [height, -32000.0, 32000.0, 0.0]);
+        assert_eq!(output[48 + 40 * DISPLACEMENTS_RESOLUTION], [height, 16000.0, -8000.0, 0.0]);
+    }
+
+    #[test]
+    fn plane_sample_spacing() {
+        let ubo = GenDisplacementsUniforms {
+            node_center: [0.0, 0.0, 0.0],
+            flat_extent: 64000.0,
+            ..root_displacements_ubo()
+        };
+        let output =
+            displacements(&ubo, &pattern(), HEIGHTMAPS_RESOLUTION, DISPLACEMENTS_RESOLUTION);
+        let heights = pattern();
+
+        // Neighboring samples are one sixty-fourth of the extent apart, and the slope between them
+        // matches the slope between the same samples on the planet.
+        let planet_spacing = 6371000.0 * std::f64::consts::FRAC_PI_2 / 64.0;
+        for &(x, y) in &[(10, 10), (20, 31), (40, 50)] {
+            let (a, b) = (x + y * DISPLACEMENTS_RESOLUTION, x + 1 + y * DISPLACEMENTS_RESOLUTION);
+            assert_eq!(output[b][1] - output[a][1], 1000.0);
+            assert_eq!(output[b][2] - output[a][2], 0.0);
+
+            let height = |x: usize| {
+                let encoded = heights[4 + x * 8 + (4 + y * 8) * HEIGHTMAPS_RESOLUTION];
+                extract_height(encoded) as f64
+            };
+            let slope = (height(x + 1) - height(x)) / planet_spacing;
+            assert_relative_eq!(
+                ((output[b][0] - output[a][0]) / 1000.0) as f64,
+                slope,
+                max_relative = 1e-4
+            );
+        }
+    }
+
+    #[test]
+    fn materials_golden() {
+        // Flat ground gets straight up normals, which compress to the same block everywhere.
+        const FLAT_BLOCK: [u32; 4] = [3681386368, 3067833782, 3681386368, 3067833782];
+
+        let output = materials(&materials_ubo(), &flat(100.0), HEIGHTMAPS_RESOLUTION, None, 516);
+        assert_eq!(output.normals.len(), 129 * 129);
+        assert!(output.normals.iter().all(|&b| b == FLAT_BLOCK));
+        assert!(output.albedo.unwrap().iter().all(|&a| a == [0, 26, 0, 179]));
+
+        let output = materials(&materials_ubo(), &flat(-10.0), HEIGHTMAPS_RESOLUTION, None, 516);
+        assert!(output.normals.iter().all(|&b| b == FLAT_BLOCK));
+        assert!(output.albedo.unwrap().iter().all(|&a| a == [0, 8, 51, 26]));
+
+        let output = materials(&materials_ubo(), &pattern(), HEIGHTMAPS_RESOLUTION, None, 516);
+        assert_eq!(output.normals[1000], [2521964466, 690262341, 885596921, 1687309101]);
+        assert_eq!(output.albedo.unwrap()[1000], [5, 5, 5, 179]);
+
+        let parent_albedo: Vec<[u8; 4]> = (0..NORMALS_RESOLUTION * NORMALS_RESOLUTION)
+            .map(|i| [(i % 256) as u8, (i / 7 % 256) as u8, 40, if i % 3 == 0 { 20 } else { 200 }])
+            .collect();
+        let ubo = GenMaterialsUniforms { parent_slot: 1, ..materials_ubo() };
+        let output = materials(&ubo, &pattern(), HEIGHTMAPS_RESOLUTION, Some(&parent_albedo), 516);
+        let albedo = output.albedo.unwrap();
+        assert_eq!(albedo[0], [5, 73, 40, 200]);
+        assert_eq!(albedo[1000], [51, 51, 38, 204]);
+        assert_eq!(albedo[5000], [199, 138, 40, 200]);
+    }
+
+    #[test]
+    fn bc5_roundtrip() {
+        let mut values = [[0.0; 2]; 16];
+        for (i, value) in values.iter_mut().enumerate() {
+            *value = [0.3 + 0.01 * i as f32, 0.9 - 0.03 * (i * 7 % 16) as f32];
+        }
+        let decoded = decode_bc5(encode_bc5(&values));
+        for i in 0..16 {
+            assert!((decoded[0][i] - values[i][0]).abs() < 0.15 / 7.0 + 1.0 / 255.0);
+            assert!((decoded[1][i] - values[i][1]).abs() < 0.45 / 7.0 + 1.0 / 255.0);
+        }
+    }
+
+    /// Mirrors `gen-horizons.comp` for a root node on a flat surface, at a single texel.
+    fn horizons_at(
+        heights: &[f32],
+        resolution: usize,
+        x: usize,
+        y: usize,
+        spacing: f32,
+    ) -> [f32; 4] {
+        const DIRECTIONS: [[f32; 2]; 4] = [[1.0, 0.0], [0.0, 1.0], [-1.0, 0.0], [0.0, -1.0]];
+        let base_height = heights[x + y * resolution];
+        let mut horizons = [-1.0f32; 4];
+        for (i, d) in DIRECTIONS.iter().enumerate() {
+            let mut s = 1.0f32;
+            loop {
+                let p = [x as f32 + d[0] * s, y as f32 + d[1] * s];
+                if p.iter().any(|&c| c < 0.0 || c > (resolution - 1) as f32) {
+                    break;
+                }
+                let distance = s * spacing;
+                let rise = heights[p[0].round() as usize + p[1].round() as usize * resolution]
+                    - base_height;
+                horizons[i] = horizons[i].max(rise / (distance * distance + rise * rise).sqrt());
+                s = (s + 1.0).max(s * 1.1);
+            }
+        }
+        horizons
+    }
+
+    /// Mirrors `horizon_visibility` in `terrain.frag`, given the vectors `terrain.vert` computes.
+    fn horizon_visibility(
+        h: [f32; 4],
+        sun: Vector3<f32>,
+        normal: Vector3<f32>,
+        tangent: Vector3<f32>,
+        bitangent: Vector3<f32>,
+    ) -> f32 {
+        let azimuth = (-sun.dot(bitangent)).atan2(-sun.dot(tangent));
+        let sector = (azimuth / (0.5 * std::f32::consts::PI)).rem_euclid(4.0);
+        let i = sector as usize % 4;
+        let horizon = mix(h[i], h[(i + 1) % 4], sector.fract());
+        smoothstep(horizon - 0.01, horizon + 0.01, sun.dot(normal))
+    }
+
+    #[test]
+    fn horizons_shadow_towards_sun() {
+        // A ridge along the +x side of the heightmap.
+        let resolution = 65;
+        let heights: Vec<f32> = (0..resolution * resolution)
+            .map(|i| if i % resolution >= 48 { 1000.0 } else { 0.0 })
+            .collect();
+        let h = horizons_at(&heights, resolution, 32, 32, 100.0);
+
+        // Face 0 maps heightmap +x to ecef +y and heightmap +y to ecef -z.
+        let normal = Vector3::new(1.0, 0.0, 0.0);
+        let bitangent = normal.cross(Vector3::new(0.0, 1.0, 0.0)).normalize();
+        let tangent = normal.cross(bitangent).normalize();
+
+        let visibility =
+            |sun: Vector3<f32>| horizon_visibility(h, sun.normalize(), normal, tangent, bitangent);
+        assert_eq!(visibility(Vector3::new(0.2, 1.0, 0.0)), 0.0);
+        assert_eq!(visibility(Vector3::new(0.2, -1.0, 0.0)), 1.0);
+        assert_eq!(visibility(Vector3::new(0.2, 0.0, 1.0)), 1.0);
+        assert_eq!(visibility(Vector3::new(0.2, 0.0, -1.0)), 1.0);
+    }
+
+    #[test]
+    fn check_against_self() {
+        let mut layers = VecMap::new();
+        for (layer_type, texture_resolution) in [
+            (LayerType::Heightmaps, HEIGHTMAPS_RESOLUTION),
+            (LayerType::Displacements, DISPLACEMENTS_RESOLUTION),
+            (LayerType::Normals, NORMALS_RESOLUTION),
+        ]
+        .iter()
+        .copied()
+        {
+            layers.insert(
+                layer_type.index(),
+                LayerParams {
+                    layer_type,
+                    name: String::new(),
+                    texture_resolution: texture_resolution as u32,
+                    texture_border_size: 0,
+                    texture_format: crate::cache::TextureFormat::R32,
+                },
+            );
+        }
+
+        let input: Vec<u8> = pattern().iter().flat_map(|h| h.to_le_bytes().to_vec()).collect();
+        let ubo = heightmaps_ubo();
+        let output: Vec<u8> = heightmaps(&ubo, &pattern(), HEIGHTMAPS_RESOLUTION)
+            .iter()
+            .flat_map(|h| h.to_le_bytes().to_vec())
+            .collect();
+
+        let comparisons = Reference::Heightmaps(ubo).check(
+            &layers,
+            std::slice::from_ref(&input),
+            std::slice::from_ref(&output),
+        );
+        assert_eq!(comparisons.len(), 1);
+        assert_eq!(comparisons[0].mismatches, 0);
+        assert_eq!(comparisons[0].values, HEIGHTMAPS_RESOLUTION * HEIGHTMAPS_RESOLUTION);
+
+        let comparisons =
+            Reference::Heightmaps(ubo).check(&layers, &[input], &[raise_heights(&output)]);
+        assert_eq!(comparisons[0].mismatches, HEIGHTMAPS_RESOLUTION * HEIGHTMAPS_RESOLUTION);
+    }
+
+    /// Raise every encoded height by one meter.
+    fn raise_heights(data: &[u8]) -> Vec<u8> {
+        data.chunks_exact(4)
+            .flat_map(|b| {
+                (u32::from_le_bytes([b[0], b[1], b[2], b[3]]) + 512).to_le_bytes().to_vec()
+            })
+            .collect()
+    }
+}
@@ -1,11 +1,15 @@
 use std::{borrow::Cow, collections::HashMap};
 
 use crate::{
-    cache::{LayerType, MeshType, SingularLayerType, UnifiedPriorityCache},
+    cache::{LayerType, MeshType, SingularLayerType, UnifiedPriorityCache, MAX_CUSTOM_LAYERS},
+    coordinates::PlanetDesc,
     mapfile::MapFile,
-    terrain::quadtree::NodeState,
+    ocean::{self, Ocean},
+    sky,
+    terrain::{hiz::HiZUniforms, quadtree::NodeState, shadows, tonemap},
 };
 use vec_map::VecMap;
+use wgpu::util::DeviceExt;
 
 #[repr(C)]
 pub(crate) struct DrawIndexedIndirect {
@@ -30,6 +34,15 @@ pub(crate) struct GlobalUniformBlock {
     pub frustum_planes: [[f32; 4]; 5],
     pub camera: [f32; 4],
     pub sun_direction: [f32; 4],
+    pub hiz: HiZUniforms,
+    pub debug_mode: u32,
+    pub flat_surface: u32,
+    pub atmosphere: u32,
+    pub planet_radius: f32,
+    pub atmosphere_radius: f32,
+    pub tonemapping: u32,
+    pub shadows: u32,
+    pub _padding: u32,
 }
 unsafe impl bytemuck::Pod for GlobalUniformBlock {}
 unsafe impl bytemuck::Zeroable for GlobalUniformBlock {}
@@ -44,9 +57,42 @@ pub(crate) struct GpuState {
 
     pub globals: wgpu::Buffer,
     pub node_buffer: wgpu::Buffer,
+    pub node_indirect: wgpu::Buffer,
+    pub custom_layer_descs: wgpu::Buffer,
+
+    /// Depth pyramid built from the previous frame, replaced whenever the frame size changes.
+    pub hiz: wgpu::Texture,
+
+    /// Single sampled HDR target that the scene is rendered or resolved into before tonemapping.
+    pub hdr_color: wgpu::Texture,
+    /// Exposure applied to colors written to the HDR target, which is updated on the GPU.
+    pub exposure: wgpu::Buffer,
+    pub luminance_histogram: wgpu::Buffer,
+
+    /// Depth of the closest shadow caster for each cascade, replaced if shadows are enabled.
+    pub shadow_map: wgpu::Texture,
+    pub shadow_cascades: wgpu::Buffer,
+
+    /// Orientation of the stars and position of the moon, see `NightSkyUniforms`.
+    pub night_sky: wgpu::Buffer,
+    /// Altitudes and parameters of the cloud layer, see `CloudUniforms`.
+    pub clouds: wgpu::Buffer,
+
+    /// Cascades of the wave simulation, see `OceanUniforms`. The initial spectrum is uploaded once,
+    /// and the waves are computed from it each frame by way of the intermediate FFT texture.
+    pub ocean: wgpu::Buffer,
+    pub ocean_spectrum: wgpu::Texture,
+    ocean_fft: wgpu::Texture,
+    ocean_waves: wgpu::Texture,
+
+    custom_tile_layers: HashMap<String, LayerType>,
+    mesh_layers: HashMap<String, MeshType>,
 
     noise: wgpu::Texture,
+    cloud_weather: wgpu::Texture,
+    cloud_coverage: wgpu::Texture,
     sky: wgpu::Texture,
+    stars: wgpu::Texture,
     transmittance: wgpu::Texture,
     inscattering: wgpu::Texture,
     aerial_perspective: wgpu::Texture,
@@ -54,6 +100,7 @@ pub(crate) struct GpuState {
     nearest: wgpu::Sampler,
     linear: wgpu::Sampler,
     linear_wrap: wgpu::Sampler,
+    shadow: wgpu::Sampler,
 }
 impl GpuState {
     pub(crate) fn new(
@@ -61,14 +108,31 @@ impl GpuState {
         queue: &wgpu::Queue,
         mapfile: &MapFile,
         cache: &UnifiedPriorityCache,
+        max_rendered_nodes: usize,
+        sky_planet: &PlanetDesc,
     ) -> Result<Self, anyhow::Error> {
         Ok(GpuState {
             noise: mapfile.read_texture(device, queue, "noise")?,
+            cloud_weather: mapfile.read_texture(device, queue, "cloud_weather")?,
+            cloud_coverage: mapfile.read_texture(device, queue, "cloud_coverage")?,
             sky: mapfile.read_texture(device, queue, "sky")?,
-            transmittance: mapfile.read_texture(device, queue, "transmittance")?,
-            inscattering: mapfile.read_texture(device, queue, "inscattering")?,
+            stars: mapfile.read_texture(device, queue, "stars")?,
+            transmittance: mapfile.read_texture(
+                device,
+                queue,
+                &sky::table_name("transmittance", sky_planet),
+            )?,
+            inscattering: mapfile.read_texture(
+                device,
+                queue,
+                &sky::table_name("inscattering", sky_planet),
+            )?,
             aerial_perspective: device.create_texture(&wgpu::TextureDescriptor {
-                size: wgpu::Extent3d { width: 17, height: 17, depth_or_array_layers: 1024 },
+                size: wgpu::Extent3d {
+                    width: 17,
+                    height: 17,
+                    depth_or_array_layers: max_rendered_nodes as u32,
+                },
                 format: wgpu::TextureFormat::Rgba16Float,
                 mip_level_count: 1,
                 sample_count: 1,
@@ -113,13 +177,91 @@ impl GpuState {
                 mapped_at_creation: false,
             }),
             node_buffer: device.create_buffer(&wgpu::BufferDescriptor {
-                size: (std::mem::size_of::<NodeState>() * 1024) as u64,
+                size: (std::mem::size_of::<NodeState>() * max_rendered_nodes) as u64,
                 usage: wgpu::BufferUsage::COPY_DST
                     | wgpu::BufferUsage::UNIFORM
                     | wgpu::BufferUsage::STORAGE,
                 label: Some("buffer.nodes"),
                 mapped_at_creation: false,
             }),
+            node_indirect: device.create_buffer(&wgpu::BufferDescriptor {
+                size: (std::mem::size_of::<DrawIndexedIndirect>() * max_rendered_nodes) as u64,
+                usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::INDIRECT,
+                label: Some("buffer.nodes_indirect"),
+                mapped_at_creation: false,
+            }),
+            hiz: crate::terrain::hiz::HiZ::create_texture(device, (1, 1), 1),
+            hdr_color: tonemap::Tonemapper::create_texture(device, (1, 1), 1),
+            exposure: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("buffer.exposure"),
+                contents: bytemuck::bytes_of(&tonemap::INITIAL_EXPOSURE),
+                usage: wgpu::BufferUsage::STORAGE,
+            }),
+            luminance_histogram: device.create_buffer(&wgpu::BufferDescriptor {
+                size: 256 * 4,
+                usage: wgpu::BufferUsage::STORAGE,
+                label: Some("buffer.luminance_histogram"),
+                mapped_at_creation: false,
+            }),
+            shadow_map: shadows::Shadows::create_texture(device, 1),
+            shadow_cascades: device.create_buffer(&wgpu::BufferDescriptor {
+                size: (std::mem::size_of::<shadows::ShadowCascadeUniforms>()
+                    * shadows::NUM_CASCADES) as u64,
+                usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::UNIFORM,
+                label: Some("buffer.shadow_cascades"),
+                mapped_at_creation: false,
+            }),
+            night_sky: device.create_buffer(&wgpu::BufferDescriptor {
+                size: std::mem::size_of::<sky::night::NightSkyUniforms>() as u64,
+                usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::UNIFORM,
+                label: Some("buffer.night_sky"),
+                mapped_at_creation: false,
+            }),
+            clouds: device.create_buffer(&wgpu::BufferDescriptor {
+                size: std::mem::size_of::<sky::clouds::CloudUniforms>() as u64,
+                usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::UNIFORM,
+                label: Some("buffer.clouds"),
+                mapped_at_creation: false,
+            }),
+            ocean: device.create_buffer(&wgpu::BufferDescriptor {
+                size: std::mem::size_of::<ocean::OceanUniforms>() as u64,
+                usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::UNIFORM,
+                label: Some("buffer.ocean"),
+                mapped_at_creation: false,
+            }),
+            ocean_spectrum: Ocean::create_texture(
+                device,
+                wgpu::TextureFormat::Rgba32Float,
+                wgpu::TextureUsage::COPY_DST | wgpu::TextureUsage::SAMPLED,
+                "texture.ocean_spectrum",
+            ),
+            ocean_fft: Ocean::create_texture(
+                device,
+                wgpu::TextureFormat::Rgba32Float,
+                wgpu::TextureUsage::STORAGE | wgpu::TextureUsage::SAMPLED,
+                "texture.ocean_fft",
+            ),
+            ocean_waves: Ocean::create_texture(
+                device,
+                wgpu::TextureFormat::Rgba16Float,
+                wgpu::TextureUsage::STORAGE | wgpu::TextureUsage::SAMPLED,
+                "texture.ocean_waves",
+            ),
+            custom_layer_descs: device.create_buffer(&wgpu::BufferDescriptor {
+                size: (std::mem::size_of::<[[f32; 4]; 2]>()
+                    * MAX_CUSTOM_LAYERS
+                    * max_rendered_nodes) as u64,
+                usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::STORAGE,
+                label: Some("buffer.custom_layer_descs"),
+                mapped_at_creation: false,
+            }),
+            custom_tile_layers: mapfile
+                .layers()
+                .values()
+                .filter(|l| matches!(l.layer_type, LayerType::Custom(_)))
+                .map(|l| (l.name.clone(), l.layer_type))
+                .collect(),
+            mesh_layers: cache.mesh_layers().map(|d| (d.name.clone(), d.ty)).collect(),
             nearest: device.create_sampler(&wgpu::SamplerDescriptor {
                 address_mode_u: wgpu::AddressMode::ClampToEdge,
                 address_mode_v: wgpu::AddressMode::ClampToEdge,
@@ -150,6 +292,17 @@ impl GpuState {
                 label: Some("sampler.linear_wrap"),
                 ..Default::default()
             }),
+            shadow: device.create_sampler(&wgpu::SamplerDescriptor {
+                address_mode_u: wgpu::AddressMode::ClampToEdge,
+                address_mode_v: wgpu::AddressMode::ClampToEdge,
+                address_mode_w: wgpu::AddressMode::ClampToEdge,
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                mipmap_filter: wgpu::FilterMode::Nearest,
+                compare: Some(wgpu::CompareFunction::LessEqual),
+                label: Some("sampler.shadow"),
+                ..Default::default()
+            }),
         })
     }
 
@@ -170,12 +323,15 @@ impl GpuState {
             let name = &**name.as_ref().unwrap();
             match layout.ty {
                 wgpu::BindingType::StorageTexture { .. } | wgpu::BindingType::Texture { .. } => {
-                    if !image_views.contains_key(name) {
+                    if !image_views.contains_key(name) && !buffers.contains_key(name) {
                         image_views.insert(
                             name.into(),
                             match name {
                                 "noise" => &self.noise,
+                                "cloud_weather" => &self.cloud_weather,
+                                "cloud_coverage" => &self.cloud_coverage,
                                 "sky" => &self.sky,
+                                "stars" => &self.stars,
                                 "transmittance" => &self.transmittance,
                                 "inscattering" => &self.inscattering,
                                 "aerial_perspective" => &self.aerial_perspective,
@@ -184,11 +340,22 @@ impl GpuState {
                                 "roughness" => &self.tile_cache[LayerType::Roughness],
                                 "normals" => &self.tile_cache[LayerType::Normals],
                                 "heightmaps" => &self.tile_cache[LayerType::Heightmaps],
+                                "horizons" => &self.tile_cache[LayerType::Horizons],
+                                "water" => &self.tile_cache[LayerType::Water],
                                 "grass_canopy" => {
                                     &self.texture_cache[SingularLayerType::GrassCanopy]
                                 }
                                 "bc4_staging" => &self.bc4_staging,
                                 "bc5_staging" => &self.bc5_staging,
+                                "hiz" => &self.hiz,
+                                "hdr_color" => &self.hdr_color,
+                                "shadow_map" => &self.shadow_map,
+                                "ocean_spectrum" => &self.ocean_spectrum,
+                                "ocean_fft" => &self.ocean_fft,
+                                "ocean_waves" => &self.ocean_waves,
+                                _ if self.custom_tile_layers.contains_key(name) => {
+                                    &self.tile_cache[self.custom_tile_layers[name]]
+                                }
                                 _ => unreachable!("unrecognized image: {}", name),
                             }
                             .create_view(
@@ -202,12 +369,31 @@ impl GpuState {
                 }
                 wgpu::BindingType::Buffer { .. } => {
                     if !buffers.contains_key(name) {
+                        let mesh_layer = name
+                            .rsplitn(2, '_')
+                            .nth(1)
+                            .and_then(|mesh| self.mesh_layers.get(mesh))
+                            .map(|ty| &self.mesh_cache[*ty]);
                         let buffer = match name {
-                            "grass_indirect" => &self.mesh_cache[MeshType::Grass].indirect,
-                            "grass_bounding" => &self.mesh_cache[MeshType::Grass].bounding,
-                            "grass_storage" => &self.mesh_cache[MeshType::Grass].storage,
+                            _ if mesh_layer.is_some() && name.ends_with("_indirect") => {
+                                &mesh_layer.unwrap().indirect
+                            }
+                            _ if mesh_layer.is_some() && name.ends_with("_bounding") => {
+                                &mesh_layer.unwrap().bounding
+                            }
+                            _ if mesh_layer.is_some() && name.ends_with("_storage") => {
+                                &mesh_layer.unwrap().storage
+                            }
                             "nodes" => &self.node_buffer,
+                            "nodes_indirect" => &self.node_indirect,
+                            "custom_layer_descs" => &self.custom_layer_descs,
                             "globals" => &self.globals,
+                            "exposure" => &self.exposure,
+                            "luminance_histogram" => &self.luminance_histogram,
+                            "shadow_cascades" => &self.shadow_cascades,
+                            "night_sky" => &self.night_sky,
+                            "clouds" => &self.clouds,
+                            "ocean" => &self.ocean,
                             _ => unreachable!("unrecognized storage buffer: {}", name),
                         };
                         let resource = wgpu::BindingResource::Buffer(wgpu::BufferBinding {
@@ -228,7 +414,7 @@ impl GpuState {
             bindings.push(wgpu::BindGroupEntry {
                 binding: layout.binding,
                 resource: match layout.ty {
-                    wgpu::BindingType::Sampler { ref mut filtering, .. } => {
+                    wgpu::BindingType::Sampler { ref mut filtering, ref mut comparison } => {
                         wgpu::BindingResource::Sampler(match name {
                             "nearest" => {
                                 *filtering = false;
@@ -236,20 +422,30 @@ impl GpuState {
                             }
                             "linear" => &self.linear,
                             "linear_wrap" => &self.linear_wrap,
+                            "shadow" => {
+                                *comparison = true;
+                                &self.shadow
+                            }
                             _ => unreachable!("unrecognized sampler: {}", name),
                         })
                     }
-                    wgpu::BindingType::StorageTexture { .. } => {
-                        wgpu::BindingResource::TextureView(&image_views[name])
-                    }
+                    wgpu::BindingType::StorageTexture { .. } => match buffers.get(name) {
+                        Some((_, resource)) => resource.clone(),
+                        None => wgpu::BindingResource::TextureView(&image_views[name]),
+                    },
                     wgpu::BindingType::Texture { ref mut sample_type, .. } => {
                         match name {
-                            "transmittance" | "inscattering" | "displacements" => {
+                            "transmittance" | "inscattering" | "displacements" | "hiz"
+                            | "hiz_input" | "stars" | "ocean_spectrum" | "ocean_fft" => {
                                 *sample_type = wgpu::TextureSampleType::Float { filterable: false }
                             }
+                            "depth" | "shadow_map" => *sample_type = wgpu::TextureSampleType::Depth,
                             _ => {}
                         }
-                        wgpu::BindingResource::TextureView(&image_views[name])
+                        match buffers.get(name) {
+                            Some((_, resource)) => resource.clone(),
+                            None => wgpu::BindingResource::TextureView(&image_views[name]),
+                        }
                     }
                     wgpu::BindingType::Buffer { ref mut has_dynamic_offset, .. } => {
                         let (d, ref buf) = buffers[name];
@@ -14,6 +14,7 @@ mod coordinates;
 mod generate;
 mod gpu_state;
 mod mapfile;
+mod ocean;
 mod sky;
 mod srgb;
 mod stream;
@@ -21,23 +22,85 @@ mod terrain;
 mod types;
 mod utils;
 
-use crate::cache::{LayerType, MeshCacheDesc, MeshType};
 use crate::generate::MapFileBuilder;
 use crate::mapfile::MapFile;
 use crate::terrain::quadtree::node::VNode;
 use anyhow::Error;
-use cache::{SingularLayerDesc, SingularLayerType, TextureFormat, UnifiedPriorityCache};
+use cache::{SingularLayerDesc, UnifiedPriorityCache};
 use cgmath::SquareMatrix;
 use generate::ComputeShader;
 use gpu_state::{GlobalUniformBlock, GpuState};
+use maplit::hashmap;
+use ocean::Ocean;
+use sky::clouds::Clouds;
+use sky::night::NightSky;
 use std::array::IntoIter;
 use std::collections::HashMap;
 use std::sync::Arc;
-use terrain::quadtree::QuadTree;
+use std::time::{Duration, Instant, SystemTime};
+use terrain::hiz::HiZ;
+use terrain::quadtree::{LodMetric, QuadTree};
+use terrain::shadows::Shadows;
+use terrain::tonemap::{Tonemapper, HDR_FORMAT};
 use utils::math::InfiniteFrustum;
 use wgpu::util::DeviceExt;
 
+pub use crate::cache::{
+    CustomLayerDesc, LayerMask, LayerType, MeshCacheDesc, MeshGenerateUniforms, MeshType,
+    SingularLayerType, TextureFormat, MAX_CUSTOM_LAYERS, MAX_CUSTOM_MESHES,
+};
+pub use crate::coordinates::PlanetDesc;
 pub use crate::generate::BLUE_MARBLE_URLS;
+pub use crate::sky::clouds::CloudLayer;
+pub use crate::terrain::quadtree::{
+    key::{LatLonBounds, TileKey, WebTileKey},
+    lod::LodQuality,
+    node::Surface,
+    RenderStats,
+};
+
+/// Visualizations that replace or overlay the normal terrain shading, for debugging.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DebugMode {
+    None = 0,
+    /// Color each node by its level in the quadtree.
+    LodLevel = 1,
+    /// Outline every node, colored by its level.
+    NodeBoundaries = 2,
+    /// Color by the mip level sampled from the albedo texture.
+    TextureMipLevel = 3,
+    /// Color by the number of albedo texels per pixel, from blue (too few) to red (too many).
+    TexelDensity = 4,
+    /// Outline every triangle.
+    Wireframe = 5,
+    /// Highlight nodes that are drawn with tiles borrowed from one of their ancestors, because
+    /// their own tiles haven't been streamed or generated yet.
+    AncestorFallback = 6,
+}
+impl Default for DebugMode {
+    fn default() -> Self {
+        DebugMode::None
+    }
+}
+
+/// Operators for mapping the HDR scene colors into the range of the output target.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Tonemapping {
+    /// Write exposed colors without any tonemapping, for callers that render to a float target
+    /// and do their own post processing.
+    None = 0,
+    /// Curve fit of the ACES filmic reference rendering transform.
+    Aces = 1,
+    /// Simple `x / (1 + x)` curve, which preserves hues but looks flat.
+    Reinhard = 2,
+    /// The AgX view transform, which desaturates bright colors more gracefully than ACES.
+    AgX = 3,
+}
+impl Default for Tonemapping {
+    fn default() -> Self {
+        Tonemapping::Aces
+    }
+}
 
 pub struct Terrain {
     shader: rshader::ShaderSet,
@@ -47,26 +110,343 @@ pub struct Terrain {
     sky_shader: rshader::ShaderSet,
     sky_bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::RenderPipeline)>,
     aerial_perspective: ComputeShader<u32>,
+    night_sky: NightSky,
+    clouds: Clouds,
+    ocean: Ocean,
+
+    shadows: Option<Shadows>,
+    shadow_shader: rshader::ShaderSet,
+    /// Bind group and pipeline for drawing terrain into each shadow cascade.
+    shadow_bindgroup_pipelines: Vec<(wgpu::BindGroup, wgpu::RenderPipeline)>,
 
     gpu_state: GpuState,
     quadtree: QuadTree,
+    hiz: HiZ,
+    tonemapper: Tonemapper,
+    debug_mode: DebugMode,
+    tonemapping: Tonemapping,
+    exposure_compensation: f32,
+    /// How long `render_to_image` waits for loading to settle before returning an error.
+    render_to_image_timeout: Duration,
+    /// Direction towards the sun in ecef coordinates.
+    sun_direction: cgmath::Vector3<f64>,
+    atmosphere: bool,
+    targets: RenderTargets,
     mapfile: Arc<MapFile>,
 
     cache: UnifiedPriorityCache,
 }
+
+/// Formats and sample count of the color and depth buffers that the terrain is rendered into. The
+/// scene itself is drawn into an HDR target with the same sample count, and then tonemapped into the
+/// color buffer.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct RenderTargets {
+    color_format: wgpu::TextureFormat,
+    depth_format: wgpu::TextureFormat,
+    sample_count: u32,
+}
+impl RenderTargets {
+    pub fn hdr_target(&self) -> wgpu::ColorTargetState {
+        wgpu::ColorTargetState { format: HDR_FORMAT, ..self.output_target() }
+    }
+
+    pub fn output_target(&self) -> wgpu::ColorTargetState {
+        wgpu::ColorTargetState {
+            format: self.color_format,
+            blend: Some(wgpu::BlendState {
+                color: wgpu::BlendComponent::REPLACE,
+                alpha: wgpu::BlendComponent::REPLACE,
+            }),
+            write_mask: wgpu::ColorWrite::ALL,
+        }
+    }
+
+    pub fn depth_stencil(
+        &self,
+        depth_write_enabled: bool,
+        depth_compare: wgpu::CompareFunction,
+    ) -> wgpu::DepthStencilState {
+        wgpu::DepthStencilState {
+            format: self.depth_format,
+            depth_write_enabled,
+            depth_compare,
+            bias: Default::default(),
+            stencil: Default::default(),
+        }
+    }
+
+    pub fn multisample(&self) -> wgpu::MultisampleState {
+        wgpu::MultisampleState { count: self.sample_count, ..Default::default() }
+    }
+}
+/// Constructs a `Terrain` with additional user-defined layers.
+#[derive(Default)]
+pub struct TerrainBuilder {
+    custom_layers: Vec<CustomLayerDesc>,
+    custom_meshes: Vec<MeshCacheDesc>,
+    persist_generated: bool,
+    cross_check_generators: bool,
+    max_rendered_nodes: Option<usize>,
+    surface: Surface,
+    atmosphere: Option<bool>,
+    shadows: Option<bool>,
+    color_format: Option<wgpu::TextureFormat>,
+    depth_format: Option<wgpu::TextureFormat>,
+    sample_count: Option<u32>,
+}
+impl TerrainBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a user-defined tile layer.
+    ///
+    /// The i-th registered layer is assigned `LayerType::Custom(i)`, and its per-node descriptor
+    /// is stored at index `node_index * MAX_CUSTOM_LAYERS + i` of the `custom_layer_descs` buffer
+    /// that is available to shaders.
+    pub fn custom_layer(mut self, desc: CustomLayerDesc) -> Self {
+        self.custom_layers.push(desc);
+        self
+    }
+
+    /// Register a user-defined instanced mesh layer. Its type must be a `MeshType::Custom` that
+    /// isn't used by any other mesh layer.
+    pub fn custom_mesh(mut self, desc: MeshCacheDesc) -> Self {
+        self.custom_meshes.push(desc);
+        self
+    }
+
+    /// Write generated normals, displacements and custom layer tiles to disk so that later
+    /// sessions (or the same session after cache evictions) can stream them in instead of
+    /// regenerating them. Persisted tiles are discarded whenever any shader involved in
+    /// generating them changes.
+    pub fn persist_generated(mut self, persist: bool) -> Self {
+        self.persist_generated = persist;
+        self
+    }
+
+    /// Compare the output of the built-in tile generators against CPU reference implementations
+    /// and log any tiles that differ. This is slow and only intended for debugging generator
+    /// shaders.
+    pub fn cross_check_generators(mut self, enabled: bool) -> Self {
+        self.cross_check_generators = enabled;
+        self
+    }
+
+    /// Set the capacity of the buffer holding per-node rendering state. Defaults to 1024. If more
+    /// nodes would be visible than fit, the least important ones are drawn at a coarser level of
+    /// detail instead.
+    pub fn max_rendered_nodes(mut self, max_rendered_nodes: usize) -> Self {
+        self.max_rendered_nodes = Some(max_rendered_nodes);
+        self
+    }
+
+    /// Select the shape of the terrain. Defaults to `Surface::Planet(PlanetDesc::EARTH)`.
+    ///
+    /// Flat surfaces are covered by a single root node, and use the same tiles and generators as
+    /// planets. Positions passed to and returned from a flat `Terrain` are relative to the plane
+    /// described by `Surface::Flat` rather than the center of the planet.
+    pub fn surface(mut self, surface: Surface) -> Self {
+        self.surface = surface;
+        self
+    }
+
+    /// Render the sky and apply aerial perspective to the terrain. Enabled by default for planets
+    /// that have an atmosphere, and not supported otherwise.
+    pub fn atmosphere(mut self, enabled: bool) -> Self {
+        self.atmosphere = Some(enabled);
+        self
+    }
+
+    /// Cast shadows from the sun onto terrain and grass using cascaded shadow maps. Enabled by
+    /// default.
+    pub fn shadows(mut self, enabled: bool) -> Self {
+        self.shadows = Some(enabled);
+        self
+    }
+
+    /// Set the format of the color buffer passed to `Terrain::render`. Defaults to
+    /// `Bgra8UnormSrgb`.
+    pub fn color_format(mut self, format: wgpu::TextureFormat) -> Self {
+        self.color_format = Some(format);
+        self
+    }
+
+    /// Set the format of the depth buffer passed to `Terrain::render`. Defaults to
+    /// `Depth32Float`. Formats with a stencil component are not supported, because the depth
+    /// buffer is sampled to build the occlusion culling pyramid.
+    pub fn depth_format(mut self, format: wgpu::TextureFormat) -> Self {
+        self.depth_format = Some(format);
+        self
+    }
+
+    /// Set the number of samples per pixel of the color and depth buffers. Defaults to 1. When
+    /// multisampling, pass a single sampled `resolve_target` to `Terrain::render` to receive the
+    /// resolved image.
+    pub fn sample_count(mut self, sample_count: u32) -> Self {
+        self.sample_count = Some(sample_count);
+        self
+    }
+
+    pub fn build(self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<Terrain, Error> {
+        Terrain::from_builder(device, queue, self)
+    }
+}
+
 impl Terrain {
     /// Create a new Terrain object.
     pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Result<Self, Error> {
-        let mapfile = Arc::new(futures::executor::block_on(MapFileBuilder::new().build())?);
+        TerrainBuilder::new().build(device, queue)
+    }
+
+    fn from_builder(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        builder: TerrainBuilder,
+    ) -> Result<Self, Error> {
+        let TerrainBuilder {
+            custom_layers,
+            custom_meshes,
+            persist_generated,
+            cross_check_generators,
+            max_rendered_nodes,
+            surface,
+            atmosphere,
+            shadows,
+            color_format,
+            depth_format,
+            sample_count,
+        } = builder;
+        let has_atmosphere = surface.planet().map_or(false, PlanetDesc::has_atmosphere);
+        let atmosphere = atmosphere.unwrap_or(has_atmosphere);
+        anyhow::ensure!(
+            !atmosphere || has_atmosphere,
+            "atmosphere is only supported for planets that have one"
+        );
+        match surface {
+            Surface::Planet(planet) => anyhow::ensure!(
+                planet.radius > 0.0
+                    && planet.equatorial_radius > 0.0
+                    && (0.0..1.0).contains(&planet.flattening)
+                    && planet.atmosphere_height >= 0.0,
+                "invalid planet description {:?}",
+                planet
+            ),
+            Surface::Flat { extent } => {
+                anyhow::ensure!(extent > 0.0, "flat surface must have a positive extent")
+            }
+        }
+        let targets = RenderTargets {
+            color_format: color_format.unwrap_or(wgpu::TextureFormat::Bgra8UnormSrgb),
+            depth_format: depth_format.unwrap_or(wgpu::TextureFormat::Depth32Float),
+            sample_count: sample_count.unwrap_or(1),
+        };
+        anyhow::ensure!(
+            matches!(
+                targets.depth_format,
+                wgpu::TextureFormat::Depth32Float | wgpu::TextureFormat::Depth24Plus
+            ),
+            "unsupported depth format {:?}",
+            targets.depth_format
+        );
+        anyhow::ensure!(
+            targets.sample_count == 1 || targets.sample_count == 4,
+            "sample count must be 1 or 4"
+        );
+        let max_rendered_nodes = max_rendered_nodes.unwrap_or(1024);
+        anyhow::ensure!(
+            max_rendered_nodes >= surface.roots().len(),
+            "max_rendered_nodes must be at least large enough to draw every root node"
+        );
+        anyhow::ensure!(custom_layers.len() <= MAX_CUSTOM_LAYERS, "too many custom layers");
+        for (i, desc) in custom_layers.iter().enumerate() {
+            anyhow::ensure!(
+                !desc.name.is_empty()
+                    && desc.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-'),
+                "invalid custom layer name {:?}",
+                desc.name
+            );
+            anyhow::ensure!(
+                !LayerType::is_reserved_name(&desc.name)
+                    && custom_layers[..i].iter().all(|d| !d.name.eq_ignore_ascii_case(&desc.name)),
+                "duplicate layer name {}",
+                desc.name
+            );
+            anyhow::ensure!(
+                !desc.texture_format.is_compressed(),
+                "custom layer {} must not use a compressed format",
+                desc.name
+            );
+            for input in desc.peer_inputs.iter().chain(&desc.parent_inputs) {
+                if let LayerType::Custom(j) = *input {
+                    anyhow::ensure!(
+                        (j as usize) < custom_layers.len(),
+                        "custom layer {} depends on unregistered layer {:?}",
+                        desc.name,
+                        input
+                    );
+                }
+            }
+            anyhow::ensure!(
+                !desc.peer_inputs.contains(&LayerType::Custom(i as u8)),
+                "custom layer {} cannot be a peer input of itself",
+                desc.name
+            );
+        }
+        anyhow::ensure!(custom_meshes.len() <= MAX_CUSTOM_MESHES, "too many custom meshes");
+        for (i, desc) in custom_meshes.iter().enumerate() {
+            anyhow::ensure!(
+                !matches!(desc.ty, MeshType::Custom(j) if j as usize >= MAX_CUSTOM_MESHES),
+                "mesh layer {} has type {:?} but at most {} custom meshes are supported",
+                desc.name,
+                desc.ty,
+                MAX_CUSTOM_MESHES
+            );
+            anyhow::ensure!(
+                desc.ty != MeshType::Grass
+                    && custom_meshes[..i].iter().all(|d| d.ty != desc.ty && d.name != desc.name),
+                "duplicate mesh layer {}",
+                desc.name
+            );
+        }
+
+        let mut builder = MapFileBuilder::new();
+        let custom_layers: Vec<_> = custom_layers
+            .into_iter()
+            .enumerate()
+            .map(|(i, desc)| (desc.layer_params(LayerType::Custom(i as u8)), desc))
+            .collect();
+        for (params, _) in &custom_layers {
+            builder = builder.custom_layer(params.clone());
+        }
+        // The sky lookup tables are never sampled without an atmosphere, so just reuse the earth's.
+        let sky_planet = match surface {
+            Surface::Planet(planet) if atmosphere => planet,
+            _ => PlanetDesc::EARTH,
+        };
+        let mapfile = Arc::new(futures::executor::block_on(builder.build(&sky_planet))?);
+
+        let mut generators = crate::generate::generators(
+            mapfile.layers(),
+            surface,
+            !device.features().contains(wgpu::Features::SHADER_FLOAT64),
+        );
+        for (params, desc) in custom_layers {
+            generators.push(crate::generate::custom_generator(desc, &params, surface));
+        }
+
         let cache = UnifiedPriorityCache::new(
             device,
+            queue,
             Arc::clone(&mapfile),
             512,
-            crate::generate::generators(
-                mapfile.layers(),
-                !device.features().contains(wgpu::Features::SHADER_FLOAT64),
-            ),
-            vec![MeshCacheDesc {
+            generators,
+            surface,
+            persist_generated,
+            cross_check_generators,
+            std::iter::once(MeshCacheDesc {
+                name: "grass".to_owned(),
                 size: 96,
                 ty: MeshType::Grass,
                 max_bytes_per_entry: 128 * 128 * 64,
@@ -91,14 +471,12 @@ impl Terrain {
                         usage: wgpu::BufferUsage::INDEX,
                     })
                 },
-                generate: ComputeShader::new(
-                    rshader::shader_source!(
-                        "shaders",
-                        "gen-grass.comp",
-                        "declarations.glsl",
-                        "hash.glsl"
-                    ),
-                    "gen-grass".to_string(),
+                indices_per_entry: 15,
+                generate: rshader::shader_source!(
+                    "shaders",
+                    "gen-grass.comp",
+                    "declarations.glsl",
+                    "hash.glsl"
                 ),
                 render: rshader::ShaderSet::simple(
                     rshader::shader_source!("shaders", "grass.vert", "declarations.glsl"),
@@ -106,11 +484,21 @@ impl Terrain {
                         "shaders",
                         "grass.frag",
                         "declarations.glsl",
-                        "pbr.glsl"
+                        "pbr.glsl",
+                        "shadows.glsl"
                     ),
                 )
                 .unwrap(),
-            }],
+                shadow: Some(
+                    rshader::ShaderSet::simple(
+                        rshader::shader_source!("shaders", "grass.vert", "declarations.glsl"),
+                        rshader::shader_source!("shaders", "shadow.frag"),
+                    )
+                    .unwrap(),
+                ),
+            })
+            .chain(custom_meshes)
+            .collect(),
             vec![SingularLayerDesc {
                 generate: ComputeShader::new(
                     rshader::shader_source!(
@@ -129,15 +517,37 @@ impl Terrain {
                 texture_format: TextureFormat::RGBA8,
             }],
         );
-        let gpu_state = GpuState::new(device, queue, &mapfile, &cache)?;
-        let quadtree =
-            QuadTree::new(cache.tile_desc(LayerType::Displacements).texture_resolution - 1);
+        let mut gpu_state =
+            GpuState::new(device, queue, &mapfile, &cache, max_rendered_nodes, &sky_planet)?;
+        let shadows = match shadows.unwrap_or(true) {
+            true => Some(Shadows::new(device, &mut gpu_state, max_rendered_nodes)),
+            false => None,
+        };
+        let quadtree = QuadTree::new(
+            cache.tile_desc(LayerType::Displacements).texture_resolution - 1,
+            max_rendered_nodes,
+            surface,
+        );
 
         let index_buffer = quadtree.create_index_buffers(device);
 
         let shader = rshader::ShaderSet::simple(
-            rshader::shader_source!("shaders", "terrain.vert", "declarations.glsl"),
-            rshader::shader_source!("shaders", "terrain.frag", "declarations.glsl", "pbr.glsl"),
+            rshader::shader_source!("shaders", "terrain.vert", "declarations.glsl", "ocean.glsl"),
+            rshader::shader_source!(
+                "shaders",
+                "terrain.frag",
+                "declarations.glsl",
+                "pbr.glsl",
+                "shadows.glsl",
+                "clouds.glsl",
+                "ocean.glsl",
+                "atmosphere.glsl"
+            ),
+        )
+        .unwrap();
+        let shadow_shader = rshader::ShaderSet::simple(
+            rshader::shader_source!("shaders", "terrain.vert", "declarations.glsl", "ocean.glsl"),
+            rshader::shader_source!("shaders", "shadow.frag"),
         )
         .unwrap();
         let sky_shader = rshader::ShaderSet::simple(
@@ -160,6 +570,11 @@ impl Terrain {
             ),
             "gen-aerial-perspective".to_string(),
         );
+        // Noon UTC on January 1st, 2000 until `set_time` is called.
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(946_728_000);
+        let night_sky = NightSky::new(&mapfile, time)?;
+        let clouds = Clouds::new()?;
+        let ocean = Ocean::new(queue, &gpu_state);
 
         Ok(Self {
             bindgroup_pipeline: None,
@@ -170,16 +585,32 @@ impl Terrain {
             sky_shader,
             sky_bindgroup_pipeline: None,
             aerial_perspective,
+            night_sky,
+            clouds,
+            ocean,
+
+            shadows,
+            shadow_shader,
+            shadow_bindgroup_pipelines: Vec::new(),
 
             gpu_state,
             quadtree,
+            hiz: HiZ::new(targets.sample_count > 1),
+            tonemapper: Tonemapper::new(targets.color_format),
+            debug_mode: DebugMode::None,
+            tonemapping: Tonemapping::default(),
+            exposure_compensation: 0.0,
+            render_to_image_timeout: Duration::from_secs(300),
+            sun_direction: coordinates::sun_direction(time),
+            atmosphere,
+            targets,
             mapfile,
             cache,
         })
     }
 
     fn loading_complete(&self) -> bool {
-        VNode::roots().iter().copied().all(|root| {
+        self.quadtree.surface().roots().iter().copied().all(|root| {
             self.cache.tiles.contains(root, LayerType::Heightmaps)
                 && self.cache.tiles.contains(root, LayerType::Albedo)
                 && self.cache.tiles.contains(root, LayerType::Roughness)
@@ -198,7 +629,7 @@ impl Terrain {
         queue: &wgpu::Queue,
         camera: mint::Point3<f64>,
     ) -> bool {
-        self.quadtree.update_priorities(&self.cache.tiles, camera);
+        self.quadtree.update_priorities(&mut self.cache.tiles, camera);
         if !self.loading_complete() {
             self.cache.update(device, queue, &self.gpu_state, &self.mapfile, &self.quadtree);
             self.loading_complete()
@@ -212,19 +643,37 @@ impl Terrain {
     /// This function will block if the root tiles haven't been downloaded/loaded from disk. If
     /// you want to avoid this, call `poll_loading_status` first to see whether this function will
     /// block.
+    ///
+    /// `depth_buffer` must be created with `TextureUsage::SAMPLED`, because it is used to cull
+    /// geometry hidden behind terrain in the following frame. It is assumed to only be replaced
+    /// when `frame_size` changes.
+    ///
+    /// The scene is drawn into an internal HDR target, and then tonemapped into `color_buffer`.
+    /// Both buffers must match the formats and sample count the terrain was built with. If
+    /// `resolve_target` is provided, the multisampled `color_buffer` is resolved into it.
     pub fn render(
         &mut self,
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         color_buffer: &wgpu::TextureView,
+        resolve_target: Option<&wgpu::TextureView>,
         depth_buffer: &wgpu::TextureView,
-        _frame_size: (u32, u32),
+        frame_size: (u32, u32),
         view_proj: mint::ColumnMatrix4<f32>,
         camera: mint::Point3<f64>,
     ) {
         if self.shader.refresh() {
             self.bindgroup_pipeline = None;
         }
+        if self.shadow_shader.refresh() {
+            self.shadow_bindgroup_pipelines.clear();
+        }
+        if self.hiz.resize(device, &mut self.gpu_state, frame_size) {
+            self.cache.reset_cull_bindings();
+            self.quadtree.reset_cull_bindings();
+            self.clouds.reset_bindings();
+        }
+        self.tonemapper.resize(device, &mut self.gpu_state, &self.targets, frame_size);
 
         if self.bindgroup_pipeline.is_none() {
             let (bind_group, bind_group_layout) = self.gpu_state.bind_group_for_shader(
@@ -260,27 +709,16 @@ impl Terrain {
                             flags: wgpu::ShaderFlags::empty(),
                         }),
                         entry_point: "main",
-                        targets: &[wgpu::ColorTargetState {
-                            format: wgpu::TextureFormat::Bgra8UnormSrgb,
-                            blend: Some(wgpu::BlendState {
-                                color: wgpu::BlendComponent::REPLACE,
-                                alpha: wgpu::BlendComponent::REPLACE,
-                            }),
-                            write_mask: wgpu::ColorWrite::ALL,
-                        }],
+                        targets: &[self.targets.hdr_target()],
                     }),
                     primitive: wgpu::PrimitiveState {
                         cull_mode: Some(wgpu::Face::Front),
                         ..Default::default()
                     },
-                    depth_stencil: Some(wgpu::DepthStencilState {
-                        format: wgpu::TextureFormat::Depth32Float,
-                        depth_write_enabled: true,
-                        depth_compare: wgpu::CompareFunction::Greater,
-                        bias: Default::default(),
-                        stencil: Default::default(),
-                    }),
-                    multisample: Default::default(),
+                    depth_stencil: Some(
+                        self.targets.depth_stencil(true, wgpu::CompareFunction::Greater),
+                    ),
+                    multisample: self.targets.multisample(),
                     label: Some("pipeline.terrain"),
                 }),
             ));
@@ -323,29 +761,74 @@ impl Terrain {
                             flags: wgpu::ShaderFlags::VALIDATION,
                         }),
                         entry_point: "main",
-                        targets: &[wgpu::ColorTargetState {
-                            format: wgpu::TextureFormat::Bgra8UnormSrgb,
-                            blend: Some(wgpu::BlendState {
-                                color: wgpu::BlendComponent::REPLACE,
-                                alpha: wgpu::BlendComponent::REPLACE,
-                            }),
-                            write_mask: wgpu::ColorWrite::ALL,
-                        }],
+                        targets: &[self.targets.hdr_target()],
                     }),
                     primitive: Default::default(),
-                    depth_stencil: Some(wgpu::DepthStencilState {
-                        format: wgpu::TextureFormat::Depth32Float,
-                        depth_compare: wgpu::CompareFunction::GreaterEqual,
-                        depth_write_enabled: false,
-                        bias: Default::default(),
-                        stencil: Default::default(),
-                    }),
-                    multisample: Default::default(),
+                    depth_stencil: Some(
+                        self.targets.depth_stencil(false, wgpu::CompareFunction::GreaterEqual),
+                    ),
+                    multisample: self.targets.multisample(),
                     label: Some("pipeline.sky"),
                 }),
             ));
         }
 
+        if let Some(ref shadows) = self.shadows {
+            for (i, cascade) in
+                shadows.cascades.iter().enumerate().skip(self.shadow_bindgroup_pipelines.len())
+            {
+                let globals =
+                    wgpu::BufferBinding { buffer: &cascade.globals, offset: 0, size: None };
+                let (bind_group, bind_group_layout) = self.gpu_state.bind_group_for_shader(
+                    device,
+                    &self.shadow_shader,
+                    hashmap!["globals".into() => (false, wgpu::BindingResource::Buffer(globals))],
+                    HashMap::new(),
+                    &format!("terrain.shadow{}", i),
+                );
+                let render_pipeline_layout =
+                    device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                        bind_group_layouts: &[&bind_group_layout],
+                        push_constant_ranges: &[],
+                        label: Some("pipeline.terrain.shadow.layout"),
+                    });
+                self.shadow_bindgroup_pipelines.push((
+                    bind_group,
+                    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                        layout: Some(&render_pipeline_layout),
+                        vertex: wgpu::VertexState {
+                            module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                                label: Some("shader.terrain.shadow.vertex"),
+                                source: wgpu::ShaderSource::SpirV(
+                                    self.shadow_shader.vertex().into(),
+                                ),
+                                flags: wgpu::ShaderFlags::empty(),
+                            }),
+                            entry_point: "main",
+                            buffers: &[],
+                        },
+                        fragment: Some(wgpu::FragmentState {
+                            module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                                label: Some("shader.terrain.shadow.fragment"),
+                                source: wgpu::ShaderSource::SpirV(
+                                    self.shadow_shader.fragment().into(),
+                                ),
+                                flags: wgpu::ShaderFlags::empty(),
+                            }),
+                            entry_point: "main",
+                            targets: &[],
+                        }),
+                        // Draw both sides so that terrain seen edge-on from the sun still casts
+                        // shadows.
+                        primitive: Default::default(),
+                        depth_stencil: Some(Shadows::depth_stencil_state()),
+                        multisample: Default::default(),
+                        label: Some("pipeline.terrain.shadow"),
+                    }),
+                ));
+            }
+        }
+
         let frustum = {
             let view_proj: cgmath::Matrix4<f64> =
                 cgmath::Matrix4::<f32>::from(view_proj).cast().unwrap();
@@ -356,7 +839,14 @@ impl Terrain {
             InfiniteFrustum::from_matrix(view_proj)
         };
 
-        self.quadtree.update_priorities(&self.cache.tiles, camera);
+        self.quadtree.set_lod(LodMetric {
+            pixel_scale: LodMetric::pixel_scale(
+                frame_size,
+                cgmath::Matrix4::<f32>::from(view_proj).cast().unwrap(),
+            ),
+            ..*self.quadtree.lod()
+        });
+        self.quadtree.update_priorities(&mut self.cache.tiles, camera);
 
         // Update the tile cache and then block until root tiles have been downloaded and streamed
         // to the GPU.
@@ -365,54 +855,145 @@ impl Terrain {
             std::thread::sleep(std::time::Duration::from_millis(10));
         }
 
-        self.quadtree.update_visibility(&self.cache.tiles, &frustum, camera);
+        let sun_direction = self.sun_direction;
+        let shadow_volumes = match self.shadows {
+            Some(ref mut shadows) => {
+                shadows.update(
+                    cgmath::Matrix4::<f32>::from(view_proj).cast().unwrap(),
+                    camera,
+                    sun_direction,
+                );
+                shadows.caster_volumes()
+            }
+            None => Vec::new(),
+        };
+        self.quadtree.update_visibility(&frustum, &shadow_volumes);
         self.quadtree.prepare_vertex_buffer(
             queue,
-            &mut self.gpu_state.node_buffer,
+            &self.gpu_state.node_buffer,
+            &self.gpu_state.custom_layer_descs,
             &self.cache,
             camera,
         );
 
         let relative_frustum =
             InfiniteFrustum::from_matrix(cgmath::Matrix4::<f32>::from(view_proj).cast().unwrap());
-        queue.write_buffer(
-            &self.gpu_state.globals,
-            0,
-            bytemuck::bytes_of(&GlobalUniformBlock {
-                view_proj,
-                view_proj_inverse: cgmath::Matrix4::from(view_proj).invert().unwrap().into(),
-                frustum_planes: [
-                    relative_frustum.planes[0].cast().unwrap().into(),
-                    relative_frustum.planes[1].cast().unwrap().into(),
-                    relative_frustum.planes[2].cast().unwrap().into(),
-                    relative_frustum.planes[3].cast().unwrap().into(),
-                    relative_frustum.planes[4].cast().unwrap().into(),
-                ],
-                camera: [camera.x as f32, camera.y as f32, camera.z as f32, 0.0],
-                sun_direction: [0.4, 0.7, 0.2, 0.0],
-            }),
-        );
+        let planet = self.quadtree.surface().planet().copied().unwrap_or_default();
+        let globals = GlobalUniformBlock {
+            view_proj,
+            view_proj_inverse: cgmath::Matrix4::from(view_proj).invert().unwrap().into(),
+            frustum_planes: [
+                relative_frustum.planes[0].cast().unwrap().into(),
+                relative_frustum.planes[1].cast().unwrap().into(),
+                relative_frustum.planes[2].cast().unwrap().into(),
+                relative_frustum.planes[3].cast().unwrap().into(),
+                relative_frustum.planes[4].cast().unwrap().into(),
+            ],
+            camera: [camera.x as f32, camera.y as f32, camera.z as f32, 0.0],
+            sun_direction: sun_direction.cast().unwrap().extend(0.0).into(),
+            hiz: self.hiz.uniforms(camera),
+            debug_mode: self.debug_mode as u32,
+            flat_surface: self.quadtree.surface().is_flat() as u32,
+            atmosphere: self.atmosphere as u32,
+            planet_radius: planet.radius as f32,
+            atmosphere_radius: planet.atmosphere_radius() as f32,
+            tonemapping: self.tonemapping as u32,
+            shadows: self.shadows.is_some() as u32,
+            _padding: 0,
+        };
+        queue.write_buffer(&self.gpu_state.globals, 0, bytemuck::bytes_of(&globals));
+        let ocean_uniforms = self.ocean.uniforms(camera);
+        queue.write_buffer(&self.gpu_state.ocean, 0, bytemuck::bytes_of(&ocean_uniforms));
+        if let Some(ref shadows) = self.shadows {
+            shadows.write_uniforms(queue, &self.gpu_state, &globals);
+        }
+        self.night_sky.prepare(device, queue, &self.gpu_state, &self.targets, frame_size);
+        self.clouds.prepare(device, queue, &self.gpu_state, &self.targets);
 
         let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
             label: Some("encoder.render"),
         });
 
         {
-            self.cache.cull_meshes(device, &mut encoder, &self.gpu_state, &frustum, camera);
-
-            self.aerial_perspective.refresh();
-            self.aerial_perspective.run(
+            let surface = *self.quadtree.surface();
+            self.cache.cull_meshes(
                 device,
                 &mut encoder,
                 &self.gpu_state,
-                (1, 1, self.quadtree.node_buffer_length() as u32),
-                &0,
+                &surface,
+                &frustum,
+                camera,
             );
+            self.quadtree.cull_nodes(device, &mut encoder, &self.gpu_state);
+            self.ocean.update(device, &mut encoder, &self.gpu_state);
+
+            if let Some(ref shadows) = self.shadows {
+                for (i, cascade) in shadows.cascades.iter().enumerate() {
+                    self.cache.cull_shadow_meshes(
+                        device,
+                        &mut encoder,
+                        &self.gpu_state,
+                        &surface,
+                        camera,
+                        i,
+                        cascade,
+                    );
+                    self.quadtree.cull_shadow_nodes(
+                        device,
+                        &mut encoder,
+                        &self.gpu_state,
+                        i,
+                        &cascade.globals,
+                        &cascade.node_indirect,
+                    );
+
+                    let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                        color_attachments: &[],
+                        depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                            view: &cascade.view,
+                            depth_ops: Some(wgpu::Operations {
+                                load: wgpu::LoadOp::Clear(1.0),
+                                store: true,
+                            }),
+                            stencil_ops: None,
+                        }),
+                        label: Some("renderpass.shadow"),
+                    });
+                    let (bind_group, pipeline) = &self.shadow_bindgroup_pipelines[i];
+                    rpass.set_pipeline(pipeline);
+                    self.quadtree.render(
+                        device,
+                        &mut rpass,
+                        &self.index_buffer,
+                        bind_group,
+                        &cascade.node_indirect,
+                    );
+                    self.cache.render_shadow_meshes(
+                        device,
+                        &mut rpass,
+                        &self.gpu_state,
+                        i,
+                        cascade,
+                    );
+                }
+            }
+
+            if self.atmosphere {
+                self.aerial_perspective.refresh();
+                self.aerial_perspective.run(
+                    device,
+                    &mut encoder,
+                    &self.gpu_state,
+                    (1, 1, self.quadtree.node_buffer_length() as u32),
+                    &0,
+                );
+            }
 
+            let (hdr_view, hdr_resolve_target) = self.tonemapper.color_attachment();
             let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 color_attachments: &[wgpu::RenderPassColorAttachment {
-                    view: color_buffer,
-                    resolve_target: None,
+                    view: hdr_view,
+                    resolve_target: hdr_resolve_target,
                     ops: wgpu::Operations {
                         load: wgpu::LoadOp::Clear(wgpu::Color { r: 0.0, g: 0.0, b: 0.0, a: 1.0 }),
                         store: true,
@@ -430,24 +1011,340 @@ impl Terrain {
             });
             rpass.set_pipeline(&self.bindgroup_pipeline.as_ref().unwrap().1);
             self.quadtree.render(
+                device,
                 &mut rpass,
                 &self.index_buffer,
                 &self.bindgroup_pipeline.as_ref().unwrap().0,
+                &self.gpu_state.node_indirect,
+            );
+
+            self.cache.render_meshes(
+                device,
+                &queue,
+                &mut rpass,
+                &self.gpu_state,
+                &self.targets,
+                &surface,
+                camera,
+            );
+
+            if self.atmosphere {
+                rpass.set_pipeline(&self.sky_bindgroup_pipeline.as_ref().unwrap().1);
+                rpass.set_bind_group(0, &self.sky_bindgroup_pipeline.as_ref().unwrap().0, &[]);
+                rpass.draw(0..3, 0..1);
+            }
+            self.night_sky.render(&mut rpass);
+        }
+
+        self.hiz.build(device, &mut encoder, &self.gpu_state, depth_buffer, view_proj, camera);
+        let (hdr_view, hdr_resolve_target) = self.tonemapper.color_attachment();
+        self.clouds.render(&mut encoder, hdr_view, hdr_resolve_target);
+        self.tonemapper.update_exposure(
+            device,
+            &mut encoder,
+            &self.gpu_state,
+            self.exposure_compensation,
+        );
+        self.tonemapper.render(
+            device,
+            &mut encoder,
+            &self.gpu_state,
+            &self.targets,
+            color_buffer,
+            resolve_target,
+        );
+
+        queue.submit(Some(encoder.finish()));
+    }
+
+    /// Render a single frame into a new image, without needing a window or swapchain.
+    ///
+    /// This blocks until every tile needed for the view has been streamed in or generated, so it
+    /// is mainly intended for thumbnails and other still images. Returns an error if loading
+    /// hasn't settled within `render_to_image_timeout`. The image is always tonemapped to 8-bit
+    /// sRGB, whatever color format the terrain was built with.
+    pub fn render_to_image(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        camera: mint::Point3<f64>,
+        view_proj: mint::ColumnMatrix4<f32>,
+        size: (u32, u32),
+    ) -> Result<image::RgbaImage, Error> {
+        // Tonemap with a separate tonemapper and HDR target, so that on-screen rendering picks up
+        // where it left off afterwards.
+        let format = wgpu::TextureFormat::Rgba8UnormSrgb;
+        let targets = RenderTargets { color_format: format, ..self.targets };
+        let hdr_color = Tonemapper::create_texture(device, (1, 1), 1);
+        let targets = std::mem::replace(&mut self.targets, targets);
+        let tonemapper = std::mem::replace(&mut self.tonemapper, Tonemapper::new(format));
+        let hdr_color = std::mem::replace(&mut self.gpu_state.hdr_color, hdr_color);
+
+        let image = self.render_image_frames(device, queue, camera, view_proj, size);
+
+        self.targets = targets;
+        self.tonemapper = tonemapper;
+        self.gpu_state.hdr_color = hdr_color;
+        image
+    }
+
+    fn render_image_frames(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        camera: mint::Point3<f64>,
+        view_proj: mint::ColumnMatrix4<f32>,
+        size: (u32, u32),
+    ) -> Result<image::RgbaImage, Error> {
+        let extent = wgpu::Extent3d { width: size.0, height: size.1, depth_or_array_layers: 1 };
+        let create_target = |format, sample_count, usage| {
+            device
+                .create_texture(&wgpu::TextureDescriptor {
+                    size: extent,
+                    mip_level_count: 1,
+                    sample_count,
+                    dimension: wgpu::TextureDimension::D2,
+                    format,
+                    usage,
+                    label: Some("texture.render_to_image"),
+                })
+                .create_view(&Default::default())
+        };
+
+        let output = device.create_texture(&wgpu::TextureDescriptor {
+            size: extent,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: self.targets.color_format,
+            usage: wgpu::TextureUsage::RENDER_ATTACHMENT | wgpu::TextureUsage::COPY_SRC,
+            label: Some("texture.render_to_image.output"),
+        });
+        let output_view = output.create_view(&Default::default());
+        let multisampled = if self.targets.sample_count > 1 {
+            Some(create_target(
+                self.targets.color_format,
+                self.targets.sample_count,
+                wgpu::TextureUsage::RENDER_ATTACHMENT,
+            ))
+        } else {
+            None
+        };
+        let depth_buffer = create_target(
+            self.targets.depth_format,
+            self.targets.sample_count,
+            wgpu::TextureUsage::RENDER_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+        );
+        let (color_buffer, resolve_target) = match multisampled {
+            Some(ref view) => (view, Some(&output_view)),
+            None => (&output_view, None),
+        };
+
+        // Keep rendering until a frame neither finishes loading any tiles nor has any left in
+        // flight. Each frame only generates as many tiles as the generation budget allows.
+        let start = Instant::now();
+        let mut last_progress = None;
+        loop {
+            self.render(
+                device,
+                queue,
+                color_buffer,
+                resolve_target,
+                &depth_buffer,
+                size,
+                view_proj,
+                camera,
             );
+            device.poll(wgpu::Maintain::Wait);
 
-            self.cache.render_meshes(device, &queue, &mut rpass, &self.gpu_state, camera);
+            let (progress, streaming) = self.cache.loading_progress();
+            if !streaming && last_progress == Some(progress) {
+                break;
+            }
+            anyhow::ensure!(
+                start.elapsed() < self.render_to_image_timeout,
+                "tiles were still loading after {:?}",
+                self.render_to_image_timeout
+            );
+            if streaming {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            last_progress = Some(progress);
+        }
 
-            rpass.set_pipeline(&self.sky_bindgroup_pipeline.as_ref().unwrap().1);
-            rpass.set_bind_group(0, &self.sky_bindgroup_pipeline.as_ref().unwrap().0, &[]);
-            rpass.draw(0..3, 0..1);
+        // Exposure computed from one frame is only applied to the next, so render twice more to
+        // avoid the result depending on how long loading took.
+        self.tonemapper.reset_adaptation();
+        for _ in 0..2 {
+            self.render(
+                device,
+                queue,
+                color_buffer,
+                resolve_target,
+                &depth_buffer,
+                size,
+                view_proj,
+                camera,
+            );
         }
 
+        let row_bytes = size.0 as usize * 4;
+        let row_pitch = (row_bytes + 255) & !255;
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            size: (row_pitch * size.1 as usize) as u64,
+            usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::MAP_READ,
+            label: Some("buffer.render_to_image.download"),
+            mapped_at_creation: false,
+        });
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("encoder.render_to_image"),
+        });
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                texture: &output,
+                mip_level: 0,
+                origin: wgpu::Origin3d::default(),
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(std::num::NonZeroU32::new(row_pitch as u32).unwrap()),
+                    rows_per_image: None,
+                },
+            },
+            extent,
+        );
         queue.submit(Some(encoder.finish()));
+
+        let mapped = buffer.slice(..).map_async(wgpu::MapMode::Read);
+        device.poll(wgpu::Maintain::Wait);
+        futures::executor::block_on(mapped)?;
+
+        let mut data = Vec::with_capacity(row_bytes * size.1 as usize);
+        {
+            let mapped_buffer = buffer.slice(..).get_mapped_range();
+            for row in mapped_buffer.chunks_exact(row_pitch) {
+                data.extend_from_slice(&row[..row_bytes]);
+            }
+        }
+        buffer.unmap();
+
+        Ok(image::RgbaImage::from_raw(size.0, size.1, data).unwrap())
+    }
+
+    /// Returns how long `render_to_image` waits for tiles to load before giving up.
+    pub fn render_to_image_timeout(&self) -> Duration {
+        self.render_to_image_timeout
+    }
+
+    /// Set how long `render_to_image` waits for tiles to load before giving up. Defaults to five
+    /// minutes.
+    pub fn set_render_to_image_timeout(&mut self, timeout: Duration) {
+        self.render_to_image_timeout = timeout;
+    }
+
+    /// Returns the amount of GPU time per frame that may be spent generating tiles.
+    pub fn generation_budget(&self) -> Duration {
+        self.cache.generation_budget()
+    }
+
+    /// Set how much GPU time per frame may be spent generating tiles. At least one tile is always
+    /// generated each frame if any are needed, regardless of the budget.
+    pub fn set_generation_budget(&mut self, budget: Duration) {
+        self.cache.set_generation_budget(budget);
+    }
+
+    /// Returns the largest screen-space error, in pixels, that is tolerated before terrain tiles
+    /// are refined.
+    pub fn max_pixel_error(&self) -> f32 {
+        self.quadtree.lod().max_pixel_error
+    }
+
+    /// Set the largest screen-space error, in pixels, that is tolerated before terrain tiles are
+    /// refined. Smaller values give more detailed terrain at the cost of rendering and generating
+    /// more tiles.
+    pub fn set_max_pixel_error(&mut self, max_pixel_error: f32) {
+        assert!(max_pixel_error > 0.0);
+        self.quadtree.set_lod(LodMetric { max_pixel_error, ..*self.quadtree.lod() });
+    }
+
+    /// Use the screen-space error threshold of one of the quality presets.
+    pub fn set_lod_quality(&mut self, quality: LodQuality) {
+        self.set_max_pixel_error(quality.max_pixel_error());
+    }
+
+    pub fn debug_mode(&self) -> DebugMode {
+        self.debug_mode
+    }
+
+    /// Select a debug visualization to apply when rendering the terrain.
+    pub fn set_debug_mode(&mut self, mode: DebugMode) {
+        self.debug_mode = mode;
+    }
+
+    pub fn tonemapping(&self) -> Tonemapping {
+        self.tonemapping
+    }
+
+    /// Select the operator used to map the HDR scene into the color buffer. Defaults to
+    /// `Tonemapping::Aces`.
+    pub fn set_tonemapping(&mut self, tonemapping: Tonemapping) {
+        self.tonemapping = tonemapping;
+    }
+
+    /// Returns the adjustment applied to the automatic exposure, in stops.
+    pub fn exposure_compensation(&self) -> f32 {
+        self.exposure_compensation
+    }
+
+    /// Brighten (positive values) or darken (negative values) the image relative to the exposure
+    /// picked from the average scene luminance, in stops.
+    pub fn set_exposure_compensation(&mut self, stops: f32) {
+        assert!(stops.is_finite());
+        self.exposure_compensation = stops;
+    }
+
+    /// Position the sun, moon and stars for the given time. Until this is called, they are
+    /// positioned for noon UTC on January 1st, 2000.
+    pub fn set_time(&mut self, utc: SystemTime) {
+        self.sun_direction = coordinates::sun_direction(utc);
+        self.night_sky.set_time(utc);
+    }
+
+    /// Returns the parameters of the cloud layer, if there is one.
+    pub fn clouds(&self) -> Option<CloudLayer> {
+        self.clouds.layer()
+    }
+
+    /// Add, change or remove (by passing `None`) the layer of volumetric clouds. Clouds are drawn
+    /// over the terrain and sky, and shadow the terrain below them.
+    pub fn set_clouds(&mut self, clouds: Option<CloudLayer>) {
+        self.clouds.set_layer(clouds);
+    }
+
+    /// Returns statistics about the most recently rendered frame.
+    pub fn render_stats(&self) -> RenderStats {
+        self.quadtree.render_stats()
+    }
+
+    /// Returns the custom layer registered under `name`, if any.
+    pub fn custom_layer(&self, name: &str) -> Option<LayerType> {
+        self.cache.custom_layers().find(|l| l.name == name).map(|l| l.layer_type)
+    }
+
+    /// The surface the terrain covers. Planets also describe the body being rendered, including
+    /// its surface gravity.
+    pub fn surface(&self) -> &Surface {
+        self.quadtree.surface()
     }
 
+    /// Returns the height of the terrain at the given latitude and longitude (in radians), using
+    /// the most detailed tile in cache. Only meaningful for planets.
     pub fn get_height(&self, latitude: f64, longitude: f64) -> f32 {
+        let planet = self.quadtree.surface().planet().copied().unwrap_or_default();
         for level in (0..=VNode::LEVEL_CELL_1M).rev() {
-            if let Some(height) = self.cache.tiles.get_height(latitude, longitude, level) {
+            if let Some(height) = self.cache.tiles.get_height(&planet, latitude, longitude, level) {
                 return height;
             }
         }
@@ -54,6 +54,7 @@ pub(crate) struct MapFile {
     _db: sled::Db,
     tiles: sled::Tree,
     textures: sled::Tree,
+    shaders: sled::Tree,
 }
 impl MapFile {
     pub(crate) fn new(layers: VecMap<LayerParams>) -> Self {
@@ -81,6 +82,7 @@ impl MapFile {
             layers,
             tiles: db.open_tree("tiles").unwrap(),
             textures: db.open_tree("textures").unwrap(),
+            shaders: db.open_tree("shaders").unwrap(),
             _db: db,
         }
     }
@@ -92,11 +94,11 @@ impl MapFile {
         })
     }
     pub(crate) async fn read_tile(&self, layer: LayerType, node: VNode) -> Result<Vec<u8>, Error> {
-        let filename = Self::tile_path(layer, node);
+        let filename = self.tile_path(layer, node);
         if !filename.exists() {
             match layer {
                 LayerType::Albedo | LayerType::Heightmaps | LayerType::Roughness => {
-                    let url = Self::tile_url(layer, node);
+                    let url = self.tile_url(layer, node);
                     let client = hyper::Client::builder()
                         .build::<_, hyper::Body>(hyper_tls::HttpsConnector::new());
                     let resp = client.get(url.parse()?).await?;
@@ -126,7 +128,7 @@ impl MapFile {
         data: &[u8],
         base: bool,
     ) -> Result<(), Error> {
-        let filename = Self::tile_path(layer, node);
+        let filename = self.tile_path(layer, node);
         if let Some(parent) = filename.parent() {
             fs::create_dir_all(parent)?;
         }
@@ -261,7 +263,18 @@ impl MapFile {
         &self.layers
     }
 
-    fn tile_name(layer: LayerType, node: VNode) -> String {
+    pub(crate) fn add_layer(&mut self, layer: LayerParams) {
+        let index = layer.layer_type.index();
+        assert!(!self.layers.contains_key(index), "duplicate layer {:?}", layer.layer_type);
+        assert!(
+            self.layers.values().all(|l| l.name != layer.name),
+            "duplicate layer {}",
+            layer.name
+        );
+        self.layers.insert(index, layer);
+    }
+
+    fn tile_name(&self, layer: LayerType, node: VNode) -> String {
         let face = match node.face() {
             0 => "0E",
             1 => "180E",
@@ -271,22 +284,26 @@ impl MapFile {
             5 => "S",
             _ => unreachable!(),
         };
-        let (layer, ext) = match layer {
+        let (layer, ext): (&str, &str) = match layer {
             LayerType::Displacements => ("displacements", "raw"),
             LayerType::Albedo => ("albedo", "png"),
             LayerType::Roughness => ("roughness", "raw.lz4"),
             LayerType::Normals => ("normals", "raw"),
             LayerType::Heightmaps => ("heightmaps", "raw"),
+            LayerType::Horizons => ("horizons", "raw"),
+            LayerType::Water => ("water", "raw"),
+            // Named after the layer so that tiles don't depend on the order layers are registered.
+            LayerType::Custom(_) => (self.layers[layer].name.as_str(), "raw"),
         };
         format!("{}/{}_{}_{}_{}x{}.{}", layer, layer, node.level(), face, node.x(), node.y(), ext)
     }
 
-    fn tile_path(layer: LayerType, node: VNode) -> PathBuf {
-        TERRA_DIRECTORY.join("tiles").join(&Self::tile_name(layer, node))
+    fn tile_path(&self, layer: LayerType, node: VNode) -> PathBuf {
+        TERRA_DIRECTORY.join("tiles").join(&self.tile_name(layer, node))
     }
 
-    fn tile_url(layer: LayerType, node: VNode) -> String {
-        format!("{}{}", TERRA_TILES_URL, Self::tile_name(layer, node))
+    fn tile_url(&self, layer: LayerType, node: VNode) -> String {
+        format!("{}{}", TERRA_TILES_URL, self.tile_name(layer, node))
     }
 
     pub(crate) fn reload_tile_state(
@@ -295,7 +312,7 @@ impl MapFile {
         node: VNode,
         base: bool,
     ) -> Result<TileState, Error> {
-        let filename = Self::tile_path(layer, node);
+        let filename = self.tile_path(layer, node);
         let meta = self.lookup_tile_meta(layer, node);
 
         let exists = filename.exists();
@@ -320,7 +337,6 @@ impl MapFile {
         self.update_tile_meta(layer, node, new_meta)?;
         Ok(target_state)
     }
-    #[allow(unused)]
     pub(crate) fn clear_generated(&self, layer: LayerType) -> Result<(), Error> {
         self.scan_tile_meta(layer, |node, meta| {
             if let TileState::Generated = meta.state {
@@ -329,6 +345,22 @@ impl MapFile {
             Ok(())
         })
     }
+    /// Record the hash of the shader used to generate `layer`, discarding any generated tiles if
+    /// they were produced by a different shader. Returns whether tiles were discarded.
+    pub(crate) fn update_shader_hash(
+        &self,
+        layer: LayerType,
+        hash: [u8; 32],
+    ) -> Result<bool, Error> {
+        if let Some(desc) = self.lookup_shader(layer)? {
+            if desc.hash == hash {
+                return Ok(false);
+            }
+        }
+        self.clear_generated(layer)?;
+        self.update_shader(layer, ShaderDescriptor { hash })?;
+        Ok(true)
+    }
     /// Return a list of the missing bases for a layer, as well as the total number bases in the layer.
     pub(crate) fn get_missing_base(&self, layer: LayerType) -> Result<(Vec<VNode>, usize), Error> {
         let mut total = 0;
@@ -376,7 +408,18 @@ impl MapFile {
         Ok(())
     }
 
-    fn lookup_texture(&self, name: &str) -> Result<Option<TextureDescriptor>, Error> {
+    fn lookup_shader(&self, layer: LayerType) -> Result<Option<ShaderDescriptor>, Error> {
+        let key = bincode::serialize(&layer).unwrap();
+        Ok(self.shaders.get(key)?.map(|value| bincode::deserialize(&value).unwrap()))
+    }
+    fn update_shader(&self, layer: LayerType, desc: ShaderDescriptor) -> Result<(), Error> {
+        let key = bincode::serialize(&layer).unwrap();
+        let value = bincode::serialize(&desc).unwrap();
+        self.shaders.insert(key, value)?;
+        Ok(())
+    }
+
+    pub(crate) fn lookup_texture(&self, name: &str) -> Result<Option<TextureDescriptor>, Error> {
         Ok(self.textures.get(name)?.map(|value| serde_json::from_slice(&value).unwrap()))
     }
     fn update_texture(&self, name: &str, desc: TextureDescriptor) -> Result<(), Error> {
@@ -1,257 +1,195 @@
+use crate::generate::ComputeShader;
+use crate::gpu_state::GpuState;
+use rand::SeedableRng;
+use rand_distr::{Distribution, Normal};
 use std::f32::consts::PI;
-
-use cgmath::*;
-use gfx;
-use gfx::format::*;
-use gfx_core;
-use rand;
-use rand::distributions::{Distribution, Normal};
-use rustfft::algorithm::Radix4;
-use rustfft::num_complex::Complex;
-use rustfft::num_traits::Zero;
-use rustfft::FFT;
-
-// See: https://github.com/deiss/fftocean
-
-const RESOLUTION: usize = 128;
-const MIPMAPS: u8 = 8;
-
-pub struct Ocean<R: gfx::Resources> {
-    side_length: f32,
+use std::num::NonZeroU32;
+use std::time::Instant;
+
+// See: Jerry Tessendorf, "Simulating Ocean Water"
+
+/// Number of samples along each side of the wave simulation grids. Must match `N` in
+/// ocean-fft.comp.
+pub(crate) const RESOLUTION: u32 = 256;
+
+/// Number of overlapping wave simulations, each covering a smaller area in more detail than the
+/// one before. Must match `NUM_OCEAN_CASCADES` in declarations.glsl.
+pub(crate) const NUM_CASCADES: usize = 3;
+
+/// Side length of each cascade, in meters. They aren't multiples of each other, which would make
+/// the repetition of the larger cascades more visible.
+const CASCADE_SIZES: [f32; NUM_CASCADES] = [1000.0, 137.0, 23.0];
+
+/// Time after which the waves repeat, in seconds. Wave frequencies are rounded down to multiples
+/// of `2π / WAVE_PERIOD` so that the simulation time can wrap around without the waves jumping.
+/// Must match `WAVE_PERIOD` in ocean-fft.comp.
+const WAVE_PERIOD: f64 = 200.0;
+
+const GRAVITY: f32 = 9.81;
+const WIND_SPEED: f32 = 12.0;
+const WIND_DIRECTION: [f32; 2] = [1.0, 0.0];
+
+/// Phillips constant, which sets the overall height of the waves.
+const PHILLIPS_ALPHA: f32 = 0.0081;
+
+/// Uniforms for sampling the wave cascades, see `Ocean` in declarations.glsl.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub(crate) struct OceanUniforms {
+    /// For each cascade, the camera position modulo the size of the cascade followed by the size
+    /// itself. This keeps texture coordinates precise even far from the origin.
+    cascades: [[f32; 4]; NUM_CASCADES],
     time: f32,
-
-    texture_data: Vec<Vec<[u8; 4]>>,
-
-    /// Initial wave spectrum.
-    spectrum: Vec<Complex<f32>>,
-    /// Current wave heights.
-    heights: Vec<f32>,
-
-    pub(crate) texture_view: gfx_core::handle::ShaderResourceView<R, [f32; 4]>,
-    texture: gfx_core::handle::Texture<R, gfx_core::format::R8_G8_B8_A8>,
+    _padding: [f32; 3],
 }
+unsafe impl bytemuck::Pod for OceanUniforms {}
+unsafe impl bytemuck::Zeroable for OceanUniforms {}
+
+/// Deep water waves simulated with an inverse FFT of a wind driven wave spectrum, which is done
+/// on the GPU every frame.
+pub(crate) struct Ocean {
+    start: Instant,
+    horizontal: ComputeShader<u32>,
+    vertical: ComputeShader<u32>,
+}
+impl Ocean {
+    pub fn new(queue: &wgpu::Queue, gpu_state: &GpuState) -> Self {
+        let spectrum = Self::initial_spectrum();
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &gpu_state.ocean_spectrum,
+                mip_level: 0,
+                origin: wgpu::Origin3d { x: 0, y: 0, z: 0 },
+            },
+            bytemuck::cast_slice(&spectrum),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(NonZeroU32::new(RESOLUTION * 16).unwrap()),
+                rows_per_image: Some(NonZeroU32::new(RESOLUTION).unwrap()),
+            },
+            wgpu::Extent3d {
+                width: RESOLUTION,
+                height: RESOLUTION,
+                depth_or_array_layers: NUM_CASCADES as u32,
+            },
+        );
 
-impl<R: gfx::Resources> Ocean<R> {
-    pub fn new<F: gfx::Factory<R>>(factory: &mut F) -> Self {
-        let texture = factory
-            .create_texture::<R8_G8_B8_A8>(
-                gfx::texture::Kind::D2Array(
-                    RESOLUTION as u16,
-                    RESOLUTION as u16,
-                    1,
-                    gfx::texture::AaMode::Single,
-                ),
-                MIPMAPS,
-                gfx::memory::Bind::SHADER_RESOURCE,
-                gfx::memory::Usage::Dynamic,
-                Some(ChannelType::Unorm),
-            ).unwrap();
-
-        let texture_view = factory
-            .view_texture_as_shader_resource::<gfx::format::Rgba8>(&texture, (0, 0), Swizzle::new())
-            .unwrap();
-
-        let side_length = 500.0;
-
-        // Parameters for ocean simulation; currently all hardcoded.
-        let g = 9.81;
-        let wind_speed = 30.0;
-        let min_wave_size = 1.0;
-        let l = (wind_speed * wind_speed) / g;
-        let (wx, wy) = (1.0, 0.0);
-
-        // Initialize the wave spectrum, recalling that the DC terms will be zero.
-        let mut rng = rand::thread_rng();
-        let normal = Normal::new(0.0, 1.0);
-        let mut spectrum = vec![Zero::zero(); (RESOLUTION + 1) * (RESOLUTION + 1)];
-        for y in 0..(RESOLUTION + 1) {
-            for x in 1..(RESOLUTION + 1) {
-                spectrum[x + y * (RESOLUTION + 1)] = {
-                    let kx = (2.0 * PI * x as f32 - PI * RESOLUTION as f32) / side_length;
-                    let ky = (2.0 * PI * y as f32 - PI * RESOLUTION as f32) / side_length;
-                    let k2 = kx * kx + ky * ky;
-                    if (x as i64 - RESOLUTION as i64 / 2).abs() < RESOLUTION as i64 / 8
-                        || (y as i64 - RESOLUTION as i64 / 2).abs() < RESOLUTION as i64 / 8
-                    {
-                        Zero::zero()
-                    } else {
-                        let power = 1.0
-                            * f32::exp(-1.0 / (k2 * l * l))
-                            * f32::exp(-k2 * min_wave_size * min_wave_size)
-                            * f32::powi(kx * wx + ky * wy, 2)
-                            / (k2 * k2);
-                        Complex::new(
-                            normal.sample(&mut rng) as f32,
-                            normal.sample(&mut rng) as f32,
-                        ) * (power / 2.0).sqrt()
-                    }
-                };
-            }
-        }
-
-        let mut texture_data = Vec::new();
-        for i in 0..MIPMAPS {
-            texture_data.push(vec![[0; 4]; (RESOLUTION >> i) * (RESOLUTION >> i)]);
-        }
         Self {
-            spectrum,
-            side_length,
-            texture_data,
-            heights: vec![0.0; RESOLUTION * RESOLUTION],
-            texture_view,
-            texture,
-            time: 0.0,
+            start: Instant::now(),
+            horizontal: ComputeShader::new(
+                rshader::shader_source!("../shaders", "ocean-fft.comp", "declarations.glsl"; "VERTICAL" = "0"),
+                "ocean-fft-horizontal".to_string(),
+            ),
+            vertical: ComputeShader::new(
+                rshader::shader_source!("../shaders", "ocean-fft.comp", "declarations.glsl"; "VERTICAL" = "1"),
+                "ocean-fft-vertical".to_string(),
+            ),
         }
     }
 
-    fn update_texture_data(&mut self) {
-        let heights = &self.heights;
-        let get = |x, y| {
-            let x = ((x % RESOLUTION as i64) + RESOLUTION as i64) % RESOLUTION as i64;
-            let y = ((y % RESOLUTION as i64) + RESOLUTION as i64) % RESOLUTION as i64;
-            heights[x as usize + y as usize * RESOLUTION]
-        };
-
-        let min = heights.iter().cloned().fold(0. / 0., f32::min);
-        let max = heights.iter().cloned().fold(0. / 0., f32::max);
-
-        for i in 0..MIPMAPS {
-            let resolution = RESOLUTION >> i;
-
-            for y in 0..(resolution as i64) {
-                for x in 0..(resolution as i64) {
-                    self.texture_data[i as usize][x as usize + y as usize * resolution] = if i == 0
-                    {
-                        let sx = get(x + 1, y) - get(x - 1, y);
-                        let sy = get(x, y + 1) - get(x, y - 1);
-                        let n = Vector3::new(sx, 2000.0, sy).normalize();
-
-                        [
-                            (n.x * 127.5 + 127.5) as u8,
-                            (n.z * 127.5 + 127.5) as u8,
-                            (n.y * 127.5 + 127.5) as u8,
-                            ((get(x, y) - min) / (max - min) * 255.0) as u8,
-                        ]
-                    } else if i < 3 {
-                        let (px, py, presolution) =
-                            (x as usize * 2, y as usize * 2, resolution * 2);
-                        let p = [
-                            self.texture_data[i as usize - 1][px + py * presolution],
-                            self.texture_data[i as usize - 1][(px + 1) + py * presolution],
-                            self.texture_data[i as usize - 1][px + (py + 1) * presolution],
-                            self.texture_data[i as usize - 1][(px + 1) + (py + 1) * presolution],
-                        ];
+    /// Create one of the textures that hold a value for every sample of every cascade.
+    pub fn create_texture(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        usage: wgpu::TextureUsage,
+        label: &str,
+    ) -> wgpu::Texture {
+        device.create_texture(&wgpu::TextureDescriptor {
+            size: wgpu::Extent3d {
+                width: RESOLUTION,
+                height: RESOLUTION,
+                depth_or_array_layers: NUM_CASCADES as u32,
+            },
+            format,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            usage,
+            label: Some(label),
+        })
+    }
 
-                        let mut sum = [0.0; 4];
-                        for j in 0..4 {
-                            for k in 0..4 {
-                                sum[j] += p[k][j] as f32;
-                            }
-                        }
+    /// Returns the wave amplitudes at time zero for every cascade. Each texel holds `h0(k)` and
+    /// the conjugate of `h0(-k)`, where the wave vector `k` is zero at the center of the texture.
+    fn initial_spectrum() -> Vec<[f32; 4]> {
+        let n = RESOLUTION as usize;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let normal = Normal::new(0.0f32, 1.0).unwrap();
+
+        let largest_wave = WIND_SPEED * WIND_SPEED / GRAVITY;
+        let mut h0 = vec![[0.0f32; 2]; n * n * NUM_CASCADES];
+        for (cascade, &size) in CASCADE_SIZES.iter().enumerate() {
+            // Each cascade only includes the wavelengths that the next finer one can't represent
+            // well, so that no wave is counted twice.
+            let max_k = CASCADE_SIZES.get(cascade + 1).map(|s| 8.0 * PI / s).unwrap_or(f32::MAX);
+            let min_k = if cascade > 0 { 8.0 * PI / size } else { 0.0 };
+            let dk = 2.0 * PI / size;
+
+            for y in 0..n {
+                for x in 0..n {
+                    let kx = (x as f32 - (n / 2) as f32) * dk;
+                    let ky = (y as f32 - (n / 2) as f32) * dk;
+                    let k = (kx * kx + ky * ky).sqrt();
+                    if k == 0.0 || k < min_k || k >= max_k {
+                        continue;
+                    }
 
-                        [
-                            ((sum[0] / 4.0 - 127.5) / i as f32 + 127.0) as u8,
-                            ((sum[1] / 4.0 - 127.5) / i as f32 + 127.0) as u8,
-                            ((sum[2] / 4.0 - 127.5) / i as f32 + 255.0 * (1.0 - 0.5 / i as f32))
-                                as u8,
-                            ((sum[3] / 4.0) / i as f32) as u8,
-                        ]
-                    } else {
-                        [128, 128, 255, 0]
-                    };
+                    let alignment = (kx * WIND_DIRECTION[0] + ky * WIND_DIRECTION[1]) / k;
+                    let phillips = 0.5 * PHILLIPS_ALPHA / k.powi(4)
+                        * (-1.0 / (k * largest_wave).powi(2)).exp()
+                        * (2.0 / PI)
+                        * alignment
+                        * alignment;
+                    let amplitude = (phillips * 0.5).sqrt() * dk;
+                    h0[x + (y + cascade * n) * n] =
+                        [normal.sample(&mut rng) * amplitude, normal.sample(&mut rng) * amplitude];
                 }
             }
         }
-    }
-
-    fn update_simulation(&mut self, dt: f32) {
-        self.time += dt;
-
-        let mut input: Vec<Complex<f32>> = vec![Zero::zero(); RESOLUTION * RESOLUTION];
-        let mut output: Vec<Complex<f32>> = vec![Zero::zero(); RESOLUTION * RESOLUTION];
-        let mut input2: Vec<Complex<f32>> = vec![Zero::zero(); RESOLUTION * RESOLUTION];
-        let mut output2: Vec<Complex<f32>> = vec![Zero::zero(); RESOLUTION * RESOLUTION];
-
-        for y in 0..RESOLUTION {
-            for x in 0..RESOLUTION {
-                let x = if x == 0 {
-                    RESOLUTION / 2
-                } else if x <= RESOLUTION / 2 {
-                    RESOLUTION / 2 - x
-                } else {
-                    RESOLUTION * 3 / 2 - x
-                };
-                let y = if y == 0 {
-                    RESOLUTION / 2
-                } else if y <= RESOLUTION / 2 {
-                    RESOLUTION / 2 - y
-                } else {
-                    RESOLUTION * 3 / 2 - y
-                };
-
-                let exponent = {
-                    let x = x as f32 - (RESOLUTION / 2) as f32;
-                    let y = y as f32 - (RESOLUTION / 2) as f32;
 
-                    let kx = (2.0 * PI * x as f32 - PI * RESOLUTION as f32) / self.side_length;
-                    let ky = (2.0 * PI * y as f32 - PI * RESOLUTION as f32) / self.side_length;
-                    let k2 = kx * kx + ky * ky;
-                    Complex::new(
-                        0.0,
-                        self.time * f32::sqrt(9.81 * k2.sqrt() * (1.0 + k2 * 0.01)),
-                    )
-                };
-
-                let h0_k = self.spectrum[x + y * (RESOLUTION + 1)];
-                let h0_nk =
-                    self.spectrum[(RESOLUTION - x) + (RESOLUTION - y) * (RESOLUTION + 1)].conj();
-
-                input[x + y * RESOLUTION] =
-                    h0_k * Complex::exp(&exponent) + h0_nk * Complex::exp(&-exponent);
+        let mut spectrum = vec![[0.0; 4]; n * n * NUM_CASCADES];
+        for cascade in 0..NUM_CASCADES {
+            for y in 0..n {
+                for x in 0..n {
+                    let k = h0[x + (y + cascade * n) * n];
+                    let minus_k = h0[(n - x) % n + ((n - y) % n + cascade * n) * n];
+                    spectrum[x + (y + cascade * n) * n] = [k[0], k[1], minus_k[0], -minus_k[1]];
+                }
             }
         }
+        spectrum
+    }
 
-        let fft = Radix4::new(RESOLUTION, true);
-        fft.process_multi(&mut input, &mut output);
-        for x in 0..RESOLUTION {
-            for y in 0..RESOLUTION {
-                input2[x + y * RESOLUTION] = output[y + x * RESOLUTION];
-            }
+    /// Compute the uniforms for the current frame.
+    pub fn uniforms(&self, camera: mint::Point3<f64>) -> OceanUniforms {
+        let mut cascades = [[0.0; 4]; NUM_CASCADES];
+        for (c, &size) in cascades.iter_mut().zip(CASCADE_SIZES.iter()) {
+            let size64 = size as f64;
+            *c = [
+                camera.x.rem_euclid(size64) as f32,
+                camera.y.rem_euclid(size64) as f32,
+                camera.z.rem_euclid(size64) as f32,
+                size,
+            ];
         }
-        fft.process_multi(&mut input2, &mut output2);
 
-        for y in 0..RESOLUTION {
-            for x in 0..RESOLUTION {
-                self.heights[x + y * RESOLUTION] = output2[x + y * RESOLUTION].re;
-            }
-        }
+        // Wrap the time so that it doesn't lose precision. The waves are periodic, so this is seamless.
+        let time = (self.start.elapsed().as_secs_f64() % WAVE_PERIOD) as f32;
+        OceanUniforms { cascades, time, _padding: [0.0; 3] }
     }
 
-    pub fn update<C: gfx_core::command::Buffer<R>>(
+    /// Record commands to update the wave heights and slopes. The uniforms for the frame must
+    /// already have been written to the `ocean` buffer.
+    pub fn update(
         &mut self,
-        encoder: &mut gfx::Encoder<R, C>,
-        dt: f32,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        gpu_state: &GpuState,
     ) {
-        self.update_simulation(dt);
-        self.update_texture_data();
-
-        for (i, ref mipmap_data) in self.texture_data.iter().enumerate() {
-            encoder
-                .update_texture::<R8_G8_B8_A8, gfx::format::Srgba8>(
-                    &self.texture,
-                    None,
-                    gfx_core::texture::NewImageInfo {
-                        xoffset: 0,
-                        yoffset: 0,
-                        zoffset: 0,
-                        width: (RESOLUTION as u16) >> i,
-                        height: (RESOLUTION as u16) >> i,
-                        depth: 1,
-                        format: (),
-                        mipmap: i as u8,
-                    },
-                    &mipmap_data[..],
-                ).unwrap();
-        }
+        let dimensions = (1, RESOLUTION, NUM_CASCADES as u32);
+        self.horizontal.refresh();
+        self.horizontal.run(device, encoder, gpu_state, dimensions, &0);
+        self.vertical.refresh();
+        self.vertical.run(device, encoder, gpu_state, dimensions, &0);
     }
 }
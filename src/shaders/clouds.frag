@@ -0,0 +1,135 @@
+#version 450 core
+#include "declarations.glsl"
+
+layout(set = 0, binding = 0, std140) uniform UniformBlock {
+	Globals globals;
+};
+layout(set = 0, binding = 1, std140) uniform CloudsBlock {
+	Clouds clouds;
+};
+layout(set = 0, binding = 2) uniform sampler linear_wrap;
+layout(set = 0, binding = 3) uniform sampler nearest;
+layout(set = 0, binding = 4) uniform texture2D cloud_weather;
+layout(set = 0, binding = 5) uniform texture2D cloud_coverage;
+layout(set = 0, binding = 6) uniform texture2D transmittance;
+layout(set = 0, binding = 7) uniform texture2D hiz;
+layout(set = 0, binding = 8, std430) readonly buffer ExposureBlock {
+	float exposure;
+};
+
+#include "clouds.glsl"
+
+layout(location = 0) in vec4 position;
+
+layout(location = 0) out vec4 out_color;
+
+#define planetRadius globals.planet_radius
+#define atmosphereRadius globals.atmosphere_radius
+
+const float SUN_ILLUMINANCE = 100000.0;
+
+// Longest distance through the cloud layer that is marched along each ray.
+const float MAX_MARCH_DISTANCE = 80000.0;
+
+vec2 rsi(vec3 r0, vec3 rd, float sr);
+vec3 precomputed_transmittance(float r, float mu);
+vec3 precomputed_transmittance2(vec3 x, vec3 y);
+vec3 atmosphere(vec3 r0, vec3 r1, vec3 pSun);
+
+float henyey_greenstein(float mu, float g) {
+	return (1 - g * g) / (4 * 3.141592 * pow(1 + g * g - 2 * g * mu, 1.5));
+}
+
+// Sunlight reaching a point, after passing through the atmosphere but not the clouds.
+vec3 sun_transmittance(vec3 x, vec3 sun_direction) {
+	if (globals.atmosphere == 0)
+		return vec3(1);
+	return precomputed_transmittance(length(x), dot(normalize(x), sun_direction));
+}
+
+// Optical depth of the clouds between a point and the sun, with steps that grow with distance.
+float optical_depth_towards_sun(vec3 x, vec3 sun_direction) {
+	float optical_depth = 0;
+	float t = 0;
+	for (int i = 0; i < 6; i++) {
+		float step_size = 100.0 * (i + 1);
+		optical_depth += cloud_density(x + sun_direction * (t + 0.5 * step_size), false) * step_size;
+		t += step_size;
+	}
+	return optical_depth;
+}
+
+void main() {
+	vec4 r0 = globals.view_proj_inverse * vec4(position.xy, 1, 1);
+	vec4 r1 = globals.view_proj_inverse * vec4(position.xy, 1e-9, 1);
+	vec3 r = normalize(r1.xyz / r1.w - r0.xyz / r0.w);
+	vec3 x0 = globals.camera;
+
+	// Stop at the terrain, using the depth pyramid that was just built for this frame. Its first
+	// level holds the farthest depth of each two by two block of pixels, which is plenty of
+	// resolution for soft clouds.
+	ivec2 texel = min(ivec2(gl_FragCoord.xy) / 2, textureSize(hiz, 0) - 1);
+	float depth = texelFetch(hiz, texel, 0).x;
+	float max_distance = 1e30;
+	if (depth > 0) {
+		vec4 p = globals.view_proj_inverse * vec4(position.xy, depth, 1);
+		max_distance = length(p.xyz / p.w);
+	}
+
+	vec2 segment = cloud_layer_segment(x0, r);
+	segment.y = min(min(segment.y, max_distance), segment.x + MAX_MARCH_DISTANCE);
+	if (segment.x >= segment.y)
+		discard;
+
+	vec3 sun_direction = normalize(globals.sun_direction);
+	float mu = dot(r, sun_direction);
+	float phase = mix(henyey_greenstein(mu, 0.6), henyey_greenstein(mu, -0.3), 0.3);
+
+	// Offset the samples by a different amount for each pixel, which trades banding for noise.
+	float jitter = fract(52.9829189 * fract(dot(gl_FragCoord.xy, vec2(0.06711056, 0.00583715))));
+
+	const int STEPS = 64;
+	float step_size = (segment.y - segment.x) / STEPS;
+	vec3 scattered = vec3(0);
+	float cloud_transmittance = 1;
+	float weighted_distance = 0;
+	for (int i = 0; i < STEPS && cloud_transmittance > 0.01; i++) {
+		float t = segment.x + (i + jitter) * step_size;
+		vec3 x = x0 + r * t;
+		float density = cloud_density(x, true);
+		if (density <= 0)
+			continue;
+
+		// Direct sunlight scattered towards the camera, plus light from the rest of the sky which is
+		// approximated as a fraction of the sunlight reaching the top of the clouds.
+		vec3 sunlight = SUN_ILLUMINANCE * sun_transmittance(x, sun_direction);
+		float h = (cloud_altitude(x) - clouds.min_altitude) / (clouds.max_altitude - clouds.min_altitude);
+		vec3 ambient = sunlight * vec3(0.6, 0.75, 1.0) * (0.05 + 0.05 * h) / 3.141592;
+		vec3 source = sunlight * phase * exp(-optical_depth_towards_sun(x, sun_direction)) + ambient;
+
+		// Integrate the scattered light analytically over the step, assuming constant density.
+		float step_transmittance = exp(-density * step_size);
+		scattered += cloud_transmittance * source * (1 - step_transmittance);
+		weighted_distance += cloud_transmittance * (1 - step_transmittance) * t;
+		cloud_transmittance *= step_transmittance;
+	}
+	if (cloud_transmittance >= 1)
+		discard;
+
+	// Apply aerial perspective as if all the light came from the average distance of the clouds.
+	// The scene behind them already includes the light scattered by the air in front of the
+	// clouds, so add back the part of that which the clouds now block.
+	if (globals.atmosphere != 0) {
+		vec3 x1 = x0 + r * (weighted_distance / (1 - cloud_transmittance));
+		vec2 p = rsi(x0, r, atmosphereRadius);
+		if (p.x < p.y && p.y > 0.0) {
+			vec3 start = x0 + r * max(p.x, 0.0);
+			scattered = scattered * precomputed_transmittance2(x1, start)
+				+ (1 - cloud_transmittance) * atmosphere(start, x1, sun_direction);
+		}
+	}
+
+	out_color = vec4(scattered * exposure, cloud_transmittance);
+}
+
+#include "atmosphere.glsl"
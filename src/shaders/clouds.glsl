@@ -0,0 +1,105 @@
+// Density of the volumetric cloud layer. The including shader must declare `globals`, the `clouds`
+// uniform block, the `cloud_weather` and `cloud_coverage` textures and the `linear_wrap` sampler.
+
+// Distances in meters over which the weather and coverage textures repeat, and the scale of the
+// coverage texture when it is sampled a second time to erode the edges of the clouds.
+const float CLOUD_WEATHER_SCALE = 400000.0;
+const float CLOUD_COVERAGE_SCALE = 60000.0;
+const float CLOUD_DETAIL_SCALE = 6000.0;
+
+float cloud_altitude(vec3 x) {
+	return globals.flat_surface != 0 ? x.x : length(x) - globals.planet_radius;
+}
+
+// Range of distances along a ray, given in ecef coordinates, where it is below the given altitude.
+// As with rsi(), there is no intersection when result.x > result.y.
+vec2 cloud_shell(vec3 x, vec3 r, float altitude) {
+	if (globals.flat_surface != 0) {
+		float t = (altitude - x.x) / (abs(r.x) > 1e-6 ? r.x : 1e-6);
+		return r.x > 0 ? vec2(-1e30, t) : vec2(t, 1e30);
+	}
+
+	// Factor the difference of squares to avoid losing precision at planetary distances.
+	float radius = globals.planet_radius + altitude;
+	float b = dot(r, x);
+	float d = b * b + (radius - length(x)) * (radius + length(x));
+	if (d < 0)
+		return vec2(1e30, -1e30);
+	return vec2(-b - sqrt(d), -b + sqrt(d));
+}
+
+// Range of distances along a ray where it passes through the cloud layer, stopping at the first
+// time it drops below the bottom of the layer.
+vec2 cloud_layer_segment(vec3 x, vec3 r) {
+	vec2 outer = cloud_shell(x, r, clouds.max_altitude);
+	vec2 inner = cloud_shell(x, r, clouds.min_altitude);
+	if (outer.x > outer.y || outer.y < 0)
+		return vec2(1e30, -1e30);
+
+	vec2 segment = vec2(max(outer.x, 0), outer.y);
+	if (inner.x <= inner.y) {
+		if (inner.x > 0)
+			segment.y = min(segment.y, inner.x);
+		else if (inner.y > 0)
+			segment.x = max(segment.x, inner.y);
+	}
+	return segment;
+}
+
+// Samples a tiling texture with a planar projection along each axis, blended by the given weights.
+// This is a macro since not every shader compiler accepts textures as function arguments.
+#define CLOUD_TEXTURE(tex, x, weights, scale) \
+	(textureLod(sampler2D(tex, linear_wrap), (x).yz / (scale), 0) * (weights).x \
+		+ textureLod(sampler2D(tex, linear_wrap), (x).zx / (scale), 0) * (weights).y \
+		+ textureLod(sampler2D(tex, linear_wrap), (x).xy / (scale), 0) * (weights).z)
+
+// Extinction coefficient of the clouds at a point in ecef coordinates. Skipping the detail
+// texture is cheaper, which is good enough for shadows and lighting.
+float cloud_density(vec3 x, bool detailed) {
+	float h = (cloud_altitude(x) - clouds.min_altitude) / (clouds.max_altitude - clouds.min_altitude);
+	if (h <= 0 || h >= 1)
+		return 0;
+
+	// Weight each planar projection by how closely its axis lines up with the vertical.
+	vec3 up = globals.flat_surface != 0 ? vec3(1, 0, 0) : normalize(x);
+	vec3 weights = pow(abs(up), vec3(4));
+	weights /= weights.x + weights.y + weights.z;
+
+	vec4 weather = CLOUD_TEXTURE(cloud_weather, x, weights, CLOUD_WEATHER_SCALE);
+	vec4 coverage = CLOUD_TEXTURE(cloud_coverage, x, weights, CLOUD_COVERAGE_SCALE);
+
+	// Large scale weather makes some regions cloudier than others, and also sets how tall the
+	// clouds grow. Each channel is uniformly distributed, so thresholding against the coverage
+	// parameter controls what fraction of the sky is cloudy.
+	float c = mix(coverage.r * 0.6 + coverage.g * 0.4, weather.r, 0.3);
+	float density = clamp((c - (1 - clouds.coverage)) * 4, 0, 1);
+	float top = mix(0.5, 1.0, weather.g);
+	density *= smoothstep(0, 0.1, h) * (1 - smoothstep(top * 0.7, top, h));
+
+	if (detailed && density > 0) {
+		vec4 detail = CLOUD_TEXTURE(cloud_coverage, x, weights, CLOUD_DETAIL_SCALE);
+		density = clamp((density - (detail.b * 0.6 + detail.a * 0.4) * 0.4) / 0.6, 0, 1);
+	}
+	return density * clouds.density;
+}
+
+// Fraction of sunlight that passes through the cloud layer to reach a point given relative to the
+// camera.
+float cloud_shadow(vec3 position, vec3 sun_direction) {
+	if (clouds.enabled == 0)
+		return 1.0;
+
+	vec3 x = globals.camera + position;
+	vec2 segment = cloud_layer_segment(x, sun_direction);
+	if (segment.x >= segment.y)
+		return 1.0;
+
+	const int STEPS = 4;
+	float step_size = min(segment.y - segment.x, 20000.0) / STEPS;
+	float optical_depth = 0;
+	for (int i = 0; i < STEPS; i++) {
+		float t = segment.x + (i + 0.5) * step_size;
+		optical_depth += cloud_density(x + sun_direction * t, false) * step_size;
+	}
+	return exp(-optical_depth);
+}
@@ -6,6 +6,92 @@ struct Globals {
 	vec4 frustum_planes[5];
 	vec3 camera;
 	vec3 sun_direction;
+
+	// Depth pyramid from the previous frame, see hiz.glsl.
+	mat4 hiz_view_proj;
+	vec3 hiz_camera_offset;
+	uint hiz_levels;
+	uvec2 hiz_resolution;
+	uvec2 hiz_padding;
+
+	// One of the values of `DebugMode`.
+	uint debug_mode;
+
+	// Whether the terrain covers a flat surface (with +x pointing up) instead of a planet, and
+	// whether the sky and aerial perspective are being rendered.
+	uint flat_surface;
+	uint atmosphere;
+
+	// Radii of the planet and of the top of its atmosphere, in meters.
+	float planet_radius;
+	float atmosphere_radius;
+
+	// One of the values of `Tonemapping`.
+	uint tonemapping;
+
+	// Whether the shadow cascades have been rendered, see shadows.glsl.
+	uint shadows;
+};
+
+const uint DEBUG_MODE_NONE = 0;
+const uint DEBUG_MODE_LOD_LEVEL = 1;
+const uint DEBUG_MODE_NODE_BOUNDARIES = 2;
+const uint DEBUG_MODE_TEXTURE_MIP_LEVEL = 3;
+const uint DEBUG_MODE_TEXEL_DENSITY = 4;
+const uint DEBUG_MODE_WIREFRAME = 5;
+const uint DEBUG_MODE_ANCESTOR_FALLBACK = 6;
+
+const uint TONEMAPPING_NONE = 0;
+const uint TONEMAPPING_ACES = 1;
+const uint TONEMAPPING_REINHARD = 2;
+const uint TONEMAPPING_AGX = 3;
+
+const uint NUM_SHADOW_CASCADES = 4;
+
+struct ShadowCascade {
+	// Projection from positions relative to the camera into the cascade, with depth increasing
+	// away from the sun.
+	mat4 view_proj;
+	// Size of a texel of the cascade, in meters.
+	float texel_size;
+	float _padding0;
+	vec2 _padding1;
+};
+
+struct NightSky {
+	// Rotation from the equatorial coordinates of the star catalog to ecef.
+	mat4 equatorial_to_ecef;
+	// Rotation from ecef to the galactic coordinates that the Milky Way panorama is stored in.
+	mat4 ecef_to_galactic;
+	// Direction towards the moon, and its angular radius in radians.
+	vec4 moon;
+	// Size of a pixel in normalized device coordinates.
+	vec2 pixel_size;
+	uint num_stars;
+	uint _padding;
+};
+
+struct Clouds {
+	// Altitudes of the bottom and top of the cloud layer, in meters.
+	float min_altitude;
+	float max_altitude;
+	// Fraction of the sky covered by clouds, and extinction per meter where they are thickest.
+	float coverage;
+	float density;
+	// Whether there is a cloud layer at all.
+	uint enabled;
+	uint _padding0;
+	uvec2 _padding1;
+};
+
+const uint NUM_OCEAN_CASCADES = 3;
+struct Ocean {
+	// Camera position modulo the size of each wave cascade, followed by that size in meters.
+	vec4 cascades[NUM_OCEAN_CASCADES];
+	// Seconds since the wave simulation started, wrapped every `WAVE_PERIOD` in ocean/mod.rs.
+	float time;
+	float _padding0;
+	vec2 _padding1;
 };
 
 struct LayerDesc {
@@ -19,6 +105,8 @@ struct NodeState {
 	LayerDesc albedo;
 	LayerDesc roughness;
 	LayerDesc normals;
+	LayerDesc horizons;
+	LayerDesc water;
 	vec3 grass_canopy_origin;
 	float grass_canopy_step;
 	uint resolution;
@@ -28,10 +116,17 @@ struct NodeState {
 	vec3 relative_position;
 	float min_distance;
 	vec3 parent_relative_position;
-	float padding1;
-	vec4 padding2[4];
+	uint coarse_edges;
+	vec4 bounds;
+	uint ancestor_layers;
+	uint padding2;
+	uvec2 padding3;
 };
 
+// Descriptors for custom tile layers are stored in the `custom_layer_descs` buffer, at index
+// `node_index * MAX_CUSTOM_LAYERS + layer`. Layers without any tiles have a slot of -1.
+const uint MAX_CUSTOM_LAYERS = 16;
+
 struct Indirect {
     uint vertex_count;
     uint instance_count;
@@ -49,4 +144,17 @@ float extract_height_above_water(uint encoded) {
 		height = max(height, 0);
 	}
 	return height;
+}
+
+// Lakes and rivers have both the water bit and this one set, and store the height of the water
+// surface rather than the bottom. Flowing water also records the direction of the flow in the top
+// bits, in 64ths of a turn counterclockwise from the x axis of the heightmap.
+bool is_inland_water(uint encoded) {
+	return (encoded & 0x1000000) != 0;
+}
+vec2 extract_water_flow(uint encoded) {
+	if ((encoded & 0x2000000) == 0)
+		return vec2(0);
+	float angle = float(encoded >> 26) * (2 * 3.14159265 / 64);
+	return vec2(cos(angle), sin(angle));
 }
\ No newline at end of file
@@ -22,6 +22,16 @@ layout(set = 0, binding = 3) uniform sampler linear;
 layout(set = 0, binding = 4) uniform texture2DArray normals;
 layout(set = 0, binding = 5) uniform texture2DArray albedo;
 layout(set = 0, binding = 6) uniform texture2DArray roughness;
+layout(set = 0, binding = 7, std430) readonly buffer ExposureBlock {
+	float exposure;
+};
+layout(set = 0, binding = 8, std140) uniform ShadowBlock {
+	ShadowCascade shadow_cascades[NUM_SHADOW_CASCADES];
+};
+layout(set = 0, binding = 9) uniform texture2DArray shadow_map;
+layout(set = 0, binding = 10) uniform samplerShadow shadow;
+
+#include "shadows.glsl"
 
 
 layout(location = 0) in vec3 position;
@@ -43,6 +53,7 @@ void main() {
     // vec3 albedo_value = texture(sampler2DArray(albedo, linear), vec3(texcoord, node.nodes_slot)).xyz;
     // vec3 snormal = extract_normal(texture(sampler2DArray(normals, linear), vec3(texcoord, node.slot)).xy);
 	float roughness_value = 0.5;
+	float visibility = sun_visibility(position, normal);
 
 	out_color = vec4(1);
 	out_color.rgb = pbr(color,
@@ -50,16 +61,16 @@ void main() {
 						position,
 						normal,
 						globals.camera,
-						normalize(vec3(0.4, .7, 0.2)),
-						vec3(100000.0));
+						globals.sun_direction,
+						vec3(100000.0) * visibility);
 
 	out_color.rgb += pbr(color,
 						roughness_value,
 						position,
 						-normal,
 						globals.camera,
-						normalize(vec3(0.4, .7, 0.2)),
-						vec3(100000.0));
+						globals.sun_direction,
+						vec3(100000.0) * visibility);
 
 	// out_color.rgb = out_color.rgb * 0.3 + 0.7 * pbr(color,
 	// 					roughness_value,
@@ -69,7 +80,5 @@ void main() {
 	// 					normalize(vec3(0.4, .7, 0.2)),
 	// 					vec3(100000.0));
 
-   	float ev100 = 15.0;
-	float exposure = 1.0 / (pow(2.0, ev100) * 1.2);
-	out_color = tonemap(out_color, exposure, 2.2);
+	out_color.rgb *= exposure;
 }
\ No newline at end of file
@@ -58,7 +58,7 @@ void main() {
     Entry entry = grass_storage.entries[node.slot * 16 + gl_InstanceIndex % 16][entry_index];
     position = entry.position - node.relative_position;
 
-    vec3 up = normalize(position + globals.camera);
+    vec3 up = globals.flat_surface != 0 ? vec3(1, 0, 0) : normalize(position + globals.camera);
 	vec3 bitangent = normalize(cross(up, tangents[node.face]));
 	vec3 tangent = normalize(cross(up, bitangent));
 
@@ -0,0 +1,56 @@
+// Occlusion culling against the depth pyramid built from the previous frame. The including shader
+// must declare `globals` and the `hiz` texture.
+
+// Returns whether a sphere, given relative to the camera, is hidden behind geometry that was drawn
+// during the previous frame.
+bool hiz_occluded(vec3 center, float radius) {
+	if (globals.hiz_levels == 0)
+		return false;
+
+	// Move into the frame of reference of the camera used to build the pyramid.
+	center += globals.hiz_camera_offset;
+	if (dot(center, center) <= radius * radius)
+		return false;
+
+	vec2 ndc_min = vec2(1e30);
+	vec2 ndc_max = vec2(-1e30);
+	float nearest_depth = 0;
+	for (int i = 0; i < 8; i++) {
+		vec3 corner = center + radius * vec3((i & 1) != 0 ? 1 : -1,
+		                                     (i & 2) != 0 ? 1 : -1,
+		                                     (i & 4) != 0 ? 1 : -1);
+		vec4 p = globals.hiz_view_proj * vec4(corner, 1);
+		if (p.w <= 0)
+			return false;
+		p.xyz /= p.w;
+		ndc_min = min(ndc_min, p.xy);
+		ndc_max = max(ndc_max, p.xy);
+		nearest_depth = max(nearest_depth, p.z);
+	}
+	if (any(lessThan(ndc_max, vec2(-1))) || any(greaterThan(ndc_min, vec2(1))))
+		return false;
+
+	// Bounds in pixels of the depth buffer. Rows are stored from the top of the screen down.
+	vec2 resolution = vec2(globals.hiz_resolution);
+	vec2 pixel_min = clamp(vec2(ndc_min.x, -ndc_max.y) * 0.5 + 0.5, 0, 1) * resolution;
+	vec2 pixel_max = clamp(vec2(ndc_max.x, -ndc_min.y) * 0.5 + 0.5, 0, 1) * resolution;
+
+	// Texels of level `l` cover 2^(l+1) pixels, so pick the level where the bounds span at most
+	// two texels in each direction.
+	vec2 extent = max(pixel_max - pixel_min, vec2(1));
+	int level = clamp(int(ceil(log2(max(extent.x, extent.y)))) - 1, 0, int(globals.hiz_levels) - 1);
+
+	ivec2 size = textureSize(hiz, level);
+	ivec2 texel_min = min(ivec2(pixel_min) >> (level + 1), size - 1);
+	ivec2 texel_max = min(ivec2(min(pixel_max, resolution - 1)) >> (level + 1), size - 1);
+
+	float farthest_depth = 1;
+	for (int y = texel_min.y; y <= texel_max.y; y++) {
+		for (int x = texel_min.x; x <= texel_max.x; x++) {
+			farthest_depth = min(farthest_depth, texelFetch(hiz, ivec2(x, y), level).x);
+		}
+	}
+
+	// Depth is reversed, so nearer points have larger depths.
+	return nearest_depth < farthest_depth;
+}
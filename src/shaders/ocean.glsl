@@ -0,0 +1,33 @@
+// Waves from the ocean simulation. The including shader must declare `globals`, the `ocean`
+// uniform block, the `ocean_waves` texture and the `linear_wrap` sampler.
+
+// Returns the height of the waves at a point given relative to the camera, followed by the
+// gradient of that height in ecef coordinates. Each cascade is projected along every axis and
+// blended based on the direction `up`, and cascades fade out once they are too far away for their
+// waves to be resolved.
+vec4 ocean_waves_at(vec3 position, vec3 up) {
+	vec3 weights = pow(abs(up), vec3(8));
+	weights /= weights.x + weights.y + weights.z;
+
+	float distance = length(position);
+	float height = 0;
+	vec3 gradient = vec3(0);
+	for (uint i = 0; i < NUM_OCEAN_CASCADES; i++) {
+		float size = ocean.cascades[i].w;
+		float fade = 1 - smoothstep(size * 10, size * 20, distance);
+		if (fade <= 0)
+			continue;
+
+		// Texture channels hold the height and its slopes along the two texture axes.
+		vec3 p = (ocean.cascades[i].xyz + position) / size;
+		vec3 yz = textureLod(sampler2DArray(ocean_waves, linear_wrap), vec3(p.yz, i), 0).xyz;
+		vec3 zx = textureLod(sampler2DArray(ocean_waves, linear_wrap), vec3(p.zx, i), 0).xyz;
+		vec3 xy = textureLod(sampler2DArray(ocean_waves, linear_wrap), vec3(p.xy, i), 0).xyz;
+
+		height += fade * dot(vec3(yz.x, zx.x, xy.x), weights);
+		gradient += fade * (vec3(0, yz.y, yz.z) * weights.x
+			+ vec3(zx.z, 0, zx.y) * weights.y
+			+ vec3(xy.y, xy.z, 0) * weights.z);
+	}
+	return vec4(height, gradient);
+}
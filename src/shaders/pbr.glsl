@@ -20,25 +20,6 @@ const float PBR_WORKFLOW_SPECULAR_GLOSINESS = 1.0f;
 
 #define MANUAL_SRGB 1
 
-vec3 Uncharted2Tonemap(vec3 color)
-{
-	float A = 0.15;
-	float B = 0.50;
-	float C = 0.10;
-	float D = 0.20;
-	float E = 0.02;
-	float F = 0.30;
-	float W = 11.2;
-	return ((color*(A*color+C*B)+D*E)/(color*(A*color+B)+D*F))-E/F;
-}
-
-vec4 tonemap(vec4 color, float exposure, float gamma)
-{
-	vec3 outcol = Uncharted2Tonemap(color.rgb * exposure);
-	outcol = outcol * (1.0f / Uncharted2Tonemap(vec3(11.2f)));
-	return vec4(pow(outcol, vec3(1.0f / gamma)), color.a);
-}
-
 // vec4 SRGBtoLINEAR(vec4 srgbIn)
 // {
 // 	#ifdef MANUAL_SRGB
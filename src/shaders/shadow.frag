@@ -0,0 +1,4 @@
+#version 450 core
+
+// Shadow cascades only store depth, so there is nothing to output.
+void main() {}
@@ -0,0 +1,30 @@
+// Sun shadows from the cascaded shadow maps. The including shader must declare `globals`, the
+// `shadow_cascades` uniform block, the `shadow_map` texture and the `shadow` comparison sampler.
+
+// Returns the fraction of the sun that is visible from a point given relative to the camera.
+float sun_visibility(vec3 position, vec3 normal) {
+	if (globals.shadows == 0)
+		return 1.0;
+
+	// Use the first (and so most detailed) cascade that covers the point.
+	for (uint i = 0; i < NUM_SHADOW_CASCADES; i++) {
+		// Offsetting along the normal avoids self shadowing on surfaces facing away from the sun.
+		vec3 p = position + normal * shadow_cascades[i].texel_size * 1.5;
+		vec4 s = shadow_cascades[i].view_proj * vec4(p, 1);
+		vec2 uv = vec2(s.x, -s.y) * 0.5 + 0.5;
+		if (any(lessThan(uv, vec2(0.01))) || any(greaterThan(uv, vec2(0.99))) || s.z > 1)
+			continue;
+
+		// 3x3 percentage closer filtering, on top of the bilinear filtering done by the sampler.
+		vec2 texel = 1.0 / vec2(textureSize(shadow_map, 0).xy);
+		float visibility = 0;
+		for (int y = -1; y <= 1; y++) {
+			for (int x = -1; x <= 1; x++) {
+				visibility += texture(sampler2DArrayShadow(shadow_map, shadow),
+				                      vec4(uv + vec2(x, y) * texel, i, s.z));
+			}
+		}
+		return visibility / 9.0;
+	}
+	return 1.0;
+}
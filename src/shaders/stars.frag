@@ -0,0 +1,61 @@
+#version 450 core
+#include "declarations.glsl"
+
+layout(set = 0, binding = 0, std140) uniform UniformBlock {
+	Globals globals;
+};
+layout(set = 0, binding = 1, std140) uniform NightSkyBlock {
+	NightSky night_sky;
+};
+layout(set = 0, binding = 3) uniform texture2D transmittance;
+layout(set = 0, binding = 4) uniform sampler nearest;
+layout(set = 0, binding = 5, std430) readonly buffer ExposureBlock {
+	float exposure;
+};
+
+layout(location = 0) in vec3 direction;
+layout(location = 1) in vec2 uv;
+layout(location = 2) in vec3 color;
+layout(location = 3) flat in uint moon;
+
+layout(location = 0) out vec4 out_color;
+
+#define planetRadius globals.planet_radius
+#define atmosphereRadius globals.atmosphere_radius
+
+// Radiance of the fully lit part of the moon, which reflects 12% of the sunlight reaching it.
+const float MOON_RADIANCE = 100000.0 * 0.12 / 3.141592;
+
+vec2 rsi(vec3 r0, vec3 rd, float sr);
+vec3 precomputed_transmittance(float r, float mu);
+
+void main() {
+	vec3 radiance;
+	if (moon != 0) {
+		float r2 = dot(uv, uv);
+		if (r2 > 1)
+			discard;
+
+		// Light the visible hemisphere of the moon from the sun, which produces its phase.
+		vec3 center = night_sky.moon.xyz;
+		vec3 right = normalize(cross(center, abs(center.z) < 0.9 ? vec3(0, 0, 1) : vec3(1, 0, 0)));
+		vec3 up = cross(right, center);
+		vec3 normal = right * uv.x + up * uv.y - center * sqrt(1 - r2);
+		radiance = vec3(MOON_RADIANCE) * max(dot(normal, normalize(globals.sun_direction)), 0);
+	} else {
+		radiance = color * exp(-4 * dot(uv, uv));
+	}
+
+	if (globals.atmosphere != 0) {
+		vec3 r = normalize(direction);
+		vec2 p = rsi(globals.camera, r, atmosphereRadius);
+		if (p.x < p.y && p.y > 0.0) {
+			vec3 x0 = globals.camera + r * max(p.x, 0.0);
+			radiance *= precomputed_transmittance(length(x0), dot(normalize(x0), r));
+		}
+	}
+
+	out_color = vec4(radiance * exposure, 1);
+}
+
+#include "atmosphere.glsl"
@@ -0,0 +1,56 @@
+#version 450 core
+#include "declarations.glsl"
+
+layout(set = 0, binding = 0, std140) uniform UniformBlock {
+	Globals globals;
+};
+layout(set = 0, binding = 1, std140) uniform NightSkyBlock {
+	NightSky night_sky;
+};
+layout(set = 0, binding = 2) uniform texture2D stars;
+
+layout(location = 0) out vec3 direction;
+layout(location = 1) out vec2 uv;
+layout(location = 2) out vec3 color;
+layout(location = 3) flat out uint moon;
+
+// Radius of each star's sprite, in pixels.
+const float STAR_RADIUS = 1.5;
+
+// Radiance of a magnitude zero star.
+const float STAR_BRIGHTNESS = 1000.0;
+
+const vec2 corners[6] = vec2[6](
+	vec2(-1, -1), vec2(1, -1), vec2(-1, 1),
+	vec2(-1, 1), vec2(1, -1), vec2(1, 1));
+
+void main() {
+	uv = corners[gl_VertexIndex];
+
+	if (uint(gl_InstanceIndex) == night_sky.num_stars) {
+		// The moon is drawn as a square facing the camera that is just large enough to hold its
+		// disk, since it may cover many pixels.
+		moon = 1;
+		color = vec3(0);
+		vec3 center = night_sky.moon.xyz;
+		vec3 right = normalize(cross(center, abs(center.z) < 0.9 ? vec3(0, 0, 1) : vec3(1, 0, 0)));
+		vec3 up = cross(right, center);
+		direction = center + (right * uv.x + up * uv.y) * tan(night_sky.moon.w);
+		gl_Position = globals.view_proj * vec4(direction, 0);
+	} else {
+		moon = 0;
+		int texel = 2 * gl_InstanceIndex;
+		ivec2 position = ivec2(texel % textureSize(stars, 0).x, texel / textureSize(stars, 0).x);
+		vec4 star = texelFetch(stars, position, 0);
+		color = texelFetch(stars, position + ivec2(1, 0), 0).rgb
+			* STAR_BRIGHTNESS * pow(10.0, -0.4 * star.w);
+		direction = mat3(night_sky.equatorial_to_ecef) * star.xyz;
+
+		// Stars are far too small to resolve, so they are always drawn a few pixels across.
+		gl_Position = globals.view_proj * vec4(direction, 0);
+		gl_Position.xy += uv * night_sky.pixel_size * STAR_RADIUS * gl_Position.w;
+	}
+
+	// Place everything on the far plane, behind any terrain.
+	gl_Position.z = 0;
+}
@@ -19,6 +19,31 @@ layout(set = 0, binding = 6) uniform texture2DArray grass_canopy;
 layout(set = 0, binding = 7) uniform texture2DArray aerial_perspective;
 //layout(set = 0, binding = 8) uniform texture2DArray displacements;
 layout(set = 0, binding = 9) uniform sampler nearest;
+layout(set = 0, binding = 10, std430) readonly buffer ExposureBlock {
+	float exposure;
+};
+layout(set = 0, binding = 11, std140) uniform ShadowBlock {
+	ShadowCascade shadow_cascades[NUM_SHADOW_CASCADES];
+};
+layout(set = 0, binding = 12) uniform texture2DArray shadow_map;
+layout(set = 0, binding = 13) uniform samplerShadow shadow;
+layout(set = 0, binding = 14) uniform texture2DArray horizons;
+layout(set = 0, binding = 15, std140) uniform CloudsBlock {
+	Clouds clouds;
+};
+layout(set = 0, binding = 16) uniform sampler linear_wrap;
+layout(set = 0, binding = 17) uniform texture2D cloud_weather;
+layout(set = 0, binding = 18) uniform texture2D cloud_coverage;
+layout(set = 0, binding = 19, std140) uniform OceanBlock {
+	Ocean ocean;
+};
+layout(set = 0, binding = 20) uniform texture2DArray ocean_waves;
+layout(set = 0, binding = 21) uniform texture2D transmittance;
+layout(set = 0, binding = 22) uniform texture2DArray water;
+
+#include "shadows.glsl"
+#include "clouds.glsl"
+#include "ocean.glsl"
 
 layout(location = 0) in vec3 position;
 layout(location = 1) in vec2 texcoord;
@@ -28,9 +53,16 @@ layout(location = 4) in vec3 tangent;
 layout(location = 5) in vec3 bitangent;
 layout(location = 6) in vec2 i_position;
 layout(location = 7) flat in uint instance;
+layout(location = 8) in float water_depth;
 
 layout(location = 0) out vec4 out_color;
 
+#define planetRadius globals.planet_radius
+#define atmosphereRadius globals.atmosphere_radius
+
+vec2 rsi(vec3 r0, vec3 rd, float sr);
+vec3 atmosphere(vec3 r0, vec3 r1, vec3 pSun);
+
 float mipmap_level(in vec2 texture_coordinate)
 {
     vec2  dx_vtc        = dFdx(texture_coordinate);
@@ -39,171 +71,126 @@ float mipmap_level(in vec2 texture_coordinate)
     return 0.5 * log2(delta_max_sqr);
 }
 
-vec3 debug_overlay(vec3 color) {
-	// if((fract(0.5*position.x/32) < 0.5) != (fract(0.5*position.z/32) < 0.5))
-	// 	color = mix(color, vec3(0,0,1), 0.3);
-
-	// vec2 ts = vec2(520);//vec2(textureSize(normals, 0).xy);
-	// vec2 tc = normals_texcoord.xy;//;
-	// float ml = mipmap_level(tc * ts);
-
-	// vec3 pc = normalize((position+vec3(0,6371000.0,0)) / (position.y+6371000.0));
-	// vec3 cc = normalize((globals.camera.xyz+vec3(0,6371000.0,0)) / (globals.camera.y+6371000.0));
-	// if(distance(pc, cc) < min_distance && distance(pc, cc) > min_distance*0.9)
-	// 	color.rgb = mix(color.rgb, vec3(1,0,0), 0.3);
-
-	// vec3 level_color = vec3(0);
-	// if(level_resolution <= 256) level_color = vec3(1,0,0);
-	// else if(level_resolution <= 512) level_color = vec3(1,1,0);
-	// else if(level_resolution <= 1024) level_color = vec3(0,1,0);
-	// else if(level_resolution <= 2048) level_color = vec3(0,1,1);
-	// else if(level_resolution <= 4096) level_color = vec3(0,0,1);
-	// else if(level_resolution <= 8192) level_color = vec3(1,1,1);
-
-	// vec2 ip = vec2(1) - abs(vec2(1) - 2*tc);
-	// if(ip.x < 0.05 || ip.y < 0.05)
-	// 	color.rgb = mix(color.rgb, level_color, 0.4);
-	// else if (i_position.x / resolution > 0.97 || i_position.y / resolution > 0.97)
-	// 	color.rgb = mix(color.rgb, level_color, 0.2);
-
-	// ml = mipmap_level(normals_texcoord.xy*vec2(textureSize(normals,0).xy));
-	// vec3 overlay_color = vec3(0);
-	// if (ml < 0.0 /*&& side_length <= 16.0*/)
-	// 	overlay_color = vec3(0.4);
-	// else if (ml < -1.0)
-	// 	overlay_color = vec3(1,0,0);
-	// else if (ml < 0.0) // 1024
-	// 	overlay_color = vec3(0.5,0,0);
-	// else if (ml < 1.0) // 512
-	// 	overlay_color = vec3(0,0.2,0);
-	// else if (ml < 2.0) // 256
-	// 	overlay_color = vec3(0,0.4,0);
-	// else if (ml < 3.0) // 128
-	// 	overlay_color = vec3(0,0,.7);
-	// else               // 64
-	// 	overlay_color = vec3(0,0,.1);
-	// // overlay_color = mix(overlay_color, vec3(0), 0.3-0.3*fract(ml));
-	// color = mix(color, overlay_color, 0.9);
-
-	// if((fract(0.5*position.x/(4*1024*1024)) < 0.5) != (fract(0.5*position.z/(4*1024*1024)) < 0.5))
-	// 	color = mix(color, vec3(0,0,0), 0.2);
-
-	// if((fract(0.5*tc.x*ts.x/8) < 0.5) != (fract(0.5*tc.y*ts.y/8) < 0.5))
-	// 	color = mix(color, vec3(0,0,0), 0.2);
-
-	// if(length(position.xz) > 30000.0)
-	// 	color = mix(color, vec3(0), 0.3);
-	// if(length(position.xz) < 10000) {
-	// 	if((fract(0.5*position.x/1000) < 0.5) != (fract(0.5*position.z/1000) < 0.5))
-	// 		color = mix(color, vec3(0,0,0), 0.3);
-	// }
-
-	// if(abs(length(position.xz-globals.camera.xz) - 32*1024) < 100)
-	// 	color = vec3(1);
-
-	// if(abs(max(abs(position.x), abs(position.z)) - 2048*1.5) < 30)
-	// 	color = vec3(1);
+vec3 level_color(uint level) {
+	const vec3 colors[8] = vec3[8](
+		vec3(1, 0, 0),
+		vec3(1, 0.5, 0),
+		vec3(1, 1, 0),
+		vec3(0, 1, 0),
+		vec3(0, 1, 1),
+		vec3(0, 0, 1),
+		vec3(0.5, 0, 1),
+		vec3(1, 0, 1)
+	);
+	return colors[level % 8];
+}
 
-	// vec3 line_color = vec3(.5,0,0);
- 	// vec2 grid = abs(fract(i_position + 0.5) - 0.5) / fwidth(i_position);
-	// float line = min(grid.x, grid.y);
-	// color = mix(color, line_color, .3* smoothstep(1, 0, line));
+// Blue for t=0, through green, to red for t=1.
+vec3 heatmap(float t) {
+	t = clamp(t, 0, 1);
+	return t < 0.5 ? mix(vec3(0, 0, 1), vec3(0, 1, 0), t * 2) : mix(vec3(0, 1, 0), vec3(1, 0, 0), t * 2 - 1);
+}
 
-	// if (any(lessThan(0.5 - abs(0.5 - fract(position.xz / side_length)), vec2(0.01))))
-	// 	color = mix(color, vec3(0.1), 0.3);
+// One where `v` is an integer, fading to zero over about a pixel.
+float lines(float v) {
+	return 1 - smoothstep(0, 1, abs(fract(v + 0.5) - 0.5) / fwidth(v));
+}
+float grid_lines(vec2 v) {
+	return max(lines(v.x), lines(v.y));
+}
 
-	// if((fract(texcoord.x*ts.x/2) < 0.5) != (fract(texcoord.y*ts.y/2) < 0.5))
-	// 	color *= 0.4;
+vec3 debug_overlay(vec3 color, NodeState node, vec3 albedo_texcoord) {
+	if (globals.debug_mode == DEBUG_MODE_LOD_LEVEL) {
+		color = mix(color, level_color(node.level), 0.5);
+	} else if (globals.debug_mode == DEBUG_MODE_NODE_BOUNDARIES) {
+		color = mix(color, level_color(node.level), grid_lines(i_position / float(node.resolution)));
+	} else if (globals.debug_mode == DEBUG_MODE_TEXTURE_MIP_LEVEL) {
+		float level = textureQueryLod(sampler2DArray(albedo, linear), albedo_texcoord.xy).x;
+		color = mix(color, level_color(uint(level)), 0.5);
+	} else if (globals.debug_mode == DEBUG_MODE_TEXEL_DENSITY) {
+		// Log2 of the number of texels per pixel, so zero is an exact match.
+		float density = mipmap_level(albedo_texcoord.xy * vec2(textureSize(albedo, 0).xy));
+		color = mix(color, heatmap(density / 8 + 0.5), 0.7);
+	} else if (globals.debug_mode == DEBUG_MODE_WIREFRAME) {
+		// Triangles are split along the diagonal where i_position.x + i_position.y is an integer.
+		float line = max(grid_lines(i_position), lines(i_position.x + i_position.y));
+		color = mix(color, vec3(0), line);
+	} else if (globals.debug_mode == DEBUG_MODE_ANCESTOR_FALLBACK) {
+		if (node.ancestor_layers != 0)
+			color = mix(color, vec3(1, 0, 1), 0.5);
+	}
+	return color;
+}
 
-	// if(min_distance == 16*64.0*1.95)
-	// 	color = mix(color, vec3(0,1,0), .1);
-	// if(min_distance == 16*512.0*1.95)
-	// 	color = mix(color, vec3(1,0,0), .1);
-	// if(min_distance == 32*512.0*1.95)
-	// 	color = mix(color, vec3(0,0,1), .1);
+vec3 extract_normal(vec2 n) {
+	n = n * 2.0 - vec2(1.0);
+	float y = sqrt(max(1.0 - dot(n, n),0));
+	return normalize(vec3(n.x, y, n.y));
+}
 
-	// color = mix(color, vec3(1,1,1), .3 * fract(heights_origin.y / 40));
-	// color = mix(color, vec3(1,1,1), .3 * fract(position.y / 100-0.5));
+// Fraction of the sun that is above the horizon formed by distant terrain. The horizon map stores
+// the sine of the horizon elevation towards +x, +y, -x and -y, which are interpolated between
+// based on the azimuth of the sun.
+float horizon_visibility(NodeState node, vec3 sun_direction) {
+	if (node.horizons.origin.z < 0)
+		return 1.0;
+
+	vec3 texcoord3 = node.horizons.origin + vec3(texcoord * node.horizons._step, 0);
+	vec4 h = texture(sampler2DArray(horizons, linear), texcoord3);
+	if (node.horizons.parent_origin.z >= 0) {
+		vec3 parent_texcoord = node.horizons.parent_origin + vec3(texcoord * node.horizons.parent_step, 0);
+		h = mix(texture(sampler2DArray(horizons, linear), parent_texcoord), h, morph);
+	}
 
-	// vec2 v = vec2(i_position) / float(level_resolution) *2;
-	// // v = v * (1.4511 + (1 - 1.4511)*abs(v));
-	// // v = v * (1.4511 + (1 - 1.4511)*abs(v));
-	// // v = sign(v) * (1.4511 - sqrt(1.4511 * 1.4511 - 1.8044 * abs(v))) / 0.9022;
-	// // v = sign(v) * (1.4511 - sqrt(1.4511 * 1.4511 - 1.8044 * abs(v))) / 0.9022;
+	// Azimuth from the heightmap +x axis towards +y, which are opposite the tangent and bitangent.
+	float azimuth = atan(-dot(sun_direction, bitangent), -dot(sun_direction, tangent));
+	float sector = mod(azimuth / (0.5 * 3.14159265), 4.0);
+	int i = int(sector);
+	float horizon = mix(h[i], h[(i + 1) % 4], fract(sector));
 
-	// float s = 0;
-	// vec2 vv = fract((v*.25+0.25)*16);
-	// if (vv.x < 0.5 != vv.y < 0.5) {
-	// 	s += 0.1;
-	// }
-	// vv = fract((v*.25+0.25)*16*16);
-	// if (vv.x < 0.5 != vv.y < 0.5) {
-	// 	s += 0.05;
-	// }
-	// vv = fract((v*.25+0.25)*16*16*16);
-	// if (vv.x < 0.5 != vv.y < 0.5) {
-	// 	s += 0.05;
-	// }
+	float sun_elevation = dot(sun_direction, normalize(normal));
+	return smoothstep(horizon - 0.01, horizon + 0.01, sun_elevation);
+}
 
-	// if(node.face == 0) color = mix(color, vec3(1,0,0), 0.1);
-	// if(node.face == 1) color = mix(color, vec3(0,1,0), 0.1);
-	// if(node.face == 2) color = mix(color, vec3(0,0,1), 0.1);
-	// if(node.face == 3) color = mix(color, vec3(1,1,0), 0.1);
-	// if(node.face == 4) color = mix(color, vec3(1,1,1), 0.1);
-	// if(node.face == 5) color = mix(color, vec3(0,0,0), 0.1);
-
-	// if(node.level == 10)color = mix(color, vec3(1,0,0), .3); // 19m cell
-	// if(node.level == 11)color = mix(color, vec3(0,1,0), .3); // 10m
-	// if(node.level == 12)color = mix(color, vec3(0,0,1), .3); //  5m 
-	// if(node.level == 13)color = mix(color, vec3(0,1,1), .3); //  2m
-	// if(node.level == 14)color = mix(color, vec3(1,1,0), .3); //  1m
-	// if(node.level == 15)color = mix(color, vec3(1,0,1), .3); // 60cm
-	// if(node.level == 16)color = mix(color, vec3(1,1,1), .3); // 30cm
-	// if(node.level == 17)color = mix(color, vec3(1,0,0), .2); // 15cm
-	// if(node.level == 18)color = mix(color, vec3(0,1,0), .2); //  7cm
-	// if(node.level == 19)color = mix(color, vec3(0,0,1), .2); //  4cm / 30cm vertex / 19m tile
-	// if(node.level == 20)color = mix(color, vec3(0,0,0), .2); //  2cm / 30cm vertex / 10m tile
-
-
-	// if (int(level_resolution/2 + i_position.x / 64) % 2 !=
-	// 	int(level_resolution/2 + i_position.y / 64) % 2)
-	// 	color *= 0.5;
-
-	// if(resolution == 64)color = mix(color, vec3(0,0,1), .4);
-
-	// if(resolution == 32 && sin(gl_FragCoord.x) * sin(gl_FragCoord.y) < 0.4 )discard;
-	// if(face == 2) discard;
-
-	// if (level_resolution > 512*pow(2,4) || level_resolution == 512*pow(2,4) && morph > 0.99)
-	// 	color = mix(color, vec3(0,0,1), .4);
-	// if (abs(max(max(position.x, position.y), position.z)-1050) <10)
-	// if (abs(length(position)-1000) < 10)
-	//  	color = mix(color, vec3(0), 0.5);
-
-	// float line2 = abs(length(position) - 10) / fwidth(length(position)) * 0.5;
-	// color = mix(color, vec3(0.1), smoothstep(1, 0, line2));
-
-	// vec3 p = normalize(position + vec3(globals.camera));
-	// vec2 coord = vec2((acos(p.z) * 180.0 / 3.141592),
-	// 				  (atan(p.x, p.y)*180.0/3.141592));
-	// vec2 grid = .5*fract(coord) / fwidth(fract(coord));
-	// float line = min(grid.x, grid.y);
-	// if (coord.x > 90-60 && coord.x < 90+56)
-	// color = mix(color, vec3(0.), smoothstep(1, 0, line) * 0.6);
-
-	// if (face != 2) discard;
-
-	// if (node.resolution == 32)
-	// 	color *= 0.5;
-	// color = mix(color, vec3(0,0,0), (1-morph)*.4);
-
- 	return color;
+// Gradient of the waves on the surface of water. Lakes and rivers carry their waves along with the
+// flow by sampling them at a position that moves upstream over time. The offset is reset every few
+// seconds, so two copies half a period apart are crossfaded to hide the jump.
+vec3 water_gradient(NodeState node, vec3 up) {
+	vec3 gradient = ocean_waves_at(position, up).yzw;
+	if (node.water.origin.z < 0)
+		return gradient;
+
+	vec3 texcoord3 = node.water.origin + vec3(texcoord * node.water._step, 0);
+	vec4 w = texture(sampler2DArray(water, linear), texcoord3);
+	if (node.water.parent_origin.z >= 0) {
+		vec3 parent_texcoord = node.water.parent_origin + vec3(texcoord * node.water.parent_step, 0);
+		w = mix(texture(sampler2DArray(water, linear), parent_texcoord), w, morph);
+	}
+	if (w.b <= 0)
+		return gradient;
+
+	// The tangent and bitangent point along the negative x and y axes of the heightmap.
+	vec2 flow = w.rg * 2 - 1;
+	vec3 velocity = -(flow.x * tangent + flow.y * bitangent) * 1.5;
+
+	// Divides the ocean's wave period, so that the flow doesn't jump when the time wraps around.
+	const float period = 8.0;
+	float phase = fract(ocean.time / period);
+	vec3 a = ocean_waves_at(position - velocity * phase * period, up).yzw;
+	vec3 b = ocean_waves_at(position - velocity * fract(phase + 0.5) * period, up).yzw;
+	vec3 flowing = mix(a, b, abs(2 * phase - 1));
+	return mix(gradient, flowing, smoothstep(0.0, 0.5, w.b));
 }
 
-vec3 extract_normal(vec2 n) {
-	n = n * 2.0 - vec2(1.0);
-	float y = sqrt(max(1.0 - dot(n, n),0));
-	return normalize(vec3(n.x, y, n.y));
+// Radiance of the sky seen from a point in ecef coordinates, looking in direction `r`.
+vec3 sky_radiance(vec3 x, vec3 r) {
+	if (globals.atmosphere == 0)
+		return vec3(0);
+
+	vec2 p = rsi(x, r, atmosphereRadius);
+	if (p.x > p.y || p.y <= 0.0)
+		return vec3(0);
+	return atmosphere(x + r * max(p.x, 0.0), x + r * p.y, globals.sun_direction);
 }
 
 void main() {
@@ -216,7 +203,6 @@ void main() {
 	vec3 normals_texcoord = node.normals.origin + vec3(texcoord * node.normals._step, 0);
 	vec3 normals_parent_texcoord = node.normals.parent_origin + vec3(texcoord * node.normals.parent_step, 0);
 
-	vec3 light_direction = normalize(vec3(0.4, 0.7,0.2));
 	vec3 tex_normal = extract_normal(texture(sampler2DArray(normals, linear), normals_texcoord).xy);
 	if (node.normals.parent_origin.z >= 0) {
 		vec3 pn = extract_normal(texture(sampler2DArray(normals, linear), normals_parent_texcoord).xy);
@@ -244,6 +230,10 @@ void main() {
 	// 	}
 	// }
 
+	vec3 sunlight = vec3(100000.0) * sun_visibility(position, bent_normal)
+		* horizon_visibility(node, normalize(globals.sun_direction))
+		* cloud_shadow(position, normalize(globals.sun_direction));
+
 	out_color = vec4(1);
 	out_color.rgb = pbr(albedo_value,
 						roughness_value,
@@ -251,16 +241,40 @@ void main() {
 						bent_normal,
 						globals.camera,
 						globals.sun_direction,
-						vec3(100000.0));
+						sunlight);
+
+	// Cover water with the ocean surface, which reflects the sky and the sun. The terrain color
+	// beneath already includes the tint of the water, so it stands in for light coming up from
+	// below the surface. Both the waves and the reflections fade out as the water gets shallow.
+	if (water_depth > 0) {
+		vec3 up = normalize(normal);
+		vec3 gradient = water_gradient(node, up);
+		gradient -= up * dot(gradient, up);
+		vec3 water_normal = normalize(up - gradient * smoothstep(1, 3, water_depth));
+
+		vec3 v = normalize(-position);
+		vec3 r = reflect(-v, water_normal);
+		// Waves can tilt reflections below the horizon, but the sea would block those anyway.
+		r = normalize(r + up * max(0.01 - dot(r, up), 0));
+		float fresnel = 0.02 + 0.98 * pow(1 - max(dot(v, water_normal), 0), 5);
+
+		float coverage = smoothstep(1, 2, water_depth);
+		vec3 sun_specular = pbr(vec3(0), 0.1, position, water_normal, vec3(0),
+								globals.sun_direction, sunlight);
+		out_color.rgb = mix(out_color.rgb, sky_radiance(globals.camera + position, r), fresnel * coverage)
+			+ sun_specular * coverage;
+	}
 
-	vec4 ap = texture(sampler2DArray(aerial_perspective, linear),
-					  vec3((texcoord / 64.0 * 16 + 0.5) / 17, node.node_index));
-	out_color.rgb *= ap.a * 16.0;
-	out_color.rgb += ap.rgb * 16.0;
+	if (globals.atmosphere != 0) {
+		vec4 ap = texture(sampler2DArray(aerial_perspective, linear),
+						  vec3((texcoord / 64.0 * 16 + 0.5) / 17, node.node_index));
+		out_color.rgb *= ap.a * 16.0;
+		out_color.rgb += ap.rgb * 16.0;
+	}
 
-	float ev100 = 15.0;
-	float exposure = 1.0 / (pow(2.0, ev100) * 1.2);
-	out_color = tonemap(out_color, exposure, 2.2);
+	out_color.rgb *= exposure;
 
-	out_color.rgb = debug_overlay(out_color.rgb);
+	out_color.rgb = debug_overlay(out_color.rgb, node, albedo_texcoord);
 }
+
+#include "atmosphere.glsl"
@@ -8,6 +8,13 @@ layout(set = 0, binding = 1, std140) readonly buffer NodeBlock {
 	NodeState nodes[];
 };
 layout(set = 0, binding = 8) uniform texture2DArray displacements;
+layout(set = 0, binding = 16) uniform sampler linear_wrap;
+layout(set = 0, binding = 19, std140) uniform OceanBlock {
+	Ocean ocean;
+};
+layout(set = 0, binding = 20) uniform texture2DArray ocean_waves;
+
+#include "ocean.glsl"
 
 layout(location = 0) out vec3 out_position;
 layout(location = 1) out vec2 out_texcoord;
@@ -17,6 +24,7 @@ layout(location = 4) out vec3 out_tangent;
 layout(location = 5) out vec3 out_bitangent;
 layout(location = 6) out vec2 out_i_position;
 layout(location = 7) flat out uint out_instance;
+layout(location = 8) out float out_water;
 
 const vec3 tangents[6] = vec3[6](
 	vec3(0,1,0),
@@ -27,14 +35,14 @@ const vec3 tangents[6] = vec3[6](
 	vec3(-1,0,0)
 );
 
-vec3 sample_displacements(vec3 texcoord) {
+vec4 sample_displacements(vec3 texcoord) {
 	vec2 t = texcoord.xy * textureSize(displacements, 0).xy - 0.5;
 	vec2 f = fract(t);
 	vec4 w = vec4(f.x * (1-f.y), (1-f.x)*(1-f.y), (1-f.x)*f.y, f.x * f.y);
-	return texelFetch(displacements, ivec3(t, texcoord.z), 0).xyz* (1-f.x) * (1-f.y)
-		+ texelFetch(displacements, ivec3(t+ivec2(1,0), texcoord.z), 0).xyz * (f.x) * (1-f.y)
-		+ texelFetch(displacements, ivec3(t+ivec2(1,1), texcoord.z), 0).xyz * (f.x) * (f.y)
-		+ texelFetch(displacements, ivec3(t+ivec2(0,1), texcoord.z), 0).xyz * (1-f.x) * (f.y);
+	return texelFetch(displacements, ivec3(t, texcoord.z), 0) * (1-f.x) * (1-f.y)
+		+ texelFetch(displacements, ivec3(t+ivec2(1,0), texcoord.z), 0) * (f.x) * (1-f.y)
+		+ texelFetch(displacements, ivec3(t+ivec2(1,1), texcoord.z), 0) * (f.x) * (f.y)
+		+ texelFetch(displacements, ivec3(t+ivec2(0,1), texcoord.z), 0) * (1-f.x) * (f.y);
 }
 
 void main() {
@@ -44,27 +52,53 @@ void main() {
 							(gl_VertexIndex) / (node.resolution+1));
 
 	vec3 texcoord = node.displacements.origin + vec3(vec2(iPosition) * node.displacements._step, 0);
-	vec3 position = sample_displacements(texcoord) - node.relative_position;
+	vec4 displacement = sample_displacements(texcoord);
+	vec3 position = displacement.xyz - node.relative_position;
 
 	float morph = 1 - smoothstep(0.9, 1, length(position) / node.min_distance);
-	vec2 nPosition = mix(vec2((iPosition / 2) * 2), vec2(iPosition), morph);
+
+	// Vertices along edges shared with coarser terrain must match it exactly.
+	int resolution = int(node.resolution);
+	bvec4 on_edge = bvec4(iPosition.x == 0, iPosition.x == resolution,
+						  iPosition.y == 0, iPosition.y == resolution);
+	for (int i = 0; i < 4; i++) {
+		if (on_edge[i] && (node.coarse_edges & (1u << i)) != 0)
+			morph = 0;
+	}
+
+	// Odd vertices morph to the midpoint of the edge of the coarser grid that they lie on (which
+	// follows the diagonals of the index buffer). Unlike snapping to one end of the edge, this
+	// gives the same result regardless of the orientation of the face the node is on.
+	ivec2 odd = iPosition % 2;
+	ivec2 coarse_a = iPosition + ivec2(-odd.x, odd.y);
+	ivec2 coarse_b = iPosition + ivec2(odd.x, -odd.y);
+	vec2 nPosition = mix(vec2(coarse_a + coarse_b) * 0.5, vec2(iPosition), morph);
 
 	if (morph < 1.0) {
+		vec3 coarse_position;
 		if (node.displacements.parent_origin.z >= 0) {
-			vec3 ptexcoord = node.displacements.parent_origin + vec3(vec2((iPosition / 2) * 2) * node.displacements.parent_step, 0);
-			vec3 displacement = sample_displacements(ptexcoord) - node.parent_relative_position;
-			position = mix(displacement, position, morph);
+			vec3 a = node.displacements.parent_origin + vec3(vec2(coarse_a) * node.displacements.parent_step, 0);
+			vec3 b = node.displacements.parent_origin + vec3(vec2(coarse_b) * node.displacements.parent_step, 0);
+			coarse_position = 0.5 * (sample_displacements(a).xyz + sample_displacements(b).xyz) - node.parent_relative_position;
 		} else {
-			vec3 itexcoord = node.displacements.origin + vec3(vec2((iPosition / 2) * 2) * node.displacements._step, 0);
-			vec3 displacement = sample_displacements(itexcoord) - node.relative_position;
-			position = mix(displacement, position, morph);
+			vec3 a = node.displacements.origin + vec3(vec2(coarse_a) * node.displacements._step, 0);
+			vec3 b = node.displacements.origin + vec3(vec2(coarse_b) * node.displacements._step, 0);
+			coarse_position = 0.5 * (sample_displacements(a).xyz + sample_displacements(b).xyz) - node.relative_position;
 		}
+		position = mix(coarse_position, position, morph);
 	}
 
-	vec3 normal = normalize(position + globals.camera);
+	vec3 normal = globals.flat_surface != 0 ? vec3(1, 0, 0) : normalize(position + globals.camera);
 	vec3 bitangent = normalize(cross(normal, tangents[node.face]));
 	vec3 tangent = normalize(cross(normal, bitangent));
 
+	// The alpha channel of the displacements is one plus the depth of the water, or zero on land.
+	// Waves move the sea surface up and down, but die out as the water gets shallow.
+	float water = displacement.w;
+	if (water > 0) {
+		position += normal * ocean_waves_at(position, normal).x * smoothstep(1, 3, water);
+	}
+
 	out_position = position;
 	out_texcoord = nPosition;
 	out_morph = morph;
@@ -73,6 +107,7 @@ void main() {
 	out_bitangent = bitangent;
 	out_i_position = vec2(iPosition);
 	out_instance = gl_InstanceIndex;
+	out_water = water;
 
 	gl_Position = globals.view_proj * vec4(position, 1.0);
 }
@@ -0,0 +1,67 @@
+#version 450 core
+#include "declarations.glsl"
+
+layout(set = 0, binding = 0, std140) uniform UniformBlock {
+	Globals globals;
+};
+layout(set = 0, binding = 1) uniform texture2D hdr_color;
+
+layout(location = 0) out vec4 out_color;
+
+// Curve fit of the ACES reference rendering transform by Krzysztof Narkowicz.
+vec3 aces(vec3 x) {
+	x *= 0.6;
+	return clamp((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14), 0.0, 1.0);
+}
+
+vec3 reinhard(vec3 x) {
+	return x / (1.0 + x);
+}
+
+// Polynomial approximation of AgX by Benjamin Wrensch.
+vec3 agx(vec3 x) {
+	const mat3 inset = mat3(
+		0.842479062253094, 0.0423282422610123, 0.0423756549057051,
+		0.0784335999999992, 0.878468636469772, 0.0784336,
+		0.0792237451477643, 0.0791661274605434, 0.879142973793104);
+	const mat3 outset = mat3(
+		1.19687900512017, -0.0528968517574562, -0.0529716355144438,
+		-0.0980208811401368, 1.15190312990417, -0.0980434501171241,
+		-0.0990297440797205, -0.0989611768448433, 1.15107367264116);
+	const float min_ev = -12.47393;
+	const float max_ev = 4.026069;
+
+	x = clamp(log2(max(inset * x, 1e-10)), min_ev, max_ev);
+	x = (x - min_ev) / (max_ev - min_ev);
+
+	vec3 x2 = x * x;
+	vec3 x4 = x2 * x2;
+	x = 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x
+		- 0.00232;
+
+	// The curve produces display encoded values, so convert back to linear.
+	return pow(max(outset * x, 0.0), vec3(2.2));
+}
+
+vec3 linear_to_srgb(vec3 c) {
+	return mix(c * 12.92, 1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055, step(vec3(0.0031308), c));
+}
+
+void main() {
+	// Colors are already scaled by the exposure when they are rendered.
+	vec3 color = texelFetch(hdr_color, ivec2(gl_FragCoord.xy), 0).rgb;
+
+	if (globals.tonemapping == TONEMAPPING_ACES)
+		color = aces(color);
+	else if (globals.tonemapping == TONEMAPPING_REINHARD)
+		color = reinhard(color);
+	else if (globals.tonemapping == TONEMAPPING_AGX)
+		color = agx(color);
+
+	// Targets with an sRGB format do the encoding themselves, and float targets receive linear
+	// colors.
+#if ENCODE_SRGB == 1
+	color = linear_to_srgb(clamp(color, 0.0, 1.0));
+#endif
+	out_color = vec4(color, 1.0);
+}
@@ -0,0 +1,188 @@
+use crate::gpu_state::GpuState;
+use crate::RenderTargets;
+use anyhow::Error;
+use std::collections::HashMap;
+
+/// A layer of volumetric clouds between two altitudes.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CloudLayer {
+    /// Altitude of the bottom of the layer above the planet's surface, in meters.
+    pub min_altitude: f32,
+    /// Altitude of the top of the layer, in meters. Must be above `min_altitude`.
+    pub max_altitude: f32,
+    /// Fraction of the sky covered by clouds, from 0 (clear) to 1 (overcast).
+    pub coverage: f32,
+    /// Extinction coefficient in the thickest parts of the clouds, per meter.
+    pub density: f32,
+}
+impl Default for CloudLayer {
+    fn default() -> Self {
+        Self { min_altitude: 1500.0, max_altitude: 4000.0, coverage: 0.5, density: 0.02 }
+    }
+}
+
+/// Uniforms for the cloud layer, see `Clouds` in declarations.glsl.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub(crate) struct CloudUniforms {
+    min_altitude: f32,
+    max_altitude: f32,
+    coverage: f32,
+    density: f32,
+    enabled: u32,
+    _padding: [u32; 3],
+}
+unsafe impl bytemuck::Pod for CloudUniforms {}
+unsafe impl bytemuck::Zeroable for CloudUniforms {}
+
+/// Raymarches the cloud layer over the scene once the depth pyramid has been built, and provides
+/// the uniforms the terrain shader uses to compute cloud shadows.
+pub(crate) struct Clouds {
+    layer: Option<CloudLayer>,
+    shader: rshader::ShaderSet,
+    bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::RenderPipeline)>,
+}
+impl Clouds {
+    pub fn new() -> Result<Self, Error> {
+        Ok(Self {
+            layer: None,
+            shader: rshader::ShaderSet::simple(
+                rshader::shader_source!("../shaders", "sky.vert", "declarations.glsl"),
+                rshader::shader_source!(
+                    "../shaders",
+                    "clouds.frag",
+                    "declarations.glsl",
+                    "atmosphere.glsl",
+                    "clouds.glsl"
+                ),
+            )?,
+            bindgroup_pipeline: None,
+        })
+    }
+
+    pub fn layer(&self) -> Option<CloudLayer> {
+        self.layer
+    }
+
+    pub fn set_layer(&mut self, layer: Option<CloudLayer>) {
+        self.layer = layer;
+    }
+
+    /// Forget the bind group, which must be done whenever the depth pyramid is replaced.
+    pub fn reset_bindings(&mut self) {
+        self.bindgroup_pipeline = None;
+    }
+
+    /// Write the uniforms for the current frame and create the pipeline if necessary. Must be
+    /// called before `render`, even when there is no cloud layer.
+    pub fn prepare(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        gpu_state: &GpuState,
+        targets: &RenderTargets,
+    ) {
+        let uniforms = match self.layer {
+            Some(layer) => CloudUniforms {
+                min_altitude: layer.min_altitude,
+                max_altitude: layer.max_altitude.max(layer.min_altitude + 1.0),
+                coverage: layer.coverage.max(0.0).min(1.0),
+                density: layer.density.max(0.0),
+                enabled: 1,
+                _padding: [0; 3],
+            },
+            None => bytemuck::Zeroable::zeroed(),
+        };
+        queue.write_buffer(&gpu_state.clouds, 0, bytemuck::bytes_of(&uniforms));
+
+        if self.shader.refresh() {
+            self.bindgroup_pipeline = None;
+        }
+        if self.layer.is_some() && self.bindgroup_pipeline.is_none() {
+            let (bind_group, bind_group_layout) = gpu_state.bind_group_for_shader(
+                device,
+                &self.shader,
+                HashMap::new(),
+                HashMap::new(),
+                "clouds",
+            );
+            let render_pipeline_layout =
+                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    bind_group_layouts: &[&bind_group_layout],
+                    push_constant_ranges: &[],
+                    label: Some("pipeline.clouds.layout"),
+                });
+            self.bindgroup_pipeline = Some((
+                bind_group,
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    layout: Some(&render_pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                            label: Some("shader.clouds.vertex"),
+                            source: wgpu::ShaderSource::SpirV(self.shader.vertex().into()),
+                            flags: wgpu::ShaderFlags::VALIDATION,
+                        }),
+                        entry_point: "main",
+                        buffers: &[],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                            label: Some("shader.clouds.fragment"),
+                            source: wgpu::ShaderSource::SpirV(self.shader.fragment().into()),
+                            flags: wgpu::ShaderFlags::VALIDATION,
+                        }),
+                        entry_point: "main",
+                        // The shader outputs the light scattered towards the camera along with
+                        // the fraction of the scene behind the clouds that remains visible.
+                        targets: &[wgpu::ColorTargetState {
+                            blend: Some(wgpu::BlendState {
+                                color: wgpu::BlendComponent {
+                                    src_factor: wgpu::BlendFactor::One,
+                                    dst_factor: wgpu::BlendFactor::SrcAlpha,
+                                    operation: wgpu::BlendOperation::Add,
+                                },
+                                alpha: wgpu::BlendComponent {
+                                    src_factor: wgpu::BlendFactor::Zero,
+                                    dst_factor: wgpu::BlendFactor::One,
+                                    operation: wgpu::BlendOperation::Add,
+                                },
+                            }),
+                            ..targets.hdr_target()
+                        }],
+                    }),
+                    primitive: Default::default(),
+                    depth_stencil: None,
+                    multisample: targets.multisample(),
+                    label: Some("pipeline.clouds"),
+                }),
+            ));
+        }
+    }
+
+    /// Draw the cloud layer over the HDR target, if there is one. The depth pyramid must already
+    /// have been built from the current frame's depth buffer.
+    pub fn render(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        hdr_view: &wgpu::TextureView,
+        hdr_resolve_target: Option<&wgpu::TextureView>,
+    ) {
+        if self.layer.is_none() {
+            return;
+        }
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view: hdr_view,
+                resolve_target: hdr_resolve_target,
+                ops: wgpu::Operations { load: wgpu::LoadOp::Load, store: true },
+            }],
+            depth_stencil_attachment: None,
+            label: Some("renderpass.clouds"),
+        });
+        let (bind_group, pipeline) = self.bindgroup_pipeline.as_ref().unwrap();
+        rpass.set_pipeline(pipeline);
+        rpass.set_bind_group(0, bind_group, &[]);
+        rpass.draw(0..3, 0..1);
+    }
+}
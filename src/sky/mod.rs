@@ -1,9 +1,12 @@
 use crate::asset::AssetLoadContext;
+use crate::coordinates::PlanetDesc;
 use crate::sky::lut::{LookupTable, LookupTableDefinition};
-use crate::sky::precompute::{InscatteringTable, TransmittanceTable};
+use crate::sky::precompute::{InscatteringTable, Radii, TransmittanceTable};
 use anyhow::Error;
 
+pub(crate) mod clouds;
 mod lut;
+pub(crate) mod night;
 mod precompute;
 
 pub(crate) struct Atmosphere {
@@ -11,11 +14,24 @@ pub(crate) struct Atmosphere {
     pub inscattering: LookupTable,
 }
 impl Atmosphere {
-    pub fn new(context: &mut AssetLoadContext) -> Result<Self, Error> {
-        let transmittance = TransmittanceTable { steps: 1000 }.generate(context)?;
-        let inscattering =
-            InscatteringTable { steps: 30, transmittance: &transmittance }.generate(context)?;
+    pub fn new(planet: &PlanetDesc, context: &mut AssetLoadContext) -> Result<Self, Error> {
+        let radii = Radii { Rg: planet.radius, Rt: planet.atmosphere_radius() };
+        let transmittance = TransmittanceTable { steps: 1000, radii }.generate(context)?;
+        let inscattering = InscatteringTable { steps: 30, radii, transmittance: &transmittance }
+            .generate(context)?;
 
         Ok(Self { transmittance, inscattering })
     }
 }
+
+/// Name of the map file texture holding lookup table `base` for the atmosphere of `planet`. The
+/// earth's tables keep their plain names so that existing map files stay valid.
+pub(crate) fn table_name(base: &str, planet: &PlanetDesc) -> String {
+    if planet.radius == PlanetDesc::EARTH.radius
+        && planet.atmosphere_height == PlanetDesc::EARTH.atmosphere_height
+    {
+        base.to_owned()
+    } else {
+        format!("{}-{}-{}", base, planet.radius, planet.atmosphere_height)
+    }
+}
@@ -0,0 +1,313 @@
+use crate::asset::{AssetLoadContext, WebAsset};
+use crate::cache::TextureFormat;
+use crate::coordinates;
+use crate::gpu_state::GpuState;
+use crate::mapfile::{MapFile, TextureDescriptor};
+use crate::RenderTargets;
+use anyhow::Error;
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use cgmath::{InnerSpace, Matrix, Matrix3, Matrix4, Vector3};
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+/// Number of texels in each row of the `stars` texture. Every star takes up two adjacent texels:
+/// its direction in equatorial coordinates along with its visual magnitude, followed by its color.
+const STARS_TEXTURE_WIDTH: u32 = 256;
+
+/// Mean radius of the moon, in meters.
+const MOON_RADIUS: f64 = 1737400.0;
+
+/// Uniforms shared by the sky and star shaders, see `NightSky` in declarations.glsl.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub(crate) struct NightSkyUniforms {
+    equatorial_to_ecef: [[f32; 4]; 4],
+    ecef_to_galactic: [[f32; 4]; 4],
+    moon: [f32; 4],
+    pixel_size: [f32; 2],
+    num_stars: u32,
+    _padding: u32,
+}
+unsafe impl bytemuck::Pod for NightSkyUniforms {}
+unsafe impl bytemuck::Zeroable for NightSkyUniforms {}
+
+/// The Yale Bright Star Catalog, which lists every star visible to the naked eye. It is converted
+/// into the layout of the `stars` texture.
+pub(crate) struct StarCatalog;
+impl WebAsset for StarCatalog {
+    type Type = (TextureDescriptor, Vec<u8>);
+
+    fn url(&self) -> String {
+        "http://tdc-www.harvard.edu/catalogs/BSC5".to_owned()
+    }
+    fn filename(&self) -> String {
+        "BSC5".to_owned()
+    }
+    fn parse(&self, _context: &mut AssetLoadContext, data: Vec<u8>) -> Result<Self::Type, Error> {
+        anyhow::ensure!(data.len() >= 28, "star catalog is truncated");
+
+        // The catalog is distributed in both byte orders, so pick whichever one gives the expected
+        // number of bytes per entry.
+        let big_endian = LittleEndian::read_i32(&data[24..]) != 32;
+        let read_i32 =
+            |b: &[u8]| if big_endian { BigEndian::read_i32(b) } else { LittleEndian::read_i32(b) };
+        let read_i16 =
+            |b: &[u8]| if big_endian { BigEndian::read_i16(b) } else { LittleEndian::read_i16(b) };
+        let read_f64 =
+            |b: &[u8]| if big_endian { BigEndian::read_f64(b) } else { LittleEndian::read_f64(b) };
+
+        anyhow::ensure!(read_i32(&data[24..]) == 32, "unrecognized star catalog format");
+        let entries = read_i32(&data[8..]).abs() as usize;
+        anyhow::ensure!(data.len() >= 28 + 32 * entries, "star catalog is truncated");
+
+        let mut texels = Vec::new();
+        for entry in data[28..].chunks_exact(32).take(entries) {
+            let right_ascension = read_f64(&entry[4..]);
+            let declination = read_f64(&entry[12..]);
+            let magnitude = read_i16(&entry[22..]) as f32 * 0.01;
+
+            // Entries for objects that have been removed from the catalog have no position.
+            if right_ascension == 0.0 && declination == 0.0 {
+                continue;
+            }
+
+            // Approximate linear color for each spectral class.
+            let color = match entry[20] {
+                b'O' => [0.33, 0.50, 1.0],
+                b'B' => [0.46, 0.61, 1.0],
+                b'A' => [0.72, 0.77, 1.0],
+                b'F' => [1.0, 0.92, 0.85],
+                b'G' => [1.0, 0.81, 0.56],
+                b'K' => [1.0, 0.61, 0.30],
+                b'M' => [1.0, 0.42, 0.20],
+                _ => [1.0, 1.0, 1.0],
+            };
+
+            texels.push([
+                (declination.cos() * right_ascension.cos()) as f32,
+                (declination.cos() * right_ascension.sin()) as f32,
+                declination.sin() as f32,
+                magnitude,
+            ]);
+            texels.push([color[0], color[1], color[2], 0.0]);
+        }
+
+        // Pad out the last row with stars that have no color, and so are never visible.
+        let height = (texels.len() as u32 + STARS_TEXTURE_WIDTH - 1) / STARS_TEXTURE_WIDTH;
+        texels.resize((STARS_TEXTURE_WIDTH * height) as usize, [0.0; 4]);
+
+        let data: Vec<u8> = bytemuck::cast_slice(&texels).to_vec();
+        Ok((
+            TextureDescriptor {
+                width: STARS_TEXTURE_WIDTH,
+                height,
+                depth: 1,
+                format: TextureFormat::RGBA32F,
+                bytes: data.len(),
+            },
+            data,
+        ))
+    }
+}
+
+/// Draws the stars and the moon behind the atmosphere, and orients the Milky Way panorama sampled
+/// by the sky shader.
+pub(crate) struct NightSky {
+    shader: rshader::ShaderSet,
+    bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::RenderPipeline)>,
+    num_stars: u32,
+
+    equatorial_to_ecef: Matrix3<f64>,
+    moon_position: (Vector3<f64>, f64),
+}
+impl NightSky {
+    /// Create the night sky, with the stars and moon positioned for `time`.
+    pub fn new(mapfile: &MapFile, time: SystemTime) -> Result<Self, Error> {
+        let stars = mapfile
+            .lookup_texture("stars")?
+            .ok_or_else(|| anyhow::anyhow!("star catalog texture is missing"))?;
+
+        Ok(Self {
+            shader: rshader::ShaderSet::simple(
+                rshader::shader_source!("../shaders", "stars.vert", "declarations.glsl"),
+                rshader::shader_source!(
+                    "../shaders",
+                    "stars.frag",
+                    "declarations.glsl",
+                    "atmosphere.glsl"
+                ),
+            )?,
+            bindgroup_pipeline: None,
+            num_stars: stars.width * stars.height / 2,
+            equatorial_to_ecef: coordinates::equatorial_to_ecef(time),
+            moon_position: coordinates::moon_position(time),
+        })
+    }
+
+    /// Rotate the stars and move the moon to where they are at the given time.
+    pub fn set_time(&mut self, utc: SystemTime) {
+        self.equatorial_to_ecef = coordinates::equatorial_to_ecef(utc);
+        self.moon_position = coordinates::moon_position(utc);
+    }
+
+    /// Write the uniforms for the current frame and create the pipeline if necessary. Must be
+    /// called before `render`.
+    pub fn prepare(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        gpu_state: &GpuState,
+        targets: &RenderTargets,
+        frame_size: (u32, u32),
+    ) {
+        // Rotation from equatorial to galactic coordinates, with rows written out in order.
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        let equatorial_to_galactic = Matrix3::new(
+            -0.0548755604, -0.8734370902, -0.4838350155,
+            0.4941094279, -0.4448296300, 0.7469822445,
+            -0.8676661490, -0.1980763734, 0.4559837762,
+        )
+        .transpose();
+        let ecef_to_galactic = equatorial_to_galactic * self.equatorial_to_ecef.transpose();
+
+        let (moon_direction, moon_distance) = self.moon_position;
+        let moon_direction = moon_direction.normalize();
+        let uniforms = NightSkyUniforms {
+            equatorial_to_ecef: Matrix4::from(self.equatorial_to_ecef).cast().unwrap().into(),
+            ecef_to_galactic: Matrix4::from(ecef_to_galactic).cast().unwrap().into(),
+            moon: moon_direction
+                .extend((MOON_RADIUS / moon_distance).asin())
+                .cast()
+                .unwrap()
+                .into(),
+            pixel_size: [2.0 / frame_size.0 as f32, 2.0 / frame_size.1 as f32],
+            num_stars: self.num_stars,
+            _padding: 0,
+        };
+        queue.write_buffer(&gpu_state.night_sky, 0, bytemuck::bytes_of(&uniforms));
+
+        if self.shader.refresh() {
+            self.bindgroup_pipeline = None;
+        }
+        if self.bindgroup_pipeline.is_none() {
+            let (bind_group, bind_group_layout) = gpu_state.bind_group_for_shader(
+                device,
+                &self.shader,
+                HashMap::new(),
+                HashMap::new(),
+                "stars",
+            );
+            let render_pipeline_layout =
+                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    bind_group_layouts: &[&bind_group_layout],
+                    push_constant_ranges: &[],
+                    label: Some("pipeline.stars.layout"),
+                });
+            self.bindgroup_pipeline = Some((
+                bind_group,
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    layout: Some(&render_pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                            label: Some("shader.stars.vertex"),
+                            source: wgpu::ShaderSource::SpirV(self.shader.vertex().into()),
+                            flags: wgpu::ShaderFlags::VALIDATION,
+                        }),
+                        entry_point: "main",
+                        buffers: &[],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                            label: Some("shader.stars.fragment"),
+                            source: wgpu::ShaderSource::SpirV(self.shader.fragment().into()),
+                            flags: wgpu::ShaderFlags::VALIDATION,
+                        }),
+                        entry_point: "main",
+                        // Stars and the moon are added on top of the light scattered by the
+                        // atmosphere, which drowns them out during the day.
+                        targets: &[wgpu::ColorTargetState {
+                            blend: Some(wgpu::BlendState {
+                                color: wgpu::BlendComponent {
+                                    src_factor: wgpu::BlendFactor::One,
+                                    dst_factor: wgpu::BlendFactor::One,
+                                    operation: wgpu::BlendOperation::Add,
+                                },
+                                alpha: wgpu::BlendComponent::REPLACE,
+                            }),
+                            ..targets.hdr_target()
+                        }],
+                    }),
+                    primitive: Default::default(),
+                    depth_stencil: Some(
+                        targets.depth_stencil(false, wgpu::CompareFunction::GreaterEqual),
+                    ),
+                    multisample: targets.multisample(),
+                    label: Some("pipeline.stars"),
+                }),
+            ));
+        }
+    }
+
+    /// Draw every star, followed by the moon.
+    pub fn render<'a>(&'a self, rpass: &mut wgpu::RenderPass<'a>) {
+        let (bind_group, pipeline) = self.bindgroup_pipeline.as_ref().unwrap();
+        rpass.set_pipeline(pipeline);
+        rpass.set_bind_group(0, bind_group, &[]);
+        rpass.draw(0..6, 0..self.num_stars + 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asset::AssetLoadContextBuf;
+    use approx::assert_relative_eq;
+    use std::array::IntoIter;
+
+    /// Builds a catalog in the BSC5 format with the given right ascensions, declinations, spectral
+    /// classes and magnitudes.
+    fn catalog<B: ByteOrder>(stars: &[(f64, f64, u8, i16)]) -> Vec<u8> {
+        let mut data = vec![0; 28 + 32 * stars.len()];
+        B::write_i32(&mut data[8..], -(stars.len() as i32));
+        B::write_i32(&mut data[24..], 32);
+        for (entry, &(ra, dec, class, magnitude)) in data[28..].chunks_exact_mut(32).zip(stars) {
+            B::write_f64(&mut entry[4..], ra);
+            B::write_f64(&mut entry[12..], dec);
+            entry[20] = class;
+            B::write_i16(&mut entry[22..], magnitude);
+        }
+        data
+    }
+
+    fn parse(data: Vec<u8>) -> Result<(TextureDescriptor, Vec<[f32; 4]>), Error> {
+        let mut context = AssetLoadContextBuf::new();
+        let mut context = context.context("parsing star catalog", 0);
+        let (desc, data) = StarCatalog.parse(&mut context, data)?;
+        Ok((desc, bytemuck::cast_slice(&data).to_vec()))
+    }
+
+    #[test]
+    fn parse_star_catalog() {
+        let stars = [(1.0, 0.5, b'G', 150), (4.0, -1.2, b'B', -146)];
+        for data in IntoIter::new([catalog::<LittleEndian>(&stars), catalog::<BigEndian>(&stars)]) {
+            let (desc, texels) = parse(data).unwrap();
+            assert_eq!((desc.width, desc.height), (STARS_TEXTURE_WIDTH, 1));
+            assert_eq!(desc.bytes, STARS_TEXTURE_WIDTH as usize * 16);
+
+            for (i, &(ra, dec, _, magnitude)) in stars.iter().enumerate() {
+                let [x, y, z, m] = texels[i * 2];
+                assert_relative_eq!(x, (f64::cos(dec) * f64::cos(ra)) as f32);
+                assert_relative_eq!(y, (f64::cos(dec) * f64::sin(ra)) as f32);
+                assert_relative_eq!(z, f64::sin(dec) as f32);
+                assert_relative_eq!(m, magnitude as f32 * 0.01);
+            }
+            assert_eq!(texels[1], [1.0, 0.81, 0.56, 0.0]);
+            assert_eq!(texels[3], [0.46, 0.61, 1.0, 0.0]);
+            assert!(texels[4..].iter().all(|t| *t == [0.0; 4]));
+        }
+
+        let mut truncated = catalog::<LittleEndian>(&stars);
+        truncated.truncate(28 + 32);
+        assert!(parse(truncated).is_err());
+    }
+}
@@ -1,5 +1,5 @@
 use crate::cache::LayerType;
-use crate::generate::heightmap::HeightmapCache;
+use crate::generate::heightmap::{HeightmapCache, HeightmapTile};
 use crate::mapfile::MapFile;
 use crate::terrain::quadtree::node::VNode;
 use anyhow::Error;
@@ -10,17 +10,18 @@ use std::thread;
 use tokio::runtime::Runtime;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 
-#[derive(Copy, Clone, Debug)]
-struct TileRequest {
-    node: VNode,
-    layer: LayerType,
+#[derive(Debug)]
+enum TileRequest {
+    Read { node: VNode, layer: LayerType },
+    Write { node: VNode, layer: LayerType, data: Vec<u8> },
 }
 
 #[derive(Debug)]
 pub(crate) enum TileResult {
-    Heightmaps(VNode, Arc<Vec<i16>>),
+    Heightmaps(VNode, Arc<HeightmapTile>),
     Albedo(VNode, Vec<u8>),
     Roughness(VNode, Vec<u8>),
+    Generated(VNode, LayerType, Vec<u8>),
 }
 impl TileResult {
     pub fn layer(&self) -> LayerType {
@@ -28,13 +29,15 @@ impl TileResult {
             TileResult::Heightmaps(..) => LayerType::Heightmaps,
             TileResult::Albedo(..) => LayerType::Albedo,
             TileResult::Roughness(..) => LayerType::Roughness,
+            TileResult::Generated(_, layer, _) => *layer,
         }
     }
     pub fn node(&self) -> VNode {
         match self {
             TileResult::Heightmaps(node, ..)
             | TileResult::Albedo(node, ..)
-            | TileResult::Roughness(node, ..) => *node,
+            | TileResult::Roughness(node, ..)
+            | TileResult::Generated(node, ..) => *node,
         }
     }
 }
@@ -71,13 +74,22 @@ impl TileStreamerEndpoint {
     }
 
     pub(crate) fn request_tile(&mut self, node: VNode, layer: LayerType) {
-        if let Err(_) = self.sender.send(TileRequest { node, layer }) {
+        self.send(TileRequest::Read { node, layer });
+        self.num_inflight += 1;
+    }
+
+    /// Write a generated tile to disk so that it can be streamed in later.
+    pub(crate) fn write_tile(&mut self, node: VNode, layer: LayerType, data: Vec<u8>) {
+        self.send(TileRequest::Write { node, layer, data });
+    }
+
+    fn send(&mut self, request: TileRequest) {
+        if let Err(_) = self.sender.send(request) {
             // The worker thread has panicked (we still have the sender open, so that cannot be why
             // it exited). Join it to see what the panic message was.
             self.join_handle.take().unwrap().join().unwrap().expect("TileStreamer panicked");
             unreachable!("TileStreamer exited without panicking");
         }
-        self.num_inflight += 1;
     }
 
     pub(crate) fn try_complete(&mut self) -> Option<TileResult> {
@@ -103,37 +115,50 @@ struct TileStreamer {
 
 impl TileStreamer {
     async fn run(self) -> Result<(), Error> {
-        let TileStreamer { mut requests, results, mapfile, mut heightmap_tiles } = self;
-        let mapfile = &*mapfile;
+        let TileStreamer { mut requests, results, mapfile: shared_mapfile, mut heightmap_tiles } =
+            self;
+        let mapfile = &*shared_mapfile;
 
         let mut pending = futures::stream::futures_unordered::FuturesUnordered::new();
+        let mut pending_writes = futures::stream::futures_unordered::FuturesUnordered::new();
         loop {
             futures::select! {
-                request = requests.recv().fuse() => if let Some(request) = request {
-                    match request.layer {
+                request = requests.recv().fuse() => match request {
+                    Some(TileRequest::Read { node, layer }) => match layer {
                         LayerType::Heightmaps => {
-                            let fut = heightmap_tiles.get_tile(mapfile, request.node);
+                            let fut = heightmap_tiles.get_tile(mapfile, node);
 
                             pending.push(async move {
-                                Ok(TileResult::Heightmaps(request.node, fut.await?))
+                                Ok(TileResult::Heightmaps(node, fut.await?))
                             }.boxed());
                         }
                         LayerType::Albedo => pending.push(async move {
-                            let raw_data = mapfile.read_tile(request.layer, request.node).await?;
+                            let raw_data = mapfile.read_tile(layer, node).await?;
                             let data = tokio::task::spawn_blocking(move || {
                                 Ok::<Vec<u8>, Error>(image::load_from_memory(&raw_data)?.to_rgba8().to_vec())
                             }).await??;
-                            Ok::<TileResult, Error>(TileResult::Albedo(request.node, data))
+                            Ok::<TileResult, Error>(TileResult::Albedo(node, data))
                         }.boxed()),
                         LayerType::Roughness => pending.push(async move {
                             let mut data = Vec::new();
-                            let raw_data = mapfile.read_tile(request.layer, request.node).await?;
+                            let raw_data = mapfile.read_tile(layer, node).await?;
                             lz4::Decoder::new(Cursor::new(&raw_data))?.read_to_end(&mut data)?;
-                            Ok::<TileResult, Error>(TileResult::Roughness(request.node, data))
+                            Ok::<TileResult, Error>(TileResult::Roughness(node, data))
+                        }.boxed()),
+                        LayerType::Normals | LayerType::Displacements | LayerType::Horizons | LayerType::Water | LayerType::Custom(_) => pending.push(async move {
+                            let data = mapfile.read_tile(layer, node).await?;
+                            Ok::<TileResult, Error>(TileResult::Generated(node, layer, data))
                         }.boxed()),
-                        LayerType::Normals | LayerType::Displacements => unreachable!(),
+                    },
+                    Some(TileRequest::Write { node, layer, data }) => {
+                        let mapfile = Arc::clone(&shared_mapfile);
+                        pending_writes.push(tokio::task::spawn_blocking(move || {
+                            mapfile.write_tile(layer, node, &data, false)
+                        }));
                     }
+                    None => {}
                 },
+                write_result = pending_writes.select_next_some() => write_result??,
                 tile_result = pending.select_next_some() => {
                     results.send(tile_result?)?;
                 },
@@ -125,7 +125,10 @@ impl RasterSource for DemSource {
         }
     }
     fn bands(&self) -> usize {
-        1
+        match *self {
+            DemSource::Srtm90m(_) => 1,
+            DemSource::Nasadem(_) => 2,
+        }
     }
 }
 
@@ -251,47 +254,64 @@ fn parse_srtm3_hgt(latitude: i16, longitude: i16, hgt: Vec<u8>) -> Result<Raster
     })
 }
 
-/// Load a ZIP file containing a HGT file in the format for the NASA's nasadem 30m dataset.
+/// Load a ZIP file containing a HGT file in the format for the NASA's nasadem 30m dataset. The
+/// second band is one for samples that the SRTM water body data (the SWB file) marks as water, and
+/// zero elsewhere.
 fn parse_nasadem(latitude: i16, longitude: i16, data: Vec<u8>) -> Result<Raster<f32>, Error> {
     let resolution = 3601;
     let cell_size = 1.0 / 3600.0;
 
     let mut zip = ZipArchive::new(Cursor::new(data))?;
-    ensure!(zip.len() == 3, "Unexpected zip file contents");
+    ensure!((1..=3).contains(&zip.len()), "Unexpected zip file contents");
 
     let filename = zip.file_names().find(|name| name.ends_with(".hgt")).map(str::to_owned);
     ensure!(filename.is_some(), "Zip doesn't contain .hgt");
-    let mut file = zip.by_name(&filename.unwrap())?;
-
     let mut hgt = Vec::with_capacity(resolution * resolution * 2);
-    file.read_to_end(&mut hgt)?;
+    zip.by_name(&filename.unwrap())?.read_to_end(&mut hgt)?;
     assert_eq!(hgt.len(), resolution * resolution * 2);
     if hgt.len() != resolution * resolution * 2 {
         Err(DemParseError)?;
     }
 
+    // Without the water body mask, treat the whole tile as dry land.
+    let filename = zip.file_names().find(|name| name.ends_with(".swb")).map(str::to_owned);
+    let mut swb = Vec::with_capacity(resolution * resolution);
+    match filename {
+        Some(filename) => {
+            zip.by_name(&filename)?.read_to_end(&mut swb)?;
+            if swb.len() != resolution * resolution {
+                Err(DemParseError)?;
+            }
+        }
+        None => {
+            log::warn!("NASADEM tile {} {} has no water body mask", latitude, longitude);
+            swb.resize(resolution * resolution, 0);
+        }
+    }
+
     let hgt = bytemuck::cast_slice(&hgt[..]);
-    let mut elevations: Vec<f32> = Vec::with_capacity(resolution * resolution);
+    let mut values: Vec<f32> = Vec::with_capacity(resolution * resolution * 2);
 
     for y in 0..resolution {
         for x in 0..resolution {
             let h = i16::from_be(hgt[x + y * resolution]);
             if h == -32768 {
-                elevations.push(0.0);
+                values.push(0.0);
             } else {
-                elevations.push(h as f32);
+                values.push(h as f32);
             }
+            values.push(if swb[x + y * resolution] != 0 { 1.0 } else { 0.0 });
         }
     }
 
     Ok(Raster {
         width: resolution,
         height: resolution,
-        bands: 1,
+        bands: 2,
         latitude_llcorner: latitude as f64,
         longitude_llcorner: longitude as f64,
         cell_size,
-        values: elevations,
+        values,
     })
 }
 
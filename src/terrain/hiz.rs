@@ -0,0 +1,198 @@
+use crate::{generate::ComputeShader, gpu_state::GpuState};
+use maplit::hashmap;
+use std::num::NonZeroU32;
+
+/// Parameters of the depth pyramid, stored in the global uniform block so that culling shaders can
+/// test bounding spheres against it.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub(crate) struct HiZUniforms {
+    /// View projection matrix of the frame the pyramid was built from.
+    pub view_proj: mint::ColumnMatrix4<f32>,
+    /// Offset from the current camera position to the one used to build the pyramid.
+    pub camera_offset: [f32; 3],
+    /// Number of levels in the pyramid, or zero if there isn't one yet.
+    pub levels: u32,
+    /// Resolution of the depth buffer the pyramid was built from.
+    pub resolution: [u32; 2],
+    pub _padding: [u32; 2],
+}
+unsafe impl bytemuck::Pod for HiZUniforms {}
+unsafe impl bytemuck::Zeroable for HiZUniforms {}
+
+/// Hierarchical depth buffer built from the previous frame. Each texel of level `l` holds the
+/// farthest depth of the `2^(l+1)` by `2^(l+1)` block of depth buffer pixels that it covers.
+pub(crate) struct HiZ {
+    resolution: (u32, u32),
+    levels: u32,
+    valid: bool,
+
+    view_proj: mint::ColumnMatrix4<f32>,
+    camera: mint::Point3<f64>,
+
+    from_depth: ComputeShader<[u32; 4]>,
+    downsample: Vec<ComputeShader<[u32; 4]>>,
+}
+impl HiZ {
+    /// Create an empty pyramid. `multisampled` must match whether the depth buffers that it will be
+    /// built from have more than one sample per pixel.
+    pub fn new(multisampled: bool) -> Self {
+        let from_depth = if multisampled {
+            ComputeShader::new(
+                rshader::shader_source!("../shaders", "hiz-downsample.comp", "declarations.glsl"; "FROM_DEPTH" = "2"),
+                "hiz.level0".to_owned(),
+            )
+        } else {
+            ComputeShader::new(
+                rshader::shader_source!("../shaders", "hiz-downsample.comp", "declarations.glsl"; "FROM_DEPTH" = "1"),
+                "hiz.level0".to_owned(),
+            )
+        };
+        Self {
+            resolution: (0, 0),
+            levels: 0,
+            valid: false,
+            view_proj: cgmath::Matrix4::<f32>::from_scale(1.0).into(),
+            camera: mint::Point3 { x: 0.0, y: 0.0, z: 0.0 },
+            from_depth,
+            downsample: Vec::new(),
+        }
+    }
+
+    pub fn create_texture(device: &wgpu::Device, size: (u32, u32), levels: u32) -> wgpu::Texture {
+        device.create_texture(&wgpu::TextureDescriptor {
+            size: wgpu::Extent3d { width: size.0, height: size.1, depth_or_array_layers: 1 },
+            format: wgpu::TextureFormat::R32Float,
+            mip_level_count: levels,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            usage: wgpu::TextureUsage::STORAGE | wgpu::TextureUsage::SAMPLED,
+            label: Some("texture.hiz"),
+        })
+    }
+
+    /// Size of the given level of the pyramid.
+    fn level_size(&self, level: u32) -> (u32, u32) {
+        (((self.resolution.0 / 2) >> level).max(1), ((self.resolution.1 / 2) >> level).max(1))
+    }
+
+    /// Reallocate the pyramid if the frame size has changed. Returns whether it was replaced, in
+    /// which case any bind groups referring to it must be recreated.
+    pub fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        gpu_state: &mut GpuState,
+        resolution: (u32, u32),
+    ) -> bool {
+        if resolution == self.resolution {
+            return false;
+        }
+
+        self.resolution = resolution;
+        self.valid = false;
+        self.levels = if resolution.0 >= 2 && resolution.1 >= 2 {
+            32 - (resolution.0 / 2).max(resolution.1 / 2).leading_zeros()
+        } else {
+            0
+        };
+
+        gpu_state.hiz = if self.levels > 0 {
+            Self::create_texture(device, self.level_size(0), self.levels)
+        } else {
+            Self::create_texture(device, (1, 1), 1)
+        };
+        self.from_depth.reset_bindings();
+        self.downsample = (1..self.levels)
+            .map(|level| {
+                ComputeShader::new(
+                    rshader::shader_source!("../shaders", "hiz-downsample.comp", "declarations.glsl"; "FROM_DEPTH" = "0"),
+                    format!("hiz.level{}", level),
+                )
+            })
+            .collect();
+        true
+    }
+
+    pub fn uniforms(&self, camera: mint::Point3<f64>) -> HiZUniforms {
+        HiZUniforms {
+            view_proj: self.view_proj,
+            camera_offset: [
+                (camera.x - self.camera.x) as f32,
+                (camera.y - self.camera.y) as f32,
+                (camera.z - self.camera.z) as f32,
+            ],
+            levels: if self.valid { self.levels } else { 0 },
+            resolution: [self.resolution.0, self.resolution.1],
+            _padding: [0; 2],
+        }
+    }
+
+    /// Record commands to rebuild the pyramid from a depth buffer that was just rendered with the
+    /// given view projection matrix and camera position.
+    pub fn build(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        gpu_state: &GpuState,
+        depth_buffer: &wgpu::TextureView,
+        view_proj: mint::ColumnMatrix4<f32>,
+        camera: mint::Point3<f64>,
+    ) {
+        if self.levels == 0 {
+            return;
+        }
+
+        let views: Vec<_> = (0..self.levels)
+            .map(|level| {
+                gpu_state.hiz.create_view(&wgpu::TextureViewDescriptor {
+                    label: Some(&format!("view.hiz.level{}", level)),
+                    base_mip_level: level,
+                    mip_level_count: NonZeroU32::new(1),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        for level in 0..self.levels {
+            let input_size = if level == 0 { self.resolution } else { self.level_size(level - 1) };
+            let output_size = self.level_size(level);
+            let dimensions = ((output_size.0 + 7) / 8, (output_size.1 + 7) / 8, 1);
+            let uniforms = [input_size.0, input_size.1, output_size.0, output_size.1];
+
+            let output = wgpu::BindingResource::TextureView(&views[level as usize]);
+            if level == 0 {
+                self.from_depth.refresh();
+                self.from_depth.run_with_buffers(
+                    device,
+                    encoder,
+                    gpu_state,
+                    dimensions,
+                    &uniforms,
+                    hashmap![
+                        "depth".into() => (false, wgpu::BindingResource::TextureView(depth_buffer)),
+                        "hiz_output".into() => (false, output),
+                    ],
+                );
+            } else {
+                let input = wgpu::BindingResource::TextureView(&views[level as usize - 1]);
+                let shader = &mut self.downsample[level as usize - 1];
+                shader.refresh();
+                shader.run_with_buffers(
+                    device,
+                    encoder,
+                    gpu_state,
+                    dimensions,
+                    &uniforms,
+                    hashmap![
+                        "hiz_input".into() => (false, input),
+                        "hiz_output".into() => (false, output),
+                    ],
+                );
+            }
+        }
+
+        self.view_proj = view_proj;
+        self.camera = camera;
+        self.valid = true;
+    }
+}
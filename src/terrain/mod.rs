@@ -3,4 +3,7 @@ pub mod dem;
 pub mod quadtree;
 
 pub(crate) mod heightmap;
+pub(crate) mod hiz;
 pub(crate) mod raster;
+pub(crate) mod shadows;
+pub(crate) mod tonemap;
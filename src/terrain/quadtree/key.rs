@@ -0,0 +1,426 @@
+use crate::coordinates::{self, PlanetDesc};
+use crate::terrain::quadtree::node::{Edge, Surface, VNode};
+use anyhow::Error;
+use cgmath::{InnerSpace, Vector3};
+use std::f64::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+
+/// Public name for a tile of the terrain quadtree.
+///
+/// The planet is covered by the six faces of a cube projected onto a sphere, and each face is
+/// recursively split into four tiles. A key identifies a face, a subdivision `level` and the `x`
+/// and `y` position of the tile among the `2^level` tiles along each side of the face. Latitudes
+/// and longitudes are in radians, and ECEF positions assume a spherical planet.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct TileKey(pub(crate) VNode);
+
+/// Address of a tile in the web mercator scheme used by most web maps. `y` counts down from the
+/// northern edge of the map, as in XYZ tile URLs.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct WebTileKey {
+    pub zoom: u8,
+    pub x: u32,
+    pub y: u32,
+}
+
+/// Range of latitudes and longitudes covered by a tile. If `west > east` the range crosses the
+/// antimeridian.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct LatLonBounds {
+    pub south: f64,
+    pub north: f64,
+    pub west: f64,
+    pub east: f64,
+}
+
+impl TileKey {
+    /// The most detailed level that tiles can have.
+    pub const MAX_LEVEL: u8 = VNode::LEVEL_CELL_5MM;
+
+    /// Returns `None` if `face`, `x` or `y` are out of range for the given level.
+    pub fn new(level: u8, face: u8, x: u32, y: u32) -> Option<Self> {
+        if level > Self::MAX_LEVEL || face >= 6 || x >= 1 << level || y >= 1 << level {
+            return None;
+        }
+        Some(Self(VNode::new(level, face, x, y)))
+    }
+
+    /// The six tiles at level zero.
+    pub fn roots() -> impl Iterator<Item = Self> {
+        Surface::default().roots().iter().map(|&node| Self(node))
+    }
+
+    /// The tile at `level` containing the given latitude and longitude.
+    pub fn from_lat_lon(latitude: f64, longitude: f64, level: u8) -> Self {
+        let cspace = coordinates::polar_to_cspace(Vector3::new(latitude, longitude, 0.0));
+        Self(VNode::from_cspace(cspace, level.min(Self::MAX_LEVEL)).0)
+    }
+
+    /// The tile at `level` that is directly below `ecef`, which must not be the planet center.
+    pub fn from_ecef(ecef: mint::Point3<f64>, level: u8) -> Self {
+        let ecef = Vector3::new(ecef.x, ecef.y, ecef.z);
+        assert!(ecef.magnitude2() > 0.0);
+        let cspace = ecef / ecef.x.abs().max(ecef.y.abs()).max(ecef.z.abs());
+        Self(VNode::from_cspace(cspace, level.min(Self::MAX_LEVEL)).0)
+    }
+
+    pub fn level(&self) -> u8 {
+        self.0.level()
+    }
+    pub fn face(&self) -> u8 {
+        self.0.face()
+    }
+    pub fn x(&self) -> u32 {
+        self.0.x()
+    }
+    pub fn y(&self) -> u32 {
+        self.0.y()
+    }
+
+    pub fn parent(&self) -> Option<Self> {
+        self.0.parent().map(|(parent, _)| Self(parent))
+    }
+
+    /// The four tiles one level down, or `None` at `MAX_LEVEL`.
+    pub fn children(&self) -> Option<[Self; 4]> {
+        if self.level() == Self::MAX_LEVEL {
+            return None;
+        }
+        let c = self.0.children();
+        Some([Self(c[0]), Self(c[1]), Self(c[2]), Self(c[3])])
+    }
+
+    /// The tiles at the same level across the edges with smallest x, largest x, smallest y and
+    /// largest y, in that order. Neighbors may be on other faces.
+    pub fn neighbors(&self) -> [Self; 4] {
+        let n = |edge| Self(self.0.neighbor(edge).node);
+        [n(Edge::Left), n(Edge::Right), n(Edge::Bottom), n(Edge::Top)]
+    }
+
+    /// Latitude and longitude of the center of the tile.
+    pub fn center_lat_lon(&self) -> (f64, f64) {
+        Self::cspace_to_lat_lon(self.0.cell_position_cspace(0, 0, 0, 1))
+    }
+
+    /// Latitude and longitude of each corner, going around the tile starting from the one with
+    /// the smallest x and y.
+    pub fn corners_lat_lon(&self) -> [(f64, f64); 4] {
+        let c = self.corners_cspace();
+        [
+            Self::cspace_to_lat_lon(c[0]),
+            Self::cspace_to_lat_lon(c[1]),
+            Self::cspace_to_lat_lon(c[2]),
+            Self::cspace_to_lat_lon(c[3]),
+        ]
+    }
+
+    /// Position of each corner at sea level, in the same order as `corners_lat_lon`.
+    pub fn corners_ecef(&self, planet: &PlanetDesc) -> [mint::Point3<f64>; 4] {
+        let c = self.corners_cspace();
+        let ecef = |c: Vector3<f64>| {
+            let p = c.normalize() * planet.radius;
+            mint::Point3 { x: p.x, y: p.y, z: p.z }
+        };
+        [ecef(c[0]), ecef(c[1]), ecef(c[2]), ecef(c[3])]
+    }
+
+    /// Smallest range of latitudes and longitudes containing the tile. Found by sampling its
+    /// edges, so may be very slightly too small.
+    pub fn bounds_lat_lon(&self) -> LatLonBounds {
+        const SAMPLES: i32 = 32;
+
+        let (_, center_longitude) = self.center_lat_lon();
+        let relative =
+            |longitude: f64| (longitude - center_longitude + PI).rem_euclid(2.0 * PI) - PI;
+
+        let mut bounds = LatLonBounds {
+            south: f64::INFINITY,
+            north: f64::NEG_INFINITY,
+            west: f64::INFINITY,
+            east: f64::NEG_INFINITY,
+        };
+        for i in 0..SAMPLES {
+            for &(x, y) in &[(i, 0), (SAMPLES, i), (SAMPLES - i, SAMPLES), (0, SAMPLES - i)] {
+                let cspace = self.0.grid_position_cspace(x, y, 0, SAMPLES as u32 + 1);
+                let (latitude, longitude) = Self::cspace_to_lat_lon(cspace);
+                let longitude = relative(longitude);
+                bounds.south = bounds.south.min(latitude);
+                bounds.north = bounds.north.max(latitude);
+                bounds.west = bounds.west.min(longitude);
+                bounds.east = bounds.east.max(longitude);
+            }
+        }
+
+        // Tiles containing a pole extend all the way to it and span every longitude.
+        let contains = |latitude| Self::from_lat_lon(latitude, 0.0, self.level()) == *self;
+        let (north_pole, south_pole) = (contains(PI * 0.5), contains(-PI * 0.5));
+        if north_pole {
+            bounds.north = PI * 0.5;
+        }
+        if south_pole {
+            bounds.south = -PI * 0.5;
+        }
+        if north_pole || south_pole {
+            bounds.west = -PI;
+            bounds.east = PI;
+        } else {
+            bounds.west += center_longitude;
+            bounds.east += center_longitude;
+            if bounds.west < -PI {
+                bounds.west += 2.0 * PI;
+            }
+            if bounds.east > PI {
+                bounds.east -= 2.0 * PI;
+            }
+        }
+        bounds
+    }
+
+    /// Center and radius of a sphere containing the tile, assuming its heights relative to sea
+    /// level are between `min_height` and `max_height`.
+    pub fn bounding_sphere_ecef(
+        &self,
+        planet: &PlanetDesc,
+        min_height: f32,
+        max_height: f32,
+    ) -> (mint::Point3<f64>, f64) {
+        let (center, radius2) =
+            self.0.bounding_sphere(&Surface::Planet(*planet), (min_height, max_height));
+        (mint::Point3 { x: center.x, y: center.y, z: center.z }, radius2.sqrt())
+    }
+
+    /// The web tile of roughly the same size that contains the center of this tile.
+    pub fn to_web_tile(&self) -> WebTileKey {
+        let (latitude, longitude) = self.center_lat_lon();
+        WebTileKey::from_lat_lon(latitude, longitude, self.level() + 2).unwrap()
+    }
+
+    /// The tile of roughly the same size that contains the center of a web tile.
+    pub fn from_web_tile(tile: WebTileKey) -> Self {
+        let (latitude, longitude) = tile.center_lat_lon();
+        Self::from_lat_lon(latitude, longitude, tile.zoom.saturating_sub(2))
+    }
+
+    fn corners_cspace(&self) -> [Vector3<f64>; 4] {
+        [
+            self.0.grid_position_cspace(0, 0, 0, 2),
+            self.0.grid_position_cspace(1, 0, 0, 2),
+            self.0.grid_position_cspace(1, 1, 0, 2),
+            self.0.grid_position_cspace(0, 1, 0, 2),
+        ]
+    }
+
+    fn cspace_to_lat_lon(cspace: Vector3<f64>) -> (f64, f64) {
+        let polar = coordinates::cspace_to_polar(cspace);
+        (polar.x, polar.y)
+    }
+}
+
+/// Tiles are written as `N<level>-<face>-<x>x<y>`, where the face is named by the point at its
+/// center: `0E`, `180E`, `90E`, `90W`, `N` or `S`. This encoding is stable across releases.
+impl fmt::Display for TileKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+impl FromStr for TileKey {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let parse = || -> Option<Self> {
+            let mut parts = s.strip_prefix('N')?.splitn(3, '-');
+            let level = parts.next()?.parse().ok()?;
+            let face = match parts.next()? {
+                "0E" => 0,
+                "180E" => 1,
+                "90E" => 2,
+                "90W" => 3,
+                "N" => 4,
+                "S" => 5,
+                _ => return None,
+            };
+            let mut xy = parts.next()?.splitn(2, 'x');
+            let x = xy.next()?.parse().ok()?;
+            let y = xy.next()?.parse().ok()?;
+            Self::new(level, face, x, y)
+        };
+        parse().ok_or_else(|| anyhow::format_err!("invalid tile key '{}'", s))
+    }
+}
+
+impl WebTileKey {
+    /// The most detailed zoom level representable with 32-bit tile coordinates.
+    pub const MAX_ZOOM: u8 = 32;
+
+    /// Returns `None` if `x` or `y` are out of range for the given zoom.
+    pub fn new(zoom: u8, x: u32, y: u32) -> Option<Self> {
+        let key = Self { zoom, x, y };
+        if !key.is_valid() {
+            return None;
+        }
+        Some(key)
+    }
+
+    /// Converts from a TMS key, where `y` counts up from the southern edge of the map. Returns
+    /// `None` if `x` or `y` are out of range for the given zoom.
+    pub fn from_tms(zoom: u8, x: u32, y: u32) -> Option<Self> {
+        let key = Self::new(zoom, x, y)?;
+        Some(Self { y: key.max_coordinate() - y, ..key })
+    }
+
+    /// The `y` coordinate of this tile in the TMS scheme, or `None` if the key is out of range.
+    pub fn tms_y(&self) -> Option<u32> {
+        if !self.is_valid() {
+            return None;
+        }
+        Some(self.max_coordinate() - self.y)
+    }
+
+    /// The tile at `zoom` containing the given latitude and longitude. Latitudes beyond the edge
+    /// of the web mercator projection map to the tiles along its northern or southern edge.
+    /// Returns `None` if `zoom` exceeds `MAX_ZOOM` or the coordinates aren't finite.
+    pub fn from_lat_lon(latitude: f64, longitude: f64, zoom: u8) -> Option<Self> {
+        if zoom > Self::MAX_ZOOM || !latitude.is_finite() || !longitude.is_finite() {
+            return None;
+        }
+        let n = (1u64 << zoom) as f64;
+        let x = (longitude + PI) / (2.0 * PI) * n;
+        let y = (1.0 - latitude.tan().asinh() / PI) * 0.5 * n;
+        let clamp = |v: f64| (v.max(0.0) as u64).min((1u64 << zoom) - 1) as u32;
+        Some(Self { zoom, x: clamp(x), y: clamp(y) })
+    }
+
+    fn is_valid(&self) -> bool {
+        self.zoom <= Self::MAX_ZOOM
+            && u64::from(self.x) < 1u64 << self.zoom
+            && u64::from(self.y) < 1u64 << self.zoom
+    }
+
+    /// Largest `x` or `y` coordinate at this key's zoom. Only meaningful for valid keys.
+    fn max_coordinate(&self) -> u32 {
+        ((1u64 << self.zoom) - 1) as u32
+    }
+
+    /// Latitude and longitude of the center of the tile.
+    pub fn center_lat_lon(&self) -> (f64, f64) {
+        let n = 2f64.powi(self.zoom as i32);
+        let longitude = (self.x as f64 + 0.5) / n * 2.0 * PI - PI;
+        let latitude = (PI * (1.0 - 2.0 * (self.y as f64 + 0.5) / n)).sinh().atan();
+        (latitude, longitude)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    fn vector(p: mint::Point3<f64>) -> Vector3<f64> {
+        Vector3::new(p.x, p.y, p.z)
+    }
+
+    #[test]
+    fn string_encoding() {
+        let key = TileKey::new(7, 3, 100, 5).unwrap();
+        assert_eq!(key.to_string(), "N7-90W-100x5");
+        assert_eq!("N7-90W-100x5".parse::<TileKey>().unwrap(), key);
+        for root in TileKey::roots() {
+            assert_eq!(root.to_string().parse::<TileKey>().unwrap(), root);
+        }
+
+        assert!("N7-90W-128x5".parse::<TileKey>().is_err());
+        assert!("N7-45E-1x5".parse::<TileKey>().is_err());
+        assert!("7-90W-1x5".parse::<TileKey>().is_err());
+        assert!("N7-90W-1".parse::<TileKey>().is_err());
+    }
+
+    #[test]
+    fn lat_lon() {
+        let key = TileKey::from_lat_lon(0.7, -2.1, 12);
+        assert_eq!(key.level(), 12);
+        let (latitude, longitude) = key.center_lat_lon();
+        assert_eq!(TileKey::from_lat_lon(latitude, longitude, 12), key);
+        assert_eq!(TileKey::from_lat_lon(0.7, -2.1, 11), key.parent().unwrap());
+        assert!(key.children().unwrap().contains(&TileKey::from_lat_lon(0.7, -2.1, 13)));
+
+        let planet = PlanetDesc::EARTH;
+        let ecef = coordinates::polar_to_ecef(Vector3::new(0.7, -2.1, 0.0), &planet);
+        assert_eq!(TileKey::from_ecef(mint::Point3 { x: ecef.x, y: ecef.y, z: ecef.z }, 12), key);
+        for &corner in &key.corners_ecef(&planet) {
+            assert_relative_eq!(vector(corner).magnitude(), planet.radius);
+        }
+
+        // The tile on the far side of an edge of the cube is still a neighbor.
+        let edge = TileKey::from_lat_lon(0.0, PI * 0.25, 5);
+        assert!(edge.neighbors().iter().any(|n| n.face() != edge.face()));
+    }
+
+    #[test]
+    fn bounds() {
+        let root = TileKey::new(0, 0, 0, 0).unwrap();
+        let bounds = root.bounds_lat_lon();
+        assert_relative_eq!(bounds.north, PI * 0.25, epsilon = 1e-9);
+        assert_relative_eq!(bounds.south, -PI * 0.25, epsilon = 1e-9);
+        assert_relative_eq!(bounds.east, PI * 0.25, epsilon = 1e-9);
+        assert_relative_eq!(bounds.west, -PI * 0.25, epsilon = 1e-9);
+
+        let antimeridian = TileKey::new(0, 1, 0, 0).unwrap().bounds_lat_lon();
+        assert_relative_eq!(antimeridian.west, PI * 0.75, epsilon = 1e-9);
+        assert_relative_eq!(antimeridian.east, -PI * 0.75, epsilon = 1e-9);
+
+        let north = TileKey::new(0, 4, 0, 0).unwrap().bounds_lat_lon();
+        assert_eq!((north.north, north.west, north.east), (PI * 0.5, -PI, PI));
+
+        let key = TileKey::from_lat_lon(-0.3, 1.2, 9);
+        let (center, radius) = key.bounding_sphere_ecef(&PlanetDesc::EARTH, 0.0, 0.0);
+        for &corner in &key.corners_ecef(&PlanetDesc::EARTH) {
+            assert!((vector(corner) - vector(center)).magnitude() <= radius * (1.0 + 1e-9));
+        }
+    }
+
+    #[test]
+    fn web_tiles() {
+        let root = TileKey::new(0, 0, 0, 0).unwrap();
+        let tile = root.to_web_tile();
+        assert_eq!(tile, WebTileKey { zoom: 2, x: 2, y: 2 });
+        assert_eq!(tile.tms_y(), Some(1));
+        assert_eq!(WebTileKey::from_tms(2, 2, 1), Some(tile));
+        assert_eq!(TileKey::from_web_tile(tile).level(), 0);
+
+        let tile = WebTileKey { zoom: 14, x: 2620, y: 6332 };
+        let key = TileKey::from_web_tile(tile);
+        assert_eq!(key.level(), 12);
+        let (latitude, longitude) = tile.center_lat_lon();
+        assert_eq!(WebTileKey::from_lat_lon(latitude, longitude, 14), Some(tile));
+        assert_eq!(key, TileKey::from_lat_lon(latitude, longitude, 12));
+    }
+
+    #[test]
+    fn web_tile_ranges() {
+        assert_eq!(WebTileKey::new(0, 0, 0), Some(WebTileKey { zoom: 0, x: 0, y: 0 }));
+        assert_eq!(WebTileKey::new(0, 0, 1), None);
+        assert_eq!(WebTileKey::new(3, 8, 0), None);
+        assert_eq!(WebTileKey::new(33, 0, 0), None);
+
+        assert_eq!(WebTileKey::from_tms(0, 0, 0), Some(WebTileKey { zoom: 0, x: 0, y: 0 }));
+        assert_eq!(WebTileKey::from_tms(2, 0, 4), None);
+        assert_eq!(WebTileKey::from_tms(64, 0, 0), None);
+        assert_eq!(WebTileKey::from_tms(255, 0, 0), None);
+        assert_eq!(
+            WebTileKey::from_tms(32, u32::MAX, u32::MAX),
+            Some(WebTileKey { zoom: 32, x: u32::MAX, y: 0 })
+        );
+
+        assert_eq!(WebTileKey { zoom: 32, x: 0, y: 0 }.tms_y(), Some(u32::MAX));
+        assert_eq!(WebTileKey { zoom: 2, x: 0, y: 4 }.tms_y(), None);
+        assert_eq!(WebTileKey { zoom: 40, x: 0, y: 0 }.tms_y(), None);
+
+        let north = WebTileKey::from_lat_lon(1.5, -PI, 32).unwrap();
+        assert_eq!((north.x, north.y), (0, 0));
+        let south = WebTileKey::from_lat_lon(-1.5, PI, 32).unwrap();
+        assert_eq!((south.x, south.y), (u32::MAX, u32::MAX));
+        assert_eq!(WebTileKey::from_lat_lon(0.0, 0.0, 33), None);
+        assert_eq!(WebTileKey::from_lat_lon(f64::NAN, 0.0, 4), None);
+        assert_eq!(WebTileKey::from_lat_lon(0.0, f64::INFINITY, 4), None);
+    }
+}
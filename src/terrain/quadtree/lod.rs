@@ -0,0 +1,72 @@
+use crate::terrain::quadtree::node::{Surface, VNode};
+use cgmath::*;
+
+/// Preset screen-space error thresholds, trading rendering quality for speed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LodQuality {
+    Low,
+    Medium,
+    High,
+    Ultra,
+}
+impl LodQuality {
+    /// Largest projected geometric error, in pixels, that is tolerated before a tile is refined.
+    pub fn max_pixel_error(self) -> f32 {
+        match self {
+            LodQuality::Low => 16.0,
+            LodQuality::Medium => 8.0,
+            LodQuality::High => 4.0,
+            LodQuality::Ultra => 2.0,
+        }
+    }
+}
+impl Default for LodQuality {
+    fn default() -> Self {
+        LodQuality::Medium
+    }
+}
+
+/// Screen-space error metric used to decide which nodes need to be refined.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) struct LodMetric {
+    /// Number of vertices along each edge of a node's mesh.
+    pub resolution: u32,
+    /// Largest projected error (in pixels) that doesn't require refinement.
+    pub max_pixel_error: f32,
+    /// Number of pixels spanned by an object of unit size at unit distance from the camera.
+    pub pixel_scale: f64,
+}
+impl LodMetric {
+    pub fn new(resolution: u32) -> Self {
+        // Assume a 1080p viewport with a 90 degree vertical field of view until told otherwise.
+        Self {
+            resolution,
+            max_pixel_error: LodQuality::default().max_pixel_error(),
+            pixel_scale: 540.0,
+        }
+    }
+
+    /// Compute the pixel scale for a viewport of the given size. Assumes `view_proj` is a
+    /// perspective projection composed with a rotation, so the vertical scale of the projection is
+    /// the length of the second row of its upper 3x3 block.
+    pub fn pixel_scale(frame_size: (u32, u32), view_proj: Matrix4<f64>) -> f64 {
+        let vertical_scale = Vector3::new(view_proj.x.y, view_proj.y.y, view_proj.z.y).magnitude();
+        0.5 * frame_size.1 as f64 * vertical_scale
+    }
+
+    /// Whether `other` would select the same nodes as this metric. The pixel scale is derived from
+    /// a single precision projection matrix, so it is compared with a relative tolerance to keep
+    /// rounding errors as the camera rotates from counting as a change.
+    pub fn approx_eq(&self, other: &Self) -> bool {
+        self.resolution == other.resolution
+            && self.max_pixel_error == other.max_pixel_error
+            && (self.pixel_scale - other.pixel_scale).abs() <= 1e-4 * self.pixel_scale.abs()
+    }
+
+    /// Distance from the camera within which `node` should be rendered: the distance at which the
+    /// spacing between vertices of its parent projects to `max_pixel_error` pixels.
+    pub fn min_distance(&self, surface: &Surface, node: VNode) -> f64 {
+        let parent_error = 2.0 * node.aprox_side_length(surface) as f64 / self.resolution as f64;
+        parent_error * self.pixel_scale / self.max_pixel_error as f64
+    }
+}
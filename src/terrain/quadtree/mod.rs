@@ -1,20 +1,63 @@
 use crate::cache::Priority;
 use crate::cache::TileCache;
-use crate::utils::math::InfiniteFrustum;
+use crate::generate::ComputeShader;
+use crate::utils::math::{HorizonOccluder, InfiniteFrustum};
 use cgmath::*;
 use fnv::FnvHashMap;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::convert::TryInto;
 
+pub(crate) mod key;
+pub(crate) mod lod;
 pub(crate) mod node;
 pub(crate) mod render;
 
+pub(crate) use crate::terrain::quadtree::lod::*;
 pub(crate) use crate::terrain::quadtree::node::*;
 pub(crate) use crate::terrain::quadtree::render::*;
 
+/// Statistics about the most recently rendered frame.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RenderStats {
+    /// Number of entries in the node buffer. Each quarter of a partially drawn node counts as a
+    /// separate entry.
+    pub rendered_nodes: usize,
+    /// Capacity of the node buffer.
+    pub max_rendered_nodes: usize,
+    /// Number of nodes that would have been drawn but were replaced by their parents because the
+    /// node buffer was full.
+    pub coarsened_nodes: usize,
+}
+
+/// Relative amount that cached node priorities may differ from their true values.
+const MAX_PRIORITY_DRIFT: f64 = 0.1;
+
+/// Cached state of a node in the active tree.
+#[derive(Copy, Clone, Debug)]
+struct NodeEntry {
+    priority: Priority,
+    /// Bounding sphere center and squared radius.
+    bounds: (Vector3<f64>, f64),
+    below_horizon: bool,
+    /// Value of `camera_travel` after which the entry must be recomputed.
+    recheck_travel: f64,
+    /// Whether the node or some of its descendants are drawn this frame.
+    visible: bool,
+    /// Whether the node would have been visible, but was hidden by `coarsen` so that its parent is
+    /// drawn in its place.
+    coarsened: bool,
+}
+impl NodeEntry {
+    /// Whether the children of the node are part of the active tree.
+    fn refined(&self, node: VNode) -> bool {
+        self.priority >= Priority::cutoff() && node.level() < VNode::LEVEL_CELL_5MM
+    }
+}
+
 /// The central object in terra. It holds all relevant state and provides functions to update and
 /// render the terrain.
 pub(crate) struct QuadTree {
-    // ocean: Ocean<R>,
     /// List of nodes that will be rendered.
     visible_nodes: Vec<VNode>,
     partially_visible_nodes: Vec<(VNode, u8)>,
@@ -22,9 +65,24 @@ pub(crate) struct QuadTree {
     heights_resolution: u32,
 
     node_states: Vec<NodeState>,
+    custom_layer_descs: Vec<[[f32; 4]; 2]>,
 
-    node_priorities: FnvHashMap<VNode, Priority>,
+    /// Every node whose parent has priority above the cutoff, along with the root nodes.
+    nodes: FnvHashMap<VNode, NodeEntry>,
+    /// Nodes that were marked visible by the last call to `update_visibility`, parents first.
+    visible: Vec<VNode>,
     last_camera_position: Option<mint::Point3<f64>>,
+    /// Total distance the camera has moved.
+    camera_travel: f64,
+
+    max_rendered_nodes: usize,
+    coarsened_nodes: usize,
+
+    surface: Surface,
+    lod: LodMetric,
+    cull: ComputeShader<CullNodesUniforms>,
+    /// Culling shaders for each shadow cascade, created as needed.
+    shadow_cull: Vec<ComputeShader<CullNodesUniforms>>,
 }
 
 impl std::fmt::Debug for QuadTree {
@@ -35,14 +93,52 @@ impl std::fmt::Debug for QuadTree {
 
 #[allow(unused)]
 impl QuadTree {
-    pub(crate) fn new(heights_resolution: u32) -> Self {
+    pub(crate) fn new(
+        heights_resolution: u32,
+        max_rendered_nodes: usize,
+        surface: Surface,
+    ) -> Self {
         Self {
             visible_nodes: Vec::new(),
             partially_visible_nodes: Vec::new(),
             node_states: Vec::new(),
+            custom_layer_descs: Vec::new(),
             heights_resolution,
-            node_priorities: FnvHashMap::default(),
+            nodes: FnvHashMap::default(),
+            visible: Vec::new(),
             last_camera_position: None,
+            camera_travel: 0.0,
+            max_rendered_nodes,
+            coarsened_nodes: 0,
+            surface,
+            lod: LodMetric::new(heights_resolution),
+            cull: ComputeShader::new(
+                rshader::shader_source!(
+                    "../../shaders",
+                    "cull-nodes.comp",
+                    "declarations.glsl",
+                    "hiz.glsl"
+                ),
+                "cull-nodes".to_owned(),
+            ),
+            shadow_cull: Vec::new(),
+        }
+    }
+
+    pub fn surface(&self) -> &Surface {
+        &self.surface
+    }
+
+    pub fn lod(&self) -> &LodMetric {
+        &self.lod
+    }
+
+    /// Change the LOD metric, forcing node priorities to be recomputed if it differs from the
+    /// current one by more than rounding error.
+    pub fn set_lod(&mut self, lod: LodMetric) {
+        if !lod.approx_eq(&self.lod) {
+            self.lod = lod;
+            self.last_camera_position = None;
         }
     }
 
@@ -78,85 +174,354 @@ impl QuadTree {
         buffer
     }
 
-    pub fn update_priorities(&mut self, tile_cache: &TileCache, camera: mint::Point3<f64>) {
-        if self.last_camera_position == Some(camera) {
+    pub fn update_priorities(&mut self, tile_cache: &mut TileCache, camera: mint::Point3<f64>) {
+        let changed = tile_cache.take_height_range_changes();
+        self.update_priorities_with(camera, &changed, |node| tile_cache.get_height_range(node));
+    }
+
+    /// Update the active tree for a new camera position. Only nodes whose priority could have
+    /// crossed the cutoff (or drifted by more than `MAX_PRIORITY_DRIFT`) since they were last
+    /// evaluated are recomputed, and the tree is refined or coarsened below them as needed. Nodes
+    /// in `changed` had their height ranges change, so they are recomputed along with all their
+    /// descendants. Everything is recomputed if the height range of a root node changed, since
+    /// the horizon depends on them.
+    fn update_priorities_with<H: Fn(VNode) -> (f32, f32)>(
+        &mut self,
+        camera: mint::Point3<f64>,
+        changed: &[VNode],
+        height_range: H,
+    ) {
+        let rebuild =
+            self.last_camera_position.is_none() || changed.iter().any(|node| node.level() == 0);
+        if !rebuild && changed.is_empty() && self.last_camera_position == Some(camera) {
             return;
         }
+
+        let position = Vector3::new(camera.x, camera.y, camera.z);
+        if let Some(last) = self.last_camera_position {
+            self.camera_travel += position.distance(Vector3::new(last.x, last.y, last.z));
+        }
         self.last_camera_position = Some(camera);
-        let camera = Vector3::new(camera.x, camera.y, camera.z);
 
-        self.node_priorities.clear();
-        VNode::breadth_first(|node| {
-            let priority = node.priority(camera, tile_cache.get_height_range(node));
-            self.node_priorities.insert(node, priority);
-            priority >= Priority::cutoff() && node.level() < VNode::LEVEL_CELL_5MM
-        });
+        let horizon = self.horizon(&height_range, position);
+        let horizon = horizon.as_ref();
+
+        if rebuild {
+            self.nodes.clear();
+            for &root in self.surface.roots() {
+                self.insert_subtree(root, position, horizon, &height_range);
+            }
+            return;
+        }
+
+        for &node in changed {
+            // Nodes outside the active tree have no descendants in it either.
+            if self.nodes.contains_key(&node) {
+                self.remove_descendants(node);
+                self.insert_subtree(node, position, horizon, &height_range);
+            }
+        }
+
+        let stale: Vec<VNode> = self
+            .nodes
+            .iter()
+            .filter(|(_, entry)| entry.recheck_travel <= self.camera_travel)
+            .map(|(&node, _)| node)
+            .collect();
+        for node in stale {
+            // Skip nodes that were removed along with one of their ancestors.
+            let was_refined = match self.nodes.get(&node) {
+                Some(entry) => entry.refined(node),
+                None => continue,
+            };
+
+            let entry = self.evaluate(node, position, horizon, &height_range);
+            self.nodes.insert(node, entry);
+            match (was_refined, entry.refined(node)) {
+                (true, false) => self.remove_descendants(node),
+                (false, true) => {
+                    for &child in node.children().iter() {
+                        self.insert_subtree(child, position, horizon, &height_range);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn evaluate<H: Fn(VNode) -> (f32, f32)>(
+        &self,
+        node: VNode,
+        camera: Vector3<f64>,
+        horizon: Option<&HorizonOccluder>,
+        height_range: &H,
+    ) -> NodeEntry {
+        let height_range = height_range(node);
+        let bounds = node.bounding_sphere(&self.surface, height_range);
+        let below_horizon = horizon.map_or(false, |h| h.occludes_sphere(bounds.0, bounds.1.sqrt()));
+
+        let min_distance = self.lod.min_distance(&self.surface, node);
+        let distance = node.distance(&self.surface, camera, height_range);
+        let mut priority = if below_horizon {
+            Priority::none()
+        } else {
+            Priority::from_f32((min_distance / distance.max(1e-6)) as f32)
+        };
+        // Root nodes must always be streamed in since they are needed for loading to complete.
+        if node.level() == 0 {
+            priority = priority.max(Priority::cutoff());
+        }
+
+        // Moving the camera by less than `slack` can neither move the priority across the cutoff
+        // nor change it by more than `MAX_PRIORITY_DRIFT`. There is no such bound for nodes below
+        // the horizon, so they are checked again on every update.
+        let slack = if below_horizon {
+            0.0
+        } else {
+            (distance - min_distance).abs().min(distance * MAX_PRIORITY_DRIFT)
+        };
+
+        NodeEntry {
+            priority,
+            bounds,
+            below_horizon,
+            recheck_travel: self.camera_travel + slack,
+            visible: false,
+            coarsened: false,
+        }
     }
 
+    /// Add `node` to the active tree, along with any of its descendants that are needed.
+    fn insert_subtree<H: Fn(VNode) -> (f32, f32)>(
+        &mut self,
+        node: VNode,
+        camera: Vector3<f64>,
+        horizon: Option<&HorizonOccluder>,
+        height_range: &H,
+    ) {
+        let mut pending = vec![node];
+        while let Some(node) = pending.pop() {
+            let entry = self.evaluate(node, camera, horizon, height_range);
+            self.nodes.insert(node, entry);
+            if entry.refined(node) {
+                pending.extend_from_slice(&node.children());
+            }
+        }
+    }
+
+    fn remove_descendants(&mut self, node: VNode) {
+        let mut pending = vec![node];
+        while let Some(node) = pending.pop() {
+            if node.level() < VNode::LEVEL_CELL_5MM {
+                for &child in node.children().iter() {
+                    if self.nodes.remove(&child).is_some() {
+                        pending.push(child);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Build a horizon occluder for the given camera position. The occluder sits at the lowest
+    /// height of any root node, so it is always below the terrain. Flat surfaces have no horizon.
+    fn horizon<H: Fn(VNode) -> (f32, f32)>(
+        &self,
+        height_range: &H,
+        camera: Vector3<f64>,
+    ) -> Option<HorizonOccluder> {
+        let planet = self.surface.planet()?;
+        let min_height =
+            self.surface.roots().iter().map(|&root| height_range(root).0).fold(0.0f32, f32::min);
+        let radius = planet.radius + min_height as f64;
+        Some(HorizonOccluder::new(camera, Vector3::new(radius, radius, radius)))
+    }
+
+    /// Decide which nodes to draw. Uses the bounds and horizon culling results cached by the last
+    /// call to `update_priorities`. Nodes within any of the `shadow_volumes` are included even if
+    /// they aren't in the view frustum, so that they can cast shadows into it.
+    ///
+    /// The frustum changes every frame, so visibility is always recomputed, but only for the part
+    /// of the active tree below visible nodes. The flags set on the previous call are cleared using
+    /// the list of nodes it marked rather than by visiting every node.
     pub fn update_visibility(
         &mut self,
-        tile_cache: &TileCache,
         frustum: &InfiniteFrustum,
-        camera: mint::Point3<f64>,
+        shadow_volumes: &[InfiniteFrustum],
     ) {
         self.visible_nodes.clear();
         self.partially_visible_nodes.clear();
 
+        // Entries that have left the active tree since the last call no longer need clearing.
+        for node in self.visible.drain(..) {
+            if let Some(entry) = self.nodes.get_mut(&node) {
+                entry.visible = false;
+                entry.coarsened = false;
+            }
+        }
+
         // Any node with all needed layers in cache is visible...
-        let mut node_visibilities: FnvHashMap<VNode, bool> = FnvHashMap::default();
-        VNode::breadth_first(|node| {
-            let priority = self.node_priority(node);
-            let visible = (node.level() == 0 || priority >= Priority::cutoff())
-                && node.in_frustum(&frustum, tile_cache.get_height_range(node));
-
-            node_visibilities.insert(node, visible);
-            visible && node.level() < VNode::LEVEL_CELL_5MM
-        });
-        // let min_missing_level = node_visibilities
-        //     .iter()
-        //     .filter(|(&n, &v)| v && !tile_cache.contains(n, LayerType::Displacements))
-        //     .map(|(n, v)| n.level())
-        //     .min();
-        // if let Some(min) = min_missing_level {
-        //     for (n, v) in node_visibilities.iter_mut() {
-        //         if n.level() >= min {
-        //             *v = false;
-        //         }
-        //     }
-        // }
+        let mut pending = self.surface.roots().to_vec();
+        while let Some(node) = pending.pop() {
+            let entry = match self.nodes.get_mut(&node) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let (center, radius2) = entry.bounds;
+            entry.visible = (node.level() == 0 || entry.priority >= Priority::cutoff())
+                && (frustum.intersects_sphere(center, radius2) && !entry.below_horizon
+                    || shadow_volumes.iter().any(|v| v.intersects_sphere(center, radius2)));
+
+            if entry.visible {
+                self.visible.push(node);
+                if node.level() < VNode::LEVEL_CELL_5MM {
+                    pending.extend_from_slice(&node.children());
+                }
+            }
+        }
+
+        // Coarsen the least important parts of the terrain if there isn't room to draw them all.
+        self.coarsened_nodes = self.coarsen();
 
         // ...Except if all its children are visible instead.
-        VNode::breadth_first(|node| {
-            if node.level() < VNode::LEVEL_CELL_5MM && node_visibilities[&node] {
-                let mut mask = 0;
-                for (i, c) in node.children().iter().enumerate() {
-                    if !node_visibilities[c] {
-                        mask = mask | (1 << i);
-                    }
+        for &node in &self.visible {
+            if !self.is_visible(node) {
+                continue;
+            }
+            if node.level() == VNode::LEVEL_CELL_5MM {
+                self.visible_nodes.push(node);
+                continue;
+            }
+
+            let mut mask = 0;
+            for (i, c) in node.children().iter().enumerate() {
+                if !self.is_visible(*c) {
+                    mask |= 1 << i;
                 }
+            }
+            if mask == 15 {
+                self.visible_nodes.push(node);
+            } else if mask > 0 {
+                self.partially_visible_nodes.push((node, mask));
+            }
+        }
+    }
 
-                if mask == 15 {
-                    self.visible_nodes.push(node);
-                } else if mask > 0 {
-                    self.partially_visible_nodes.push((node, mask));
+    /// Whether `node` was marked visible by the last call to `update_visibility`.
+    fn is_visible(&self, node: VNode) -> bool {
+        self.nodes.get(&node).map_or(false, |entry| entry.visible)
+    }
+
+    /// Whether the area covered by `node` is drawn by one of its ancestors, because the priority
+    /// of `node` (or of an ancestor below the drawn one) is under the cutoff or it was coarsened.
+    fn drawn_by_ancestor(&self, node: VNode) -> bool {
+        let mut n = node;
+        loop {
+            match self.nodes.get(&n) {
+                Some(entry) if entry.visible => return n != node,
+                Some(entry) if !entry.coarsened && entry.priority >= Priority::cutoff() => {
+                    return false
                 }
+                _ => {}
+            }
+            match n.parent() {
+                Some((parent, _)) => n = parent,
+                None => return false,
+            }
+        }
+    }
 
-                mask < 15
-            } else if node_visibilities[&node] {
-                self.visible_nodes.push(node);
-                false
-            } else {
-                false
+    /// Number of node buffer entries needed to draw the visible nodes: one per visible node without
+    /// visible children, plus one per hidden quarter of the others.
+    fn count_rendered_nodes(&self) -> usize {
+        let mut count = 0;
+        for &node in &self.visible {
+            if !self.is_visible(node) {
+                continue;
             }
-        });
+            if node.level() == VNode::LEVEL_CELL_5MM {
+                count += 1;
+                continue;
+            }
+
+            let hidden = node.children().iter().filter(|c| !self.is_visible(**c)).count();
+            count += if hidden == 4 { 1 } else { hidden };
+        }
+        count
+    }
+
+    /// The priority with which the children of `node` should be hidden by `coarsen`, or None if
+    /// it can't be coarsened. A node can be coarsened if it has visible children but no visible
+    /// grandchildren.
+    fn coarsen_priority(&self, node: VNode) -> Option<Priority> {
+        if node.level() == VNode::LEVEL_CELL_5MM || !self.is_visible(node) {
+            return None;
+        }
+        let children = node.children();
+        if children.iter().any(|c| {
+            self.is_visible(*c)
+                && c.level() < VNode::LEVEL_CELL_5MM
+                && c.children().iter().any(|g| self.is_visible(*g))
+        }) {
+            return None;
+        }
+        children.iter().filter(|c| self.is_visible(**c)).map(|&c| self.node_priority(c)).max()
+    }
+
+    /// Hide the children of the lowest priority nodes until the visible nodes fit in the node
+    /// buffer. Returns the number of nodes that were hidden.
+    fn coarsen(&mut self) -> usize {
+        let mut count = self.count_rendered_nodes();
+        if count <= self.max_rendered_nodes {
+            return 0;
+        }
+
+        // The children are hidden in order of their highest priority, which makes each coarsening
+        // step replace between one and four entries with a single entry for the parent.
+        let mut candidates = BinaryHeap::new();
+        for &node in &self.visible {
+            if let Some(priority) = self.coarsen_priority(node) {
+                candidates.push(Reverse((priority, node)));
+            }
+        }
+
+        let mut hidden = 0;
+        while count > self.max_rendered_nodes {
+            let node = match candidates.pop() {
+                Some(Reverse((_, node))) => node,
+                None => break,
+            };
+            for c in node.children().iter() {
+                if let Some(entry) = self.nodes.get_mut(c).filter(|entry| entry.visible) {
+                    entry.visible = false;
+                    entry.coarsened = true;
+                    hidden += 1;
+                }
+            }
+            count -= 3;
+
+            if let Some((parent, _)) = node.parent() {
+                if let Some(priority) = self.coarsen_priority(parent) {
+                    candidates.push(Reverse((priority, parent)));
+                }
+            }
+        }
+        hidden
     }
 
     pub fn node_buffer_length(&self) -> usize {
         self.node_states.len()
     }
 
+    pub fn render_stats(&self) -> RenderStats {
+        RenderStats {
+            rendered_nodes: self.node_states.len(),
+            max_rendered_nodes: self.max_rendered_nodes,
+            coarsened_nodes: self.coarsened_nodes,
+        }
+    }
+
     pub fn node_priority(&self, node: VNode) -> Priority {
-        self.node_priorities.get(&node).cloned().unwrap_or(Priority::none())
+        self.nodes.get(&node).map(|entry| entry.priority).unwrap_or(Priority::none())
     }
 
     // pub fn get_height(
@@ -223,3 +588,304 @@ impl QuadTree {
     //     }
     // }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coordinates::PlanetDesc;
+    use test::Bencher;
+
+    const EARTH: Surface = Surface::Planet(PlanetDesc::EARTH);
+
+    /// Camera position `meters` along a path that starts 1 km above the center of a root node.
+    fn camera_path(meters: f64) -> mint::Point3<f64> {
+        let up = EARTH.roots()[0].center_wspace(&EARTH).normalize();
+        let tangent = up.cross(Vector3::new(0.3, 0.5, 0.8)).normalize();
+        let p = up * (PlanetDesc::EARTH.radius + 1000.0) + tangent * meters;
+        mint::Point3 { x: p.x, y: p.y, z: p.z }
+    }
+
+    fn height_range(_: VNode) -> (f32, f32) {
+        (0.0, 1000.0)
+    }
+
+    fn entry(priority: Priority, visible: bool) -> NodeEntry {
+        NodeEntry {
+            priority,
+            bounds: (Vector3::zero(), 0.0),
+            below_horizon: false,
+            recheck_travel: 0.0,
+            visible,
+            coarsened: false,
+        }
+    }
+
+    /// A quadtree where exactly the given nodes are visible.
+    fn visible_tree(max_rendered_nodes: usize, nodes: &[VNode]) -> QuadTree {
+        let mut quadtree = QuadTree::new(64, max_rendered_nodes, EARTH);
+        for &node in nodes {
+            quadtree.nodes.insert(node, entry(Priority::none(), true));
+            quadtree.visible.push(node);
+        }
+        quadtree
+    }
+
+    #[test]
+    fn test_coarsen() {
+        let root = EARTH.roots()[0];
+        let child = root.children()[0];
+        let nodes: Vec<VNode> = EARTH
+            .roots()
+            .iter()
+            .chain(&root.children())
+            .chain(&child.children())
+            .copied()
+            .collect();
+
+        let mut quadtree = visible_tree(12, &nodes);
+        assert_eq!(quadtree.count_rendered_nodes(), 12);
+        assert_eq!(quadtree.coarsen(), 0);
+
+        let mut quadtree = visible_tree(10, &nodes);
+        assert_eq!(quadtree.coarsen(), 4);
+        assert_eq!(quadtree.count_rendered_nodes(), 9);
+        assert!(quadtree.is_visible(child) && !quadtree.is_visible(child.children()[0]));
+        assert!(quadtree.nodes[&child.children()[0]].coarsened);
+
+        let mut quadtree = visible_tree(6, &nodes);
+        assert_eq!(quadtree.coarsen(), 8);
+        assert_eq!(quadtree.count_rendered_nodes(), 6);
+        assert!(quadtree.is_visible(root) && !quadtree.is_visible(child));
+    }
+
+    #[test]
+    fn test_coarse_edges() {
+        let root = EARTH.roots()[0];
+        let child = root.children()[0];
+        let nodes: Vec<VNode> = EARTH
+            .roots()
+            .iter()
+            .chain(&root.children())
+            .chain(&child.children())
+            .copied()
+            .collect();
+        let mut quadtree = visible_tree(1024, &nodes);
+
+        // The right neighbor of this node isn't in the tree, so its parent is drawn instead.
+        let node = child.children()[1];
+        let neighbor = root.children()[1].children()[0];
+        assert_eq!(node.neighbor(Edge::Right).node, neighbor);
+        let edges = [Edge::Left, Edge::Right, Edge::Top];
+        assert_eq!(quadtree.coarse_edges(node, &edges), 1 << Edge::Right as u32);
+
+        // A neighbor that is culled isn't drawn by its parent either.
+        quadtree.nodes.insert(neighbor, entry(Priority::cutoff(), false));
+        assert_eq!(quadtree.coarse_edges(node, &edges), 0);
+
+        quadtree.nodes.get_mut(&neighbor).unwrap().coarsened = true;
+        assert_eq!(quadtree.coarse_edges(node, &edges), 1 << Edge::Right as u32);
+
+        // Neighbors on the other faces are culled too.
+        let flat = Surface::Flat { extent: 1000.0 };
+        let mut quadtree = QuadTree::new(64, 1024, flat);
+        quadtree.nodes.insert(flat.roots()[0], entry(Priority::none(), true));
+        assert_eq!(quadtree.coarse_edges(flat.roots()[0], &Edge::ALL), 0);
+    }
+
+    /// Frustum for a camera at `camera` looking along the surface in the direction `angle` radians
+    /// around the vertical.
+    fn frustum(camera: mint::Point3<f64>, angle: f64) -> InfiniteFrustum {
+        let eye = Point3::new(camera.x, camera.y, camera.z);
+        let up = eye.to_vec().normalize();
+        let direction =
+            Basis3::from_axis_angle(up, Rad(angle)).rotate_vector(up.cross(Vector3::unit_z()));
+        let view = Matrix4::look_at_rh(Point3::origin(), Point3::from_vec(direction), up);
+        let translation = Matrix4::from_translation(-eye.to_vec());
+        InfiniteFrustum::from_matrix(
+            perspective(Deg(90.0), 16.0 / 9.0, 1.0, 1e8) * view * translation,
+        )
+    }
+
+    #[test]
+    fn test_incremental_visibility() {
+        let camera = camera_path(0.0);
+        let mut incremental = QuadTree::new(64, 1024, EARTH);
+        incremental.update_priorities_with(camera, &[], height_range);
+
+        for i in 0..8 {
+            let frustum = frustum(camera, i as f64 * 0.8);
+            incremental.update_visibility(&frustum, &[]);
+
+            let mut full = QuadTree::new(64, 1024, EARTH);
+            full.update_priorities_with(camera, &[], height_range);
+            full.update_visibility(&frustum, &[]);
+
+            let sorted = |mut nodes: Vec<VNode>| {
+                nodes.sort();
+                nodes
+            };
+            assert!(!full.visible_nodes.is_empty());
+            assert_eq!(
+                sorted(incremental.visible_nodes.clone()),
+                sorted(full.visible_nodes.clone())
+            );
+            assert_eq!(
+                sorted(
+                    incremental.nodes.iter().filter(|(_, e)| e.visible).map(|(n, _)| *n).collect()
+                ),
+                sorted(full.visible.clone())
+            );
+        }
+    }
+
+    #[test]
+    fn test_incremental_priorities() {
+        let mut incremental = QuadTree::new(64, 1024, EARTH);
+        for i in 0..50 {
+            let camera = camera_path(i as f64 * i as f64 * 20.0);
+            incremental.update_priorities_with(camera, &[], height_range);
+
+            let mut full = QuadTree::new(64, 1024, EARTH);
+            full.update_priorities_with(camera, &[], height_range);
+
+            // The distance bound used to skip nodes is only approximate, so ignore nodes right
+            // at the cutoff.
+            for (node, entry) in &full.nodes {
+                let cached = incremental.node_priority(*node);
+                if entry.priority > Priority::from_f32(1.01) {
+                    assert!(cached >= Priority::cutoff(), "{:?}", node);
+                } else if entry.priority < Priority::from_f32(0.99) {
+                    assert!(cached < Priority::cutoff(), "{:?}", node);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_height_range_changes() {
+        let camera = camera_path(0.0);
+        let mut incremental = QuadTree::new(64, 1024, EARTH);
+        incremental.update_priorities_with(camera, &[], height_range);
+
+        // Raise the terrain below one of the refined nodes near the camera.
+        let hill = *incremental
+            .nodes
+            .iter()
+            .find(|(node, entry)| node.level() == 6 && entry.refined(**node))
+            .unwrap()
+            .0;
+        let raised = move |node: VNode| {
+            let shift = node.level().saturating_sub(hill.level());
+            let below_hill = node.face() == hill.face()
+                && node.level() >= hill.level()
+                && node.x() >> shift == hill.x()
+                && node.y() >> shift == hill.y();
+            if below_hill {
+                (0.0, 8000.0)
+            } else {
+                height_range(node)
+            }
+        };
+        incremental.update_priorities_with(camera, &[hill], raised);
+
+        let mut full = QuadTree::new(64, 1024, EARTH);
+        full.update_priorities_with(camera, &[], raised);
+
+        assert_eq!(incremental.nodes.len(), full.nodes.len());
+        for (node, entry) in &full.nodes {
+            assert_eq!(incremental.node_priority(*node), entry.priority, "{:?}", node);
+        }
+    }
+
+    #[test]
+    fn test_rotation_keeps_priorities() {
+        let camera = camera_path(0.0);
+        let eye = Point3::new(camera.x, camera.y, camera.z);
+        let up = eye.to_vec().normalize();
+        let projection = perspective(Deg(90.0), 16.0 / 9.0, 1.0, 1e8);
+
+        let mut quadtree = QuadTree::new(64, 1024, EARTH);
+        for i in 0..100 {
+            // Look around in a circle, rounding the matrix to single precision like `render` does.
+            let angle = Rad(i as f64 * 0.1);
+            let direction =
+                Basis3::from_axis_angle(up, angle).rotate_vector(up.cross(Vector3::unit_z()));
+            let view = Matrix4::look_at_rh(eye, eye + direction, up);
+            let view_proj: Matrix4<f64> =
+                (projection * view).cast::<f32>().unwrap().cast().unwrap();
+
+            quadtree.set_lod(LodMetric {
+                pixel_scale: LodMetric::pixel_scale((1920, 1080), view_proj),
+                ..*quadtree.lod()
+            });
+            if i > 0 {
+                assert!(
+                    quadtree.last_camera_position.is_some(),
+                    "rebuilt after rotating {:?}",
+                    angle
+                );
+            }
+            quadtree.update_priorities_with(camera, &[], height_range);
+        }
+
+        // Resizing the viewport still forces priorities to be recomputed.
+        quadtree.set_lod(LodMetric {
+            pixel_scale: quadtree.lod().pixel_scale * 2.0,
+            ..*quadtree.lod()
+        });
+        assert!(quadtree.last_camera_position.is_none());
+    }
+
+    #[test]
+    fn test_flat_priorities() {
+        let surface = Surface::Flat { extent: 100000.0 };
+        let mut quadtree = QuadTree::new(64, 1024, surface);
+        let camera = mint::Point3 { x: 1000.0, y: 0.0, z: 0.0 };
+        quadtree.update_priorities_with(camera, &[], height_range);
+
+        let root = surface.roots()[0];
+        assert!(quadtree.nodes.keys().all(|node| node.face() == root.face()));
+        assert!(quadtree.nodes.values().all(|entry| !entry.below_horizon));
+        assert!(quadtree.node_priority(root) >= Priority::cutoff());
+
+        // Nodes under the camera are refined further than those at the edge of the surface.
+        let center = VNode::from_cspace(Vector3::new(1.0, 0.0, 0.0), 8).0;
+        let edge = VNode::from_cspace(Vector3::new(1.0, 1.0, 0.0), 8).0;
+        assert!(quadtree.node_priority(center) >= Priority::cutoff());
+        assert!(quadtree.node_priority(edge) < Priority::cutoff());
+    }
+
+    #[bench]
+    fn bench_update_priorities_full(b: &mut Bencher) {
+        let mut quadtree = QuadTree::new(64, 1024, EARTH);
+        b.iter(|| {
+            quadtree.last_camera_position = None;
+            quadtree.update_priorities_with(camera_path(0.0), &[], height_range);
+        });
+    }
+
+    #[bench]
+    fn bench_update_priorities_incremental(b: &mut Bencher) {
+        let mut quadtree = QuadTree::new(64, 1024, EARTH);
+        let mut meters = 0.0;
+        quadtree.update_priorities_with(camera_path(meters), &[], height_range);
+        b.iter(|| {
+            meters += 1.0;
+            quadtree.update_priorities_with(camera_path(meters), &[], height_range);
+        });
+    }
+
+    #[bench]
+    fn bench_update_visibility(b: &mut Bencher) {
+        let camera = camera_path(0.0);
+        let eye = Point3::new(camera.x, camera.y, camera.z);
+        let target = Point3::from(camera_path(5000.0)) * (1.0 - 2000.0 / PlanetDesc::EARTH.radius);
+        let view = Matrix4::look_at_rh(eye, target, eye.to_vec().normalize());
+        let frustum =
+            InfiniteFrustum::from_matrix(perspective(Deg(90.0), 16.0 / 9.0, 1.0, 1e8) * view);
+
+        let mut quadtree = QuadTree::new(64, 1024, EARTH);
+        quadtree.update_priorities_with(camera, &[], height_range);
+        b.iter(|| quadtree.update_visibility(&frustum, &[]));
+    }
+}
@@ -1,14 +1,85 @@
 use crate::cache::Priority;
-use crate::coordinates::PLANET_RADIUS;
-use crate::generate::{EARTH_CIRCUMFERENCE, EARTH_RADIUS};
-use crate::utils::math::InfiniteFrustum;
+use crate::coordinates::PlanetDesc;
+use crate::terrain::quadtree::lod::LodMetric;
+use crate::utils::math::{HorizonOccluder, InfiniteFrustum};
 use cgmath::*;
 use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
 
-const ROOT_SIDE_LENGTH: f32 = (EARTH_CIRCUMFERENCE * 0.25) as f32;
+/// Shape of the surface covered by the terrain.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Surface {
+    /// A planet, covered by the six faces of a cube projected onto a sphere.
+    Planet(PlanetDesc),
+    /// A square with sides `extent` meters long, covered by a single root node. It lies in the
+    /// plane tangent to the planet at 0°N 0°E with +x pointing up, +y east and +z north.
+    ///
+    /// The square shows a scale model of the face of Earth centered on that point, which is about
+    /// 10,000 km across. Heights are scaled by the same factor as horizontal distances (see
+    /// `Surface::scale`), so that slopes look the same as on the planet.
+    Flat { extent: f64 },
+}
+impl Surface {
+    /// Nodes at level zero, which together cover the whole surface.
+    pub(crate) fn roots(&self) -> &'static [VNode] {
+        match self {
+            Surface::Planet(_) => &ROOTS[..],
+            Surface::Flat { .. } => &ROOTS[..1],
+        }
+    }
+
+    pub(crate) fn is_flat(&self) -> bool {
+        matches!(self, Surface::Flat { .. })
+    }
+
+    /// The planet being rendered, or `None` if the surface is flat.
+    pub(crate) fn planet(&self) -> Option<&PlanetDesc> {
+        match self {
+            Surface::Planet(planet) => Some(planet),
+            Surface::Flat { .. } => None,
+        }
+    }
+
+    /// The planet whose elevation data covers this surface.
+    pub(crate) fn source_planet(&self) -> PlanetDesc {
+        match self {
+            Surface::Planet(planet) => *planet,
+            Surface::Flat { .. } => PlanetDesc::EARTH,
+        }
+    }
+
+    /// Ratio of lengths on this surface to the matching lengths on `source_planet`.
+    pub(crate) fn scale(&self) -> f64 {
+        match self {
+            Surface::Planet(_) => 1.0,
+            Surface::Flat { extent } => {
+                extent / Surface::Planet(self.source_planet()).root_side_length()
+            }
+        }
+    }
+
+    fn root_side_length(&self) -> f64 {
+        match self {
+            Surface::Planet(planet) => planet.radius * std::f64::consts::PI * 0.5,
+            Surface::Flat { extent } => *extent,
+        }
+    }
+}
+impl Default for Surface {
+    fn default() -> Self {
+        Surface::Planet(PlanetDesc::EARTH)
+    }
+}
 
 lazy_static! {
+    static ref ROOTS: [VNode; 6] = [
+        VNode::new(0, 0, 0, 0),
+        VNode::new(0, 1, 0, 0),
+        VNode::new(0, 2, 0, 0),
+        VNode::new(0, 3, 0, 0),
+        VNode::new(0, 4, 0, 0),
+        VNode::new(0, 5, 0, 0),
+    ];
     pub static ref OFFSETS: [Vector2<i32>; 4] =
         [Vector2::new(0, 0), Vector2::new(1, 0), Vector2::new(0, 1), Vector2::new(1, 1),];
     pub static ref CENTER_OFFSETS: [Vector2<i32>; 4] =
@@ -18,6 +89,33 @@ lazy_static! {
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash, Serialize, Deserialize)]
 pub(crate) struct VNode(u64);
 
+/// One of the four edges of a node, named by its position in the face coordinates of the node.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) enum Edge {
+    /// The edge with the smallest x coordinate.
+    Left = 0,
+    /// The edge with the largest x coordinate.
+    Right = 1,
+    /// The edge with the smallest y coordinate.
+    Bottom = 2,
+    /// The edge with the largest y coordinate.
+    Top = 3,
+}
+impl Edge {
+    pub const ALL: [Edge; 4] = [Edge::Left, Edge::Right, Edge::Bottom, Edge::Top];
+}
+
+/// A node sharing an edge with another node at the same level, possibly on a different face.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) struct Neighbor {
+    pub node: VNode,
+    /// Which edge of `node` is shared.
+    pub edge: Edge,
+    /// Whether the shared edge runs in opposite directions in the face coordinates of the two
+    /// nodes, which happens across some cube edges.
+    pub reversed: bool,
+}
+
 #[allow(unused)]
 impl VNode {
     // The cell sizes assume each face is covered by a texture with resolution 512x512.
@@ -72,24 +170,13 @@ impl VNode {
 }
 
 impl VNode {
-    fn new(level: u8, face: u8, x: u32, y: u32) -> Self {
+    pub(super) fn new(level: u8, face: u8, x: u32, y: u32) -> Self {
         debug_assert!(face < 6);
         debug_assert!(level <= VNode::LEVEL_CELL_5MM);
         debug_assert!(x <= 0x3ffffff && x < (1 << level));
         debug_assert!(y <= 0x3ffffff && y < (1 << level));
         Self((level as u64) << 56 | (face as u64) << 53 | (y as u64) << 26 | (x as u64))
     }
-    pub fn roots() -> [Self; 6] {
-        [
-            Self::new(0, 0, 0, 0),
-            Self::new(0, 1, 0, 0),
-            Self::new(0, 2, 0, 0),
-            Self::new(0, 3, 0, 0),
-            Self::new(0, 4, 0, 0),
-            Self::new(0, 5, 0, 0),
-        ]
-    }
-
     pub fn x(&self) -> u32 {
         self.0 as u32 & 0x3ffffff
     }
@@ -103,14 +190,38 @@ impl VNode {
         (self.0 >> 53) as u8 & 0x7
     }
 
-    pub fn aprox_side_length(&self) -> f32 {
-        ROOT_SIDE_LENGTH / (1u32 << self.level()) as f32
+    pub fn aprox_side_length(&self, surface: &Surface) -> f32 {
+        (surface.root_side_length() / (1u32 << self.level()) as f64) as f32
     }
 
     /// Minimum distance from the center of this node on the face of a cube with coordinates from
     /// [-1, 1].
-    pub fn min_distance(&self) -> f64 {
-        ROOT_SIDE_LENGTH as f64 * 2.0 / (1u32 << self.level()) as f64
+    pub fn min_distance(&self, surface: &Surface) -> f64 {
+        surface.root_side_length() * 2.0 / (1u32 << self.level()) as f64
+    }
+
+    /// Range of face coordinates covered by this node, as `(min_x, min_y, max_x, max_y)`.
+    fn fspace_bounds(&self) -> (f64, f64, f64, f64) {
+        let scale = 2.0 / (1u32 << self.level()) as f64;
+        let x = self.x() as f64 * scale - 1.0;
+        let y = self.y() as f64 * scale - 1.0;
+        (x, y, x + scale, y + scale)
+    }
+
+    /// Position on a flat surface with sides `extent` long of the given face coordinates, and of
+    /// `height` meters above sea level on the source planet.
+    fn fspace_to_flat(x: f64, y: f64, height: f64, extent: f64) -> Vector3<f64> {
+        let scale = Surface::Flat { extent }.scale();
+        Vector3::new(height * scale, x * extent * 0.5, -y * extent * 0.5)
+    }
+
+    /// Corners of the box containing this node on a flat surface.
+    fn flat_box(&self, height_range: (f32, f32), extent: f64) -> (Vector3<f64>, Vector3<f64>) {
+        let (x0, y0, x1, y1) = self.fspace_bounds();
+        (
+            Self::fspace_to_flat(x0, y1, height_range.0 as f64, extent),
+            Self::fspace_to_flat(x1, y0, height_range.1 as f64, extent),
+        )
     }
 
     fn fspace_to_cspace(&self, x: f64, y: f64) -> Vector3<f64> {
@@ -128,6 +239,34 @@ impl VNode {
         }
     }
 
+    /// Same as `fspace_to_cspace`, except that points beyond the edges of the face are folded over
+    /// onto the neighboring face. This way skirt samples land on the positions of the matching
+    /// samples in the nodes across the edge instead of on the extended plane of this face.
+    fn fspace_to_cspace_wrapped(&self, x: f64, y: f64) -> Vector3<f64> {
+        let (edge, excess, along) = if x < -1.0 {
+            (Edge::Left, -1.0 - x, y)
+        } else if x > 1.0 {
+            (Edge::Right, x - 1.0, y)
+        } else if y < -1.0 {
+            (Edge::Bottom, -1.0 - y, x)
+        } else if y > 1.0 {
+            (Edge::Top, y - 1.0, x)
+        } else {
+            return self.fspace_to_cspace(x, y);
+        };
+
+        let neighbor = VNode::new(0, self.face(), 0, 0).neighbor(edge);
+        let inward = 1.0 - excess;
+        let along = if neighbor.reversed { -along } else { along }.max(-1.0).min(1.0);
+        let (x, y) = match neighbor.edge {
+            Edge::Left => (-inward, along),
+            Edge::Right => (inward, along),
+            Edge::Bottom => (along, -inward),
+            Edge::Top => (along, inward),
+        };
+        neighbor.node.fspace_to_cspace(x, y)
+    }
+
     /// Interpolate position on this node assuming a grid with given `resolution` and surrounded by
     /// `skirt` cells outside the borders on each edge (but counted in resolution). Assumes [grid
     /// registration](https://www.ngdc.noaa.gov/mgg/global/gridregistration.html). Used for
@@ -156,7 +295,7 @@ impl VNode {
 
         let fx = (self.x() as f64 + fx) * scale - 1.0;
         let fy = (self.y() as f64 + fy) * scale - 1.0;
-        self.fspace_to_cspace(fx, fy)
+        self.fspace_to_cspace_wrapped(fx, fy)
     }
 
     /// Same as `position_cspace_corners` but uses "cell registration". Used for textures/normalmaps.
@@ -182,7 +321,7 @@ impl VNode {
 
         let fx = (self.x() as f64 + fx) * scale - 1.0;
         let fy = (self.y() as f64 + fy) * scale - 1.0;
-        self.fspace_to_cspace(fx, fy)
+        self.fspace_to_cspace_wrapped(fx, fy)
     }
 
     fn cspace_to_fspace(cspace: Vector3<f64>) -> (u8, f64, f64) {
@@ -208,17 +347,41 @@ impl VNode {
         let x = (x * 0.5 + 0.5) * (1u32 << level) as f64;
         let y = (y * 0.5 + 0.5) * (1u32 << level) as f64;
 
-        let node = VNode::new(level, face, x.floor() as u32, y.floor() as u32);
-        (node, x.fract() as f32, y.fract() as f32)
+        // Points on the far edge of the face belong to the last node rather than one past it.
+        let max = (1u32 << level) - 1;
+        let (nx, ny) = ((x.floor() as u32).min(max), (y.floor() as u32).min(max));
+        let node = VNode::new(level, face, nx, ny);
+        (node, (x - nx as f64) as f32, (y - ny as f64) as f32)
     }
 
-    pub fn center_wspace(&self) -> Vector3<f64> {
-        self.cell_position_cspace(0, 0, 0, 1).normalize() * crate::coordinates::PLANET_RADIUS
+    pub fn center_wspace(&self, surface: &Surface) -> Vector3<f64> {
+        match *surface {
+            Surface::Planet(planet) => {
+                self.cell_position_cspace(0, 0, 0, 1).normalize() * planet.radius
+            }
+            Surface::Flat { extent } => {
+                let (x0, y0, x1, y1) = self.fspace_bounds();
+                Self::fspace_to_flat((x0 + x1) * 0.5, (y0 + y1) * 0.5, 0.0, extent)
+            }
+        }
     }
 
-    fn distance2(&self, point: Vector3<f64>, height_range: (f32, f32)) -> f64 {
-        let min_radius = EARTH_RADIUS + height_range.0 as f64;
-        let max_radius = EARTH_RADIUS + height_range.1 as f64;
+    fn distance2(&self, surface: &Surface, point: Vector3<f64>, height_range: (f32, f32)) -> f64 {
+        let radius = match *surface {
+            Surface::Planet(planet) => planet.radius,
+            Surface::Flat { extent } => {
+                let (min, max) = self.flat_box(height_range, extent);
+                let clamped = Vector3::new(
+                    point.x.max(min.x).min(max.x),
+                    point.y.max(min.y).min(max.y),
+                    point.z.max(min.z).min(max.z),
+                );
+                return clamped.distance2(point);
+            }
+        };
+
+        let min_radius = radius + height_range.0 as f64;
+        let max_radius = radius + height_range.1 as f64;
 
         let corners = [
             self.grid_position_cspace(0, 0, 0, 2),
@@ -279,7 +442,21 @@ impl VNode {
         d2
     }
 
-    pub fn in_frustum(&self, f: &InfiniteFrustum, height_range: (f32, f32)) -> bool {
+    /// Returns the center and squared radius of a sphere bounding this node, assuming its heights
+    /// are within `height_range`.
+    pub fn bounding_sphere(
+        &self,
+        surface: &Surface,
+        height_range: (f32, f32),
+    ) -> (Vector3<f64>, f64) {
+        let radius = match *surface {
+            Surface::Planet(planet) => planet.radius,
+            Surface::Flat { extent } => {
+                let (min, max) = self.flat_box(height_range, extent);
+                return ((min + max) * 0.5, (max - min).magnitude2() * 0.25);
+            }
+        };
+
         let corners = [
             self.grid_position_cspace(0, 0, 0, 2).normalize(),
             self.grid_position_cspace(1, 0, 0, 2).normalize(),
@@ -289,24 +466,60 @@ impl VNode {
 
         let center = self
             .cell_position_cspace(0, 0, 0, 1)
-            .normalize_to(EARTH_RADIUS + (height_range.0 as f64 + height_range.1 as f64) * 0.5);
+            .normalize_to(radius + (height_range.0 as f64 + height_range.1 as f64) * 0.5);
 
         let mut radius2 = 0.0f64;
         for &c in &corners {
-            radius2 = radius2.max(center.distance2(c * (EARTH_RADIUS + height_range.0 as f64)));
-            radius2 = radius2.max(center.distance2(c * (EARTH_RADIUS + height_range.1 as f64)));
+            radius2 = radius2.max(center.distance2(c * (radius + height_range.0 as f64)));
+            radius2 = radius2.max(center.distance2(c * (radius + height_range.1 as f64)));
         }
 
+        (center, radius2)
+    }
+
+    pub fn in_frustum(
+        &self,
+        surface: &Surface,
+        f: &InfiniteFrustum,
+        height_range: (f32, f32),
+    ) -> bool {
+        let (center, radius2) = self.bounding_sphere(surface, height_range);
         f.intersects_sphere(center, radius2)
     }
 
+    pub fn below_horizon(
+        &self,
+        surface: &Surface,
+        horizon: &HorizonOccluder,
+        height_range: (f32, f32),
+    ) -> bool {
+        let (center, radius2) = self.bounding_sphere(surface, height_range);
+        horizon.occludes_sphere(center, radius2.sqrt())
+    }
+
+    /// Distance from `point` to the closest point within this node.
+    pub(super) fn distance(
+        &self,
+        surface: &Surface,
+        point: Vector3<f64>,
+        height_range: (f32, f32),
+    ) -> f64 {
+        self.distance2(surface, point, height_range).sqrt()
+    }
+
     /// How much this node is needed for the current frame. Nodes with priority less than 1.0 will
     /// not be rendered (they are too detailed).
-    pub(super) fn priority(&self, camera: Vector3<f64>, height_range: (f32, f32)) -> Priority {
-        let min_distance = self.min_distance();
-        let distance2 = self.distance2(camera, height_range);
-
-        Priority::from_f32(((min_distance * min_distance) / distance2.max(1e-12)) as f32)
+    pub(super) fn priority(
+        &self,
+        surface: &Surface,
+        camera: Vector3<f64>,
+        height_range: (f32, f32),
+        lod: &LodMetric,
+    ) -> Priority {
+        let min_distance = lod.min_distance(surface, *self);
+        let distance2 = self.distance2(surface, camera, height_range);
+
+        Priority::from_f32((min_distance / distance2.max(1e-12).sqrt()) as f32)
     }
 
     pub fn parent(&self) -> Option<(VNode, u8)> {
@@ -327,6 +540,77 @@ impl VNode {
         ]
     }
 
+    /// Returns the node at the same level that shares the given edge with this one. Neighbors on
+    /// other faces are found by stepping just over the edge of the cube and mapping the result
+    /// back to face coordinates.
+    pub fn neighbor(&self, edge: Edge) -> Neighbor {
+        let n = 1i64 << self.level();
+        let (x, y) = (self.x() as i64, self.y() as i64);
+        let (nx, ny) = match edge {
+            Edge::Left => (x - 1, y),
+            Edge::Right => (x + 1, y),
+            Edge::Bottom => (x, y - 1),
+            Edge::Top => (x, y + 1),
+        };
+        if nx >= 0 && nx < n && ny >= 0 && ny < n {
+            let opposite = match edge {
+                Edge::Left => Edge::Right,
+                Edge::Right => Edge::Left,
+                Edge::Bottom => Edge::Top,
+                Edge::Top => Edge::Bottom,
+            };
+            return Neighbor {
+                node: VNode::new(self.level(), self.face(), nx as u32, ny as u32),
+                edge: opposite,
+                reversed: false,
+            };
+        }
+
+        // Find the node and position within it of the point a fraction `t` along the edge.
+        let locate = |t: f64| {
+            let (fx, fy) = match edge {
+                Edge::Left => (0.0, t),
+                Edge::Right => (1.0, t),
+                Edge::Bottom => (t, 0.0),
+                Edge::Top => (t, 1.0),
+            };
+            let scale = 2.0 / n as f64;
+            let mut p =
+                self.fspace_to_cspace((x as f64 + fx) * scale - 1.0, (y as f64 + fy) * scale - 1.0);
+
+            // Pull the point off of this face by a fraction of a cell, then project it back onto
+            // the surface of the cube where it lands on the neighboring face.
+            p[self.face() as usize / 2] *= 1.0 - 0.5 / n as f64;
+            let p = p / p.x.abs().max(p.y.abs()).max(p.z.abs());
+            VNode::from_cspace(p, self.level())
+        };
+
+        let (node, fx, fy) = locate(0.5);
+        let distances = [fx, 1.0 - fx, fy, 1.0 - fy];
+        let mut edge = Edge::Left;
+        for &e in &Edge::ALL {
+            if distances[e as usize] < distances[edge as usize] {
+                edge = e;
+            }
+        }
+
+        let (_, ax, ay) = locate(0.25);
+        let along = match edge {
+            Edge::Left | Edge::Right => ay,
+            Edge::Bottom | Edge::Top => ax,
+        };
+        Neighbor { node, edge, reversed: along > 0.5 }
+    }
+
+    pub fn neighbors(&self) -> [Neighbor; 4] {
+        [
+            self.neighbor(Edge::Left),
+            self.neighbor(Edge::Right),
+            self.neighbor(Edge::Bottom),
+            self.neighbor(Edge::Top),
+        ]
+    }
+
     pub fn find_ancestor<Visit>(&self, mut visit: Visit) -> Option<(VNode, usize, Vector2<u32>)>
     where
         Visit: FnMut(VNode) -> bool,
@@ -345,12 +629,12 @@ impl VNode {
         Some((node, generations, offset))
     }
 
-    pub fn breadth_first<Visit>(mut visit: Visit)
+    pub fn breadth_first<Visit>(surface: &Surface, mut visit: Visit)
     where
         Visit: FnMut(VNode) -> bool,
     {
         let mut pending = VecDeque::new();
-        for &n in &Self::roots() {
+        for &n in surface.roots() {
             if visit(n) {
                 pending.push_back(n);
             }
@@ -384,12 +668,188 @@ impl std::fmt::Display for VNode {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::terrain::quadtree::lod::LodQuality;
+    use approx::assert_relative_eq;
+
     #[test]
     fn test_distance() {
         let node = VNode::new(1, 1, 0, 0);
-        let camera = Vector3::new(1., 0., 1.);
+        let lod = LodMetric::new(64);
 
-        let p = node.priority(camera);
+        let surface = Surface::Planet(PlanetDesc::EARTH);
+        let camera = node.center_wspace(&surface);
+
+        let p = node.priority(&surface, camera, (0.0, 0.0), &lod);
         assert!(p > Priority::cutoff());
+
+        let far = VNode::new(10, 0, 0, 0);
+        assert!(far.priority(&surface, camera, (0.0, 0.0), &lod) < Priority::cutoff());
+    }
+
+    #[test]
+    fn test_quality_presets() {
+        let node = VNode::new(8, 2, 17, 45);
+        let camera = node.center_wspace(&Surface::Planet(PlanetDesc::EARTH)) * 1.001;
+
+        let priority = |quality: LodQuality| {
+            let lod =
+                LodMetric { max_pixel_error: quality.max_pixel_error(), ..LodMetric::new(64) };
+            node.priority(&Surface::Planet(PlanetDesc::EARTH), camera, (0.0, 0.0), &lod)
+        };
+        assert!(priority(LodQuality::Low) < priority(LodQuality::Medium));
+        assert!(priority(LodQuality::Medium) < priority(LodQuality::High));
+        assert!(priority(LodQuality::High) < priority(LodQuality::Ultra));
+    }
+
+    #[test]
+    fn test_below_horizon() {
+        let node = VNode::new(6, 0, 31, 31);
+        let surface = Surface::Planet(PlanetDesc::EARTH);
+        let radius = PlanetDesc::EARTH.radius;
+        let camera = node.center_wspace(&surface) * (1.0 + 1000.0 / radius);
+        let horizon = HorizonOccluder::new(camera, Vector3::new(radius, radius, radius));
+
+        assert!(!node.below_horizon(&surface, &horizon, (0.0, 0.0)));
+        let below = |node: &VNode| node.below_horizon(&surface, &horizon, (0.0, 9000.0));
+        for &root in &surface.roots()[1..] {
+            assert!(root.children().iter().any(below));
+        }
+        assert!(surface.roots()[1].below_horizon(&surface, &horizon, (0.0, 9000.0)));
+    }
+
+    #[test]
+    fn test_planet_size() {
+        let earth = Surface::Planet(PlanetDesc::EARTH);
+        let mars = Surface::Planet(PlanetDesc::MARS);
+        let scale = PlanetDesc::MARS.radius / PlanetDesc::EARTH.radius;
+
+        let node = VNode::new(5, 3, 12, 20);
+        assert_relative_eq!(node.center_wspace(&mars).magnitude(), PlanetDesc::MARS.radius);
+        assert_relative_eq!(
+            node.aprox_side_length(&mars) as f64,
+            node.aprox_side_length(&earth) as f64 * scale,
+            max_relative = 1e-6
+        );
+
+        let camera = node.center_wspace(&mars) * 1.001;
+        let (center, radius2) = node.bounding_sphere(&mars, (-8000.0, 20000.0));
+        assert!(center.distance2(camera) < radius2);
+        assert_relative_eq!(
+            node.distance(&mars, camera, (0.0, 0.0)),
+            node.distance(&earth, camera / scale, (0.0, 0.0)) * scale,
+            max_relative = 1e-6
+        );
+    }
+
+    #[test]
+    fn test_flat_surface() {
+        let surface = Surface::Flat { extent: 100000.0 };
+        let root = surface.roots()[0];
+        assert_eq!(surface.roots().len(), 1);
+        assert_eq!(root.center_wspace(&surface), Vector3::new(0.0, 0.0, 0.0));
+        assert_eq!(root.aprox_side_length(&surface), 100000.0);
+
+        // Children are ordered by increasing face coordinates, which point east and south.
+        let children = root.children();
+        assert_eq!(children[0].center_wspace(&surface), Vector3::new(0.0, -25000.0, 25000.0));
+        assert_eq!(children[3].center_wspace(&surface), Vector3::new(0.0, 25000.0, -25000.0));
+
+        // Height ranges are in meters on the planet, which are scaled down along with the face.
+        let scale = 100000.0 / (PlanetDesc::EARTH.radius * std::f64::consts::FRAC_PI_2);
+        assert_relative_eq!(surface.scale(), scale);
+        let meters = |height: f64| (height / scale) as f32;
+
+        let above = Vector3::new(2000.0, 100.0, -300.0);
+        assert_relative_eq!(
+            root.distance(&surface, above, (0.0, meters(500.0))),
+            1500.0,
+            max_relative = 1e-6
+        );
+        assert_relative_eq!(
+            children[1].distance(&surface, above, (0.0, meters(2000.0))),
+            300.0,
+            max_relative = 1e-6
+        );
+
+        let (center, radius2) = children[2].bounding_sphere(&surface, (-10.0, 10.0));
+        for &(x, y) in &[(-1.0, 0.0), (0.0, 0.0), (-1.0, 1.0), (0.0, 1.0)] {
+            for &height in &[-10.0, 10.0] {
+                let corner = VNode::fspace_to_flat(x, y, height, 100000.0);
+                assert!(center.distance2(corner) <= radius2 * (1.0 + 1e-12));
+            }
+        }
+    }
+
+    #[test]
+    fn test_skirts_wrap() {
+        let (skirt, resolution) = (4, 25);
+        let cells = (resolution - 1 - 2 * skirt) as f32;
+        for face in 0..6 {
+            let node = VNode::new(2, face, 3, 1);
+            let neighbor = node.neighbor(Edge::Right).node;
+            for x in resolution - skirt..resolution {
+                for y in skirt..resolution - skirt {
+                    // Skirt samples past the edge of the face must be samples of the neighbor.
+                    let cspace = node.grid_position_cspace(x as i32, y as i32, skirt, resolution);
+                    let (n, fx, fy) = VNode::from_cspace(cspace, node.level());
+                    assert_eq!(n, neighbor);
+                    assert_relative_eq!(fx * cells, (fx * cells).round(), epsilon = 1e-3);
+                    assert_relative_eq!(fy * cells, (fy * cells).round(), epsilon = 1e-3);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_neighbors() {
+        let node = VNode::new(3, 2, 4, 5);
+        assert_eq!(
+            node.neighbor(Edge::Right),
+            Neighbor { node: VNode::new(3, 2, 5, 5), edge: Edge::Left, reversed: false }
+        );
+        assert_eq!(
+            node.neighbor(Edge::Bottom),
+            Neighbor { node: VNode::new(3, 2, 4, 4), edge: Edge::Top, reversed: false }
+        );
+
+        // Corners of an edge in increasing order of face coordinates.
+        let corners = |node: VNode, edge: Edge| {
+            let (a, b) = match edge {
+                Edge::Left => ((0, 0), (0, 1)),
+                Edge::Right => ((1, 0), (1, 1)),
+                Edge::Bottom => ((0, 0), (1, 0)),
+                Edge::Top => ((0, 1), (1, 1)),
+            };
+            (node.grid_position_cspace(a.0, a.1, 0, 2), node.grid_position_cspace(b.0, b.1, 0, 2))
+        };
+
+        for &level in &[0, 1, 3, VNode::LEVEL_CELL_5MM] {
+            let n = 1u32 << level;
+            for face in 0..6 {
+                for &(x, y) in &[(0, 0), (n - 1, n - 1), (n / 2, 0), (0, n / 2)] {
+                    let node = VNode::new(level, face, x, y);
+                    for &edge in &Edge::ALL {
+                        let neighbor = node.neighbor(edge);
+                        assert_eq!(neighbor.node.level(), level);
+                        assert_eq!(
+                            neighbor.node.neighbor(neighbor.edge),
+                            Neighbor { node, edge, reversed: neighbor.reversed }
+                        );
+
+                        let (a, b) = corners(node, edge);
+                        let (mut c, mut d) = corners(neighbor.node, neighbor.edge);
+                        if neighbor.reversed {
+                            std::mem::swap(&mut c, &mut d);
+                        }
+                        assert!(
+                            a.distance(c) < 1e-9 && b.distance(d) < 1e-9,
+                            "{} {:?}",
+                            node,
+                            edge
+                        );
+                    }
+                }
+            }
+        }
     }
 }
@@ -1,5 +1,9 @@
 use super::*;
-use crate::cache::{CacheLookup, LayerType, SingularLayerType, UnifiedPriorityCache};
+use crate::cache::{
+    CacheLookup, LayerType, SingularLayerType, UnifiedPriorityCache, MAX_CUSTOM_LAYERS,
+};
+use crate::gpu_state::{DrawIndexedIndirect, GpuState};
+use maplit::hashmap;
 use std::mem;
 
 #[derive(Copy, Clone)]
@@ -9,6 +13,8 @@ pub(crate) struct NodeState {
     albedo_desc: [[f32; 4]; 2],
     roughness_desc: [[f32; 4]; 2],
     normals_desc: [[f32; 4]; 2],
+    horizons_desc: [[f32; 4]; 2],
+    water_desc: [[f32; 4]; 2],
     grass_canopy_desc: [f32; 4],
     resolution: u32,
     face: u32,
@@ -17,7 +23,15 @@ pub(crate) struct NodeState {
     relative_position: [f32; 3],
     min_distance: f32,
     parent_relative_position: [f32; 3],
-    _padding1: [u32; 17],
+    /// Bitmask of the edges (indexed by `Edge`) along which adjacent terrain is drawn at a coarser
+    /// level, so vertices there must be fully morphed to avoid cracks.
+    coarse_edges: u32,
+    /// Bounding sphere of the drawn area relative to the camera, used for occlusion culling.
+    bounds: [f32; 4],
+    /// Bitmask of the layers (displacements, albedo, roughness, normals) for which the tile of an
+    /// ancestor is used because the node's own tile isn't available.
+    ancestor_layers: u32,
+    _padding2: [u32; 3],
     // side_length: f32,
     // padding0: f32,
     // padding1: u32,
@@ -25,7 +39,16 @@ pub(crate) struct NodeState {
 unsafe impl bytemuck::Pod for NodeState {}
 unsafe impl bytemuck::Zeroable for NodeState {}
 
-const MAX_RENDERED_NODES: usize = 1024;
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub(crate) struct CullNodesUniforms {
+    num_nodes: u32,
+    num_full_nodes: u32,
+    full_indices: u32,
+    partial_indices: u32,
+}
+unsafe impl bytemuck::Pod for CullNodesUniforms {}
+unsafe impl bytemuck::Zeroable for CullNodesUniforms {}
 
 impl QuadTree {
     pub fn find_descs(
@@ -89,10 +112,97 @@ impl QuadTree {
         [offset.x, offset.y, lookup.slot as f32, scale * texture_step]
     }
 
+    /// Compute the descriptors for a layer with its own texture resolution and border, or
+    /// descriptors with a slot of -1 if no ancestor of `node` has a tile for it.
+    fn find_layer_descs(
+        node: VNode,
+        cache: &UnifiedPriorityCache,
+        ty: LayerType,
+        base_origin: Vector2<f32>,
+        resolution: u32,
+    ) -> [[f32; 4]; 2] {
+        if node.find_ancestor(|n| cache.tiles.contains(n, ty)).is_none() {
+            return [[0.0, 0.0, -1.0, 0.0]; 2];
+        }
+
+        let texture_resolution = cache.tile_desc(ty).texture_resolution;
+        let texture_border = cache.tile_desc(ty).texture_border_size;
+        let texture_ratio =
+            (texture_resolution - 2 * texture_border) as f32 / texture_resolution as f32;
+        let texture_origin = texture_border as f32 / texture_resolution as f32;
+        Self::find_descs(
+            node,
+            cache,
+            ty,
+            Vector2::new(texture_origin, texture_origin),
+            base_origin,
+            texture_ratio,
+            texture_ratio / resolution as f32,
+        )
+        .0
+    }
+
+    /// Compute the descriptors for every custom layer, indexed by their position within the
+    /// `custom_layer_descs` buffer.
+    fn find_custom_descs(
+        node: VNode,
+        cache: &UnifiedPriorityCache,
+        base_origin: Vector2<f32>,
+        resolution: u32,
+    ) -> [[[f32; 4]; 2]; MAX_CUSTOM_LAYERS] {
+        let mut descs = [[[0.0, 0.0, -1.0, 0.0]; 2]; MAX_CUSTOM_LAYERS];
+        for layer in cache.custom_layers() {
+            let i = match layer.layer_type {
+                LayerType::Custom(i) => i as usize,
+                _ => unreachable!(),
+            };
+            descs[i] =
+                Self::find_layer_descs(node, cache, layer.layer_type, base_origin, resolution);
+        }
+        descs
+    }
+
+    /// Bounding sphere of `node`, relative to the camera.
+    fn node_bounds(
+        surface: &Surface,
+        node: VNode,
+        cache: &UnifiedPriorityCache,
+        camera: mint::Point3<f64>,
+    ) -> [f32; 4] {
+        let (center, radius2) = node.bounding_sphere(surface, cache.tiles.get_height_range(node));
+        let center = center - Vector3::new(camera.x, camera.y, camera.z);
+        [center.x as f32, center.y as f32, center.z as f32, radius2.sqrt() as f32]
+    }
+
+    /// Compute which of the given edges of `node` border terrain that is drawn at a coarser level.
+    /// Neighbors that are culled aren't drawn at any level, so they don't make an edge coarse.
+    fn coarse_edges(&self, node: VNode, edges: &[Edge]) -> u32 {
+        let mut mask = 0;
+        for &edge in edges {
+            if self.drawn_by_ancestor(node.neighbor(edge).node) {
+                mask |= 1 << edge as u32;
+            }
+        }
+        mask
+    }
+
+    /// Bitmask of which of the nodes that a node's layers were found in (see `find_descs`) are
+    /// ancestors of it rather than the node itself.
+    fn ancestor_layers(node: VNode, sources: [VNode; 4]) -> u32 {
+        let mut mask = 0;
+        for (i, source) in sources.iter().enumerate() {
+            if *source != node {
+                mask |= 1 << i;
+            }
+        }
+        mask
+    }
+
     pub fn prepare_vertex_buffer(
         &mut self,
         queue: &wgpu::Queue,
         vertex_buffer: &wgpu::Buffer,
+        custom_layer_descs_buffer: &wgpu::Buffer,
         cache: &UnifiedPriorityCache,
         camera: mint::Point3<f64>,
     ) {
@@ -114,8 +224,9 @@ impl QuadTree {
         let texture_origin = texture_border as f32 / texture_resolution as f32;
 
         self.node_states.clear();
+        self.custom_layer_descs.clear();
         for &node in self.visible_nodes.iter() {
-            assert!(node.min_distance() as f32 != 0.0);
+            assert!(self.lod.min_distance(&self.surface, node) as f32 != 0.0);
             let (displacements_desc, displacements_node) = Self::find_descs(
                 node,
                 &cache,
@@ -125,7 +236,7 @@ impl QuadTree {
                 resolution as f32 / (resolution + 1) as f32,
                 1.0 / (resolution + 1) as f32,
             );
-            let albedo_desc = Self::find_descs(
+            let (albedo_desc, albedo_node) = Self::find_descs(
                 node,
                 &cache,
                 LayerType::Albedo,
@@ -133,9 +244,8 @@ impl QuadTree {
                 Vector2::new(0.0, 0.0),
                 texture_ratio,
                 texture_step,
-            )
-            .0;
-            let roughness_desc = Self::find_descs(
+            );
+            let (roughness_desc, roughness_node) = Self::find_descs(
                 node,
                 &cache,
                 LayerType::Roughness,
@@ -143,9 +253,8 @@ impl QuadTree {
                 Vector2::new(0.0, 0.0),
                 texture_ratio,
                 texture_step,
-            )
-            .0;
-            let normals_desc = Self::find_descs(
+            );
+            let (normals_desc, normals_node) = Self::find_descs(
                 node,
                 &cache,
                 LayerType::Normals,
@@ -153,8 +262,7 @@ impl QuadTree {
                 Vector2::new(0.0, 0.0),
                 texture_ratio,
                 texture_step,
-            )
-            .0;
+            );
             let grass_canopy_desc = cache
                 .lookup_texture(SingularLayerType::GrassCanopy, node)
                 .map(|lookup| {
@@ -167,26 +275,58 @@ impl QuadTree {
                     )
                 })
                 .unwrap_or([0.0, 0.0, -1.0, 0.0]);
+            let horizons_desc = Self::find_layer_descs(
+                node,
+                cache,
+                LayerType::Horizons,
+                Vector2::new(0.0, 0.0),
+                resolution,
+            );
+            let water_desc = Self::find_layer_descs(
+                node,
+                cache,
+                LayerType::Water,
+                Vector2::new(0.0, 0.0),
+                resolution,
+            );
+            self.custom_layer_descs.extend_from_slice(&Self::find_custom_descs(
+                node,
+                cache,
+                Vector2::new(0.0, 0.0),
+                resolution,
+            ));
             let node_index = self.node_states.len() as u32;
             self.node_states.push(NodeState {
-                _padding1: [0; 17],
-                min_distance: node.min_distance() as f32,
+                coarse_edges: self.coarse_edges(node, &Edge::ALL),
+                bounds: Self::node_bounds(&self.surface, node, cache, camera),
+                ancestor_layers: Self::ancestor_layers(
+                    node,
+                    [displacements_node, albedo_node, roughness_node, normals_node],
+                ),
+                _padding2: [0; 3],
+                min_distance: self.lod.min_distance(&self.surface, node) as f32,
                 displacements_desc,
                 albedo_desc,
                 roughness_desc,
                 normals_desc,
+                horizons_desc,
+                water_desc,
                 grass_canopy_desc,
                 resolution,
                 face: node.face() as u32,
                 level: node.level() as u32,
                 node_index,
                 relative_position: (cgmath::Point3::from(camera)
-                    - displacements_node.center_wspace())
+                    - displacements_node.center_wspace(&self.surface))
                 .cast::<f32>()
                 .unwrap()
                 .into(),
                 parent_relative_position: (cgmath::Point3::from(camera)
-                    - displacements_node.parent().map(|x| x.0).unwrap_or(node).center_wspace())
+                    - displacements_node
+                        .parent()
+                        .map(|x| x.0)
+                        .unwrap_or(node)
+                        .center_wspace(&self.surface))
                 .cast::<f32>()
                 .unwrap()
                 .into(),
@@ -194,7 +334,7 @@ impl QuadTree {
         }
         for &(node, mask) in self.partially_visible_nodes.iter() {
             assert!(mask < 15);
-            assert!(node.min_distance() as f32 != 0.0);
+            assert!(self.lod.min_distance(&self.surface, node) as f32 != 0.0);
             for i in 0..4u8 {
                 if mask & (1 << i) != 0 {
                     let offset = ((i % 2) as f32, (i / 2) as f32);
@@ -208,7 +348,7 @@ impl QuadTree {
                         resolution as f32 / (resolution + 1) as f32,
                         1.0 / (resolution + 1) as f32,
                     );
-                    let albedo_desc = Self::find_descs(
+                    let (albedo_desc, albedo_node) = Self::find_descs(
                         node,
                         &cache,
                         LayerType::Albedo,
@@ -216,9 +356,8 @@ impl QuadTree {
                         base_origin,
                         texture_ratio,
                         texture_step,
-                    )
-                    .0;
-                    let roughness_desc = Self::find_descs(
+                    );
+                    let (roughness_desc, roughness_node) = Self::find_descs(
                         node,
                         &cache,
                         LayerType::Roughness,
@@ -226,9 +365,8 @@ impl QuadTree {
                         base_origin,
                         texture_ratio,
                         texture_step,
-                    )
-                    .0;
-                    let normals_desc = Self::find_descs(
+                    );
+                    let (normals_desc, normals_node) = Self::find_descs(
                         node,
                         &cache,
                         LayerType::Normals,
@@ -236,8 +374,7 @@ impl QuadTree {
                         base_origin,
                         texture_ratio,
                         texture_step,
-                    )
-                    .0;
+                    );
                     let grass_canopy_desc = cache
                         .lookup_texture(SingularLayerType::GrassCanopy, node)
                         .map(|lookup| {
@@ -250,22 +387,61 @@ impl QuadTree {
                             )
                         })
                         .unwrap_or([0.0, 0.0, -1.0, 0.0]);
+                    let horizons_desc = Self::find_layer_descs(
+                        node,
+                        cache,
+                        LayerType::Horizons,
+                        base_origin,
+                        resolution,
+                    );
+                    let water_desc = Self::find_layer_descs(
+                        node,
+                        cache,
+                        LayerType::Water,
+                        base_origin,
+                        resolution,
+                    );
+                    self.custom_layer_descs.extend_from_slice(&Self::find_custom_descs(
+                        node,
+                        cache,
+                        base_origin,
+                        resolution,
+                    ));
                     let node_index = self.node_states.len() as u32;
+                    // Only the edges of the quarter on the boundary of `node` can border
+                    // coarser terrain. Its other edges are shared with siblings.
+                    let edges = [
+                        if i % 2 == 0 { Edge::Left } else { Edge::Right },
+                        if i / 2 == 0 { Edge::Bottom } else { Edge::Top },
+                    ];
                     self.node_states.push(NodeState {
-                        _padding1: [0; 17],
+                        coarse_edges: self.coarse_edges(node, &edges),
+                        bounds: Self::node_bounds(
+                            &self.surface,
+                            node.children()[i as usize],
+                            cache,
+                            camera,
+                        ),
+                        ancestor_layers: Self::ancestor_layers(
+                            node,
+                            [displacements_node, albedo_node, roughness_node, normals_node],
+                        ),
+                        _padding2: [0; 3],
                         // side_length: node.side_length() * 0.5,
-                        min_distance: node.min_distance() as f32,
+                        min_distance: self.lod.min_distance(&self.surface, node) as f32,
                         displacements_desc,
                         albedo_desc,
                         roughness_desc,
                         normals_desc,
+                        horizons_desc,
+                        water_desc,
                         grass_canopy_desc,
                         resolution: resolution / 2,
                         face: node.face() as u32,
                         level: node.level() as u32,
                         node_index,
                         relative_position: (cgmath::Point3::from(camera)
-                            - displacements_node.center_wspace())
+                            - displacements_node.center_wspace(&self.surface))
                         .cast::<f32>()
                         .unwrap()
                         .into(),
@@ -274,7 +450,7 @@ impl QuadTree {
                                 .parent()
                                 .map(|x| x.0)
                                 .unwrap_or(node)
-                                .center_wspace())
+                                .center_wspace(&self.surface))
                         .cast::<f32>()
                         .unwrap()
                         .into(),
@@ -283,31 +459,119 @@ impl QuadTree {
             }
         }
 
-        assert_eq!(mem::size_of::<NodeState>(), 256);
-        assert!(self.node_states.len() < MAX_RENDERED_NODES);
+        assert_eq!(mem::size_of::<NodeState>(), 288);
+        assert!(self.node_states.len() <= self.max_rendered_nodes);
         queue.write_buffer(vertex_buffer, 0, bytemuck::cast_slice(&self.node_states));
+        if cache.custom_layers().next().is_some() {
+            queue.write_buffer(
+                custom_layer_descs_buffer,
+                0,
+                bytemuck::cast_slice(&self.custom_layer_descs),
+            );
+        }
+    }
+
+    /// Record a compute pass that fills in the indirect draw arguments of every node, skipping any
+    /// that are hidden according to the depth pyramid.
+    pub(crate) fn cull_nodes(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        gpu_state: &GpuState,
+    ) {
+        let uniforms = self.cull_uniforms();
+        self.cull.refresh();
+        self.cull.run(
+            device,
+            encoder,
+            gpu_state,
+            ((uniforms.num_nodes + 63) / 64, 1, 1),
+            &uniforms,
+        );
+    }
+
+    /// Like `cull_nodes`, but culls against shadow cascade `cascade` (whose copy of the global
+    /// uniforms is in `globals`) and writes the draw arguments to `indirect`.
+    pub(crate) fn cull_shadow_nodes(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        gpu_state: &GpuState,
+        cascade: usize,
+        globals: &wgpu::Buffer,
+        indirect: &wgpu::Buffer,
+    ) {
+        while self.shadow_cull.len() <= cascade {
+            self.shadow_cull.push(ComputeShader::new(
+                rshader::shader_source!(
+                    "../../shaders",
+                    "cull-nodes.comp",
+                    "declarations.glsl",
+                    "hiz.glsl"
+                ),
+                format!("cull-nodes.shadow{}", self.shadow_cull.len()),
+            ));
+        }
+
+        let uniforms = self.cull_uniforms();
+        let binding = |buffer| {
+            let resource = wgpu::BufferBinding { buffer, offset: 0, size: None };
+            (false, wgpu::BindingResource::Buffer(resource))
+        };
+        let shader = &mut self.shadow_cull[cascade];
+        shader.refresh();
+        shader.run_with_buffers(
+            device,
+            encoder,
+            gpu_state,
+            ((uniforms.num_nodes + 63) / 64, 1, 1),
+            &uniforms,
+            hashmap![
+                "globals".into() => binding(globals),
+                "nodes_indirect".into() => binding(indirect),
+            ],
+        );
+    }
+
+    fn cull_uniforms(&self) -> CullNodesUniforms {
+        let resolution = self.heights_resolution;
+        CullNodesUniforms {
+            num_nodes: self.node_states.len() as u32,
+            num_full_nodes: self.visible_nodes.len() as u32,
+            full_indices: resolution * resolution * 6,
+            partial_indices: (resolution / 2) * (resolution / 2) * 6,
+        }
+    }
+
+    /// Recreate the culling bind groups, which refer to the depth pyramid.
+    pub(crate) fn reset_cull_bindings(&mut self) {
+        self.cull.reset_bindings();
+        for shader in &mut self.shadow_cull {
+            shader.reset_bindings();
+        }
     }
 
     pub(crate) fn render<'b, 'c>(
         &self,
+        device: &wgpu::Device,
         rpass: &'b mut wgpu::RenderPass<'c>,
         index_buffer: &'c wgpu::Buffer,
         bind_group: &'c wgpu::BindGroup,
+        indirect: &'c wgpu::Buffer,
     ) {
-        let resolution = self.heights_resolution;
-        let visible_nodes = self.visible_nodes.len() as u32;
         let total_nodes = self.node_states.len() as u32;
 
-        let num_indices_full = resolution * resolution * 6;
-        let num_indices_partial = (resolution / 2) * (resolution / 2) * 6;
-
         rpass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint16);
         rpass.set_bind_group(0, bind_group, &[]);
-        rpass.draw_indexed(0..num_indices_full, 0, 0..visible_nodes);
-        rpass.draw_indexed(
-            num_indices_full..(num_indices_full + num_indices_partial),
-            0,
-            visible_nodes..total_nodes,
-        );
+        if device.features().contains(wgpu::Features::MULTI_DRAW_INDIRECT) {
+            rpass.multi_draw_indexed_indirect(indirect, 0, total_nodes);
+        } else {
+            for i in 0..total_nodes {
+                rpass.draw_indexed_indirect(
+                    indirect,
+                    i as u64 * mem::size_of::<DrawIndexedIndirect>() as u64,
+                );
+            }
+        }
     }
 }
@@ -1,4 +1,4 @@
-use crate::coordinates;
+use crate::coordinates::PlanetDesc;
 use anyhow::Error;
 use bit_vec::BitVec;
 use crossbeam::channel::{self, Receiver, Sender};
@@ -61,7 +61,7 @@ impl<T: Into<f64> + Copy, C: Deref<Target = [T]>> Raster<T, C> {
     /// Returns the vertical spacing between cells, in meters.
     #[allow(unused)]
     pub fn vertical_spacing(&self) -> f64 {
-        self.cell_size.to_radians() * coordinates::PLANET_RADIUS
+        self.cell_size.to_radians() * PlanetDesc::EARTH.radius
     }
 
     /// Returns the horizontal spacing between cells, in meters.
@@ -260,8 +260,8 @@ impl<T: Into<f64> + Copy, C: Index<usize, Output = T>> GlobalRaster<T, C> {
     /// Returns the approximate grid spacing in meters.
     #[allow(unused)]
     pub fn spacing(&self) -> f64 {
-        let sx = 2.0 * PI * coordinates::PLANET_RADIUS / self.width as f64;
-        let sy = PI * coordinates::PLANET_RADIUS / self.height as f64;
+        let sx = 2.0 * PI * PlanetDesc::EARTH.radius / self.width as f64;
+        let sy = PI * PlanetDesc::EARTH.radius / self.height as f64;
         sx.min(sy)
     }
 
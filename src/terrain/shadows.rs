@@ -0,0 +1,219 @@
+use crate::{
+    gpu_state::{DrawIndexedIndirect, GlobalUniformBlock, GpuState},
+    terrain::hiz::HiZUniforms,
+    utils::math::InfiniteFrustum,
+};
+use cgmath::{InnerSpace, Matrix4, SquareMatrix, Vector3, Vector4};
+use std::num::NonZeroU32;
+
+pub(crate) const NUM_CASCADES: usize = 4;
+
+/// Width and height of each cascade, in texels.
+const RESOLUTION: u32 = 2048;
+
+/// Distances from the camera along the view direction at which each cascade starts and ends.
+/// Nothing beyond the last split receives shadows.
+const CASCADE_SPLITS: [f64; NUM_CASCADES + 1] = [0.0, 64.0, 512.0, 4096.0, 32768.0];
+
+/// How far beyond the bounds of each cascade to look for shadow casters in the direction of the
+/// sun.
+const CASTER_DISTANCE: f64 = 20000.0;
+
+pub(crate) const SHADOW_MAP_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
+/// Per cascade parameters used when sampling the shadow map, see `ShadowCascade` in
+/// declarations.glsl.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub(crate) struct ShadowCascadeUniforms {
+    view_proj: [[f32; 4]; 4],
+    texel_size: f32,
+    _padding: [f32; 3],
+}
+unsafe impl bytemuck::Pod for ShadowCascadeUniforms {}
+unsafe impl bytemuck::Zeroable for ShadowCascadeUniforms {}
+
+pub(crate) struct Cascade {
+    /// Projection from positions relative to the camera into the cascade, with depth increasing
+    /// away from the sun.
+    view_proj: Matrix4<f64>,
+    texel_size: f64,
+    /// Everything that could cast shadows into the cascade, in world space.
+    pub caster_volume: InfiniteFrustum,
+
+    /// Copy of the global uniforms with the view projection matrix and frustum of the cascade, so
+    /// that the regular vertex and culling shaders can be used to draw into it.
+    pub globals: wgpu::Buffer,
+    pub node_indirect: wgpu::Buffer,
+    pub view: wgpu::TextureView,
+}
+
+/// Cascaded shadow maps for the sun. Each cascade covers a slice of the view frustum, with nearer
+/// slices getting more detailed cascades.
+pub(crate) struct Shadows {
+    pub cascades: Vec<Cascade>,
+}
+impl Shadows {
+    pub fn new(device: &wgpu::Device, gpu_state: &mut GpuState, max_rendered_nodes: usize) -> Self {
+        gpu_state.shadow_map = Self::create_texture(device, RESOLUTION);
+        let cascades = (0..NUM_CASCADES)
+            .map(|i| Cascade {
+                view_proj: Matrix4::identity(),
+                texel_size: 0.0,
+                caster_volume: InfiniteFrustum::from_orthographic(Matrix4::identity()),
+                globals: device.create_buffer(&wgpu::BufferDescriptor {
+                    size: std::mem::size_of::<GlobalUniformBlock>() as u64,
+                    usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::UNIFORM,
+                    label: Some(&format!("buffer.shadow{}.globals", i)),
+                    mapped_at_creation: false,
+                }),
+                node_indirect: device.create_buffer(&wgpu::BufferDescriptor {
+                    size: (std::mem::size_of::<DrawIndexedIndirect>() * max_rendered_nodes) as u64,
+                    usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::INDIRECT,
+                    label: Some(&format!("buffer.shadow{}.nodes_indirect", i)),
+                    mapped_at_creation: false,
+                }),
+                view: gpu_state.shadow_map.create_view(&wgpu::TextureViewDescriptor {
+                    label: Some(&format!("view.shadow_map.cascade{}", i)),
+                    dimension: Some(wgpu::TextureViewDimension::D2),
+                    base_array_layer: i as u32,
+                    array_layer_count: NonZeroU32::new(1),
+                    ..Default::default()
+                }),
+            })
+            .collect();
+        Self { cascades }
+    }
+
+    pub fn create_texture(device: &wgpu::Device, resolution: u32) -> wgpu::Texture {
+        device.create_texture(&wgpu::TextureDescriptor {
+            size: wgpu::Extent3d {
+                width: resolution,
+                height: resolution,
+                depth_or_array_layers: NUM_CASCADES as u32,
+            },
+            format: SHADOW_MAP_FORMAT,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            usage: wgpu::TextureUsage::RENDER_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+            label: Some("texture.shadow_map"),
+        })
+    }
+
+    /// Depth state for pipelines drawing into the cascades. The bias keeps surfaces from shadowing
+    /// themselves.
+    pub fn depth_stencil_state() -> wgpu::DepthStencilState {
+        wgpu::DepthStencilState {
+            format: SHADOW_MAP_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: wgpu::CompareFunction::Less,
+            bias: wgpu::DepthBiasState { constant: 2, slope_scale: 2.0, clamp: 0.0 },
+            stencil: Default::default(),
+        }
+    }
+
+    /// Fit each cascade around its slice of the view frustum. `view_proj` maps positions relative
+    /// to the camera to clip space, with reversed depth.
+    pub fn update(
+        &mut self,
+        view_proj: Matrix4<f64>,
+        camera: mint::Point3<f64>,
+        sun_direction: Vector3<f64>,
+    ) {
+        let camera = Vector3::new(camera.x, camera.y, camera.z);
+        let inverse = view_proj.invert().unwrap();
+        let ray = |x: f64, y: f64| {
+            let p = inverse * Vector4::new(x, y, 1.0, 1.0);
+            p.truncate().normalize()
+        };
+        let forward = ray(0.0, 0.0);
+        let corner_rays = [ray(-1.0, -1.0), ray(1.0, -1.0), ray(-1.0, 1.0), ray(1.0, 1.0)];
+
+        let z_axis = sun_direction.normalize();
+        let up = if z_axis.x.abs() < 0.9 { Vector3::unit_x() } else { Vector3::unit_y() };
+        let x_axis = up.cross(z_axis).normalize();
+        let y_axis = z_axis.cross(x_axis);
+
+        for (i, cascade) in self.cascades.iter_mut().enumerate() {
+            let mut corners = Vec::with_capacity(8);
+            for &distance in &CASCADE_SPLITS[i..i + 2] {
+                for r in &corner_rays {
+                    corners.push(r * (distance / r.dot(forward)));
+                }
+            }
+
+            // Use a bounding sphere so that the size of the cascade doesn't change as the camera
+            // rotates, and snap its center to whole texels in world space so that shadow edges
+            // don't shimmer as the camera moves.
+            let center = corners.iter().fold(Vector3::new(0.0, 0.0, 0.0), |a, &c| a + c) / 8.0;
+            let radius = corners.iter().map(|&c| (c - center).magnitude()).fold(0.0, f64::max);
+            let radius = radius.ceil();
+            let texel_size = 2.0 * radius / RESOLUTION as f64;
+            let world_center = center + camera;
+            let snap = |axis: Vector3<f64>| {
+                axis * ((world_center.dot(axis) / texel_size).round() * texel_size
+                    - world_center.dot(axis))
+            };
+            let center = center + snap(x_axis) + snap(y_axis);
+
+            let eye = center + z_axis * (radius + CASTER_DISTANCE);
+            let depth = 2.0 * radius + CASTER_DISTANCE;
+            let row = |axis: Vector3<f64>, scale: f64| {
+                Vector4::new(axis.x, axis.y, axis.z, -axis.dot(eye)) * scale
+            };
+            let (rx, ry, rz) =
+                (row(x_axis, 1.0 / radius), row(y_axis, 1.0 / radius), row(-z_axis, 1.0 / depth));
+            cascade.view_proj = Matrix4::new(
+                rx.x, ry.x, rz.x, 0.0, rx.y, ry.y, rz.y, 0.0, rx.z, ry.z, rz.z, 0.0, rx.w, ry.w,
+                rz.w, 1.0,
+            );
+            cascade.texel_size = texel_size;
+            cascade.caster_volume = InfiniteFrustum::from_orthographic(
+                cascade.view_proj * Matrix4::from_translation(-camera),
+            );
+        }
+    }
+
+    /// Upload the per cascade copies of `globals`, along with the uniforms used to sample the
+    /// cascades.
+    pub fn write_uniforms(
+        &self,
+        queue: &wgpu::Queue,
+        gpu_state: &GpuState,
+        globals: &GlobalUniformBlock,
+    ) {
+        let mut cascade_uniforms = Vec::new();
+        for cascade in &self.cascades {
+            let view_proj = cascade.view_proj.cast::<f32>().unwrap();
+            let frustum = InfiniteFrustum::from_orthographic(cascade.view_proj);
+            queue.write_buffer(
+                &cascade.globals,
+                0,
+                bytemuck::bytes_of(&GlobalUniformBlock {
+                    view_proj: view_proj.into(),
+                    view_proj_inverse: view_proj.invert().unwrap().into(),
+                    frustum_planes: [
+                        frustum.planes[0].cast().unwrap().into(),
+                        frustum.planes[1].cast().unwrap().into(),
+                        frustum.planes[2].cast().unwrap().into(),
+                        frustum.planes[3].cast().unwrap().into(),
+                        frustum.planes[4].cast().unwrap().into(),
+                    ],
+                    hiz: HiZUniforms { levels: 0, ..globals.hiz },
+                    ..*globals
+                }),
+            );
+            cascade_uniforms.push(ShadowCascadeUniforms {
+                view_proj: view_proj.into(),
+                texel_size: cascade.texel_size as f32,
+                _padding: [0.0; 3],
+            });
+        }
+        queue.write_buffer(&gpu_state.shadow_cascades, 0, bytemuck::cast_slice(&cascade_uniforms));
+    }
+
+    pub fn caster_volumes(&self) -> Vec<InfiniteFrustum> {
+        self.cascades.iter().map(|c| c.caster_volume.clone()).collect()
+    }
+}
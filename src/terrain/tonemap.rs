@@ -0,0 +1,271 @@
+use crate::{generate::ComputeShader, gpu_state::GpuState, RenderTargets};
+use std::{collections::HashMap, time::Instant};
+
+/// Format of the intermediate target that the scene is rendered into before tonemapping.
+pub(crate) const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+/// Exposure used until the first luminance histogram has been computed, corresponding to an EV100
+/// of 15.
+pub(crate) const INITIAL_EXPOSURE: f32 = 1.0 / (1.2 * 32768.0);
+
+/// Range of (already exposed) log2 luminances covered by the histogram.
+const MIN_LOG_LUMINANCE: f32 = -12.0;
+const LOG_LUMINANCE_RANGE: f32 = 18.0;
+
+/// Rate at which the exposure adapts to changes in scene brightness, per second.
+const ADAPTATION_SPEED: f32 = 1.5;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub(crate) struct ExposureUniforms {
+    resolution: [u32; 2],
+    min_log_luminance: f32,
+    log_luminance_range: f32,
+    /// Fraction of the way to move from the current exposure to the target one.
+    adaptation: f32,
+    exposure_compensation: f32,
+    _padding: [u32; 2],
+}
+unsafe impl bytemuck::Pod for ExposureUniforms {}
+unsafe impl bytemuck::Zeroable for ExposureUniforms {}
+
+/// Owns the HDR target that the scene is rendered into, and the passes that pick an exposure from
+/// its luminance histogram and tonemap it into the output.
+///
+/// Shaders rendering into the HDR target must scale their output by the `exposure` buffer, so that
+/// bright scenes don't exceed the range of half floats. The exposure computed from one frame is
+/// applied to the next.
+pub(crate) struct Tonemapper {
+    resolution: (u32, u32),
+    /// Multisampled HDR target, which is resolved into `GpuState::hdr_color`. Only present when
+    /// rendering with more than one sample per pixel.
+    multisampled_view: Option<wgpu::TextureView>,
+    resolved_view: Option<wgpu::TextureView>,
+    last_frame: Option<Instant>,
+
+    histogram: ComputeShader<ExposureUniforms>,
+    average: ComputeShader<ExposureUniforms>,
+
+    shader: rshader::ShaderSet,
+    bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::RenderPipeline)>,
+}
+impl Tonemapper {
+    pub fn new(output_format: wgpu::TextureFormat) -> Self {
+        // Unorm targets without an sRGB format need the transfer function applied by the shader.
+        let encode_srgb = matches!(
+            output_format,
+            wgpu::TextureFormat::Rgba8Unorm
+                | wgpu::TextureFormat::Bgra8Unorm
+                | wgpu::TextureFormat::Rgb10a2Unorm
+        );
+        let fragment = if encode_srgb {
+            rshader::shader_source!("../shaders", "tonemap.frag", "declarations.glsl"; "ENCODE_SRGB" = "1")
+        } else {
+            rshader::shader_source!("../shaders", "tonemap.frag", "declarations.glsl"; "ENCODE_SRGB" = "0")
+        };
+
+        Self {
+            resolution: (0, 0),
+            multisampled_view: None,
+            resolved_view: None,
+            last_frame: None,
+            histogram: ComputeShader::new(
+                rshader::shader_source!(
+                    "../shaders",
+                    "exposure-histogram.comp",
+                    "declarations.glsl"
+                ),
+                "exposure-histogram".to_owned(),
+            ),
+            average: ComputeShader::new(
+                rshader::shader_source!("../shaders", "exposure-average.comp", "declarations.glsl"),
+                "exposure-average".to_owned(),
+            ),
+            shader: rshader::ShaderSet::simple(
+                rshader::shader_source!("../shaders", "sky.vert", "declarations.glsl"),
+                fragment,
+            )
+            .unwrap(),
+            bindgroup_pipeline: None,
+        }
+    }
+
+    pub fn create_texture(
+        device: &wgpu::Device,
+        size: (u32, u32),
+        sample_count: u32,
+    ) -> wgpu::Texture {
+        device.create_texture(&wgpu::TextureDescriptor {
+            size: wgpu::Extent3d { width: size.0, height: size.1, depth_or_array_layers: 1 },
+            format: HDR_FORMAT,
+            mip_level_count: 1,
+            sample_count,
+            dimension: wgpu::TextureDimension::D2,
+            usage: if sample_count > 1 {
+                wgpu::TextureUsage::RENDER_ATTACHMENT
+            } else {
+                wgpu::TextureUsage::RENDER_ATTACHMENT | wgpu::TextureUsage::SAMPLED
+            },
+            label: Some(if sample_count > 1 {
+                "texture.hdr_color.multisampled"
+            } else {
+                "texture.hdr_color"
+            }),
+        })
+    }
+
+    /// Reallocate the HDR target if the frame size has changed.
+    pub fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        gpu_state: &mut GpuState,
+        targets: &RenderTargets,
+        resolution: (u32, u32),
+    ) {
+        if resolution == self.resolution {
+            return;
+        }
+
+        self.resolution = resolution;
+        gpu_state.hdr_color = Self::create_texture(device, resolution, 1);
+        self.resolved_view = Some(gpu_state.hdr_color.create_view(&Default::default()));
+        self.multisampled_view = if targets.sample_count > 1 {
+            Some(
+                Self::create_texture(device, resolution, targets.sample_count)
+                    .create_view(&Default::default()),
+            )
+        } else {
+            None
+        };
+
+        self.histogram.reset_bindings();
+        self.bindgroup_pipeline = None;
+    }
+
+    /// Returns the view and resolve target that the scene should be rendered into.
+    pub fn color_attachment(&self) -> (&wgpu::TextureView, Option<&wgpu::TextureView>) {
+        let resolved = self.resolved_view.as_ref().unwrap();
+        match self.multisampled_view {
+            Some(ref view) => (view, Some(resolved)),
+            None => (resolved, None),
+        }
+    }
+
+    /// Make the next exposure update jump straight to the exposure for the current frame, rather
+    /// than adapting towards it over time.
+    pub fn reset_adaptation(&mut self) {
+        self.last_frame = None;
+    }
+
+    /// Record commands to build a luminance histogram of the HDR target and adapt the exposure
+    /// towards it.
+    pub fn update_exposure(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        gpu_state: &GpuState,
+        exposure_compensation: f32,
+    ) {
+        let now = Instant::now();
+        let adaptation = match self.last_frame {
+            Some(last) => 1.0 - (-(now - last).as_secs_f32() * ADAPTATION_SPEED).exp(),
+            None => 1.0,
+        };
+        self.last_frame = Some(now);
+
+        let uniforms = ExposureUniforms {
+            resolution: [self.resolution.0, self.resolution.1],
+            min_log_luminance: MIN_LOG_LUMINANCE,
+            log_luminance_range: LOG_LUMINANCE_RANGE,
+            adaptation,
+            exposure_compensation,
+            _padding: [0; 2],
+        };
+
+        self.histogram.refresh();
+        self.histogram.run(
+            device,
+            encoder,
+            gpu_state,
+            ((self.resolution.0 + 15) / 16, (self.resolution.1 + 15) / 16, 1),
+            &uniforms,
+        );
+        self.average.refresh();
+        self.average.run(device, encoder, gpu_state, (1, 1, 1), &uniforms);
+    }
+
+    /// Record a pass that tonemaps the HDR target into `color_buffer`, resolving it into
+    /// `resolve_target` if one is provided.
+    pub fn render(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        gpu_state: &GpuState,
+        targets: &RenderTargets,
+        color_buffer: &wgpu::TextureView,
+        resolve_target: Option<&wgpu::TextureView>,
+    ) {
+        if self.shader.refresh() {
+            self.bindgroup_pipeline = None;
+        }
+        if self.bindgroup_pipeline.is_none() {
+            let (bind_group, bind_group_layout) = gpu_state.bind_group_for_shader(
+                device,
+                &self.shader,
+                HashMap::new(),
+                HashMap::new(),
+                "tonemap",
+            );
+            let render_pipeline_layout =
+                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    bind_group_layouts: &[&bind_group_layout],
+                    push_constant_ranges: &[],
+                    label: Some("pipeline.tonemap.layout"),
+                });
+            self.bindgroup_pipeline = Some((
+                bind_group,
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    layout: Some(&render_pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                            label: Some("shader.tonemap.vertex"),
+                            source: wgpu::ShaderSource::SpirV(self.shader.vertex().into()),
+                            flags: wgpu::ShaderFlags::empty(),
+                        }),
+                        entry_point: "main",
+                        buffers: &[],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                            label: Some("shader.tonemap.fragment"),
+                            source: wgpu::ShaderSource::SpirV(self.shader.fragment().into()),
+                            flags: wgpu::ShaderFlags::empty(),
+                        }),
+                        entry_point: "main",
+                        targets: &[targets.output_target()],
+                    }),
+                    primitive: Default::default(),
+                    depth_stencil: None,
+                    multisample: targets.multisample(),
+                    label: Some("pipeline.tonemap"),
+                }),
+            ));
+        }
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view: color_buffer,
+                resolve_target,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color { r: 0.0, g: 0.0, b: 0.0, a: 1.0 }),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+            label: Some("renderpass.tonemap"),
+        });
+        rpass.set_pipeline(&self.bindgroup_pipeline.as_ref().unwrap().1);
+        rpass.set_bind_group(0, &self.bindgroup_pipeline.as_ref().unwrap().0, &[]);
+        rpass.draw(0..3, 0..1);
+    }
+}
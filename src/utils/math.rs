@@ -55,6 +55,21 @@ impl InfiniteFrustum {
         }
     }
 
+    /// Volume covered by an orthographic projection with depths from 0 to 1, extended infinitely
+    /// beyond its near plane.
+    pub fn from_orthographic(m: Matrix4<f64>) -> Self {
+        let m = m.transpose();
+        Self {
+            planes: [
+                Self::normalize_plane(m.w + m.x),
+                Self::normalize_plane(m.w - m.x),
+                Self::normalize_plane(m.w + m.y),
+                Self::normalize_plane(m.w - m.y),
+                Self::normalize_plane(m.w - m.z),
+            ],
+        }
+    }
+
     pub fn intersects_sphere(&self, center: Vector3<f64>, radius_squared: f64) -> bool {
         for p in &self.planes[0..5] {
             let distance = p.x * center.x + p.y * center.y + p.z * center.z + p.w;
@@ -65,3 +80,106 @@ impl InfiniteFrustum {
         true
     }
 }
+
+/// Ellipsoid centered at the origin that hides everything below the horizon as seen from the
+/// camera. Tests are done in a scaled space where the ellipsoid is a unit sphere, following
+/// <https://cesium.com/blog/2013/04/25/horizon-culling/>.
+#[derive(Clone, Debug)]
+pub struct HorizonOccluder {
+    radii: Vector3<f64>,
+    /// Camera position in scaled space.
+    camera: Vector3<f64>,
+    /// Squared distance from the camera to the horizon in scaled space.
+    horizon_distance2: f64,
+}
+impl HorizonOccluder {
+    pub fn new(camera: Vector3<f64>, radii: Vector3<f64>) -> Self {
+        let camera = camera.div_element_wise(radii);
+        Self { radii, camera, horizon_distance2: camera.magnitude2() - 1.0 }
+    }
+
+    /// Returns whether a sphere is entirely hidden behind the horizon.
+    pub fn occludes_sphere(&self, center: Vector3<f64>, radius: f64) -> bool {
+        // Nothing is hidden once the camera is inside the occluder.
+        if self.horizon_distance2 <= 0.0 {
+            return false;
+        }
+
+        // In scaled space the sphere becomes an ellipsoid, so use a sphere that bounds it instead.
+        let min_radius = self.radii.x.min(self.radii.y).min(self.radii.z);
+        let radius = radius / min_radius;
+        let v = center.div_element_wise(self.radii) - self.camera;
+
+        // The sphere must be entirely on the far side of the plane containing the horizon...
+        let camera_distance = self.camera.magnitude();
+        if -self.camera.dot(v) - radius * camera_distance <= self.horizon_distance2 {
+            return false;
+        }
+
+        // ...and entirely within the cone from the camera that is tangent to the occluder.
+        let axis_distance = -self.camera.dot(v) / camera_distance;
+        let off_axis_distance = (v.magnitude2() - axis_distance * axis_distance).max(0.0).sqrt();
+        let sin_angle = 1.0 / camera_distance;
+        let cos_angle = self.horizon_distance2.sqrt() / camera_distance;
+        axis_distance * sin_angle - off_axis_distance * cos_angle >= radius
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn horizon_unit_sphere() {
+        let occluder =
+            HorizonOccluder::new(Vector3::new(2.0, 0.0, 0.0), Vector3::new(1.0, 1.0, 1.0));
+
+        // Directly behind the sphere.
+        assert!(occluder.occludes_sphere(Vector3::new(-1.0, 0.0, 0.0), 0.1));
+        // Straddling the back of the sphere, but too large to be hidden.
+        assert!(!occluder.occludes_sphere(Vector3::new(-1.0, 0.0, 0.0), 1.6));
+        // Between the camera and the sphere.
+        assert!(!occluder.occludes_sphere(Vector3::new(1.1, 0.0, 0.0), 0.05));
+        // Just above the horizon, which touches the sphere at x = 0.5.
+        assert!(!occluder.occludes_sphere(Vector3::new(0.5, 0.9, 0.0), 0.01));
+        // Just below the horizon on the far side.
+        assert!(occluder.occludes_sphere(Vector3::new(-0.5, 0.8, 0.0), 0.01));
+
+        // Nothing is occluded from inside the sphere.
+        let inside = HorizonOccluder::new(Vector3::new(0.5, 0.0, 0.0), Vector3::new(1.0, 1.0, 1.0));
+        assert!(!inside.occludes_sphere(Vector3::new(-2.0, 0.0, 0.0), 0.1));
+    }
+
+    #[test]
+    fn horizon_ellipsoid() {
+        let radii = Vector3::new(2.0, 1.0, 1.0);
+        let occluder = HorizonOccluder::new(Vector3::new(4.0, 0.0, 0.0), radii);
+
+        assert!(occluder.occludes_sphere(Vector3::new(-2.0, 0.0, 0.0), 0.1));
+        assert!(!occluder.occludes_sphere(Vector3::new(0.0, 1.2, 0.0), 0.1));
+        assert!(!occluder.occludes_sphere(Vector3::new(0.0, 0.0, 1.2), 0.1));
+        // Hidden by the long axis of the ellipsoid even though it would be visible over a sphere
+        // with the radius of the short axis.
+        assert!(occluder.occludes_sphere(Vector3::new(-0.5, 0.0, 1.2), 0.01));
+        let sphere = HorizonOccluder::new(Vector3::new(4.0, 0.0, 0.0), Vector3::new(1.0, 1.0, 1.0));
+        assert!(!sphere.occludes_sphere(Vector3::new(-0.5, 0.0, 1.2), 0.01));
+    }
+
+    #[test]
+    fn horizon_earth() {
+        let radius = 6371000.0;
+        let occluder = HorizonOccluder::new(
+            Vector3::new(radius + 2.0, 0.0, 0.0),
+            Vector3::new(radius, radius, radius),
+        );
+
+        // A peak 300 km away must be about 7 km tall to be seen from 2 m above the surface.
+        let angle = 300000.0 / radius;
+        let peak = |height: f64| Vector3::new(angle.cos(), angle.sin(), 0.0) * (radius + height);
+        assert!(occluder.occludes_sphere(peak(5000.0), 100.0));
+        assert!(!occluder.occludes_sphere(peak(9000.0), 100.0));
+
+        // The far side of the planet is always hidden.
+        assert!(occluder.occludes_sphere(Vector3::new(-radius, 0.0, 0.0), 1000000.0));
+    }
+}